@@ -0,0 +1,121 @@
+/// Signature of the end of central directory record of a ZIP archive.
+const EOCD_SIGNATURE: u32 = 0x06054b50;
+/// Size of the end of central directory record, excluding the trailing comment.
+const EOCD_SIZE: usize = 22;
+/// Signature of a central directory file header of a ZIP archive.
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+/// Size of a central directory file header, excluding its variable length fields.
+const CENTRAL_HEADER_SIZE: usize = 46;
+/// Header id of the ZIP64 extended information extra field.
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        bytes.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// Get the total uncompressed size declared by the entries of a ZIP archive.
+///
+/// Only the central directory is read, nothing is extracted. Returns `None` if the
+/// bytes are not a ZIP archive or its central directory could not be parsed.
+pub fn zip_uncompressed_size(bytes: &[u8]) -> Option<u64> {
+    // The end of central directory record is at the end of the archive, followed only
+    // by a comment of up to 65535 bytes.
+    let search_start = bytes.len().checked_sub(EOCD_SIZE)?;
+    let search_end = search_start.saturating_sub(u16::MAX as usize);
+    let eocd = (search_end..=search_start)
+        .rev()
+        .find(|offset| read_u32(bytes, *offset) == Some(EOCD_SIGNATURE))?;
+    let entries = read_u16(bytes, eocd + 10)?;
+    let mut offset = read_u32(bytes, eocd + 16)? as usize;
+
+    let mut total: u64 = 0;
+    for _ in 0..entries {
+        if read_u32(bytes, offset)? != CENTRAL_HEADER_SIGNATURE {
+            return None;
+        }
+        let name_len = read_u16(bytes, offset + 28)? as usize;
+        let extra_len = read_u16(bytes, offset + 30)? as usize;
+        let comment_len = read_u16(bytes, offset + 32)? as usize;
+        let mut size = read_u32(bytes, offset + 24)? as u64;
+
+        // Sizes too large for the header are stored in the ZIP64 extra field instead.
+        if size == u32::MAX as u64 {
+            let extra_start = offset + CENTRAL_HEADER_SIZE + name_len;
+            let extra = bytes.get(extra_start..extra_start + extra_len)?;
+            let mut field = 0;
+            loop {
+                let id = read_u16(extra, field)?;
+                let len = read_u16(extra, field + 2)? as usize;
+                if id == ZIP64_EXTRA_ID {
+                    size = read_u64(extra, field + 4)?;
+                    break;
+                }
+                field += 4 + len;
+            }
+        }
+
+        total = total.saturating_add(size);
+        offset += CENTRAL_HEADER_SIZE + name_len + extra_len + comment_len;
+    }
+    Some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    /// Build a ZIP archive with only a central directory declaring the given entry sizes.
+    fn zip_declaring(sizes: &[u32]) -> Vec<u8> {
+        let mut bytes = b"PK\x03\x04".to_vec();
+        let central_directory = bytes.len() as u32;
+        for size in sizes {
+            bytes.extend_from_slice(&0x02014b50u32.to_le_bytes());
+            bytes.extend_from_slice(&[0; 20]);
+            bytes.extend_from_slice(&size.to_le_bytes());
+            bytes.extend_from_slice(&1u16.to_le_bytes());
+            bytes.extend_from_slice(&[0; 16]);
+            bytes.push(b'a');
+        }
+        let central_directory_size = bytes.len() as u32 - central_directory;
+        bytes.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&(sizes.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&(sizes.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&central_directory_size.to_le_bytes());
+        bytes.extend_from_slice(&central_directory.to_le_bytes());
+        bytes.extend_from_slice(&[0; 2]);
+        bytes
+    }
+
+    #[test]
+    fn test_zip_uncompressed_size() {
+        // Test the declared sizes of every entry are summed.
+        assert_eq!(
+            super::zip_uncompressed_size(&zip_declaring(&[100, 200])),
+            Some(300)
+        );
+
+        // Test a small archive declaring a huge expansion reports it without extracting.
+        let bomb = zip_declaring(&[u32::MAX - 1; 16]);
+        assert!(bomb.len() < 1024);
+        assert_eq!(
+            super::zip_uncompressed_size(&bomb),
+            Some((u32::MAX as u64 - 1) * 16)
+        );
+
+        // Test non-archives are not parsed.
+        assert_eq!(super::zip_uncompressed_size(b"not an archive"), None);
+    }
+}
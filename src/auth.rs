@@ -1,18 +1,120 @@
 use crate::AppState;
 use axum::{
     extract::{Request, State},
-    http::StatusCode,
+    http::{HeaderMap, HeaderValue, StatusCode, header},
     middleware::Next,
-    response::Response,
-};
-use axum_extra::{
-    TypedHeader,
-    headers::{Authorization, authorization::Bearer},
+    response::{IntoResponse, Response},
 };
+use bytesize::ByteSize;
+use mime_guess::Mime;
+use std::{collections::HashMap, str::FromStr};
+
+const MISSING_AUTHORIZATION_RESPONSE: (StatusCode, [(header::HeaderName, HeaderValue); 1], &str) = (
+    StatusCode::UNAUTHORIZED,
+    [(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"))],
+    "This request requires an Authorization header with a bearer token",
+);
+
+const MALFORMED_AUTHORIZATION_RESPONSE: (StatusCode, &str) = (
+    StatusCode::BAD_REQUEST,
+    "Authorization header must be in the format 'Bearer <token>'",
+);
+
+const INVALID_TOKEN_RESPONSE: (StatusCode, [(header::HeaderName, HeaderValue); 1], &str) = (
+    StatusCode::UNAUTHORIZED,
+    [(
+        header::WWW_AUTHENTICATE,
+        HeaderValue::from_static("Bearer error=\"invalid_token\""),
+    )],
+    "The provided token is not valid",
+);
 
 #[derive(Debug)]
 pub struct AuthProvider {
-    valid_tokens: Vec<String>,
+    /// Hashes of the accepted tokens, which are compared in constant time regardless of length.
+    valid_token_hashes: Vec<blake3::Hash>,
+    token_mimetypes: HashMap<String, Vec<Mime>>,
+    token_quotas: HashMap<String, TokenQuota>,
+    trusted_tokens: Vec<String>,
+}
+
+/// MIME types that uploads made with a specific token are restricted to.
+#[derive(Debug, Clone)]
+pub struct TokenMimetypes {
+    pub token: String,
+    pub mimetypes: Vec<Mime>,
+}
+
+impl FromStr for TokenMimetypes {
+    type Err = String;
+
+    /// Parse from the format `<token>=<mimetype>;<mimetype>`.
+    ///
+    /// The last `=` separates the token so tokens containing padding are still accepted.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (token, mimetypes) = s
+            .trim()
+            .rsplit_once('=')
+            .ok_or("token MIME types must be in the format '<token>=<mimetype>;<mimetype>'")?;
+        if token.is_empty() {
+            return Err("token cannot be empty".to_string());
+        }
+        let mimetypes = mimetypes
+            .split(';')
+            .map(|mimetype| Mime::from_str(mimetype.trim()).map_err(|err| err.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            token: token.to_string(),
+            mimetypes,
+        })
+    }
+}
+
+/// Limits on the uploads that can be stored with a specific token at once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenQuota {
+    pub token: String,
+    /// Maximum total size of the token's uploads in bytes.
+    pub max_bytes: Option<u64>,
+    /// Maximum number of uploads made with the token.
+    pub max_files: Option<u64>,
+}
+
+impl TokenQuota {
+    /// Whether storing another upload of the given size would exceed the quota, given the
+    /// total size and number of uploads already stored.
+    pub fn is_exceeded_by(&self, (bytes, files): (u64, u64), size: u64) -> bool {
+        self.max_bytes
+            .is_some_and(|max_bytes| bytes.saturating_add(size) > max_bytes)
+            || self.max_files.is_some_and(|max_files| files >= max_files)
+    }
+}
+
+impl FromStr for TokenQuota {
+    type Err = String;
+
+    /// Parse from the format `<token>=<max bytes>:<max files>`, either limit may be left empty.
+    ///
+    /// The last `=` separates the token so tokens containing padding are still accepted.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const FORMAT_ERROR: &str =
+            "token quotas must be in the format '<token>=<max bytes>:<max files>'";
+        let (token, limits) = s.trim().rsplit_once('=').ok_or(FORMAT_ERROR)?;
+        if token.is_empty() {
+            return Err("token cannot be empty".to_string());
+        }
+        let (max_bytes, max_files) = limits.split_once(':').ok_or(FORMAT_ERROR)?;
+        let (max_bytes, max_files) = (max_bytes.trim(), max_files.trim());
+        Ok(Self {
+            token: token.to_string(),
+            max_bytes: (!max_bytes.is_empty())
+                .then(|| ByteSize::from_str(max_bytes).map(|size| size.0))
+                .transpose()?,
+            max_files: (!max_files.is_empty())
+                .then(|| max_files.parse().map_err(|_| "max files must be a number"))
+                .transpose()?,
+        })
+    }
 }
 
 #[derive(PartialEq, Eq)]
@@ -22,29 +124,216 @@ pub enum AuthState {
 }
 
 impl AuthProvider {
-    pub fn new(valid_tokens: Vec<String>) -> Self {
-        Self { valid_tokens }
+    pub fn new(valid_tokens: Vec<String>, token_mimetypes: Vec<TokenMimetypes>) -> Self {
+        Self {
+            valid_token_hashes: valid_tokens
+                .iter()
+                .map(|token| blake3::hash(token.as_bytes()))
+                .collect(),
+            token_mimetypes: token_mimetypes
+                .into_iter()
+                .map(|restriction| (restriction.token, restriction.mimetypes))
+                .collect(),
+            token_quotas: HashMap::new(),
+            trusted_tokens: Vec::new(),
+        }
+    }
+
+    /// Limit the uploads that can be stored with specific tokens.
+    pub fn with_token_quotas(mut self, token_quotas: Vec<TokenQuota>) -> Self {
+        self.token_quotas = token_quotas
+            .into_iter()
+            .map(|quota| (quota.token.clone(), quota))
+            .collect();
+        self
+    }
+
+    /// Get the quota on uploads made with the given token, if it has one.
+    pub fn quota_for_token(&self, token: &str) -> Option<&TokenQuota> {
+        self.token_quotas.get(token)
+    }
+
+    /// Store uploads made with any of the given tokens exactly as they were received.
+    pub fn with_trusted_tokens(mut self, trusted_tokens: Vec<String>) -> Self {
+        self.trusted_tokens = trusted_tokens;
+        self
+    }
+
+    /// Whether uploads made with the given token skip all post-processing.
+    pub fn is_trusted(&self, token: &str) -> bool {
+        self.trusted_tokens.iter().any(|trusted| trusted == token)
+    }
+
+    /// Get the MIME types that uploads made with the given token are restricted to, if any.
+    ///
+    /// These further restrict the server's allowed upload MIME types rather than replacing them.
+    pub fn mimetypes_for_token(&self, token: &str) -> Option<&Vec<Mime>> {
+        self.token_mimetypes.get(token)
+    }
+
+    /// Get the number of tokens that are accepted.
+    pub fn token_count(&self) -> usize {
+        self.valid_token_hashes.len()
     }
 
     /// Get the [`AuthState`] for the provided token.
+    ///
+    /// The token's hash is compared against every accepted token without stopping early, so the
+    /// time taken reveals nothing about how much of a token matched.
     pub fn state_for_token(&self, token: &str) -> AuthState {
-        match self.valid_tokens.iter().any(|f| f == token) {
+        let hash = blake3::hash(token.as_bytes());
+        match self
+            .valid_token_hashes
+            .iter()
+            .fold(false, |valid, valid_hash| valid | (*valid_hash == hash))
+        {
             true => AuthState::Valid,
             false => AuthState::Invalid,
         }
     }
 
-    /// Middleware that will ensure that the request's [`TypedHeader<Authorization<Bearer>>`] contains a
-    /// token that resolves as [`AuthState::Valid`].
+    /// Middleware that will ensure that the request's `Authorization` header contains a
+    /// bearer token that resolves as [`AuthState::Valid`].
+    ///
+    /// Missing headers, malformed headers and invalid tokens are each given a distinct response.
     pub async fn valid_auth_middleware(
         State(state): State<AppState>,
-        TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
         request: Request,
         next: Next,
-    ) -> Result<Response, StatusCode> {
-        if state.auth_provider.state_for_token(authorization.token()) != AuthState::Valid {
-            return Err(StatusCode::UNAUTHORIZED);
+    ) -> Response {
+        let token = match bearer_token(request.headers()) {
+            Ok(Some(token)) => token,
+            Ok(None) => return MISSING_AUTHORIZATION_RESPONSE.into_response(),
+            Err(()) => return MALFORMED_AUTHORIZATION_RESPONSE.into_response(),
+        };
+        if state.auth_provider.state_for_token(token) != AuthState::Valid {
+            return INVALID_TOKEN_RESPONSE.into_response();
+        }
+        next.run(request).await
+    }
+}
+
+/// Get the bearer token from the `Authorization` header.
+///
+/// Returns `Ok(None)` when there is no header and `Err` when it is not a bearer token.
+pub fn bearer_token(headers: &HeaderMap) -> Result<Option<&str>, ()> {
+    let Some(value) = headers.get(header::AUTHORIZATION) else {
+        return Ok(None);
+    };
+    let value = value.to_str().map_err(|_| ())?;
+    let (scheme, token) = value.split_once(' ').ok_or(())?;
+    let token = token.trim();
+    if !scheme.eq_ignore_ascii_case("bearer") || token.is_empty() || token.contains(' ') {
+        return Err(());
+    }
+    Ok(Some(token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuthProvider, AuthState, TokenMimetypes, TokenQuota};
+    use axum::http::{HeaderMap, HeaderValue, header};
+    use mime_guess::mime;
+    use std::str::FromStr;
+
+    fn headers(authorization: Option<&'static str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(authorization) = authorization {
+            headers.insert(
+                header::AUTHORIZATION,
+                HeaderValue::from_static(authorization),
+            );
         }
-        Ok(next.run(request).await)
+        headers
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        // Test a missing header is distinguished from a malformed one.
+        assert_eq!(super::bearer_token(&headers(None)), Ok(None));
+
+        // Test bearer tokens are extracted regardless of scheme case.
+        assert_eq!(
+            super::bearer_token(&headers(Some("Bearer abc"))),
+            Ok(Some("abc"))
+        );
+        assert_eq!(
+            super::bearer_token(&headers(Some("bearer abc"))),
+            Ok(Some("abc"))
+        );
+
+        // Test other schemes and malformed values are rejected.
+        assert!(super::bearer_token(&headers(Some("Basic abc"))).is_err());
+        assert!(super::bearer_token(&headers(Some("Bearer"))).is_err());
+        assert!(super::bearer_token(&headers(Some("Bearer "))).is_err());
+        assert!(super::bearer_token(&headers(Some("abc"))).is_err());
+    }
+
+    #[test]
+    fn test_state_for_token() {
+        let provider = AuthProvider::new(vec!["first-token".into(), "other-token".into()], vec![]);
+
+        // Test every configured token is accepted.
+        assert!(provider.state_for_token("first-token") == AuthState::Valid);
+        assert!(provider.state_for_token("other-token") == AuthState::Valid);
+
+        // Test tokens of the same length, prefixes and empty tokens are rejected.
+        assert!(provider.state_for_token("first-tokem") == AuthState::Invalid);
+        assert!(provider.state_for_token("first-") == AuthState::Invalid);
+        assert!(provider.state_for_token("") == AuthState::Invalid);
+    }
+
+    #[test]
+    fn test_token_mimetypes_from_str() {
+        // Test a token with several MIME types is parsed.
+        let parsed = TokenMimetypes::from_str("abc=image/png;video/*").unwrap();
+        assert_eq!(parsed.token, "abc");
+        assert_eq!(
+            parsed.mimetypes,
+            vec![mime::IMAGE_PNG, mime::Mime::from_str("video/*").unwrap()]
+        );
+
+        // Test tokens ending in padding are kept intact.
+        assert_eq!(
+            TokenMimetypes::from_str("abc==image/png").unwrap().token,
+            "abc="
+        );
+
+        // Test missing tokens or invalid MIME types are rejected.
+        assert!(TokenMimetypes::from_str("image/png").is_err());
+        assert!(TokenMimetypes::from_str("=image/png").is_err());
+        assert!(TokenMimetypes::from_str("abc=not a mime").is_err());
+    }
+
+    #[test]
+    fn test_token_quota_from_str() {
+        // Test both limits are parsed, with sizes in any unit.
+        let parsed = TokenQuota::from_str("abc=1KiB:10").unwrap();
+        assert_eq!(parsed.token, "abc");
+        assert_eq!(parsed.max_bytes, Some(1024));
+        assert_eq!(parsed.max_files, Some(10));
+
+        // Test either limit can be left empty for no limit.
+        assert_eq!(TokenQuota::from_str("abc=:10").unwrap().max_bytes, None);
+        assert_eq!(TokenQuota::from_str("abc=1MB:").unwrap().max_files, None);
+
+        // Test malformed quotas are rejected.
+        assert!(TokenQuota::from_str("abc=1MB").is_err());
+        assert!(TokenQuota::from_str("=1MB:10").is_err());
+        assert!(TokenQuota::from_str("abc=lots:10").is_err());
+        assert!(TokenQuota::from_str("abc=1MB:many").is_err());
+    }
+
+    #[test]
+    fn test_token_quota_is_exceeded_by() {
+        let quota = TokenQuota::from_str("abc=100:2").unwrap();
+
+        // Test uploads within both limits are permitted.
+        assert!(!quota.is_exceeded_by((0, 0), 100));
+        assert!(!quota.is_exceeded_by((60, 1), 40));
+
+        // Test uploads past either limit are refused.
+        assert!(quota.is_exceeded_by((60, 1), 41));
+        assert!(quota.is_exceeded_by((0, 2), 1));
     }
 }
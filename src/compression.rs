@@ -0,0 +1,63 @@
+use anyhow::{Result, bail};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use infer::MatcherType;
+use std::io::{Read, Write};
+
+/// Whether compressing the bytes is likely to make them smaller.
+///
+/// Images, video, audio and archives are almost always compressed already.
+pub fn is_compressible(bytes: &[u8]) -> bool {
+    !infer::get(bytes).is_some_and(|kind| {
+        matches!(
+            kind.matcher_type(),
+            MatcherType::Image | MatcherType::Video | MatcherType::Audio | MatcherType::Archive
+        )
+    })
+}
+
+/// Compress bytes with gzip at the given level.
+pub fn compress(bytes: &[u8], level: u32) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+    encoder.write_all(bytes)?;
+    Ok(encoder.finish()?)
+}
+
+/// Decompress bytes compressed by [`compress`], failing if they decompress to more than `limit`
+/// bytes so a small payload can't be expanded into an unbounded allocation.
+pub fn decompress(bytes: &[u8], limit: u64) -> Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    GzDecoder::new(bytes)
+        .take(limit.saturating_add(1))
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() as u64 > limit {
+        bail!("decompressed upload is larger than {limit} bytes");
+    }
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_compression() {
+        let text = "compressible text ".repeat(100);
+
+        // Test compressed bytes are smaller and decompress to the original.
+        let compressed = super::compress(text.as_bytes(), 6).unwrap();
+        assert!(compressed.len() < text.len());
+        assert_eq!(
+            super::decompress(&compressed, text.len() as u64).unwrap(),
+            text.as_bytes()
+        );
+
+        // Test payloads that decompress to more than the limit are rejected.
+        let bomb = super::compress(&vec![0; 16 * 1024 * 1024], 9).unwrap();
+        assert!(bomb.len() < 64 * 1024);
+        assert!(super::decompress(&bomb, 1024 * 1024).is_err());
+        assert!(super::decompress(&compressed, text.len() as u64 - 1).is_err());
+
+        // Test already compressed types are skipped.
+        assert!(super::is_compressible(text.as_bytes()));
+        assert!(!super::is_compressible(&compressed));
+        assert!(!super::is_compressible(b"\x89PNG\r\n\x1a\n"));
+    }
+}
@@ -2,8 +2,8 @@ use anyhow::{Result, bail};
 use base64ct::Encoding;
 use blake3::Hasher;
 use chacha20poly1305::{
-    AeadCore, KeyInit,
-    aead::{Aead, OsRng, generic_array::typenum::Unsigned},
+    AeadCore, KeyInit, KeySizeUser,
+    aead::{Aead, OsRng, generic_array::typenum::Unsigned, rand_core::RngCore},
 };
 
 type CryptoImpl = chacha20poly1305::XChaCha20Poly1305;
@@ -14,14 +14,58 @@ const CRYPTO_NONCE_SIZE: usize = <CryptoImpl as AeadCore>::NonceSize::USIZE;
 #[derive(Debug)]
 pub struct Cryptography;
 
+/// A salted hash computed incrementally as bytes arrive, matching [`Cryptography::hash_bytes`]
+/// for the same bytes without needing them all at once.
+#[derive(Debug, Default)]
+pub struct StreamingHash(Hasher);
+
+impl StreamingHash {
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    /// Add the provided salt and get the hash of all bytes seen.
+    pub fn finalize(mut self, salt: &str) -> String {
+        self.0.update(salt.as_bytes());
+        self.0.finalize().to_hex().to_string()
+    }
+}
+
 impl Cryptography {
-    /// Encrypt a byte array using a random key & nonce.
+    /// Generate a random key for use with [`Cryptography::encrypt_with_key`].
+    pub fn generate_key() -> String {
+        base64ct::Base64UrlUnpadded::encode_string(&CryptoImpl::generate_key(&mut OsRng))
+    }
+
+    /// Derive a key from a byte array and the provided salt, so identical bytes are always
+    /// encrypted with the same key and can share a single encrypted copy.
+    ///
+    /// The key can only be derived by those who have the bytes, the salt alone doesn't reveal it.
+    pub fn derive_content_key(bytes: &[u8], salt: &str) -> String {
+        let key = blake3::derive_key("dollshare 2025 content keys", salt.as_bytes());
+        base64ct::Base64UrlUnpadded::encode_string(blake3::keyed_hash(&key, bytes).as_bytes())
+    }
+
+    /// Derive a key for another purpose from a key, without revealing the key it was derived from.
+    pub fn derive_subkey(key: &str, context: &str) -> String {
+        base64ct::Base64UrlUnpadded::encode_string(&blake3::derive_key(context, key.as_bytes()))
+    }
+
+    /// Check that a key is in the format produced by [`Cryptography::generate_key`].
+    pub fn is_valid_key(key: &str) -> bool {
+        base64ct::Base64UrlUnpadded::decode_vec(key)
+            .is_ok_and(|key| key.len() == <CryptoImpl as KeySizeUser>::KeySize::USIZE)
+    }
+
+    /// Encrypt a byte array using an existing key & a random nonce.
     ///
-    /// Upon success the decryption key and the encrypted bytes are provided.
-    pub fn encrypt(bytes: &[u8], aad: &[u8]) -> Result<(String, Vec<u8>)> {
-        let key = CryptoImpl::generate_key(&mut OsRng);
+    /// # Notes
+    /// Should only be used with keys provided by [`Cryptography::generate_key`] or derived by
+    /// [`Cryptography::derive_content_key`].
+    pub fn encrypt_with_key(bytes: &[u8], key: &str, aad: &[u8]) -> Result<Vec<u8>> {
+        let key = base64ct::Base64UrlUnpadded::decode_vec(key)?;
         let nonce = CryptoImpl::generate_nonce(&mut OsRng);
-        let cipher = CryptoImpl::new(&key);
+        let cipher = CryptoImpl::new_from_slice(&key)?;
         let mut ciphered_bytes = match cipher.encrypt(&nonce, CryptoPayload { msg: bytes, aad }) {
             Ok(b) => b,
             Err(err) => {
@@ -29,18 +73,17 @@ impl Cryptography {
             }
         };
         ciphered_bytes.splice(..0, nonce.iter().copied());
-        Ok((
-            base64ct::Base64UrlUnpadded::encode_string(&key),
-            ciphered_bytes,
-        ))
+        Ok(ciphered_bytes)
     }
 
     /// Decrypt a byte array with its decryption key.
     ///
     /// # Notes
-    /// Should only be used on values encrypted by [`Cryptography::encrypt`].
+    /// Should only be used on values encrypted by [`Cryptography::encrypt_with_key`].
     pub fn decrypt(bytes: &[u8], key: &str, aad: &[u8]) -> Result<Vec<u8>> {
-        let (nonce, encrypted_bytes) = bytes.split_at(CRYPTO_NONCE_SIZE);
+        let Some((nonce, encrypted_bytes)) = bytes.split_at_checked(CRYPTO_NONCE_SIZE) else {
+            bail!("encrypted bytes are shorter than a nonce");
+        };
         let key = base64ct::Base64UrlUnpadded::decode_vec(key)?;
         let cipher = CryptoImpl::new_from_slice(&key)?;
         match cipher.decrypt(
@@ -55,6 +98,43 @@ impl Cryptography {
         }
     }
 
+    /// Wrap a data key so that it can only be recovered with both the reference and the master key.
+    ///
+    /// The reference is handed out in place of the data key while the master key stays with the
+    /// server, allowing the master key to be changed with [`Cryptography::rewrap_key`] without
+    /// needing the reference.
+    pub fn wrap_key(
+        data_key: &str,
+        reference: &str,
+        master_key: &str,
+        aad: &[u8],
+    ) -> Result<Vec<u8>> {
+        let inner = Self::encrypt_with_key(data_key.as_bytes(), reference, aad)?;
+        Self::encrypt_with_key(&inner, master_key, aad)
+    }
+
+    /// Recover a data key wrapped by [`Cryptography::wrap_key`].
+    pub fn unwrap_key(
+        wrapped: &[u8],
+        reference: &str,
+        master_key: &str,
+        aad: &[u8],
+    ) -> Result<String> {
+        let inner = Self::decrypt(wrapped, master_key, aad)?;
+        Ok(String::from_utf8(Self::decrypt(&inner, reference, aad)?)?)
+    }
+
+    /// Re-wrap a data key wrapped by [`Cryptography::wrap_key`] under a new master key.
+    pub fn rewrap_key(
+        wrapped: &[u8],
+        master_key: &str,
+        new_master_key: &str,
+        aad: &[u8],
+    ) -> Result<Vec<u8>> {
+        let inner = Self::decrypt(wrapped, master_key, aad)?;
+        Self::encrypt_with_key(&inner, new_master_key, aad)
+    }
+
     /// Hash a byte array and add the provided salt.
     ///
     /// Will automatically use multiple threads when the provided
@@ -72,4 +152,129 @@ impl Cryptography {
         hasher.update(salt.as_bytes());
         Ok(hasher.finalize().to_hex().to_string())
     }
+
+    /// Generate a random identifier that is infeasible to guess.
+    pub fn random_id() -> String {
+        let mut bytes = [0; 16];
+        OsRng.fill_bytes(&mut bytes);
+        base64ct::Base64UrlUnpadded::encode_string(&bytes)
+    }
+
+    /// Sign a byte array with a key derived from the provided secret.
+    ///
+    /// The signature is returned as hex and can be checked with [`Cryptography::verify_signature`].
+    pub fn sign(bytes: &[u8], secret: &str) -> String {
+        Self::keyed_hash(bytes, secret).to_hex().to_string()
+    }
+
+    /// Check that a signature from [`Cryptography::sign`] is valid for a byte array.
+    ///
+    /// The comparison is made in constant time.
+    pub fn verify_signature(bytes: &[u8], secret: &str, signature: &str) -> bool {
+        let Ok(signature) = blake3::Hash::from_hex(signature) else {
+            return false;
+        };
+        Self::keyed_hash(bytes, secret) == signature
+    }
+
+    fn keyed_hash(bytes: &[u8], secret: &str) -> blake3::Hash {
+        let key = blake3::derive_key("dollshare 2025 signatures", secret.as_bytes());
+        blake3::keyed_hash(&key, bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cryptography, StreamingHash};
+
+    #[test]
+    fn test_streaming_hash() {
+        let bytes: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+
+        // Test hashing chunks as they arrive matches hashing the whole content at once.
+        let mut hash = StreamingHash::default();
+        for chunk in bytes.chunks(4096) {
+            hash.update(chunk);
+        }
+        assert_eq!(
+            hash.finalize("salt"),
+            Cryptography::hash_bytes(&bytes, "salt").unwrap()
+        );
+
+        // Test the salt is still applied.
+        let mut hash = StreamingHash::default();
+        hash.update(&bytes);
+        assert_ne!(
+            hash.finalize("other"),
+            Cryptography::hash_bytes(&bytes, "salt").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_derive_content_key() {
+        let key = Cryptography::derive_content_key(b"content", "salt");
+
+        // Test identical content always derives the same usable key.
+        assert!(Cryptography::is_valid_key(&key));
+        assert_eq!(Cryptography::derive_content_key(b"content", "salt"), key);
+
+        // Test other content or another salt derives a different key.
+        assert_ne!(Cryptography::derive_content_key(b"contents", "salt"), key);
+        assert_ne!(Cryptography::derive_content_key(b"content", "other"), key);
+    }
+
+    #[test]
+    fn test_wrap_key() {
+        let data_key = Cryptography::generate_key();
+        let reference = Cryptography::generate_key();
+        let master_key = Cryptography::generate_key();
+        let wrapped = Cryptography::wrap_key(&data_key, &reference, &master_key, b"id").unwrap();
+
+        // Test the data key is recovered with the reference and master key.
+        assert_eq!(
+            Cryptography::unwrap_key(&wrapped, &reference, &master_key, b"id").unwrap(),
+            data_key
+        );
+
+        // Test the data key can't be recovered without either key or for another id.
+        let other_key = Cryptography::generate_key();
+        assert!(Cryptography::unwrap_key(&wrapped, &other_key, &master_key, b"id").is_err());
+        assert!(Cryptography::unwrap_key(&wrapped, &reference, &other_key, b"id").is_err());
+        assert!(Cryptography::unwrap_key(&wrapped, &reference, &master_key, b"other").is_err());
+
+        // Test re-wrapping under a new master key keeps the same reference working.
+        let new_master_key = Cryptography::generate_key();
+        let rewrapped =
+            Cryptography::rewrap_key(&wrapped, &master_key, &new_master_key, b"id").unwrap();
+        assert_eq!(
+            Cryptography::unwrap_key(&rewrapped, &reference, &new_master_key, b"id").unwrap(),
+            data_key
+        );
+        assert!(Cryptography::unwrap_key(&rewrapped, &reference, &master_key, b"id").is_err());
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let signature = Cryptography::sign(b"message", "secret");
+
+        // Test a signature is valid for the message it was made for.
+        assert!(Cryptography::verify_signature(
+            b"message", "secret", &signature
+        ));
+
+        // Test a signature is invalid for another message or secret.
+        assert!(!Cryptography::verify_signature(
+            b"messages",
+            "secret",
+            &signature
+        ));
+        assert!(!Cryptography::verify_signature(
+            b"message", "secrets", &signature
+        ));
+
+        // Test malformed signatures are invalid.
+        assert!(!Cryptography::verify_signature(
+            b"message", "secret", "not-hex"
+        ));
+    }
 }
@@ -0,0 +1,83 @@
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use std::io;
+#[cfg(any(feature = "storage-azure", feature = "storage-webdav"))]
+use {
+    anyhow::{Context, Result},
+    axum::body::Bytes,
+    http_body_util::{BodyExt, Full},
+    hyper::{Method, Request, StatusCode, header::HeaderMap},
+    hyper_util::{
+        client::legacy::{Client, connect::HttpConnector},
+        rt::TokioExecutor,
+    },
+    serde::de::DeserializeOwned,
+    url::Url,
+};
+
+/// Client used by storage backends, which always send whole bodies.
+#[cfg(any(feature = "storage-azure", feature = "storage-webdav"))]
+pub type StorageClient = Client<HttpsConnector<HttpConnector>, Full<Bytes>>;
+
+/// Wrap a connector so it can reach servers over both HTTP and HTTPS.
+pub fn https_connector<H>(http: H) -> io::Result<HttpsConnector<H>> {
+    Ok(HttpsConnectorBuilder::new()
+        .with_provider_and_native_roots(rustls::crypto::ring::default_provider())?
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(http))
+}
+
+/// Create a client for storage backends that reaches servers over both HTTP and HTTPS.
+///
+/// Connections are tied to the runtime that opened them, so a client used to set up storage
+/// on its own runtime should not be kept for use afterwards.
+#[cfg(any(feature = "storage-azure", feature = "storage-webdav"))]
+pub fn storage_client() -> io::Result<StorageClient> {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    Ok(Client::builder(TokioExecutor::new()).build(https_connector(http)?))
+}
+
+/// Build a request with the given headers and body, which is empty when `None`.
+#[cfg(any(feature = "storage-azure", feature = "storage-webdav"))]
+pub fn request(
+    method: Method,
+    url: &Url,
+    headers: &[(&'static str, &str)],
+    body: Option<&[u8]>,
+) -> Result<Request<Full<Bytes>>> {
+    let body = Bytes::copy_from_slice(body.unwrap_or_default());
+    let mut request = Request::builder()
+        .method(method)
+        .uri(url.as_str())
+        .header("content-length", body.len())
+        .body(Full::new(body))?;
+    for (name, value) in headers {
+        request
+            .headers_mut()
+            .insert(*name, value.parse().context("invalid header value")?);
+    }
+    Ok(request)
+}
+
+/// Send a request, returning the status, headers and body of the response.
+#[cfg(any(feature = "storage-azure", feature = "storage-webdav"))]
+pub async fn send(
+    client: &StorageClient,
+    request: Request<Full<Bytes>>,
+) -> Result<(StatusCode, HeaderMap, Bytes)> {
+    let response = client.request(request).await?;
+    let (parts, body) = response.into_parts();
+    Ok((
+        parts.status,
+        parts.headers,
+        body.collect().await?.to_bytes(),
+    ))
+}
+
+/// Parse an XML response body, matching elements by name whatever namespace prefix they use.
+#[cfg(any(feature = "storage-azure", feature = "storage-webdav"))]
+pub fn parse_xml<T: DeserializeOwned>(body: &[u8]) -> Result<T> {
+    let body = std::str::from_utf8(body).context("response was not valid utf-8")?;
+    quick_xml::de::from_str(body).context("response was not valid XML")
+}
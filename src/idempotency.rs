@@ -0,0 +1,70 @@
+use crate::upload_locks::{UploadLock, UploadLocks};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Maximum length of an idempotency key supplied by an uploader.
+pub const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+
+/// Remembers the responses of requests made with an idempotency key so retried requests
+/// receive the original response instead of being processed again.
+#[derive(Debug)]
+pub struct IdempotencyCache<T> {
+    ttl: Duration,
+    locks: UploadLocks,
+    responses: Mutex<HashMap<String, (Instant, T)>>,
+}
+
+impl<T: Clone> IdempotencyCache<T> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            locks: UploadLocks::default(),
+            responses: Mutex::default(),
+        }
+    }
+
+    /// Wait for exclusive access to the given key so a retry arriving while the original
+    /// request is still being processed waits for its response.
+    pub async fn lock(&self, key: &str) -> UploadLock {
+        self.locks.lock(key).await
+    }
+
+    /// Get the response recorded for the given key, if it has not expired.
+    pub fn get(&self, key: &str) -> Option<T> {
+        let responses = self.responses.lock().unwrap();
+        let (recorded, response) = responses.get(key)?;
+        (recorded.elapsed() < self.ttl).then(|| response.clone())
+    }
+
+    /// Record the response for the given key, forgetting any that have expired.
+    pub fn insert(&self, key: &str, response: T) {
+        let mut responses = self.responses.lock().unwrap();
+        responses.retain(|_, (recorded, _)| recorded.elapsed() < self.ttl);
+        responses.insert(key.to_string(), (Instant::now(), response));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdempotencyCache;
+    use std::time::Duration;
+
+    #[test]
+    fn test_idempotency_cache() {
+        let cache = IdempotencyCache::new(Duration::from_millis(50));
+
+        // Test recorded responses are returned for the same key only.
+        cache.insert("retry", "response");
+        assert_eq!(cache.get("retry"), Some("response"));
+        assert!(cache.get("other").is_none());
+
+        // Test responses are forgotten once they expire.
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(cache.get("retry").is_none());
+        cache.insert("new", "response");
+        assert_eq!(cache.responses.lock().unwrap().len(), 1);
+    }
+}
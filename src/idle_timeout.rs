@@ -0,0 +1,157 @@
+use axum::serve::Listener;
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep, sleep},
+};
+
+/// A listener whose connections are aborted once they go without reading or writing
+/// any bytes for the idle timeout.
+pub struct IdleTimeoutListener<L> {
+    inner: L,
+    timeout: Option<Duration>,
+}
+
+impl<L> IdleTimeoutListener<L> {
+    /// Wrap a listener, connections are never timed out when `timeout` is `None`.
+    pub fn new(inner: L, timeout: Option<Duration>) -> Self {
+        Self { inner, timeout }
+    }
+}
+
+impl<L: Listener> Listener for IdleTimeoutListener<L> {
+    type Io = IdleTimeoutStream<L::Io>;
+    type Addr = L::Addr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let (io, addr) = self.inner.accept().await;
+        (IdleTimeoutStream::new(io, self.timeout), addr)
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+/// A connection that fails with [`io::ErrorKind::TimedOut`] once it has been waiting
+/// on reads or writes without making any progress for its timeout.
+pub struct IdleTimeoutStream<T> {
+    inner: T,
+    timeout: Option<Duration>,
+    /// When the connection times out, only set while it is waiting without progress.
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T> IdleTimeoutStream<T> {
+    fn new(inner: T, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            timeout,
+            deadline: None,
+        }
+    }
+
+    /// Track the result of polling the connection, failing it once it has been idle too long.
+    fn track<R>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<R>>) -> Poll<io::Result<R>> {
+        let Some(timeout) = self.timeout else {
+            return poll;
+        };
+        if poll.is_ready() {
+            self.deadline = None;
+            return poll;
+        }
+        let deadline = self
+            .deadline
+            .get_or_insert_with(|| Box::pin(sleep(timeout)));
+        match deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                // Start counting again in case the connection is polled after failing.
+                deadline.as_mut().reset(Instant::now() + timeout);
+                Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "connection idle timeout elapsed",
+                )))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for IdleTimeoutStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.track(cx, poll)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for IdleTimeoutStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.track(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.track(cx, poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdleTimeoutStream;
+    use std::{io, time::Duration};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let timeout = Some(Duration::from_millis(100));
+
+        // Test a connection that keeps making progress is not timed out.
+        let (client, server) = duplex(64);
+        let mut stream = IdleTimeoutStream::new(server, timeout);
+        let reader = tokio::spawn(async move {
+            let mut client = client;
+            let mut buf = Vec::new();
+            client.read_to_end(&mut buf).await.unwrap();
+            buf.len()
+        });
+        for _ in 0..4 {
+            stream.write_all(&[0; 64]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        drop(stream);
+        assert_eq!(reader.await.unwrap(), 256);
+
+        // Test a stalled download is aborted once the client stops reading.
+        let (_client, server) = duplex(64);
+        let mut stream = IdleTimeoutStream::new(server, timeout);
+        let err = stream.write_all(&[0; 1024]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // Test connections are never timed out without a timeout.
+        let (_client, server) = duplex(64);
+        let mut stream = IdleTimeoutStream::new(server, None);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), stream.write_all(&[0; 1024]))
+                .await
+                .is_err()
+        );
+    }
+}
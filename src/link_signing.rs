@@ -0,0 +1,79 @@
+use crate::cryptography::Cryptography;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Build the message that is signed for a link to an upload.
+fn link_message(id: &str, key: &str, expires: u64) -> Vec<u8> {
+    format!("{id}\n{expires}\n{key}").into_bytes()
+}
+
+/// Sign a link to an upload that is valid for the given lifetime from now.
+///
+/// Returns when the link expires as seconds since the Unix epoch and its signature.
+pub fn sign_link(id: &str, key: &str, lifetime: Duration, secret: &str) -> (u64, String) {
+    let expires = (SystemTime::now() + lifetime)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    (
+        expires,
+        Cryptography::sign(&link_message(id, key, expires), secret),
+    )
+}
+
+/// Check that a link to an upload has not expired and has a valid signature.
+pub fn verify_link(
+    id: &str,
+    key: &str,
+    expires: u64,
+    signature: &str,
+    secret: &str,
+    now: SystemTime,
+) -> bool {
+    if UNIX_EPOCH + Duration::from_secs(expires) < now {
+        return false;
+    }
+    Cryptography::verify_signature(&link_message(id, key, expires), secret, signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_verify_link() {
+        let now = SystemTime::now();
+        let (expires, signature) =
+            super::sign_link("abc.png", "key", Duration::from_secs(60), "secret");
+
+        // Test a valid signature is accepted.
+        assert!(super::verify_link(
+            "abc.png", "key", expires, &signature, "secret", now
+        ));
+
+        // Test an expired signature is rejected.
+        assert!(!super::verify_link(
+            "abc.png",
+            "key",
+            expires,
+            &signature,
+            "secret",
+            now + Duration::from_secs(120)
+        ));
+
+        // Test tampered links are rejected.
+        assert!(!super::verify_link(
+            "abc.png",
+            "key",
+            expires + 3600,
+            &signature,
+            "secret",
+            now
+        ));
+        assert!(!super::verify_link(
+            "def.png", "key", expires, &signature, "secret", now
+        ));
+        assert!(!super::verify_link(
+            "abc.png", "key", expires, "0000", "secret", now
+        ));
+    }
+}
@@ -0,0 +1,207 @@
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+use std::fmt;
+use tracing::{
+    Event, Subscriber,
+    field::{Field, Visit},
+    span::Record,
+};
+use tracing_subscriber::{
+    EnvFilter,
+    field::RecordFields,
+    fmt::{
+        FmtContext, FormatEvent, FormatFields, FormattedFields,
+        format::Writer,
+        time::{FormatTime, SystemTime},
+    },
+    registry::LookupSpan,
+};
+
+/// Format that log lines are written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[value(name = "text")]
+    Text,
+    /// A JSON object per line, for log aggregators.
+    #[value(name = "json")]
+    Json,
+}
+
+impl LogFormat {
+    /// Install a global subscriber that writes logs to stdout in this format.
+    pub fn init(self, filter: EnvFilter) {
+        let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+        match self {
+            Self::Text => subscriber.init(),
+            Self::Json => subscriber
+                .fmt_fields(JsonFields)
+                .event_format(JsonFormat)
+                .init(),
+        }
+    }
+}
+
+/// Collects the fields of an event or span into a JSON object.
+#[derive(Default)]
+struct JsonVisitor(Map<String, Value>);
+
+impl Visit for JsonVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}").into());
+    }
+}
+
+/// Formats the fields of spans as JSON objects so they can be included in JSON log lines.
+pub struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut visitor = JsonVisitor::default();
+        fields.record(&mut visitor);
+        write!(writer, "{}", Value::Object(visitor.0))
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &Record<'_>,
+    ) -> fmt::Result {
+        let mut visitor = JsonVisitor(
+            serde_json::from_str(&current.fields)
+                .ok()
+                .and_then(|value: Value| value.as_object().cloned())
+                .unwrap_or_default(),
+        );
+        fields.record(&mut visitor);
+        current.fields = Value::Object(visitor.0).to_string();
+        Ok(())
+    }
+}
+
+/// Formats each event as a single line JSON object, including the fields of the spans it is in.
+pub struct JsonFormat;
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+        let mut fields = JsonVisitor::default();
+        event.record(&mut fields);
+
+        let mut line = Map::new();
+        line.insert("timestamp".to_string(), timestamp.into());
+        line.insert(
+            "level".to_string(),
+            event.metadata().level().as_str().into(),
+        );
+        line.insert("target".to_string(), event.metadata().target().into());
+        line.insert("fields".to_string(), Value::Object(fields.0));
+        if let Some(scope) = ctx.event_scope() {
+            let spans = scope
+                .from_root()
+                .map(|span| {
+                    let mut object = span
+                        .extensions()
+                        .get::<FormattedFields<JsonFields>>()
+                        .and_then(|fields| serde_json::from_str::<Value>(&fields.fields).ok())
+                        .and_then(|value| value.as_object().cloned())
+                        .unwrap_or_default();
+                    object.insert("name".to_string(), span.name().into());
+                    Value::Object(object)
+                })
+                .collect();
+            line.insert("spans".to_string(), Value::Array(spans));
+        }
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JsonFields, JsonFormat};
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+    use tracing::{info, info_span};
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_format() {
+        let output = Output::default();
+        let writer = output.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .fmt_fields(JsonFields)
+            .event_format(JsonFormat)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let span = info_span!("request", method = ?axum::http::Method::GET, path = "/upload/abc", status = tracing::field::Empty);
+            let _entered = span.enter();
+            span.record("status", 200);
+            info!(bytes = 5, "Served upload");
+        });
+
+        // Test each event is a JSON object with its fields and the fields of its spans.
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let line: serde_json::Value = serde_json::from_str(output.trim_end()).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["fields"]["message"], "Served upload");
+        assert_eq!(line["fields"]["bytes"], 5);
+        assert_eq!(
+            line["spans"],
+            serde_json::json!([{
+                "name": "request",
+                "method": "GET",
+                "path": "/upload/abc",
+                "status": 200,
+            }])
+        );
+    }
+}
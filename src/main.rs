@@ -1,35 +1,79 @@
+mod archive;
 mod auth;
+mod compression;
 mod cryptography;
+#[cfg(any(
+    feature = "storage-azure",
+    feature = "storage-webdav",
+    feature = "upload-from-url"
+))]
+mod http_client;
+mod idempotency;
+mod idle_timeout;
+mod link_signing;
+mod log_format;
+mod maintenance;
+mod memory_pressure;
+mod metrics;
 mod mime;
+mod perceptual_hash;
+#[cfg(feature = "upload-from-url")]
+mod remote_fetch;
 mod routes;
 mod storage;
+#[cfg(test)]
+#[cfg(feature = "storage-memory")]
+mod test_support;
+mod throughput;
+mod transcode;
+mod upload_budget;
+mod upload_locks;
 
-use anyhow::{Context, Result};
-use auth::AuthProvider;
+use anyhow::{Context, Result, anyhow, bail};
+use auth::{AuthProvider, TokenMimetypes, TokenQuota};
 use axum::{
     Router,
     extract::{DefaultBodyLimit, Request},
     handler::Handler,
-    http::{HeaderValue, header},
+    http::{HeaderName, HeaderValue, Method, header},
     middleware::{self as axum_middleware, Next},
     routing::{delete, get, post},
 };
 use bytesize::ByteSize;
 use clap::Parser;
 use clap_duration::duration_range_value_parse;
+use cryptography::Cryptography;
 use dotenvy::dotenv;
 use duration_human::{DurationHuman, DurationHumanValidator};
+use idempotency::IdempotencyCache;
+use idle_timeout::IdleTimeoutListener;
+use log_format::LogFormat;
+use maintenance::MaintenanceMode;
+use memory_pressure::MemoryPressure;
+use metrics::Metrics;
 use mime_guess::{Mime, mime::IMAGE_STAR};
-use std::{net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
-use storage::{AppStorage, StorageProvider};
-use tokio::{net::TcpListener, signal, sync::RwLock};
+use routes::uploads::{CachePolicy, CreateUploadsResponse, IdStrategy, PublicUrlStrategy};
+use std::{
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, atomic::AtomicUsize},
+    time::{Duration, Instant},
+};
+use storage::{
+    AppStorage, MasterKeys, StorageOptions, StoragePrefix, StorageProvider, StorageScheme,
+};
+use throughput::Throughput;
+use tokio::{net::TcpListener, signal};
 use tower_http::{
     catch_panic::CatchPanicLayer,
+    cors::{AllowOrigin, CorsLayer},
     normalize_path::NormalizePathLayer,
     trace::{DefaultOnFailure, DefaultOnRequest, DefaultOnResponse, TraceLayer},
 };
-use tracing::{Level, debug, info, info_span, warn};
+use tracing::{Level, debug, error, info, info_span, warn};
 use tracing_subscriber::EnvFilter;
+use upload_budget::UploadBudget;
+use upload_locks::UploadLocks;
 use url::Url;
 
 #[derive(Debug, Clone, Parser)]
@@ -43,15 +87,31 @@ struct Arguments {
     )]
     address: SocketAddr,
 
-    /// Base url to use when generating links to uploads.
+    /// One or more base urls to use when generating links to uploads.
+    ///
+    /// The first url is the primary url, links for all urls are included in upload responses.
     ///
     /// This is only for link generation, you'll need to handle the reverse proxy yourself.
     #[arg(
         long = "public-url",
         env = "DOLLSHARE_PUBLIC_URL",
-        default_value = "http://127.0.0.1:8731"
+        default_value = "http://127.0.0.1:8731",
+        value_delimiter = ',',
+        value_parser = parse_public_url
+    )]
+    public_urls: Vec<Url>,
+
+    /// How the public URL used for the primary link to an upload is chosen.
+    ///
+    /// `first` always uses the first URL, `round-robin` cycles through them with every upload,
+    /// and `hash` picks one from the upload id so an upload always gets the same URL.
+    #[clap(
+        long = "public-url-strategy",
+        env = "DOLLSHARE_PUBLIC_URL_STRATEGY",
+        value_enum,
+        default_value_t = PublicUrlStrategy::First
     )]
-    public_url: Url,
+    public_url_strategy: PublicUrlStrategy,
 
     /// One or more bearer tokens to use when interacting with authenticated endpoints.
     #[clap(
@@ -62,22 +122,177 @@ struct Arguments {
     )]
     tokens: Vec<String>,
 
+    /// MIME types that uploads made with specific tokens are restricted to, in the format
+    /// `<token>=<mimetype>;<mimetype>`.
+    ///
+    /// Restrictions further limit the upload MIME types and never permit additional types.
+    #[clap(
+        long = "token-mimetypes",
+        env = "DOLLSHARE_TOKEN_MIMETYPES",
+        value_delimiter = ','
+    )]
+    token_mimetypes: Vec<TokenMimetypes>,
+
+    /// Limits on the total size and number of uploads stored with specific tokens, in the format
+    /// `<token>=<max bytes>:<max files>`.
+    ///
+    /// Either limit may be left empty so it is not limited. Tokens without a quota are unlimited.
+    #[clap(
+        long = "token-quotas",
+        env = "DOLLSHARE_TOKEN_QUOTAS",
+        value_delimiter = ','
+    )]
+    token_quotas: Vec<TokenQuota>,
+
+    /// Tokens whose uploads are stored exactly as received, without inferring their type,
+    /// stripping metadata or transcoding.
+    ///
+    /// The type of trusted uploads is taken from their declared content type or filename and
+    /// must still be permitted. Each trusted token must also be one of the configured tokens.
+    #[clap(
+        long = "trusted-tokens",
+        env = "DOLLSHARE_TRUSTED_TOKENS",
+        value_delimiter = ','
+    )]
+    trusted_tokens: Vec<String>,
+
     /// The storage provider to use for all persistent data.
     ///
     /// Available options depend on what was enabled at compile time, a full list of providers is below.
     ///
-    /// Providers: `memory://`, `fs://<path>`, `s3://bucket`
+    /// Providers: `memory://`, `fs://<path>`, `s3://bucket`, `redis[s]://host:port/db`
+    ///
+    /// A read-only fallback provider can be given as `<primary>?fallback=<fallback>`, data
+    /// missing from the primary provider is read from it.
     #[arg(long = "storage", env = "DOLLSHARE_STORAGE_PROVIDER")]
-    storage: StorageProvider,
+    storage: String,
+
+    /// Storage provider schemes that the `--storage` option is permitted to use.
+    ///
+    /// Schemes that were not enabled at compile time are always unavailable.
+    #[arg(
+        long = "allowed-storage-schemes",
+        env = "DOLLSHARE_ALLOWED_STORAGE_SCHEMES",
+        value_enum,
+        default_values_t = StorageScheme::ALL,
+        value_delimiter = ','
+    )]
+    allowed_storage_schemes: Vec<StorageScheme>,
+
+    /// Prefixes to store uploads of specific MIME types under, in the format
+    /// `<mimetype>;<mimetype>=<prefix>`.
+    ///
+    /// Uploads are stored under the first prefix with a matching MIME type. Uploads stored
+    /// before a prefix is added or changed can no longer be found.
+    #[arg(
+        long = "storage-prefixes",
+        env = "DOLLSHARE_STORAGE_PREFIXES",
+        value_delimiter = ','
+    )]
+    storage_prefixes: Vec<StoragePrefix>,
+
+    /// Copy data read from the fallback storage provider to the primary one.
+    ///
+    /// Gradually migrates data to the primary provider as it is read.
+    #[arg(
+        long = "storage-fallback-copy-forward",
+        env = "DOLLSHARE_STORAGE_FALLBACK_COPY_FORWARD",
+        default_value_t = false
+    )]
+    storage_fallback_copy_forward: bool,
+
+    /// Store uploads under their id without its extension so storage does not reveal their types.
+    ///
+    /// Links still include the extension and uploads can't be served under any other one.
+    /// Uploads stored before this is changed can no longer be found, and it can't be used
+    /// with storage prefixes as they are chosen by type.
+    #[arg(
+        long = "storage-hide-extensions",
+        env = "DOLLSHARE_STORAGE_HIDE_EXTENSIONS",
+        default_value_t = false
+    )]
+    storage_hide_extensions: bool,
+
+    /// File to save memory storage to on graceful shutdown and load it from on startup.
+    ///
+    /// Only used by memory storage. Anything stored since startup is lost if the server
+    /// exits without shutting down gracefully.
+    #[cfg(feature = "storage-memory")]
+    #[arg(long = "preserve-on-shutdown", env = "DOLLSHARE_PRESERVE_ON_SHUTDOWN")]
+    preserve_on_shutdown: Option<std::path::PathBuf>,
+
+    /// Delete every upload in storage before the server starts.
+    ///
+    /// Intended for ephemeral deployments, must be confirmed with `--yes`.
+    #[arg(
+        long = "purge-on-start",
+        env = "DOLLSHARE_PURGE_ON_START",
+        default_value_t = false
+    )]
+    purge_on_start: bool,
+
+    /// Confirm options that irreversibly delete data, such as `--purge-on-start`.
+    #[arg(long = "yes", env = "DOLLSHARE_YES", default_value_t = false)]
+    yes: bool,
+
+    /// Minimum free disk space to leave when storing uploads on the filesystem.
+    ///
+    /// Uploads that would leave less free space than this are rejected as insufficient storage.
+    #[cfg(feature = "storage-filesystem")]
+    #[arg(long = "min-free-disk", env = "DOLLSHARE_MIN_FREE_DISK")]
+    min_free_disk: Option<ByteSize>,
+
+    /// Maximum number of attempts for S3 operations that fail with a transient error.
+    ///
+    /// Retries are made with exponential backoff, errors that cannot succeed on retry fail immediately.
+    #[cfg(feature = "storage-s3")]
+    #[arg(
+        long = "s3-max-attempts",
+        env = "DOLLSHARE_S3_MAX_ATTEMPTS",
+        default_value_t = 3,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    s3_max_attempts: u32,
 
     /// A unique secret to use for file hashing operations.
     #[clap(long = "app-secret", env = "DOLLSHARE_APP_SECRET")]
     app_secret: String,
 
+    /// Master key to wrap the keys of new uploads with, as 32 bytes of unpadded base64url.
+    ///
+    /// Upload links then carry a reference that is unwrapped with the master key rather than the
+    /// upload's key itself, allowing the master key to be rotated without changing any links.
+    #[clap(long = "master-key", env = "DOLLSHARE_MASTER_KEY", value_parser = parse_master_key)]
+    master_key: Option<String>,
+
+    /// Previous master keys that upload keys may still be wrapped with.
+    ///
+    /// Uploads remain accessible while their key is wrapped with any of these, and can be
+    /// re-wrapped with the current master key via the `/admin/rekey` endpoint.
+    #[clap(
+        long = "previous-master-keys",
+        env = "DOLLSHARE_PREVIOUS_MASTER_KEYS",
+        value_delimiter = ',',
+        value_parser = parse_master_key,
+        requires = "master_key"
+    )]
+    previous_master_keys: Vec<String>,
+
     /// Time since since last access before a file is automatically purged from storage.
     #[clap(long = "upload-expiry", env = "DOLLSHARE_UPLOAD_EXPIRY", value_parser = duration_range_value_parse!(min: 30min, max: 100years))]
     upload_expiry: Option<DurationHuman>,
 
+    /// Minimum time after an upload is created before it can be expired, regardless of when it
+    /// was last accessed.
+    #[clap(long = "upload-expiry-grace-period", env = "DOLLSHARE_UPLOAD_EXPIRY_GRACE_PERIOD", value_parser = duration_range_value_parse!(min: 1min, max: 100years))]
+    upload_expiry_grace_period: Option<DurationHuman>,
+
+    /// How often to scan storage for expired uploads.
+    ///
+    /// A scan that takes longer than this is followed by the next one straight away.
+    #[clap(long = "expiry-scan-interval", env = "DOLLSHARE_EXPIRY_SCAN_INTERVAL", default_value = "1min", value_parser = duration_range_value_parse!(min: 10s, max: 1day))]
+    expiry_scan_interval: DurationHuman,
+
     /// Maximum file size that can be uploaded.
     #[clap(
         long = "upload-size-limit",
@@ -86,6 +301,75 @@ struct Arguments {
     )]
     upload_size_limit: ByteSize,
 
+    /// Minimum file size that can be uploaded, which rejects empty files by default.
+    #[clap(
+        long = "min-upload-size",
+        env = "DOLLSHARE_MIN_UPLOAD_SIZE",
+        default_value = "1B"
+    )]
+    min_upload_size: ByteSize,
+
+    /// Private networks that uploads from a URL may be fetched from, as CIDR ranges.
+    ///
+    /// Uploads can only be fetched from public addresses by default so that the server can't
+    /// be used to reach services on its own network.
+    #[cfg(feature = "upload-from-url")]
+    #[clap(
+        long = "upload-from-url-allowed-networks",
+        env = "DOLLSHARE_UPLOAD_FROM_URL_ALLOWED_NETWORKS",
+        value_delimiter = ','
+    )]
+    upload_from_url_allowed_networks: Vec<ipnet::IpNet>,
+
+    /// Maximum size of any single multipart field in an upload request.
+    ///
+    /// Checked separately from the upload size limit, fields over it are rejected as too large.
+    #[clap(
+        long = "upload-field-size-limit",
+        env = "DOLLSHARE_UPLOAD_FIELD_SIZE_LIMIT"
+    )]
+    upload_field_size_limit: Option<ByteSize>,
+
+    /// Maximum number of multipart fields parsed before the upload's file field.
+    ///
+    /// Requests that send more fields than this without the file are rejected, bounding the
+    /// work done on fields sent ahead of it.
+    #[clap(
+        long = "upload-max-leading-fields",
+        env = "DOLLSHARE_UPLOAD_MAX_LEADING_FIELDS"
+    )]
+    upload_max_leading_fields: Option<usize>,
+
+    /// Maximum time to wait for an upload to be decrypted before responding as unavailable.
+    ///
+    /// Stops a flood of requests for very large uploads from tying up the server.
+    #[clap(long = "decrypt-timeout", env = "DOLLSHARE_DECRYPT_TIMEOUT", value_parser = duration_range_value_parse!(min: 1s, max: 1h))]
+    decrypt_timeout: Option<DurationHuman>,
+
+    /// Time a connection may go without reading or writing any bytes before it is closed.
+    ///
+    /// Protects against clients that stall part way through an upload or download.
+    #[clap(long = "client-idle-timeout", env = "DOLLSHARE_CLIENT_IDLE_TIMEOUT", value_parser = duration_range_value_parse!(min: 1s, max: 1day))]
+    client_idle_timeout: Option<DurationHuman>,
+
+    /// Maximum total size of all uploads being processed at once.
+    ///
+    /// Each upload reserves its declared size against this budget until it completes,
+    /// uploads that would exceed it are rejected as temporarily unavailable.
+    #[clap(
+        long = "max-inflight-upload-bytes",
+        env = "DOLLSHARE_MAX_INFLIGHT_UPLOAD_BYTES"
+    )]
+    max_inflight_upload_bytes: Option<ByteSize>,
+
+    /// Resident memory of the server above which new uploads are rejected.
+    ///
+    /// Uploads are rejected as temporarily unavailable until memory usage falls back
+    /// below this size, uploads already in progress and downloads are unaffected.
+    /// Only supported on Linux.
+    #[clap(long = "upload-memory-limit", env = "DOLLSHARE_UPLOAD_MEMORY_LIMIT")]
+    upload_memory_limit: Option<ByteSize>,
+
     /// File mimetypes that can be uploaded.
     /// Supports type wildcards (e.g. 'image/*', '*/*').
     ///
@@ -102,69 +386,840 @@ struct Arguments {
         value_delimiter = ','
     )]
     upload_mimetypes: Vec<Mime>,
+
+    /// Reject uploads whose multipart file field does not include a filename.
+    #[clap(
+        long = "require-filename",
+        env = "DOLLSHARE_REQUIRE_FILENAME",
+        default_value_t = false
+    )]
+    require_filename: bool,
+
+    /// Maximum total size that the entries of an uploaded archive may declare when extracted.
+    ///
+    /// Only the archive's index is inspected, uploads declaring more than this are rejected.
+    #[clap(
+        long = "upload-archive-expansion-limit",
+        env = "DOLLSHARE_UPLOAD_ARCHIVE_EXPANSION_LIMIT"
+    )]
+    upload_archive_expansion_limit: Option<ByteSize>,
+
+    /// Stop serving existing uploads whose type is no longer permitted by the upload MIME types.
+    #[clap(
+        long = "enforce-mimetypes-on-download",
+        env = "DOLLSHARE_ENFORCE_MIMETYPES_ON_DOWNLOAD",
+        default_value_t = false
+    )]
+    enforce_mimetypes_on_download: bool,
+
+    /// Include upload metadata headers (size, creation time, expiry) when serving uploads.
+    ///
+    /// This slightly reveals information about stored uploads to anybody holding a valid link.
+    #[clap(
+        long = "upload-metadata-headers",
+        env = "DOLLSHARE_UPLOAD_METADATA_HEADERS",
+        default_value_t = false
+    )]
+    upload_metadata_headers: bool,
+
+    /// Uploader-supplied headers that are stored with uploads and replayed when they are served.
+    ///
+    /// Only headers prefixed with `X-` can be used, all other uploader headers are never stored.
+    #[clap(
+        long = "upload-passthrough-headers",
+        env = "DOLLSHARE_UPLOAD_PASSTHROUGH_HEADERS",
+        value_delimiter = ',',
+        value_parser = parse_passthrough_header
+    )]
+    upload_passthrough_headers: Vec<HeaderName>,
+
+    /// Allow uploaders to keep the original bytes of images that had EXIF data stripped.
+    ///
+    /// Enabled per upload with a `keep_original=true` multipart field. Originals are stored
+    /// encrypted alongside the stripped upload and are only served with the upload's link, a
+    /// valid bearer token and `?original=1`. This doubles the storage used by such uploads.
+    #[clap(
+        long = "upload-keep-originals",
+        env = "DOLLSHARE_UPLOAD_KEEP_ORIGINALS",
+        default_value_t = false
+    )]
+    upload_keep_originals: bool,
+
+    /// Strip EXIF data from image uploads by re-encoding them.
+    ///
+    /// When disabled, images are stored exactly as they were uploaded. Uploaders can also keep
+    /// the metadata of their own images by sending an `X-Keep-Metadata: true` header.
+    #[clap(
+        long = "strip-exif",
+        env = "DOLLSHARE_STRIP_EXIF",
+        default_value_t = true,
+        action = clap::ArgAction::Set
+    )]
+    strip_exif: bool,
+
+    /// Quality from 1 to 100 that JPEG uploads are re-encoded at when their EXIF data is stripped.
+    ///
+    /// PNG and WebP uploads are always re-encoded losslessly.
+    #[clap(
+        long = "jpeg-quality",
+        env = "DOLLSHARE_JPEG_QUALITY",
+        default_value_t = 90,
+        value_parser = clap::value_parser!(u8).range(1..=100)
+    )]
+    jpeg_quality: u8,
+
+    /// Transcode HEIC/HEIF image uploads to JPEG so they can be displayed by browsers.
+    ///
+    /// Requires `ffmpeg` with HEIF support on the `PATH` and `image/jpeg` to be an allowed
+    /// upload MIME type. Images that can't be transcoded are stored as uploaded with a warning.
+    #[clap(
+        long = "transcode-heic",
+        env = "DOLLSHARE_TRANSCODE_HEIC",
+        default_value_t = false
+    )]
+    transcode_heic: bool,
+
+    /// Hold new uploads in quarantine until they are approved by an administrator.
+    ///
+    /// Uploads pending moderation are not served until approved with `POST /admin/approve/{id}`,
+    /// or deleted with `POST /admin/reject/{id}`.
+    #[clap(
+        long = "moderation",
+        env = "DOLLSHARE_MODERATION",
+        default_value_t = false
+    )]
+    moderation: bool,
+
+    /// Compress uploads with gzip at this level (1-9) before they are encrypted.
+    ///
+    /// Images, video, audio and archives are stored uncompressed as they are almost always
+    /// compressed already, as are uploads that compression doesn't make smaller.
+    #[clap(
+        long = "upload-compression",
+        env = "DOLLSHARE_UPLOAD_COMPRESSION",
+        value_parser = clap::value_parser!(u32).range(1..=9)
+    )]
+    upload_compression: Option<u32>,
+
+    /// Compute and store a perceptual hash of image uploads so near-duplicates can be found.
+    ///
+    /// Hashes are stored unencrypted and can be searched by administrators.
+    #[clap(
+        long = "upload-perceptual-hashes",
+        env = "DOLLSHARE_UPLOAD_PERCEPTUAL_HASHES",
+        default_value_t = false
+    )]
+    upload_perceptual_hashes: bool,
+
+    /// How the id of each upload is chosen.
+    ///
+    /// `content-hash` deduplicates identical uploads but reveals when a file has already been
+    /// uploaded, `random` gives every upload its own unguessable id.
+    #[clap(
+        long = "id-strategy",
+        env = "DOLLSHARE_ID_STRATEGY",
+        value_enum,
+        default_value_t = IdStrategy::ContentHash
+    )]
+    id_strategy: IdStrategy,
+
+    /// Number of characters of the content hash used as the id of an upload.
+    ///
+    /// Only used by the `content-hash` id strategy. Longer ids make it less likely that
+    /// different uploads share an id, which is resolved by lengthening the id of the newer upload.
+    #[clap(
+        long = "upload-id-length",
+        env = "DOLLSHARE_UPLOAD_ID_LENGTH",
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u32).range(8..=64)
+    )]
+    upload_id_length: u32,
+
+    /// How long the response to an upload made with an `Idempotency-Key` header is remembered.
+    ///
+    /// Repeating an upload with the same key and token during this time returns the original
+    /// response instead of storing the upload again.
+    #[clap(long = "idempotency-key-lifetime", env = "DOLLSHARE_IDEMPOTENCY_KEY_LIFETIME", default_value = "24h", value_parser = duration_range_value_parse!(min: 1min, max: 7days))]
+    idempotency_key_lifetime: DurationHuman,
+
+    /// Origins that browsers may make cross-origin requests to the server from.
+    ///
+    /// Use `*` to allow any origin. Cross-origin requests are not permitted when unset.
+    #[clap(
+        long = "cors-allowed-origins",
+        env = "DOLLSHARE_CORS_ALLOWED_ORIGINS",
+        value_delimiter = ','
+    )]
+    cors_allowed_origins: Vec<HeaderValue>,
+
+    /// Response headers that cross-origin browser clients are permitted to read.
+    #[clap(
+        long = "cors-expose-headers",
+        env = "DOLLSHARE_CORS_EXPOSE_HEADERS",
+        default_values_t = [
+            HeaderName::from_static("x-upload-size"),
+            HeaderName::from_static("x-upload-created"),
+            HeaderName::from_static("x-upload-expires"),
+        ],
+        value_delimiter = ','
+    )]
+    cors_expose_headers: Vec<HeaderName>,
+
+    /// Charset to serve text uploads with by default.
+    ///
+    /// `utf-8` is only used for uploads that are valid UTF-8. Can be overridden per-request with
+    /// the `charset` query parameter and an empty value disables adding a charset.
+    #[clap(
+        long = "text-charset",
+        env = "DOLLSHARE_TEXT_CHARSET",
+        default_value = "utf-8",
+        value_parser = parse_charset
+    )]
+    text_charset: String,
+
+    /// `Cache-Control` header uploads are served with.
+    ///
+    /// Sensitive uploads, and uploads with a view limit or their own expiry, are never stored
+    /// by caches regardless of this policy.
+    #[clap(
+        long = "cache-control",
+        env = "DOLLSHARE_CACHE_CONTROL",
+        default_value = "private, max-age=1800"
+    )]
+    cache_control: HeaderValue,
+
+    /// `Cache-Control` header image uploads are served with instead of the default policy.
+    #[clap(long = "cache-control-images", env = "DOLLSHARE_CACHE_CONTROL_IMAGES")]
+    cache_control_images: Option<HeaderValue>,
+
+    /// `Cache-Control` header video uploads are served with instead of the default policy.
+    #[clap(long = "cache-control-videos", env = "DOLLSHARE_CACHE_CONTROL_VIDEOS")]
+    cache_control_videos: Option<HeaderValue>,
+
+    /// How long generated upload links remain valid for.
+    ///
+    /// When set, links include an expiry time and a signature made with the app secret
+    /// and uploads are only served for links that have a valid signature and have not expired.
+    #[clap(long = "signed-url-lifetime", env = "DOLLSHARE_SIGNED_URL_LIFETIME", value_parser = duration_range_value_parse!(min: 1min, max: 100years))]
+    signed_url_lifetime: Option<DurationHuman>,
+
+    /// Leave the decryption key out of signed links, only returning it alongside them.
+    ///
+    /// Links then can't be used on their own, and the key has to be sent with requests for
+    /// the upload in the `X-Upload-Key` header.
+    #[clap(
+        long = "signed-url-omit-key",
+        env = "DOLLSHARE_SIGNED_URL_OMIT_KEY",
+        default_value_t = false,
+        requires = "signed_url_lifetime"
+    )]
+    signed_url_omit_key: bool,
+
+    /// How often to log the bytes and number of uploads and downloads since the last summary.
+    ///
+    /// Summaries are not logged when unset.
+    #[clap(long = "throughput-log-interval", env = "DOLLSHARE_THROUGHPUT_LOG_INTERVAL", value_parser = duration_range_value_parse!(min: 1min, max: 1week))]
+    throughput_log_interval: Option<DurationHuman>,
+
+    /// Serve counts of uploads, downloads and rejections and the durations of storage operations
+    /// at `/metrics` in the Prometheus text format.
+    #[clap(long = "metrics", env = "DOLLSHARE_METRICS", default_value_t = false)]
+    metrics: bool,
+
+    /// Start the server in maintenance mode.
+    ///
+    /// While enabled all routes other than the health check respond as unavailable and uploads
+    /// are not expired. Maintenance mode can be toggled at runtime by sending `SIGHUP`.
+    #[clap(
+        long = "maintenance",
+        env = "DOLLSHARE_MAINTENANCE",
+        default_value_t = false
+    )]
+    maintenance: bool,
+
+    /// How long clients are told to wait before retrying while in maintenance mode.
+    #[clap(long = "maintenance-retry-after", env = "DOLLSHARE_MAINTENANCE_RETRY_AFTER", default_value = "5min", value_parser = duration_range_value_parse!(min: 1s, max: 1day))]
+    maintenance_retry_after: DurationHuman,
+
+    /// Path that the health check is served at.
+    #[clap(
+        long = "health-path",
+        env = "DOLLSHARE_HEALTH_PATH",
+        default_value = "/health",
+        value_parser = parse_health_path
+    )]
+    health_path: String,
+
+    /// Body of health check responses, which are empty when unset.
+    #[clap(long = "health-body", env = "DOLLSHARE_HEALTH_BODY")]
+    health_body: Option<String>,
+
+    /// Path that the readiness check is served at.
+    ///
+    /// Unlike the health check, the readiness check makes a request to storage and responds
+    /// as unavailable when storage can't be reached.
+    #[clap(
+        long = "readiness-path",
+        env = "DOLLSHARE_READINESS_PATH",
+        default_value = "/ready",
+        value_parser = parse_health_path
+    )]
+    readiness_path: String,
+
+    /// How long the readiness check waits for storage before responding as unavailable.
+    #[clap(long = "readiness-timeout", env = "DOLLSHARE_READINESS_TIMEOUT", default_value = "2s", value_parser = duration_range_value_parse!(min: 1s, max: 1min))]
+    readiness_timeout: DurationHuman,
+
+    /// Format that logs are written in.
+    ///
+    /// `text` writes human-readable lines, `json` writes a JSON object per line.
+    #[clap(
+        long = "log-format",
+        env = "DOLLSHARE_LOG_FORMAT",
+        value_enum,
+        default_value_t = LogFormat::Text
+    )]
+    log_format: LogFormat,
+
+    /// Title shown on the index page.
+    #[clap(
+        long = "ui-title",
+        env = "DOLLSHARE_UI_TITLE",
+        default_value = "Dollshare"
+    )]
+    ui_title: String,
+
+    /// CSS color used as the accent of the index page, such as `#565b63` or `rebeccapurple`.
+    ///
+    /// The bundled accent is used when unset.
+    #[clap(long = "ui-accent-color", env = "DOLLSHARE_UI_ACCENT_COLOR", value_parser = parse_css_color)]
+    ui_accent_color: Option<String>,
+}
+
+fn parse_charset(s: &str) -> Result<String, String> {
+    if !routes::uploads::is_valid_charset(s) {
+        return Err("charset contains invalid characters".to_string());
+    }
+    Ok(s.to_string())
+}
+
+fn parse_css_color(s: &str) -> Result<String, String> {
+    if !routes::is_valid_css_color(s) {
+        return Err("color must be a hex, named or functional CSS color".to_string());
+    }
+    Ok(s.to_string())
+}
+
+fn parse_public_url(s: &str) -> Result<Url, String> {
+    let url = Url::parse(s).map_err(|err| err.to_string())?;
+    if url.host_str().is_none() {
+        return Err("public url must include a host".to_string());
+    }
+    Ok(url)
+}
+
+fn parse_health_path(s: &str) -> Result<String, String> {
+    if !s.starts_with('/') || s == "/" || s.contains(['{', '}']) {
+        return Err(
+            "health path must start with '/' and cannot be '/' or contain '{' or '}'".to_string(),
+        );
+    }
+    Ok(s.to_string())
+}
+
+fn parse_master_key(s: &str) -> Result<String, String> {
+    if !Cryptography::is_valid_key(s) {
+        return Err("master key must be 32 bytes encoded as unpadded base64url".to_string());
+    }
+    Ok(s.to_string())
+}
+
+fn parse_passthrough_header(s: &str) -> Result<HeaderName, String> {
+    let name = HeaderName::from_str(s.trim()).map_err(|err| err.to_string())?;
+    if !name.as_str().starts_with("x-") {
+        return Err("only headers prefixed with 'X-' can be passed through".to_string());
+    }
+    Ok(name)
 }
 
 #[derive(Clone)]
 struct AppState {
-    storage: Arc<RwLock<AppStorage>>,
+    storage: Arc<AppStorage>,
+    upload_locks: Arc<UploadLocks>,
     auth_provider: Arc<AuthProvider>,
-    public_base_url: Url,
+    public_base_urls: Vec<Url>,
+    public_url_strategy: PublicUrlStrategy,
+    public_url_counter: Arc<AtomicUsize>,
     upload_allowed_mimetypes: Vec<Mime>,
+    enforce_mimetypes_on_download: bool,
+    upload_archive_expansion_limit: Option<u64>,
+    upload_size_limit: u64,
+    upload_min_size: u64,
+    upload_field_size_limit: Option<u64>,
+    upload_max_leading_fields: Option<usize>,
+    upload_require_filename: bool,
+    upload_budget: Option<Arc<UploadBudget>>,
+    memory_pressure: Option<Arc<MemoryPressure>>,
+    upload_expiry: Option<Duration>,
+    upload_metadata_headers: bool,
+    upload_passthrough_headers: Vec<HeaderName>,
+    upload_keep_originals: bool,
+    upload_strip_exif: bool,
+    upload_jpeg_quality: u8,
+    transcode_heic: bool,
+    moderation: bool,
+    upload_perceptual_hashes: bool,
+    upload_id_strategy: IdStrategy,
+    upload_id_length: usize,
+    idempotency_cache: Arc<IdempotencyCache<CreateUploadsResponse>>,
+    #[cfg(feature = "upload-from-url")]
+    remote_fetcher: Arc<remote_fetch::RemoteFetcher>,
+    cors_allowed_origins: Vec<HeaderValue>,
+    cors_expose_headers: Vec<HeaderName>,
+    text_charset: String,
+    cache_policy: CachePolicy,
+    signed_url_lifetime: Option<Duration>,
+    signed_url_omit_key: bool,
+    decrypt_timeout: Option<Duration>,
+    maintenance: Arc<MaintenanceMode>,
+    throughput: Arc<Throughput>,
+    metrics: Arc<Metrics>,
+    metrics_endpoint: bool,
+    ui_title: String,
+    ui_accent_color: Option<String>,
+    health_path: String,
+    health_body: Option<String>,
+    readiness_path: String,
+    readiness_timeout: Duration,
+    started: Instant,
     persisted_salt: String,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv().ok();
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or(EnvFilter::new("info")))
-        .init();
     let args = Arguments::parse();
+    args.log_format
+        .init(EnvFilter::try_from_default_env().unwrap_or(EnvFilter::new("info")));
+    if args
+        .trusted_tokens
+        .iter()
+        .any(|token| !args.tokens.contains(token))
+    {
+        bail!("every trusted token must also be one of the configured tokens");
+    }
+    if args.readiness_path == args.health_path {
+        bail!("--readiness-path must be different to --health-path");
+    }
+    if args.storage_hide_extensions && !args.storage_prefixes.is_empty() {
+        bail!("--storage-hide-extensions can't be used with --storage-prefixes");
+    }
 
     // Init required state.
-    let storage = Arc::new(RwLock::new(AppStorage::new(args.storage)));
+    let metrics = Arc::new(Metrics::default());
+    let storage = Arc::new(
+        AppStorage::new(
+            StorageProvider::from_str_with_options(
+                &args.storage,
+                &StorageOptions {
+                    allowed_schemes: args.allowed_storage_schemes,
+                    #[cfg(feature = "storage-memory")]
+                    memory_snapshot: args.preserve_on_shutdown,
+                    #[cfg(feature = "storage-filesystem")]
+                    fs_min_free_disk: args.min_free_disk.map(|size| size.0),
+                    #[cfg(feature = "storage-s3")]
+                    s3_max_attempts: args.s3_max_attempts,
+                    #[cfg(feature = "storage-redis")]
+                    // Redis only removes uploads the expiry check has missed, as it can't
+                    // remove what is stored alongside them.
+                    redis_ttl: args.upload_expiry.as_ref().map(|expiry| {
+                        Duration::from(expiry)
+                            + args
+                                .upload_expiry_grace_period
+                                .as_ref()
+                                .map_or(Duration::ZERO, Duration::from)
+                            + 2 * Duration::from(&args.expiry_scan_interval)
+                    }),
+                    fallback_copy_forward: args.storage_fallback_copy_forward,
+                },
+            )
+            .map_err(|err| anyhow!("invalid storage provider: {err}"))?,
+        )
+        .with_master_keys(args.master_key.map(|current| MasterKeys {
+            current,
+            previous: args.previous_master_keys,
+        }))
+        .with_prefixes(args.storage_prefixes)
+        .with_hidden_extensions(args.storage_hide_extensions)
+        .with_compression(args.upload_compression)
+        .with_decompressed_size_limit(args.upload_size_limit.0)
+        .with_metrics(Arc::clone(&metrics)),
+    );
+    if args.purge_on_start {
+        if !args.yes {
+            bail!("--purge-on-start deletes every upload and must be confirmed with --yes");
+        }
+        let purged = storage.purge_all_uploads().await?;
+        warn!("Purged {purged} uploads from storage on startup");
+    }
+    let upload_expiry = match args.upload_expiry.as_ref().map(Duration::from) {
+        Some(_) if !storage.provider_supports_expiry() => {
+            warn!(
+                "The storage provider you are using does not support expiry - uploads will not be automatically removed."
+            );
+            None
+        }
+        expire_after => expire_after,
+    };
+    let memory_pressure = match args
+        .upload_memory_limit
+        .map(|limit| MemoryPressure::new(limit.0))
+    {
+        Some(pressure) if !pressure.is_supported() => {
+            warn!(
+                "Memory usage cannot be read on this platform - uploads will not be rejected under memory pressure."
+            );
+            None
+        }
+        pressure => pressure.map(Arc::new),
+    };
     let state = AppState {
         storage: Arc::clone(&storage),
-        auth_provider: Arc::new(AuthProvider::new(args.tokens.clone())),
-        public_base_url: args.public_url.clone(),
+        upload_locks: Arc::new(UploadLocks::default()),
+        auth_provider: Arc::new(
+            AuthProvider::new(args.tokens.clone(), args.token_mimetypes.clone())
+                .with_trusted_tokens(args.trusted_tokens.clone())
+                .with_token_quotas(args.token_quotas.clone()),
+        ),
+        public_base_urls: args.public_urls.clone(),
+        public_url_strategy: args.public_url_strategy,
+        public_url_counter: Arc::new(AtomicUsize::new(0)),
         upload_allowed_mimetypes: args.upload_mimetypes.clone(),
+        enforce_mimetypes_on_download: args.enforce_mimetypes_on_download,
+        upload_archive_expansion_limit: args.upload_archive_expansion_limit.map(|size| size.0),
+        upload_size_limit: args.upload_size_limit.0,
+        upload_min_size: args.min_upload_size.0,
+        upload_field_size_limit: args.upload_field_size_limit.map(|size| size.0),
+        upload_max_leading_fields: args.upload_max_leading_fields,
+        upload_require_filename: args.require_filename,
+        upload_budget: args
+            .max_inflight_upload_bytes
+            .map(|max| Arc::new(UploadBudget::new(max.0))),
+        memory_pressure,
+        upload_expiry,
+        upload_metadata_headers: args.upload_metadata_headers,
+        upload_passthrough_headers: args.upload_passthrough_headers.clone(),
+        upload_keep_originals: args.upload_keep_originals,
+        upload_strip_exif: args.strip_exif,
+        upload_jpeg_quality: args.jpeg_quality,
+        transcode_heic: args.transcode_heic,
+        moderation: args.moderation,
+        upload_perceptual_hashes: args.upload_perceptual_hashes,
+        upload_id_strategy: args.id_strategy,
+        upload_id_length: args.upload_id_length as usize,
+        idempotency_cache: Arc::new(IdempotencyCache::new(Duration::from(
+            &args.idempotency_key_lifetime,
+        ))),
+        #[cfg(feature = "upload-from-url")]
+        remote_fetcher: Arc::new(
+            remote_fetch::RemoteFetcher::new(args.upload_from_url_allowed_networks.clone())
+                .context("failed to load native root certificates")?,
+        ),
+        cors_allowed_origins: args.cors_allowed_origins.clone(),
+        cors_expose_headers: args.cors_expose_headers.clone(),
+        text_charset: args.text_charset.clone(),
+        cache_policy: CachePolicy {
+            default: args.cache_control.clone(),
+            images: args.cache_control_images.clone(),
+            videos: args.cache_control_videos.clone(),
+        },
+        signed_url_lifetime: args.signed_url_lifetime.as_ref().map(Duration::from),
+        signed_url_omit_key: args.signed_url_omit_key,
+        decrypt_timeout: args.decrypt_timeout.as_ref().map(Duration::from),
+        maintenance: Arc::new(MaintenanceMode::new(
+            args.maintenance,
+            Duration::from(&args.maintenance_retry_after),
+        )),
+        throughput: Arc::new(Throughput::default()),
+        metrics,
+        metrics_endpoint: args.metrics,
+        ui_title: args.ui_title,
+        ui_accent_color: args.ui_accent_color,
+        health_path: args.health_path,
+        health_body: args.health_body,
+        readiness_path: args.readiness_path,
+        readiness_timeout: Duration::from(&args.readiness_timeout),
+        started: Instant::now(),
         persisted_salt: args.app_secret,
     };
 
     // Start server.
     let tcp_listener = TcpListener::bind(args.address).await?;
-    let router = Router::new()
-        .route("/", get(routes::index_handler))
-        .route("/index.css", get(routes::index_css_handler))
-        .route("/index.js", get(routes::index_js_handler))
-        .route("/favicon.ico", get(routes::favicon_handler))
-        .route("/health", get(routes::health_handler))
-        .route("/upload/{id}", get(routes::uploads::get_upload_handler))
-        .route(
-            "/upload",
-            post(
-                routes::uploads::create_upload_handler
-                    .layer(DefaultBodyLimit::max(
-                        args.upload_size_limit
-                            .0
-                            .try_into()
-                            .context("upload limit does not fit into usize")?,
-                    ))
-                    .layer(axum_middleware::from_fn_with_state(
-                        state.clone(),
-                        AuthProvider::valid_auth_middleware,
-                    )),
-            ),
-        )
-        .route(
-            "/upload/{id}",
+    let maintenance = Arc::clone(&state.maintenance);
+    let upload_locks = Arc::clone(&state.upload_locks);
+    let throughput = Arc::clone(&state.throughput);
+    let router = build_router(state)?;
+
+    // Background task for expiring files.
+    let storage_clone = Arc::clone(&storage);
+    let upload_expiry_grace_period = args.upload_expiry_grace_period.as_ref().map(Duration::from);
+    let expiry_scan_interval = Duration::from(&args.expiry_scan_interval);
+    let maintenance_clone = Arc::clone(&maintenance);
+    tokio::spawn(async move {
+        loop {
+            let started = Instant::now();
+            if maintenance_clone.is_enabled() {
+                debug!("Skipping upload expiry check during maintenance");
+            } else {
+                debug!("Running upload expiry check");
+                match storage_clone
+                    .remove_all_expired_uploads(
+                        upload_expiry,
+                        upload_expiry_grace_period,
+                        &upload_locks,
+                    )
+                    .await
+                {
+                    Ok((scanned, removed)) => debug!(
+                        "Upload expiry check scanned {scanned} uploads and removed {removed} in {:?}",
+                        started.elapsed()
+                    ),
+                    Err(err) => {
+                        error!("Failed to remove expired uploads - retrying next check: {err:?}")
+                    }
+                }
+            }
+            // Scans that overran the interval are followed by the next one straight away.
+            if let Some(remaining) = expiry_scan_interval.checked_sub(started.elapsed()) {
+                tokio::time::sleep(remaining).await;
+            } else {
+                debug!("Upload expiry check took longer than the scan interval");
+            }
+        }
+    });
+
+    // Background task for logging throughput summaries.
+    if let Some(interval) = args.throughput_log_interval.as_ref().map(Duration::from) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                throughput.log_summary();
+            }
+        });
+    }
+
+    // Background task for toggling maintenance mode.
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())
+            .expect("failed to install signal handler");
+        while hangup.recv().await.is_some() {
+            maintenance.toggle();
+        }
+    });
+
+    info!(
+        "Internal server started\n\
+         * Listening on: http://{}\n\
+         * Public URLs: {}\n\
+         * Upload size limit: {}\n\
+         * In-flight upload limit: {}\n\
+         * Upload memory limit: {}\n\
+         * Upload expiry: {}\n\
+         * Signed URL lifetime: {}\n\
+         * Allowed mimetypes: {:?}\n\
+         * Tokens configured: {}\n\
+         * Maintenance mode: {}",
+        args.address,
+        args.public_urls
+            .iter()
+            .map(Url::as_str)
+            .collect::<Vec<_>>()
+            .join(", "),
+        args.upload_size_limit.display().si(),
+        args.max_inflight_upload_bytes
+            .map_or_else(|| "disabled".to_string(), |v| v.display().si().to_string()),
+        args.upload_memory_limit
+            .map_or_else(|| "disabled".to_string(), |v| v.display().si().to_string()),
+        upload_expiry.map_or_else(
+            || "disabled".to_string(),
+            |v| format!("{:#}", DurationHuman::from(v))
+        ),
+        args.signed_url_lifetime
+            .map_or_else(|| "disabled".to_string(), |v| format!("{v:#}")),
+        args.upload_mimetypes,
+        args.tokens.len(),
+        args.maintenance
+    );
+
+    let listener = IdleTimeoutListener::new(
+        tcp_listener,
+        args.client_idle_timeout.as_ref().map(Duration::from),
+    );
+    axum::serve(listener, router)
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+    storage.shutdown()?;
+
+    Ok(())
+}
+
+/// Build the router for all of the server's routes.
+fn build_router(state: AppState) -> Result<Router> {
+    let cors = cors_layer(&state);
+    let mut router = Router::new();
+    if state.metrics_endpoint {
+        router = router.route("/metrics", get(routes::metrics_handler));
+    }
+    #[cfg(feature = "upload-from-url")]
+    {
+        router = router.route(
+            "/upload/from-url",
+            post(
+                routes::uploads::create_upload_from_url_handler
+                    .layer(axum_middleware::from_fn_with_state(
+                        state.clone(),
+                        UploadBudget::reserve_limit_middleware,
+                    ))
+                    .layer(axum_middleware::from_fn_with_state(
+                        state.clone(),
+                        routes::uploads::declared_size_middleware,
+                    ))
+                    .layer(axum_middleware::from_fn_with_state(
+                        state.clone(),
+                        MemoryPressure::shed_middleware,
+                    ))
+                    .layer(axum_middleware::from_fn_with_state(
+                        state.clone(),
+                        AuthProvider::valid_auth_middleware,
+                    )),
+            ),
+        );
+    }
+    let router = router
+        .route("/", get(routes::index_handler))
+        .route("/index.css", get(routes::index_css_handler))
+        .route("/index.js", get(routes::index_js_handler))
+        .route("/favicon.ico", get(routes::favicon_handler))
+        .route(&state.health_path, get(routes::health_handler))
+        .route(&state.readiness_path, get(routes::readiness_handler))
+        // HEAD is routed explicitly so it isn't derived from GET, which reads the whole upload.
+        .route(
+            "/upload/{id}",
+            get(routes::uploads::get_upload_handler).head(routes::uploads::head_upload_handler),
+        )
+        .route("/view/{id}", get(routes::uploads::view_upload_handler))
+        .route(
+            "/thumbnail/{id}",
+            get(routes::uploads::get_thumbnail_handler),
+        )
+        .route(
+            "/upload/{id}/{filename}",
+            get(routes::uploads::get_upload_handler).head(routes::uploads::head_upload_handler),
+        )
+        .route(
+            "/upload",
+            post(
+                routes::uploads::create_upload_handler
+                    .layer(DefaultBodyLimit::max(
+                        routes::uploads::upload_body_limit(state.upload_size_limit)
+                            .try_into()
+                            .context("upload limit does not fit into usize")?,
+                    ))
+                    .layer(axum_middleware::from_fn_with_state(
+                        state.clone(),
+                        UploadBudget::reserve_middleware,
+                    ))
+                    .layer(axum_middleware::from_fn_with_state(
+                        state.clone(),
+                        routes::uploads::declared_size_middleware,
+                    ))
+                    .layer(axum_middleware::from_fn_with_state(
+                        state.clone(),
+                        MemoryPressure::shed_middleware,
+                    ))
+                    .layer(axum_middleware::from_fn_with_state(
+                        state.clone(),
+                        AuthProvider::valid_auth_middleware,
+                    )),
+            ),
+        )
+        .route(
+            "/upload/{id}",
             delete(routes::uploads::delete_upload_handler).layer(
                 axum_middleware::from_fn_with_state(
                     state.clone(),
-                    AuthProvider::valid_auth_middleware,
+                    routes::uploads::delete_auth_middleware,
                 ),
             ),
         )
+        .route(
+            "/statistics",
+            get(routes::statistics_handler).layer(axum_middleware::from_fn_with_state(
+                state.clone(),
+                AuthProvider::valid_auth_middleware,
+            )),
+        )
+        .route(
+            "/admin/info",
+            get(routes::admin::server_info_handler).layer(axum_middleware::from_fn_with_state(
+                state.clone(),
+                AuthProvider::valid_auth_middleware,
+            )),
+        )
+        .route(
+            "/admin/approve/{id}",
+            post(routes::admin::approve_upload_handler).layer(axum_middleware::from_fn_with_state(
+                state.clone(),
+                AuthProvider::valid_auth_middleware,
+            )),
+        )
+        .route(
+            "/admin/reject/{id}",
+            post(routes::admin::reject_upload_handler).layer(axum_middleware::from_fn_with_state(
+                state.clone(),
+                AuthProvider::valid_auth_middleware,
+            )),
+        )
+        .route(
+            "/admin/purge",
+            post(routes::admin::purge_uploads_handler).layer(axum_middleware::from_fn_with_state(
+                state.clone(),
+                AuthProvider::valid_auth_middleware,
+            )),
+        )
+        .route(
+            "/admin/rekey",
+            post(routes::admin::rekey_uploads_handler).layer(axum_middleware::from_fn_with_state(
+                state.clone(),
+                AuthProvider::valid_auth_middleware,
+            )),
+        )
+        .route(
+            "/admin/similar",
+            get(routes::admin::similar_uploads_handler).layer(axum_middleware::from_fn_with_state(
+                state.clone(),
+                AuthProvider::valid_auth_middleware,
+            )),
+        )
+        .route(
+            "/admin/uploads",
+            get(routes::admin::list_uploads_handler).layer(axum_middleware::from_fn_with_state(
+                state.clone(),
+                AuthProvider::valid_auth_middleware,
+            )),
+        )
+        .route(
+            "/admin/verify",
+            post(routes::admin::verify_uploads_handler).layer(axum_middleware::from_fn_with_state(
+                state.clone(),
+                AuthProvider::valid_auth_middleware,
+            )),
+        )
+        .layer(axum_middleware::from_fn_with_state(
+            state.clone(),
+            MaintenanceMode::maintenance_middleware,
+        ))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(|request: &Request<_>| {
@@ -200,57 +1255,38 @@ async fn main() -> Result<()> {
             },
         ))
         .with_state(state);
+    Ok(match cors {
+        Some(cors) => router.layer(cors),
+        None => router,
+    })
+}
 
-    // Background task for expiring files.
-    let using_upload_expiry = if let Some(expire_after) =
-        args.upload_expiry.map(|e| Duration::from(&e))
+/// Build the CORS layer for the configured origins, if cross-origin requests are permitted.
+fn cors_layer(state: &AppState) -> Option<CorsLayer> {
+    if state.cors_allowed_origins.is_empty() {
+        return None;
+    }
+    let allow_origin = if state
+        .cors_allowed_origins
+        .iter()
+        .any(|origin| origin == "*")
     {
-        if !storage.read().await.provider_supports_expiry() {
-            warn!(
-                "The storage provider you are using does not support expiry - uploads will not be automatically removed."
-            );
-            None
-        } else {
-            let storage_clone = Arc::clone(&storage);
-            tokio::spawn(async move {
-                loop {
-                    debug!("Running upload expiry check");
-                    storage_clone
-                        .write()
-                        .await
-                        .remove_all_expired_uploads(expire_after)
-                        .await
-                        .unwrap();
-                    tokio::time::sleep(Duration::from_secs(60)).await;
-                }
-            });
-            args.upload_expiry
-        }
+        AllowOrigin::any()
     } else {
-        None
+        AllowOrigin::list(state.cors_allowed_origins.clone())
     };
-
-    info!(
-        "Internal server started\n\
-         * Listening on: http://{}\n\
-         * Public URL: {}\n\
-         * Upload size limit: {}\n\
-         * Upload expiry: {}\n\
-         * Allowed mimetypes: {:?}\n\
-         * Tokens configured: {}",
-        args.address,
-        args.public_url.as_str(),
-        args.upload_size_limit.display().si(),
-        using_upload_expiry.map_or_else(|| "disabled".to_string(), |v| format!("{v:#}")),
-        args.upload_mimetypes,
-        args.tokens.len()
-    );
-
-    axum::serve(tcp_listener, router)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
-
-    Ok(())
+    Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::HEAD, Method::POST, Method::DELETE])
+            .allow_headers([
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                HeaderName::from_static("idempotency-key"),
+                HeaderName::from_static("x-upload-key"),
+            ])
+            .expose_headers(state.cors_expose_headers.clone()),
+    )
 }
 
 // https://github.com/tokio-rs/axum/blob/15917c6dbcb4a48707a20e9cfd021992a279a662/examples/graceful-shutdown/src/main.rs#L55
@@ -277,3 +1313,1477 @@ async fn shutdown_signal() {
         _ = terminate => {},
     }
 }
+
+#[cfg(test)]
+#[cfg(feature = "storage-memory")]
+mod tests {
+    use super::{
+        AuthProvider, HeaderName, HeaderValue, IdStrategy, MemoryPressure, PublicUrlStrategy,
+        TokenMimetypes, TokenQuota,
+    };
+    use crate::{
+        storage::{AppStorage, StorageProvider},
+        test_support::{
+            TEST_TOKEN, download, download_bytes, expect_continue_status, response_body, send,
+            serve, status_line, test_state, upload, upload_json, upload_with_fields,
+        },
+    };
+    use std::{
+        str::FromStr,
+        sync::{
+            Arc,
+            atomic::{AtomicU64, Ordering},
+        },
+        time::Duration,
+    };
+    use url::Url;
+
+    #[tokio::test]
+    async fn test_upload_size_limit_boundary() {
+        let state = test_state();
+        let limit = state.upload_size_limit as usize;
+        let address = serve(state).await;
+
+        // Test uploads under the limit are accepted.
+        assert_eq!(
+            status_line(&upload(address, "", &vec![0; limit - 1]).await),
+            "HTTP/1.1 200 OK"
+        );
+
+        // Test uploads exactly at the limit are accepted.
+        assert_eq!(
+            status_line(&upload(address, "", &vec![1; limit]).await),
+            "HTTP/1.1 200 OK"
+        );
+
+        // Test uploads over the limit are rejected with the limit in the response.
+        let response = upload(address, "", &vec![2; limit + 1]).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 413 Payload Too Large");
+        assert!(response_body(&response).contains(&format!("the limit is {limit} bytes")));
+    }
+
+    #[tokio::test]
+    async fn test_min_upload_size() {
+        let address = serve(test_state()).await;
+
+        // Test empty files are rejected by default, distinctly from a missing file.
+        let response = upload(address, "", b"").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+        assert!(response_body(&response).contains("the file is empty"));
+
+        let mut state = test_state();
+        state.upload_min_size = 4;
+        let address = serve(state).await;
+
+        // Test uploads under the minimum are rejected with the minimum in the response.
+        let response = upload(address, "", b"abc").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+        assert!(response_body(&response).contains("the limit is 4 bytes"));
+
+        // Test uploads exactly at the minimum are accepted.
+        assert_eq!(
+            status_line(&upload(address, "", b"abcd").await),
+            "HTTP/1.1 200 OK"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue() {
+        let address = serve(test_state()).await;
+
+        // Test unauthenticated uploads are rejected before the body is sent.
+        assert_eq!(
+            expect_continue_status(address, "invalid-token", 512).await,
+            "HTTP/1.1 401 Unauthorized"
+        );
+
+        // Test oversized uploads are rejected before the body is sent.
+        assert_eq!(
+            expect_continue_status(address, TEST_TOKEN, 1024 * 1024).await,
+            "HTTP/1.1 413 Payload Too Large"
+        );
+
+        // Test valid uploads are told to continue.
+        assert_eq!(
+            expect_continue_status(address, TEST_TOKEN, 512).await,
+            "HTTP/1.1 100 Continue"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_head_upload() {
+        let address = serve(test_state()).await;
+        let response = upload(address, "", b"head request").await;
+        let json = upload_json(&response);
+        let url = json["url"].as_str().unwrap();
+        let path = &url[url.find("/upload/").unwrap()..];
+        let head = |path: String| async move {
+            send(
+                address,
+                format!("HEAD {path} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n")
+                    .as_bytes(),
+            )
+            .await
+        };
+
+        // Test the headers of the upload are returned without the body.
+        let response = head(path.to_string()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(response.contains("content-length: 12\r\n"));
+        assert!(response.contains("cache-control: private, max-age=1800\r\n"));
+        assert_eq!(response_body(&response), "");
+
+        // Test an invalid key is treated the same as a missing upload.
+        let response = head(path.replace("key=", "key=invalid")).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 404 Not Found");
+
+        // Test a missing key is treated the same as a missing upload, revealing nothing about it.
+        let missing = head("/upload/missing".to_string()).await;
+        assert_eq!(status_line(&missing), "HTTP/1.1 404 Not Found");
+        let response = head(path[..path.find('?').unwrap()].to_string()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 404 Not Found");
+        assert!(!response.contains("content-type"));
+        assert_eq!(
+            response
+                .split_once("\r\n\r\n")
+                .unwrap()
+                .0
+                .lines()
+                .filter(|line| !line.starts_with("date:"))
+                .collect::<Vec<_>>(),
+            missing
+                .split_once("\r\n\r\n")
+                .unwrap()
+                .0
+                .lines()
+                .filter(|line| !line.starts_with("date:"))
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_conditional_get() {
+        let address = serve(test_state()).await;
+        let response = upload(address, "", b"seen before").await;
+        let json = upload_json(&response);
+        let url = json["url"].as_str().unwrap();
+        let path = &url[url.find("/upload/").unwrap()..];
+        let request = |path: &str, etag: &str| {
+            format!(
+                "GET {path} HTTP/1.1\r\n\
+                 Host: {address}\r\n\
+                 If-None-Match: {etag}\r\n\
+                 Connection: close\r\n\r\n"
+            )
+        };
+
+        // Test downloads are tagged, the same each time.
+        let etag = |response: &str| {
+            response
+                .lines()
+                .find_map(|line| line.strip_prefix("etag: "))
+                .unwrap()
+                .to_string()
+        };
+        let tag = etag(&download(address, url).await);
+        assert_eq!(etag(&download(address, url).await), tag);
+
+        // Test other uploads are tagged by their own content.
+        let response = upload(address, "", b"seen elsewhere").await;
+        let other = upload_json(&response);
+        assert_ne!(
+            etag(&download(address, other["url"].as_str().unwrap()).await),
+            tag
+        );
+
+        // Test a matching tag is answered without the upload.
+        let etag = tag;
+        let response = send(address, request(path, &etag).as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 304 Not Modified");
+        assert!(response.contains(&format!("etag: {etag}\r\n")));
+        assert_eq!(response_body(&response), "");
+
+        // Test a different tag is answered with the upload.
+        let response = send(address, request(path, "\"stale\"").as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert_eq!(response_body(&response), "seen before");
+
+        // Test a matching tag with an invalid key is treated the same as a missing upload.
+        let response = send(
+            address,
+            request(&path.replace("key=", "key=invalid"), &etag).as_bytes(),
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn test_random_id_strategy() {
+        let mut state = test_state();
+        state.upload_id_strategy = IdStrategy::Random;
+        let address = serve(state).await;
+
+        // Test identical uploads are given distinct ids that both resolve.
+        let mut ids = Vec::new();
+        let mut urls = Vec::new();
+        for _ in 0..2 {
+            let response = upload(address, "", b"identical").await;
+            let json = upload_json(&response);
+            ids.push(json["id"].as_str().unwrap().to_string());
+            urls.push(json["url"].as_str().unwrap().to_string());
+        }
+        assert_ne!(ids[0], ids[1]);
+        for url in urls {
+            let response = download(address, &url).await;
+            assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+            assert!(response.ends_with("identical"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_public_url_strategy() {
+        let public_urls = |strategy| {
+            let mut state = test_state();
+            state.public_base_urls = vec![
+                Url::parse("https://a.example.com").unwrap(),
+                Url::parse("https://b.example.com").unwrap(),
+            ];
+            state.public_url_strategy = strategy;
+            serve(state)
+        };
+        let primary_url = |response: &str| {
+            let json = upload_json(response);
+            assert_eq!(json["urls"].as_array().unwrap().len(), 2);
+            json["url"].as_str().unwrap().to_string()
+        };
+
+        // Test round robin alternates the primary link between the public urls.
+        let address = public_urls(PublicUrlStrategy::RoundRobin).await;
+        let first = primary_url(&upload(address, "", b"round robin 1").await);
+        let second = primary_url(&upload(address, "", b"round robin 2").await);
+        assert!(first.starts_with("https://a.example.com/upload/"));
+        assert!(second.starts_with("https://b.example.com/upload/"));
+        let response = download(address, &second).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(response.ends_with("round robin 2"));
+
+        // Test hashing gives the same upload the same primary link every time.
+        let address = public_urls(PublicUrlStrategy::Hash).await;
+        let first = primary_url(&upload(address, "", b"hashed").await);
+        let second = primary_url(&upload(address, "", b"hashed").await);
+        assert_eq!(
+            first[..first.find('?').unwrap()],
+            second[..second.find('?').unwrap()]
+        );
+        let response = download(address, &second).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(response.ends_with("hashed"));
+    }
+
+    #[tokio::test]
+    async fn test_signed_url_omit_key() {
+        let mut state = test_state();
+        state.signed_url_lifetime = Some(Duration::from_secs(60));
+        state.signed_url_omit_key = true;
+        let address = serve(state).await;
+        let response = upload(address, "", b"signed without key").await;
+        let json = upload_json(&response);
+        let url = json["url"].as_str().unwrap();
+        let key = json["key"].as_str().unwrap();
+        let path = &url[url.find("/upload/").unwrap()..];
+        let download_with_key = |path: &str, key: &str| {
+            let request = format!(
+                "GET {path} HTTP/1.1\r\n\
+                 Host: {address}\r\n\
+                 X-Upload-Key: {key}\r\n\
+                 Connection: close\r\n\r\n"
+            );
+            async move { send(address, request.as_bytes()).await }
+        };
+
+        // Test the link is signed but doesn't include the key.
+        assert!(url.contains("sig=") && !url.contains("key="));
+
+        // Test the link can't be used without the key.
+        let response = download(address, url).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+
+        // Test the link is served with the key given in a header.
+        let response = download_with_key(path, key).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(response.ends_with("signed without key"));
+
+        // Test the signature is checked against the given key before decrypting.
+        let response = download_with_key(path, &"A".repeat(key.len())).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 403 Forbidden");
+    }
+
+    #[test]
+    fn test_parse_public_url() {
+        // Test public urls with a host are accepted.
+        assert!(super::parse_public_url("https://example.com").is_ok());
+        assert!(super::parse_public_url("http://127.0.0.1:8731").is_ok());
+
+        // Test public urls that links can't be built from are rejected.
+        assert!(super::parse_public_url("file:///srv/uploads").is_err());
+        assert!(super::parse_public_url("not a url").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_deduplicated_upload() {
+        let address = serve(test_state()).await;
+        let deduplicated = |response: &str| {
+            let json = upload_json(response);
+            json["deduplicated"].as_bool().unwrap_or_default()
+        };
+
+        // Test new content is not reported as deduplicated.
+        let response = upload(address, "", b"deduplicated").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(!deduplicated(&response));
+        let json = upload_json(&response);
+        let first_url = json["url"].as_str().unwrap().to_string();
+        let first_id = json["id"].as_str().unwrap().to_string();
+
+        // Test the same content uploaded again is reported as deduplicated.
+        let response = upload(address, "", b"deduplicated").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(deduplicated(&response));
+
+        // Test the link to the first upload still works after the duplicate.
+        let response = download(address, &first_url).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert_eq!(response_body(&response), "deduplicated");
+
+        // Test identical uploads with options of their own are stored apart, so burning one
+        // leaves the others in place and doesn't apply to them.
+        let response = upload_with_fields(address, "", &[("burn", "true")], b"deduplicated").await;
+        assert!(!deduplicated(&response));
+        let json = upload_json(&response);
+        let burn_url = json["url"].as_str().unwrap();
+        assert_ne!(json["id"].as_str().unwrap(), first_id);
+        for (url, status) in [
+            (burn_url, "HTTP/1.1 200 OK"),
+            (burn_url, "HTTP/1.1 404 Not Found"),
+            (first_url.as_str(), "HTTP/1.1 200 OK"),
+            (first_url.as_str(), "HTTP/1.1 200 OK"),
+        ] {
+            assert_eq!(status_line(&download(address, url).await), status);
+        }
+
+        // Test identical uploads are never deduplicated with random ids.
+        let mut state = test_state();
+        state.upload_id_strategy = IdStrategy::Random;
+        let address = serve(state).await;
+        for _ in 0..2 {
+            assert!(!deduplicated(&upload(address, "", b"deduplicated").await));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upload_id_collision() {
+        let mut state = test_state();
+        state.upload_id_length = 8;
+        let address = serve(state).await;
+
+        // Test different content sharing the start of its hash is given a longer id instead of
+        // replacing the existing upload.
+        let mut urls = Vec::new();
+        for (payload, id) in [("payload-8844", "d87c312b"), ("payload-46923", "d87c312b3")] {
+            let response = upload(address, "", payload.as_bytes()).await;
+            let json = upload_json(&response);
+            assert_eq!(json["id"].as_str().unwrap(), id);
+            urls.push(json["url"].as_str().unwrap().to_string());
+        }
+        for (url, payload) in urls.iter().zip(["payload-8844", "payload-46923"]) {
+            let response = download(address, url).await;
+            assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+            assert!(response.ends_with(payload));
+        }
+
+        // Test identical content still resolves to its existing id.
+        let response = upload(address, "", b"payload-46923").await;
+        let json = upload_json(&response);
+        assert_eq!(json["id"].as_str().unwrap(), "d87c312b3");
+    }
+
+    #[tokio::test]
+    async fn test_upload_filename() {
+        let address = serve(test_state()).await;
+
+        // Test the uploader's filename is suggested when the link does not give one.
+        let response = upload_with_fields(
+            address,
+            "",
+            &[("filename", "../holiday \"photo\".txt")],
+            b"named",
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let json = upload_json(&response);
+        let url = json["url"].as_str().unwrap();
+        let response = download(address, url).await;
+        assert!(response.contains("content-disposition: inline; filename=\"..holiday photo.txt\""));
+
+        // Test downloads can be requested as an attachment.
+        let response = download(address, &format!("{url}&download=1")).await;
+        assert!(
+            response.contains("content-disposition: attachment; filename=\"..holiday photo.txt\"")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_moderation() {
+        let mut state = test_state();
+        state.moderation = true;
+        let address = serve(state).await;
+        let moderate = |action: &str, id: &str| {
+            format!(
+                "POST /admin/{action}/{id} HTTP/1.1\r\nHost: {address}\r\nAuthorization: Bearer {TEST_TOKEN}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            )
+        };
+
+        // Test new uploads are pending and can't be downloaded until approved.
+        let response = upload(address, "", b"awaiting approval").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let json = upload_json(&response);
+        assert_eq!(json["pending"], true);
+        let id = json["id"].as_str().unwrap();
+        let url = json["url"].as_str().unwrap();
+        let response = download(address, url).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 403 Forbidden");
+        assert!(response_body(&response).contains("pending moderation"));
+
+        // Test approved uploads are served.
+        let response = send(address, moderate("approve", id).as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let response = download(address, url).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert_eq!(response_body(&response), "awaiting approval");
+
+        // Test identical uploads to an approved upload are not held again.
+        let response = upload(address, "", b"awaiting approval").await;
+        let json = upload_json(&response);
+        assert!(json.get("pending").is_none());
+
+        // Test rejected uploads are deleted and can no longer be moderated.
+        let response = upload(address, "", b"never approved").await;
+        let json = upload_json(&response);
+        let id = json["id"].as_str().unwrap();
+        let response = send(address, moderate("reject", id).as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let response = download(address, json["url"].as_str().unwrap()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 404 Not Found");
+        let response = send(address, moderate("approve", id).as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key() {
+        let mut state = test_state();
+        state.upload_id_strategy = IdStrategy::Random;
+        let storage = Arc::clone(&state.storage);
+        let address = serve(state).await;
+
+        // Test repeating an upload with the same key returns the original response.
+        let first = upload(address, "Idempotency-Key: retry\r\n", b"identical").await;
+        let second = upload(address, "Idempotency-Key: retry\r\n", b"identical").await;
+        assert_eq!(status_line(&first), "HTTP/1.1 200 OK");
+        assert_eq!(response_body(&first), response_body(&second));
+
+        // Test the upload was only stored once.
+        assert_eq!(storage.list_upload_ids().await.unwrap().len(), 1);
+
+        // Test a different key is processed as a new upload.
+        let third = upload(address, "Idempotency-Key: other\r\n", b"identical").await;
+        assert_ne!(response_body(&first), response_body(&third));
+        assert_eq!(storage.list_upload_ids().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_gif_metadata_content_id() {
+        let address = serve(test_state()).await;
+        let gif = |comment: &[u8]| {
+            let mut gif = Vec::new();
+            let mut encoder = gif::Encoder::new(&mut gif, 2, 2, &[0, 0, 0, 255, 255, 255]).unwrap();
+            encoder
+                .write_raw_extension(gif::Extension::Comment.into(), &[comment])
+                .unwrap();
+            encoder
+                .write_frame(&gif::Frame::from_indexed_pixels(2, 2, vec![1; 4], None))
+                .unwrap();
+            drop(encoder);
+            gif
+        };
+        let id = |response: String| {
+            assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+            let json = upload_json(&response);
+            json["id"].as_str().unwrap().to_string()
+        };
+
+        // Test GIFs differing only in their metadata are given the id of their stripped content.
+        let first = id(upload(address, "", &gif(b"camera-serial-1")).await);
+        let second = id(upload(address, "", &gif(b"camera-serial-2")).await);
+        assert_eq!(first, second);
+    }
+
+    #[tokio::test]
+    async fn test_jpeg_color_profile() {
+        use image::{ImageDecoder, ImageEncoder, ImageReader, metadata::Orientation};
+
+        let mut state = test_state();
+        state.upload_size_limit = 64 * 1024;
+        let address = serve(state).await;
+
+        // A landscape JPEG with a color profile and EXIF data rotating it to portrait.
+        let icc_profile = b"color profile".to_vec();
+        let exif = [
+            b"II\x2a\x00\x08\x00\x00\x00\x01\x00".as_slice(),
+            // Orientation tag as a single short of 6, rotating the image 90 degrees.
+            b"\x12\x01\x03\x00\x01\x00\x00\x00\x06\x00\x00\x00",
+            b"\x00\x00\x00\x00",
+        ]
+        .concat();
+        let mut image = Vec::new();
+        let mut encoder = image::codecs::jpeg::JpegEncoder::new(&mut image);
+        encoder.set_icc_profile(icc_profile.clone()).unwrap();
+        encoder.set_exif_metadata(exif).unwrap();
+        encoder
+            .write_image(&[128; 4 * 2 * 3], 4, 2, image::ExtendedColorType::Rgb8)
+            .unwrap();
+        let response = upload(address, "", &image).await;
+        let json = upload_json(&response);
+        let bytes = download_bytes(address, json["url"].as_str().unwrap()).await;
+        let mut decoder = ImageReader::new(std::io::Cursor::new(bytes))
+            .with_guessed_format()
+            .unwrap()
+            .into_decoder()
+            .unwrap();
+
+        // Test the color profile survives while the orientation is applied and stripped.
+        assert_eq!(decoder.icc_profile().unwrap(), Some(icc_profile));
+        assert_eq!(decoder.dimensions(), (2, 4));
+        assert_eq!(decoder.exif_metadata().unwrap(), None);
+        assert_eq!(decoder.orientation().unwrap(), Orientation::NoTransforms);
+    }
+
+    #[tokio::test]
+    async fn test_throughput() {
+        let state = test_state();
+        let throughput = Arc::clone(&state.throughput);
+        let address = serve(state).await;
+
+        // Test uploads and downloads are counted with their sizes.
+        let response = upload(address, "", b"counted").await;
+        let json = upload_json(&response);
+        download(address, json["url"].as_str().unwrap()).await;
+        download(address, json["url"].as_str().unwrap()).await;
+        let summary = throughput.take_summary();
+        assert_eq!((summary.uploads, summary.bytes_in), (1, 7));
+        assert_eq!((summary.downloads, summary.bytes_out), (2, 14));
+
+        // Test the counters start again after a summary.
+        let summary = throughput.take_summary();
+        assert_eq!(
+            (
+                summary.uploads,
+                summary.downloads,
+                summary.bytes_in,
+                summary.bytes_out
+            ),
+            (0, 0, 0, 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_require_filename() {
+        let permissive = serve(test_state()).await;
+        let mut state = test_state();
+        state.upload_require_filename = true;
+        let strict = serve(state).await;
+
+        // Test uploads without a filename are only rejected when filenames are required.
+        let response = upload_with_fields(permissive, "", &[("file", "unnamed")], b"").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let response = upload_with_fields(strict, "", &[("file", "unnamed")], b"").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+
+        // Test uploads with a filename are accepted when filenames are required.
+        let response = upload(strict, "", b"named").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+    }
+
+    #[tokio::test]
+    async fn test_upload_expiry() {
+        let no_expiry = serve(test_state()).await;
+        let mut state = test_state();
+        state.upload_expiry = Some(Duration::from_secs(24 * 60 * 60));
+        let storage = Arc::clone(&state.storage);
+        let address = serve(state).await;
+        let expiry_secs = |id: String| {
+            let storage = Arc::clone(&storage);
+            async move {
+                storage
+                    .get_upload_record(&id)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .expiry_secs
+            }
+        };
+
+        // Test uploads can shorten the server's expiry with a field or header.
+        let response = upload_with_fields(address, "", &[("expiry", "1h")], b"field").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let json = upload_json(&response);
+        assert!(json.get("expiry_clamped").is_none());
+        let id = json["id"].as_str().unwrap().to_string();
+        assert_eq!(expiry_secs(id).await, Some(60 * 60));
+        let response = upload(address, "X-Upload-Expiry: 2h\r\n", b"header").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let id = upload_json(&response)["id"].as_str().unwrap().to_string();
+        assert_eq!(expiry_secs(id).await, Some(2 * 60 * 60));
+
+        // Test expiries longer than the server's expiry are shortened to it and reported.
+        let response = upload_with_fields(address, "", &[("expiry", "2days")], b"longer").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let json = upload_json(&response);
+        assert_eq!(json["expiry_clamped"], true);
+        let id = json["id"].as_str().unwrap().to_string();
+        assert_eq!(expiry_secs(id).await, Some(24 * 60 * 60));
+
+        // Test expiries below the minimum or that can't be parsed are rejected.
+        for expiry in ["10min", "soon"] {
+            let response = upload_with_fields(address, "", &[("expiry", expiry)], b"").await;
+            assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+        }
+
+        // Test an expiry is rejected when the server does not expire uploads.
+        let response = upload_with_fields(no_expiry, "", &[("expiry", "1h")], b"").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+    }
+
+    #[tokio::test]
+    async fn test_ui_branding() {
+        let mut state = test_state();
+        state.ui_title = "Doll <Files>".to_string();
+        state.ui_accent_color = Some("#ff69b4".to_string());
+        let address = serve(state).await;
+        let get = |path: &str| {
+            format!("GET {path} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n")
+        };
+
+        // Test the served CSS contains the configured accent color.
+        let response = send(address, get("/index.css").as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(response.contains("--col-accent: #ff69b4;"));
+
+        // Test the index page shows the configured title.
+        let response = send(address, get("/").as_bytes()).await;
+        assert!(response.contains("<title>Doll &lt;Files&gt;</title>"));
+    }
+
+    #[tokio::test]
+    async fn test_server_info() {
+        let address = serve(test_state()).await;
+        upload(address, "", b"counted").await;
+        let request = |authorization: &str| {
+            format!(
+                "GET /admin/info HTTP/1.1\r\n\
+                 Host: {address}\r\n\
+                 {authorization}\
+                 Connection: close\r\n\r\n"
+            )
+        };
+
+        // Test server info is only available with a valid token.
+        let response = send(address, request("").as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 401 Unauthorized");
+
+        // Test the version, storage scheme and upload count are reported without any tokens.
+        let response = send(
+            address,
+            request(&format!("Authorization: Bearer {TEST_TOKEN}\r\n")).as_bytes(),
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let body = response_body(&response);
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(json["storage"]["scheme"], "memory");
+        assert_eq!(json["tokens"], 1);
+        assert_eq!(json["uploads"], 1);
+        assert!(!body.contains(TEST_TOKEN));
+    }
+
+    #[tokio::test]
+    async fn test_statistics() {
+        let address = serve(test_state()).await;
+        upload(address, "", b"counted").await;
+        upload(address, "", b"also counted").await;
+        let request = |authorization: &str| {
+            format!(
+                "GET /statistics HTTP/1.1\r\n\
+                 Host: {address}\r\n\
+                 {authorization}\
+                 Connection: close\r\n\r\n"
+            )
+        };
+
+        // Test statistics are only available with a valid token.
+        let response = send(address, request("").as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 401 Unauthorized");
+
+        // Test every upload is counted along with at least the size of its contents.
+        let response = send(
+            address,
+            request(&format!("Authorization: Bearer {TEST_TOKEN}\r\n")).as_bytes(),
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let json = upload_json(&response);
+        assert_eq!(json["uploads"], 2);
+        assert!(json["bytes"].as_u64().unwrap() >= 19);
+    }
+
+    #[cfg(feature = "upload-from-url")]
+    #[tokio::test]
+    async fn test_upload_from_url() {
+        // Serve files to fetch from the loopback address, which is private.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin = listener.local_addr().unwrap();
+        let router = axum::Router::new()
+            .route(
+                "/notes.txt",
+                axum::routing::get(|| async { "fetched from afar" }),
+            )
+            .route(
+                "/large.bin",
+                axum::routing::get(|| async { vec![0u8; 2048] }),
+            );
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        let request = |address: std::net::SocketAddr, body: &str| {
+            format!(
+                "POST /upload/from-url HTTP/1.1\r\n\
+                 Host: {address}\r\n\
+                 Authorization: Bearer {TEST_TOKEN}\r\n\
+                 Content-Type: application/json\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{body}",
+                body.len()
+            )
+        };
+        let body = |path: &str| format!(r#"{{"url":"http://{origin}{path}"}}"#);
+
+        // Test private addresses are rejected by default.
+        let address = serve(test_state()).await;
+        let response = send(address, request(address, &body("/notes.txt")).as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+
+        // Test URLs that aren't HTTP are rejected.
+        let response = send(
+            address,
+            request(address, r#"{"url":"file:///etc/passwd"}"#).as_bytes(),
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+
+        // Test fetched files are uploaded once their network is allowed.
+        let mut state = test_state();
+        state.remote_fetcher = Arc::new(
+            crate::remote_fetch::RemoteFetcher::new(vec!["127.0.0.0/8".parse().unwrap()]).unwrap(),
+        );
+        state.upload_size_limit = 1024;
+        let address = serve(state).await;
+        let response = send(address, request(address, &body("/notes.txt")).as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let json = upload_json(&response);
+        let response = download(address, json["url"].as_str().unwrap()).await;
+        assert_eq!(response_body(&response), "fetched from afar");
+
+        // Test files over the upload size limit are rejected.
+        let response = send(address, request(address, &body("/large.bin")).as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 413 Payload Too Large");
+
+        // Test files the origin doesn't serve are reported as a failed fetch.
+        let response = send(address, request(address, &body("/missing")).as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 502 Bad Gateway");
+
+        // Test options are validated and applied as they are for multipart uploads.
+        let with_options =
+            |path: &str, options: &str| format!(r#"{{"url":"http://{origin}{path}",{options}}}"#);
+        for options in [r#""burn":"maybe""#, r#""max_views":0"#, r#""colour":"red""#] {
+            let options_body = with_options("/notes.txt", options);
+            let response = send(address, request(address, &options_body).as_bytes()).await;
+            assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+        }
+        let options_body = with_options("/notes.txt", r#""checksum":"sha256:00""#);
+        let response = send(address, request(address, &options_body).as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+        let options_body = with_options(
+            "/notes.txt",
+            &format!(
+                r#""checksum":"blake3:{}""#,
+                blake3::hash(b"something else").to_hex()
+            ),
+        );
+        let response = send(address, request(address, &options_body).as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 422 Unprocessable Entity");
+        let options_body = with_options("/large.bin", r#""max_views":1"#);
+        let response = send(address, request(address, &options_body).as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 413 Payload Too Large");
+        let options_body = with_options("/notes.txt", r#""max_views":1"#);
+        let response = send(address, request(address, &options_body).as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let json = upload_json(&response);
+        let response = download(address, json["url"].as_str().unwrap()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let response = download(address, json["url"].as_str().unwrap()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 404 Not Found");
+
+        // Test URLs without a filename are rejected when filenames are required.
+        let mut state = test_state();
+        state.remote_fetcher = Arc::new(
+            crate::remote_fetch::RemoteFetcher::new(vec!["127.0.0.0/8".parse().unwrap()]).unwrap(),
+        );
+        state.upload_require_filename = true;
+        let address = serve(state).await;
+        let response = send(address, request(address, &body("/")).as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+        let response = send(address, request(address, &body("/notes.txt")).as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+
+        // Test fetches reserve the upload size limit against the in-flight upload budget.
+        let mut state = test_state();
+        state.upload_size_limit = 1024;
+        state.upload_budget = Some(Arc::new(crate::upload_budget::UploadBudget::new(512)));
+        let address = serve(state).await;
+        let response = send(address, request(address, &body("/notes.txt")).as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 503 Service Unavailable");
+    }
+
+    #[tokio::test]
+    async fn test_range_requests() {
+        let address = serve(test_state()).await;
+        let response = upload(address, "", b"0123456789").await;
+        let json = upload_json(&response);
+        let url = json["url"].as_str().unwrap();
+        let path = &url[url.find("/upload/").unwrap()..];
+        let request = |path: &str, range: &str| {
+            format!(
+                "GET {path} HTTP/1.1\r\n\
+                 Host: {address}\r\n\
+                 Range: {range}\r\n\
+                 Connection: close\r\n\r\n"
+            )
+        };
+
+        // Test ranges are served as partial content.
+        for (range, content_range, body) in [
+            ("bytes=2-5", "bytes 2-5/10", "2345"),
+            ("bytes=7-", "bytes 7-9/10", "789"),
+            ("bytes=-3", "bytes 7-9/10", "789"),
+        ] {
+            let response = send(address, request(path, range).as_bytes()).await;
+            assert_eq!(status_line(&response), "HTTP/1.1 206 Partial Content");
+            assert!(
+                response
+                    .to_lowercase()
+                    .contains(&format!("content-range: {content_range}"))
+            );
+            assert_eq!(response_body(&response), body);
+        }
+
+        // Test unsatisfiable ranges are rejected with the content length.
+        let response = send(address, request(path, "bytes=10-").as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 416 Range Not Satisfiable");
+        assert!(
+            response
+                .to_lowercase()
+                .contains("content-range: bytes */10")
+        );
+
+        // Test ranges don't reveal anything without a valid key.
+        let invalid_key = format!("{}key=invalid", path.split_once("key=").unwrap().0);
+        let response = send(address, request(&invalid_key, "bytes=10-").as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 404 Not Found");
+
+        // Test uploads with a view limit are served in full, so a range uses up a single view.
+        let response = upload_with_fields(address, "", &[("max_views", "2")], b"0123456789").await;
+        let json = upload_json(&response);
+        let url = json["url"].as_str().unwrap();
+        let path = &url[url.find("/upload/").unwrap()..];
+        let response = send(address, request(path, "bytes=2-5").as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(!response.to_lowercase().contains("accept-ranges"));
+        assert_eq!(response_body(&response), "0123456789");
+        let response = send(address, request(path, "bytes=7-").as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let response = send(address, request(path, "bytes=7-").as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_timeout() {
+        let mut state = test_state();
+        state.upload_size_limit = 2 * 1024 * 1024;
+        state.decrypt_timeout = Some(Duration::from_millis(1));
+        let address = serve(state).await;
+
+        // Test a large upload that can't be decrypted within the timeout is unavailable.
+        let response = upload(address, "", &vec![0; 2 * 1024 * 1024]).await;
+        let json = upload_json(&response);
+        let response = download(address, json["url"].as_str().unwrap()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 503 Service Unavailable");
+    }
+
+    #[tokio::test]
+    async fn test_upload_field_size_limit() {
+        let mut state = test_state();
+        state.upload_field_size_limit = Some(100);
+        let address = serve(state).await;
+
+        // Test requests with every field under the limit are accepted.
+        let small = "a".repeat(100);
+        let response = upload_with_fields(address, "", &[("file", &small)], &[0; 100]).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+
+        // Test a single field over the limit rejects the request, even when the total is allowed.
+        let large = "a".repeat(101);
+        let response = upload_with_fields(address, "", &[("file", &small)], &[0; 101]).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 413 Payload Too Large");
+        let response = upload_with_fields(address, "", &[("file", &large)], &[0; 100]).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 413 Payload Too Large");
+
+        // Test the rejection gives the field limit rather than the upload limit.
+        assert!(response_body(&response).contains("the limit is 100 bytes"));
+    }
+
+    #[tokio::test]
+    async fn test_upload_max_leading_fields() {
+        let mut state = test_state();
+        state.upload_max_leading_fields = Some(2);
+        let address = serve(state).await;
+
+        // Test the file is accepted when it is within the leading field limit.
+        let fields = [("sensitive", "false"), ("sensitive", "false")];
+        let response = upload_with_fields(address, "", &fields, b"leading fields").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+
+        // Test exceeding the limit without reaching the file rejects the request.
+        let fields = [("sensitive", "false"); 3];
+        let response = upload_with_fields(address, "", &fields, b"too many leading").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+    }
+
+    #[tokio::test]
+    async fn test_enforce_mimetypes_on_download() {
+        let state = test_state();
+        let storage = Arc::clone(&state.storage);
+        let address = serve(state).await;
+        let response = upload(address, "", b"plain old bytes").await;
+        let json = upload_json(&response);
+        let url = json["url"].as_str().unwrap();
+
+        // Test uploads of a type that is no longer permitted keep serving by default.
+        let mut state = test_state();
+        state.storage = Arc::clone(&storage);
+        state.upload_allowed_mimetypes = vec![mime_guess::mime::IMAGE_STAR];
+        let tightened = serve(state.clone()).await;
+        assert_eq!(
+            status_line(&download(tightened, url).await),
+            "HTTP/1.1 200 OK"
+        );
+
+        // Test they stop serving once the policy is enforced on download.
+        state.enforce_mimetypes_on_download = true;
+        let enforced = serve(state).await;
+        assert_eq!(
+            status_line(&download(enforced, url).await),
+            "HTTP/1.1 404 Not Found"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auth_failures() {
+        let address = serve(test_state()).await;
+        let delete = |authorization: &str| {
+            format!(
+                "DELETE /upload/missing HTTP/1.1\r\nHost: {address}\r\n{authorization}Connection: close\r\n\r\n"
+            )
+        };
+
+        // Test a missing header asks for a bearer token.
+        let response = send(address, delete("").as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 401 Unauthorized");
+        assert!(response.contains("www-authenticate: Bearer\r\n"));
+
+        // Test a malformed header is a bad request.
+        let response = send(address, delete("Authorization: Basic abc\r\n").as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+
+        // Test a well-formed but invalid token is unauthorized.
+        let response = send(
+            address,
+            delete("Authorization: Bearer wrong\r\n").as_bytes(),
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 401 Unauthorized");
+        assert!(response.contains("www-authenticate: Bearer error=\"invalid_token\"\r\n"));
+
+        // Test a valid token is let through.
+        let response = send(
+            address,
+            delete(&format!("Authorization: Bearer {TEST_TOKEN}\r\n")).as_bytes(),
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn test_delete_token() {
+        let state = test_state();
+        let storage = Arc::clone(&state.storage);
+        let address = serve(state).await;
+        let delete = |path: &str, authorization: &str| {
+            format!(
+                "DELETE {path} HTTP/1.1\r\nHost: {address}\r\n{authorization}Connection: close\r\n\r\n"
+            )
+        };
+        let mut uploads = Vec::new();
+        for _ in 0..2 {
+            let response = upload(address, "", b"delete me").await;
+            let json = upload_json(&response);
+            uploads.push((
+                json["id"].as_str().unwrap().to_string(),
+                json["key"].as_str().unwrap().to_string(),
+                json["delete_token"].as_str().unwrap().to_string(),
+            ));
+        }
+        let (id, key, first_token) = &uploads[0];
+        let (_, _, second_token) = &uploads[1];
+
+        // Test a wrong token can't be told apart from a missing upload and deletes nothing.
+        let wrong = send(
+            address,
+            delete(&format!("/upload/{id}?delete_token=wrong"), "").as_bytes(),
+        )
+        .await;
+        let missing = send(
+            address,
+            delete(&format!("/upload/missing?delete_token={first_token}"), "").as_bytes(),
+        )
+        .await;
+        assert_eq!(status_line(&wrong), "HTTP/1.1 404 Not Found");
+        assert_eq!(response_body(&wrong), response_body(&missing));
+        assert!(storage.upload_exists(id).await.unwrap());
+
+        // Test the key from an upload's link can't delete it without a bearer token.
+        let response = send(
+            address,
+            delete(&format!("/upload/{id}?key={key}"), "").as_bytes(),
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 401 Unauthorized");
+        assert!(storage.upload_exists(id).await.unwrap());
+
+        // Test a delete token only deletes its own upload, leaving an identical upload in place,
+        // and can't be used again.
+        for (status, exists) in [("HTTP/1.1 200 OK", true), ("HTTP/1.1 404 Not Found", true)] {
+            let response = send(
+                address,
+                delete(&format!("/upload/{id}?delete_token={first_token}"), "").as_bytes(),
+            )
+            .await;
+            assert_eq!(status_line(&response), status);
+            assert_eq!(storage.upload_exists(id).await.unwrap(), exists);
+        }
+        let response = send(
+            address,
+            delete(&format!("/upload/{id}?delete_token={second_token}"), "").as_bytes(),
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(!storage.upload_exists(id).await.unwrap());
+
+        // Test a bearer token removes content along with every upload sharing it.
+        for _ in 0..2 {
+            upload(address, "", b"delete me").await;
+        }
+        let response = send(
+            address,
+            delete(
+                &format!("/upload/{id}"),
+                &format!("Authorization: Bearer {TEST_TOKEN}\r\n"),
+            )
+            .as_bytes(),
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(!storage.upload_exists(id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_token_mimetypes() {
+        let video = b"FLV\x01\x05\x00\x00\x00\x09";
+
+        // Test a restricted token cannot upload types the server otherwise allows.
+        let mut state = test_state();
+        state.auth_provider = Arc::new(AuthProvider::new(
+            vec![TEST_TOKEN.to_string()],
+            vec![TokenMimetypes {
+                token: TEST_TOKEN.to_string(),
+                mimetypes: vec![mime_guess::mime::IMAGE_PNG],
+            }],
+        ));
+        let address = serve(state).await;
+        assert_eq!(
+            status_line(&upload(address, "", video).await),
+            "HTTP/1.1 415 Unsupported Media Type"
+        );
+        assert_eq!(
+            status_line(&upload(address, "", b"unknown").await),
+            "HTTP/1.1 415 Unsupported Media Type"
+        );
+
+        // Test the same upload is accepted without the restriction.
+        let address = serve(test_state()).await;
+        assert_eq!(
+            status_line(&upload(address, "", video).await),
+            "HTTP/1.1 200 OK"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token_quotas() {
+        let serve_with_quota = |quota: &str| {
+            let mut state = test_state();
+            state.auth_provider = Arc::new(
+                AuthProvider::new(vec![TEST_TOKEN.to_string()], vec![]).with_token_quotas(vec![
+                    TokenQuota::from_str(&format!("{TEST_TOKEN}={quota}")).unwrap(),
+                ]),
+            );
+            serve(state)
+        };
+
+        // Test uploads past a token's file limit are refused.
+        let address = serve_with_quota(":2").await;
+        let mut delete_paths = Vec::new();
+        for content in [b"first", b"other"] {
+            let response = upload(address, "", content).await;
+            assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+            let json = upload_json(&response);
+            delete_paths.push(format!(
+                "/upload/{}?delete_token={}",
+                json["id"].as_str().unwrap(),
+                json["delete_token"].as_str().unwrap()
+            ));
+        }
+        assert_eq!(
+            status_line(&upload(address, "", b"third").await),
+            "HTTP/1.1 413 Payload Too Large"
+        );
+
+        // Test duplicates of content that is already stored are charged like any other upload.
+        assert_eq!(
+            status_line(&upload(address, "", b"first").await),
+            "HTTP/1.1 413 Payload Too Large"
+        );
+
+        // Test deleting an upload frees its share of the quota.
+        let response = send(
+            address,
+            format!(
+                "DELETE {} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n",
+                delete_paths[0]
+            )
+            .as_bytes(),
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert_eq!(
+            status_line(&upload(address, "", b"third").await),
+            "HTTP/1.1 200 OK"
+        );
+
+        // Test uploads past a token's size limit are refused.
+        let address = serve_with_quota("10:").await;
+        assert_eq!(
+            status_line(&upload(address, "", b"sixsix").await),
+            "HTTP/1.1 200 OK"
+        );
+        assert_eq!(
+            status_line(&upload(address, "", b"five!").await),
+            "HTTP/1.1 413 Payload Too Large"
+        );
+
+        // Test tokens without a quota are unlimited.
+        let address = serve(test_state()).await;
+        for content in [b"first", b"other", b"third"] {
+            assert_eq!(
+                status_line(&upload(address, "", content).await),
+                "HTTP/1.1 200 OK"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upload_content_type() {
+        let address = serve(test_state()).await;
+        let post = |content_type: &str| {
+            format!(
+                "POST /upload HTTP/1.1\r\n\
+                 Host: {address}\r\n\
+                 Authorization: Bearer {TEST_TOKEN}\r\n\
+                 Content-Type: {content_type}\r\n\
+                 Content-Length: 4\r\n\
+                 Connection: close\r\n\r\n\
+                 test"
+            )
+        };
+
+        // Test multipart requests without a boundary are rejected with an explanation.
+        let response = send(address, post("multipart/form-data").as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+        assert!(response_body(&response).contains("multipart/form-data; boundary="));
+
+        // Test requests that are not multipart are rejected with the same explanation.
+        let response = send(address, post("application/octet-stream").as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+        assert!(response_body(&response).contains("multipart/form-data; boundary="));
+    }
+
+    #[tokio::test]
+    async fn test_cors_expose_headers() {
+        let mut state = test_state();
+        state.cors_allowed_origins = vec![HeaderValue::from_static("https://example.com")];
+        state.cors_expose_headers = vec![
+            HeaderName::from_static("x-upload-size"),
+            HeaderName::from_static("x-custom"),
+        ];
+        let address = serve(state).await;
+        let request = |origin: &str| {
+            format!(
+                "GET /health HTTP/1.1\r\n\
+                 Host: {address}\r\n\
+                 Origin: {origin}\r\n\
+                 Connection: close\r\n\r\n"
+            )
+        };
+
+        // Test the configured headers are exposed to allowed origins.
+        let response = send(address, request("https://example.com").as_bytes())
+            .await
+            .to_lowercase();
+        assert!(response.contains("access-control-allow-origin: https://example.com"));
+        assert!(response.contains("access-control-expose-headers: x-upload-size,x-custom"));
+
+        // Test other origins are not allowed.
+        let response = send(address, request("https://other.example").as_bytes())
+            .await
+            .to_lowercase();
+        assert!(!response.contains("access-control-allow-origin"));
+    }
+
+    #[tokio::test]
+    async fn test_list_uploads() {
+        let address = serve(test_state()).await;
+        let mut ids = Vec::new();
+        for content in [b"first", b"other", b"third"] {
+            let response = upload(address, "", content).await;
+            let json = upload_json(&response);
+            ids.push(json["id"].as_str().unwrap().to_string());
+        }
+        ids.sort();
+        let list = |query: String| async move {
+            let response = send(
+                address,
+                format!(
+                    "GET /admin/uploads?{query} HTTP/1.1\r\nHost: {address}\r\nAuthorization: Bearer {TEST_TOKEN}\r\nConnection: close\r\n\r\n"
+                )
+                .as_bytes(),
+            )
+            .await;
+            assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+            upload_json(&response)
+        };
+
+        // Test uploads are listed a page at a time with their size and without keys.
+        let first_page = list("limit=2".to_string()).await;
+        let uploads = first_page["uploads"].as_array().unwrap();
+        assert_eq!(uploads.len(), 2);
+        assert_eq!(uploads[0]["id"], ids[0]);
+        assert_eq!(uploads[1]["id"], ids[1]);
+        assert!(uploads[0]["size"].as_u64().unwrap() > 0);
+        assert!(uploads[0].get("key").is_none());
+
+        // Test the cursor continues from the end of the previous page.
+        let cursor = first_page["next_cursor"].as_str().unwrap();
+        let second_page = list(format!("limit=2&cursor={cursor}")).await;
+        let uploads = second_page["uploads"].as_array().unwrap();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0]["id"], ids[2]);
+        assert!(second_page.get("next_cursor").is_none());
+
+        // Test listing requires authentication.
+        let response = send(
+            address,
+            format!("GET /admin/uploads HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n")
+                .as_bytes(),
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 401 Unauthorized");
+    }
+
+    #[tokio::test]
+    async fn test_upload_memory_limit() {
+        static USED: AtomicU64 = AtomicU64::new(0);
+        let mut state = test_state();
+        state.memory_pressure = Some(Arc::new(
+            MemoryPressure::new(100).with_source(|| Some(USED.load(Ordering::Relaxed))),
+        ));
+        let address = serve(state).await;
+        let response = upload(address, "", b"memory").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let json = upload_json(&response);
+
+        // Test uploads are shed while memory usage is above the limit.
+        USED.store(101, Ordering::Relaxed);
+        let response = upload(address, "", b"memory pressure").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 503 Service Unavailable");
+        assert!(response.contains("retry-after: 10\r\n"));
+
+        // Test downloads remain available.
+        let response = download(address, json["url"].as_str().unwrap()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+
+        // Test uploads are accepted again once memory usage falls.
+        USED.store(100, Ordering::Relaxed);
+        let response = upload(address, "", b"memory pressure").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_mode() {
+        let state = test_state();
+        let maintenance = Arc::clone(&state.maintenance);
+        let address = serve(state).await;
+        let response = upload(address, "", b"maintenance").await;
+        let json = upload_json(&response);
+        maintenance.toggle();
+
+        // Test uploads and downloads are unavailable.
+        let response = upload(address, "", b"maintenance").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 503 Service Unavailable");
+        assert!(response.contains("retry-after: 60\r\n"));
+        let response = download(address, json["url"].as_str().unwrap()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 503 Service Unavailable");
+
+        // Test the health check remains available.
+        let response = send(
+            address,
+            format!("GET /health HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n")
+                .as_bytes(),
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+    }
+
+    #[tokio::test]
+    async fn test_health_path() {
+        let mut state = test_state();
+        state.health_path = "/healthz".to_string();
+        state.health_body = Some("ok".to_string());
+        state.maintenance.toggle();
+        let address = serve(state).await;
+        let request = |path: &str| {
+            format!("GET {path} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n")
+        };
+
+        // Test the health check is served at the configured path with the configured body,
+        // even during maintenance.
+        let response = send(address, request("/healthz").as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert_eq!(response_body(&response), "ok");
+
+        // Test the default path is no longer exempt from maintenance.
+        let response = send(address, request("/health").as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 503 Service Unavailable");
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "storage-filesystem")]
+    async fn test_readiness() {
+        let base_path = std::env::temp_dir().join("dollshare-test-readiness");
+        let _ = std::fs::remove_dir_all(&base_path);
+        let mut state = test_state();
+        state.storage = Arc::new(AppStorage::new(
+            StorageProvider::from_str(&format!("fs://{}", base_path.display())).unwrap(),
+        ));
+        let address = serve(state).await;
+        let request = |path: &str| {
+            format!("GET {path} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n")
+        };
+
+        // Test the readiness check passes while storage can be reached.
+        let response = send(address, request("/ready").as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+
+        // Test the readiness check fails once storage can't be reached, but the health check
+        // still passes.
+        std::fs::remove_dir_all(&base_path).unwrap();
+        std::fs::write(&base_path, b"not a directory").unwrap();
+        let response = send(address, request("/ready").as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 503 Service Unavailable");
+        let response = send(address, request("/health").as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+
+        let _ = std::fs::remove_file(base_path);
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let disabled = serve(test_state()).await;
+        let mut state = test_state();
+        state.metrics_endpoint = true;
+        state.upload_size_limit = 16;
+        state.storage = Arc::new(
+            AppStorage::new(StorageProvider::from_str("memory://").unwrap())
+                .with_metrics(Arc::clone(&state.metrics)),
+        );
+        let address = serve(state).await;
+        let request = |address| {
+            format!("GET /metrics HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n")
+        };
+
+        // Test metrics are not served unless enabled.
+        let response = send(disabled, request(disabled).as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 404 Not Found");
+
+        // Test uploads, downloads, rejections and failed decrypts are counted.
+        let response = upload(address, "", b"counted").await;
+        let json = upload_json(&response);
+        let url = json["url"].as_str().unwrap();
+        download(address, url).await;
+        download(
+            address,
+            &format!("{}wrong", &url[..url.find("key=").unwrap() + 4]),
+        )
+        .await;
+        upload(address, "", b"this upload is far too large").await;
+        let response = send(address, request(address).as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let body = response_body(&response);
+        assert!(body.contains("dollshare_uploads_created_total 1\n"));
+        assert!(body.contains("dollshare_uploads_served_total 1\n"));
+        assert!(body.contains("dollshare_decrypt_failures_total 1\n"));
+        assert!(body.contains("dollshare_uploads_rejected_size_total 1\n"));
+
+        // Test storage operations are timed.
+        assert!(
+            body.contains(
+                "dollshare_storage_operation_duration_seconds_count{operation=\"write\"}"
+            )
+        );
+    }
+}
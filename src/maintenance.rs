@@ -0,0 +1,79 @@
+use crate::AppState;
+use axum::{
+    extract::{Request, State},
+    http::{HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+};
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tracing::info;
+
+#[derive(Debug)]
+pub struct MaintenanceMode {
+    enabled: AtomicBool,
+    retry_after: Duration,
+}
+
+impl MaintenanceMode {
+    pub fn new(enabled: bool, retry_after: Duration) -> Self {
+        Self {
+            enabled: AtomicBool::new(enabled),
+            retry_after,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    /// Toggle maintenance mode, returning whether it is now enabled.
+    pub fn toggle(&self) -> bool {
+        let enabled = !self.enabled.fetch_xor(true, Ordering::AcqRel);
+        info!(
+            "Maintenance mode {}",
+            if enabled { "enabled" } else { "disabled" }
+        );
+        enabled
+    }
+
+    /// Middleware that responds with [`StatusCode::SERVICE_UNAVAILABLE`] to all requests
+    /// other than the health check while maintenance mode is enabled.
+    pub async fn maintenance_middleware(
+        State(state): State<AppState>,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        if !state.maintenance.is_enabled() || request.uri().path() == state.health_path {
+            return next.run(request).await;
+        }
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(
+                header::RETRY_AFTER,
+                HeaderValue::from(state.maintenance.retry_after.as_secs()),
+            )],
+            Html(include_str!("static/maintenance.html")),
+        )
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MaintenanceMode;
+    use std::time::Duration;
+
+    #[test]
+    fn test_toggle() {
+        let maintenance = MaintenanceMode::new(false, Duration::from_secs(60));
+
+        // Test toggling enables and disables maintenance mode.
+        assert!(maintenance.toggle());
+        assert!(maintenance.is_enabled());
+        assert!(!maintenance.toggle());
+        assert!(!maintenance.is_enabled());
+    }
+}
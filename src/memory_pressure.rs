@@ -0,0 +1,121 @@
+use crate::AppState;
+use axum::{
+    extract::{Request, State},
+    http::{HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{fs, time::Duration};
+use tracing::debug;
+
+/// Time clients are told to wait before retrying an upload that was shed.
+const RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// Sheds new uploads while the server's memory usage is above a high-water mark.
+#[derive(Debug)]
+pub struct MemoryPressure {
+    high_water_mark: u64,
+    source: fn() -> Option<u64>,
+}
+
+impl MemoryPressure {
+    pub fn new(high_water_mark: u64) -> Self {
+        Self {
+            high_water_mark,
+            source: resident_memory,
+        }
+    }
+
+    /// Replace the function used to sample current memory usage in bytes.
+    #[cfg(test)]
+    pub fn with_source(mut self, source: fn() -> Option<u64>) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Get whether memory usage can be sampled on this platform.
+    pub fn is_supported(&self) -> bool {
+        (self.source)().is_some()
+    }
+
+    /// Get whether memory usage is currently above the high-water mark.
+    ///
+    /// Returns `false` if memory usage cannot be sampled.
+    pub fn is_high(&self) -> bool {
+        (self.source)().is_some_and(|used| used > self.high_water_mark)
+    }
+
+    /// Middleware that rejects new uploads with a `503 Service Unavailable` while
+    /// memory usage is above the high-water mark.
+    ///
+    /// Uploads already past this point and all downloads are unaffected.
+    pub async fn shed_middleware(
+        State(state): State<AppState>,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        if let Some(pressure) = &state.memory_pressure
+            && pressure.is_high()
+        {
+            debug!("Rejecting upload - memory usage above high-water mark");
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(
+                    header::RETRY_AFTER,
+                    HeaderValue::from(RETRY_AFTER.as_secs()),
+                )],
+            )
+                .into_response();
+        }
+        next.run(request).await
+    }
+}
+
+/// Get the resident memory of the current process in bytes.
+///
+/// Only supported on Linux, returns `None` elsewhere.
+fn resident_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let kib = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    kib.checked_mul(1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryPressure;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static USED: AtomicU64 = AtomicU64::new(0);
+
+    #[test]
+    fn test_is_high() {
+        let pressure = MemoryPressure::new(100).with_source(|| Some(USED.load(Ordering::Relaxed)));
+
+        // Test usage at or below the high-water mark is allowed.
+        USED.store(100, Ordering::Relaxed);
+        assert!(!pressure.is_high());
+
+        // Test usage above the high-water mark is reported.
+        USED.store(101, Ordering::Relaxed);
+        assert!(pressure.is_high());
+
+        // Test usage that cannot be sampled never sheds uploads.
+        let unsupported = MemoryPressure::new(0).with_source(|| None);
+        assert!(!unsupported.is_supported());
+        assert!(!unsupported.is_high());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_resident_memory() {
+        // Test the current process reports some resident memory.
+        assert!(super::resident_memory().is_some_and(|used| used > 0));
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+/// Upper bounds in seconds of the buckets that storage operation durations are counted in.
+const DURATION_BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0, 5.0];
+
+/// Counts of server events and storage operation durations, exported in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    uploads_created: AtomicU64,
+    uploads_served: AtomicU64,
+    decrypt_failures: AtomicU64,
+    rejected_mimetype: AtomicU64,
+    rejected_size: AtomicU64,
+    deletes: AtomicU64,
+    storage_durations: Mutex<BTreeMap<&'static str, DurationHistogram>>,
+}
+
+/// Durations of a single kind of operation, counted into [`DURATION_BUCKETS`].
+#[derive(Debug, Default)]
+struct DurationHistogram {
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    /// Count an upload that was saved to storage.
+    pub fn record_upload_created(&self) {
+        self.uploads_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an upload that was served to a client.
+    pub fn record_upload_served(&self) {
+        self.uploads_served.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an upload that could not be decrypted.
+    pub fn record_decrypt_failure(&self) {
+        self.decrypt_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an upload rejected for its type.
+    pub fn record_rejected_mimetype(&self) {
+        self.rejected_mimetype.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an upload rejected for its size.
+    pub fn record_rejected_size(&self) {
+        self.rejected_size.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an upload that was deleted.
+    pub fn record_delete(&self) {
+        self.deletes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record how long a storage operation took.
+    pub fn record_storage_duration(&self, operation: &'static str, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut durations = self.storage_durations.lock().unwrap();
+        let histogram = durations.entry(operation).or_default();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        for (name, help, counter) in [
+            (
+                "dollshare_uploads_created_total",
+                "Uploads saved to storage.",
+                &self.uploads_created,
+            ),
+            (
+                "dollshare_uploads_served_total",
+                "Uploads served to clients.",
+                &self.uploads_served,
+            ),
+            (
+                "dollshare_decrypt_failures_total",
+                "Uploads that could not be decrypted.",
+                &self.decrypt_failures,
+            ),
+            (
+                "dollshare_uploads_rejected_mimetype_total",
+                "Uploads rejected for their type.",
+                &self.rejected_mimetype,
+            ),
+            (
+                "dollshare_uploads_rejected_size_total",
+                "Uploads rejected for their size.",
+                &self.rejected_size,
+            ),
+            (
+                "dollshare_uploads_deleted_total",
+                "Uploads deleted by their uploader or an administrator.",
+                &self.deletes,
+            ),
+        ] {
+            let _ = writeln!(output, "# HELP {name} {help}");
+            let _ = writeln!(output, "# TYPE {name} counter");
+            let _ = writeln!(output, "{name} {}", counter.load(Ordering::Relaxed));
+        }
+
+        let name = "dollshare_storage_operation_duration_seconds";
+        let _ = writeln!(output, "# HELP {name} Duration of storage operations.");
+        let _ = writeln!(output, "# TYPE {name} histogram");
+        for (operation, histogram) in self.storage_durations.lock().unwrap().iter() {
+            for (count, bound) in histogram.buckets.iter().zip(DURATION_BUCKETS) {
+                let _ = writeln!(
+                    output,
+                    "{name}_bucket{{operation=\"{operation}\",le=\"{bound}\"}} {count}"
+                );
+            }
+            let _ = writeln!(
+                output,
+                "{name}_bucket{{operation=\"{operation}\",le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(
+                output,
+                "{name}_sum{{operation=\"{operation}\"}} {}",
+                histogram.sum
+            );
+            let _ = writeln!(
+                output,
+                "{name}_count{{operation=\"{operation}\"}} {}",
+                histogram.count
+            );
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+    use std::time::Duration;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.record_upload_created();
+        metrics.record_upload_created();
+        metrics.record_rejected_size();
+        metrics.record_storage_duration("read", Duration::from_millis(20));
+        metrics.record_storage_duration("read", Duration::from_secs(10));
+        let output = metrics.render();
+
+        // Test counters are rendered with their totals.
+        assert!(output.contains("dollshare_uploads_created_total 2\n"));
+        assert!(output.contains("dollshare_uploads_rejected_size_total 1\n"));
+        assert!(output.contains("dollshare_uploads_deleted_total 0\n"));
+
+        // Test durations are counted in every bucket they fit in.
+        let name = "dollshare_storage_operation_duration_seconds";
+        assert!(output.contains(&format!(
+            "{name}_bucket{{operation=\"read\",le=\"0.01\"}} 0\n"
+        )));
+        assert!(output.contains(&format!(
+            "{name}_bucket{{operation=\"read\",le=\"0.025\"}} 1\n"
+        )));
+        assert!(output.contains(&format!("{name}_bucket{{operation=\"read\",le=\"5\"}} 1\n")));
+        assert!(output.contains(&format!(
+            "{name}_bucket{{operation=\"read\",le=\"+Inf\"}} 2\n"
+        )));
+        assert!(output.contains(&format!("{name}_count{{operation=\"read\"}} 2\n")));
+    }
+}
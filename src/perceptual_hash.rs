@@ -0,0 +1,61 @@
+use image::{DynamicImage, imageops::FilterType};
+
+/// Width and height of the grayscale image that hashes are computed from.
+const HASH_WIDTH: u32 = 9;
+const HASH_HEIGHT: u32 = 8;
+
+/// Compute the difference hash of an image.
+///
+/// Each bit records whether a pixel is brighter than its right-hand neighbour in a
+/// downscaled grayscale copy, so re-encoded or resized copies of an image produce
+/// hashes that differ in only a few bits.
+pub fn hash_image(image: &DynamicImage) -> u64 {
+    let pixels = image
+        .resize_exact(HASH_WIDTH, HASH_HEIGHT, FilterType::Triangle)
+        .to_luma8();
+    let mut hash = 0;
+    for y in 0..HASH_HEIGHT {
+        for x in 0..HASH_WIDTH - 1 {
+            hash <<= 1;
+            if pixels.get_pixel(x, y).0[0] > pixels.get_pixel(x + 1, y).0[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// Get the number of bits that differ between two hashes.
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageFormat, RgbImage};
+    use std::io::Cursor;
+
+    fn test_image() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(128, 96, |x, y| {
+            image::Rgb([(x * 2) as u8, (y * 2) as u8, ((x * y) % 256) as u8])
+        }))
+    }
+
+    #[test]
+    fn test_hash_image() {
+        let original = test_image();
+
+        // Test a re-encoded copy of an image has a close hash.
+        let mut jpeg = Vec::new();
+        original
+            .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+        let reencoded = image::load_from_memory(&jpeg).unwrap();
+        assert!(super::distance(super::hash_image(&original), super::hash_image(&reencoded)) <= 4);
+
+        // Test a different image has a distant hash.
+        let mut inverted = original.clone();
+        inverted.invert();
+        assert!(super::distance(super::hash_image(&original), super::hash_image(&inverted)) > 16);
+    }
+}
@@ -0,0 +1,347 @@
+use crate::http_client;
+use anyhow::anyhow;
+use axum::body::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::{Request, StatusCode, header};
+use hyper_rustls::HttpsConnector;
+use hyper_util::{
+    client::legacy::{
+        Client,
+        connect::{HttpConnector, dns::Name},
+    },
+    rt::TokioExecutor,
+};
+use ipnet::IpNet;
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use url::{Host, Url};
+
+/// Longest a fetch can take, including reading the whole response.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A file fetched from a remote URL.
+pub struct RemoteFile {
+    pub bytes: Bytes,
+    /// Content type the server declared the file with.
+    pub content_type: Option<String>,
+    /// Last segment of the URL's path, used as the file's declared name.
+    pub file_name: Option<String>,
+}
+
+/// Reasons a remote file could not be fetched.
+#[derive(Debug)]
+pub enum FetchError {
+    /// The URL uses a scheme other than HTTP or HTTPS.
+    UnsupportedScheme,
+    /// The URL resolves to an address that files can't be fetched from.
+    AddressNotAllowed,
+    /// The file is larger than the given limit.
+    TooLarge,
+    /// The request failed or the server didn't respond with the file.
+    Failed(anyhow::Error),
+}
+
+/// Whether files can be fetched from an address, which excludes addresses that are only
+/// reachable privately so uploads can't be used to probe the server's network.
+fn is_public_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // Shared address space used for carrier-grade NAT.
+                || (a == 100 && (64..128).contains(&b))
+                // IETF protocol assignments.
+                || (a == 192 && b == 0 && c == 0)
+                // Benchmarking.
+                || (a == 198 && (b == 18 || b == 19))
+                // Reserved for future use.
+                || a >= 240
+                || a == 0)
+        }
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_public_address(IpAddr::V4(ip)),
+            None => {
+                let segments = ip.segments();
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+                    // Site-local, deprecated but still routed privately by some networks.
+                    || segments[0] & 0xffc0 == 0xfec0
+                    // Documentation.
+                    || (segments[0] == 0x2001 && segments[1] == 0xdb8)
+                    || (segments[0] == 0x3fff && segments[1] < 0x1000)
+                    // IETF protocol assignments, including Teredo tunnels, whose IPv4 address
+                    // is obscured, and benchmarking.
+                    || (segments[0] == 0x2001 && segments[1] < 0x200)
+                    // Discard-only.
+                    || segments[..4] == [0x100, 0, 0, 0]
+                    // NAT64 for local use, translated by the network's own translators.
+                    || segments[..3] == [0x64, 0xff9b, 1])
+            }
+        },
+    }
+}
+
+/// Get the IPv4 address embedded in an IPv6 address that translation or tunnelling delivers
+/// its traffic to, so it can be checked in place of the IPv6 address.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let octets = ip.octets();
+    let last = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+    match ip.segments() {
+        // IPv4-mapped and IPv4-translated, ::ffff:a.b.c.d and ::ffff:0:a.b.c.d.
+        [0, 0, 0, 0, 0, 0xffff, ..] | [0, 0, 0, 0, 0xffff, 0, ..] => Some(last),
+        // IPv4-compatible, ::a.b.c.d, which includes the loopback and unspecified addresses.
+        [0, 0, 0, 0, 0, 0, ..] => Some(last),
+        // NAT64 using the well-known prefix, 64:ff9b::a.b.c.d.
+        [0x64, 0xff9b, 0, 0, 0, 0, ..] => Some(last),
+        // 6to4, 2002:aabb:ccdd::/48.
+        [0x2002, ..] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        _ => None,
+    }
+}
+
+/// Get the last segment of a URL's path, which files fetched from it are named after.
+pub fn file_name(url: &Url) -> Option<&str> {
+    url.path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+}
+
+/// Whether files can be fetched from an address, either because it is public or because it is
+/// inside of an allowed network.
+fn is_allowed_address(ip: IpAddr, allowed_networks: &[IpNet]) -> bool {
+    is_public_address(ip) || allowed_networks.iter().any(|network| network.contains(&ip))
+}
+
+/// Resolves hostnames, failing when any address they resolve to is not allowed.
+///
+/// Addresses are checked as part of connecting so a hostname can't resolve to an allowed
+/// address when checked and a private one when connected to.
+#[derive(Clone)]
+struct GuardedResolver {
+    allowed_networks: Arc<Vec<IpNet>>,
+}
+
+impl tower_service::Service<Name> for GuardedResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let allowed_networks = Arc::clone(&self.allowed_networks);
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .collect::<Vec<_>>();
+            if addrs
+                .iter()
+                .any(|addr| !is_allowed_address(addr.ip(), &allowed_networks))
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{name} resolves to an address that is not allowed"),
+                ));
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+/// Fetches files from remote URLs, refusing to connect to private addresses.
+pub struct RemoteFetcher {
+    client: Client<HttpsConnector<HttpConnector<GuardedResolver>>, Empty<Bytes>>,
+    allowed_networks: Arc<Vec<IpNet>>,
+}
+
+impl RemoteFetcher {
+    /// Create a fetcher that may also connect to private addresses inside of the given networks.
+    pub fn new(allowed_networks: Vec<IpNet>) -> io::Result<Self> {
+        let allowed_networks = Arc::new(allowed_networks);
+        let mut http = HttpConnector::new_with_resolver(GuardedResolver {
+            allowed_networks: Arc::clone(&allowed_networks),
+        });
+        http.enforce_http(false);
+        Ok(Self {
+            client: Client::builder(TokioExecutor::new())
+                .build(http_client::https_connector(http)?),
+            allowed_networks,
+        })
+    }
+
+    /// Fetch a file, aborting once it is known to be larger than `limit` bytes.
+    ///
+    /// Redirects are not followed, as they could lead to an address that is not allowed.
+    pub async fn fetch(&self, url: &Url, limit: u64) -> Result<RemoteFile, FetchError> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(FetchError::UnsupportedScheme);
+        }
+        // Addresses given directly are connected to without being resolved.
+        let literal = match url.host() {
+            Some(Host::Ipv4(ip)) => Some(IpAddr::V4(ip)),
+            Some(Host::Ipv6(ip)) => Some(IpAddr::V6(ip)),
+            _ => None,
+        };
+        if literal.is_some_and(|ip| !is_allowed_address(ip, &self.allowed_networks)) {
+            return Err(FetchError::AddressNotAllowed);
+        }
+        tokio::time::timeout(FETCH_TIMEOUT, self.fetch_within(url, limit))
+            .await
+            .map_err(|_| FetchError::Failed(anyhow!("fetching {url} timed out")))?
+    }
+
+    async fn fetch_within(&self, url: &Url, limit: u64) -> Result<RemoteFile, FetchError> {
+        let request = Request::get(url.as_str())
+            .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
+            .body(Empty::new())
+            .map_err(|err| FetchError::Failed(err.into()))?;
+        let response = self.client.request(request).await.map_err(|err| {
+            // Rejections by the resolver are only found in the error's sources.
+            let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&err);
+            while let Some(err) = source {
+                if err
+                    .downcast_ref::<io::Error>()
+                    .is_some_and(|err| err.kind() == io::ErrorKind::PermissionDenied)
+                {
+                    return FetchError::AddressNotAllowed;
+                }
+                source = err.source();
+            }
+            FetchError::Failed(err.into())
+        })?;
+        if response.status() != StatusCode::OK {
+            return Err(FetchError::Failed(anyhow!(
+                "{url} responded with {}",
+                response.status()
+            )));
+        }
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        if header(header::CONTENT_LENGTH)
+            .and_then(|length| length.parse::<u64>().ok())
+            .is_some_and(|length| length > limit)
+        {
+            return Err(FetchError::TooLarge);
+        }
+        let content_type = header(header::CONTENT_TYPE);
+
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(frame) = body.frame().await {
+            let frame = frame.map_err(|err| FetchError::Failed(err.into()))?;
+            if let Some(chunk) = frame.data_ref() {
+                if (bytes.len() + chunk.len()) as u64 > limit {
+                    return Err(FetchError::TooLarge);
+                }
+                bytes.extend_from_slice(chunk);
+            }
+        }
+        Ok(RemoteFile {
+            bytes: Bytes::from(bytes),
+            content_type,
+            file_name: file_name(url).map(str::to_string),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_allowed_address;
+    use ipnet::IpNet;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_is_allowed_address() {
+        let address = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        // Test public addresses are allowed, including public IPv4 addresses embedded in IPv6.
+        for ip in [
+            "93.184.216.34",
+            "2606:2800:220:1::1",
+            "::ffff:93.184.216.34",
+            "64:ff9b::93.184.216.34",
+            "2002:5db8:d822::1",
+        ] {
+            assert!(is_allowed_address(address(ip), &[]), "{ip} was not allowed");
+        }
+
+        // Test private, loopback and link-local addresses are not.
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_allowed_address(address(ip), &[]), "{ip} was allowed");
+        }
+
+        // Test private IPv4 addresses embedded in IPv6 addresses that reach them through
+        // translation or tunnelling are not allowed.
+        for ip in [
+            "::ffff:0:10.1.2.3",
+            "::127.0.0.1",
+            "::10.1.2.3",
+            "64:ff9b::127.0.0.1",
+            "64:ff9b::169.254.169.254",
+            "2002:7f00:1::1",
+            "2002:c0a8:101::1",
+        ] {
+            assert!(!is_allowed_address(address(ip), &[]), "{ip} was allowed");
+        }
+
+        // Test reserved, benchmarking and documentation ranges are not allowed, along with
+        // tunnels and translators that don't reveal the address they reach.
+        for ip in [
+            "198.18.0.1",
+            "198.19.255.254",
+            "240.0.0.1",
+            "255.255.255.255",
+            "192.0.0.1",
+            "2001:db8::1",
+            "3fff::1",
+            "fec0::1",
+            "2001:0:4136:e378:8000:63bf:3fff:fdd2",
+            "2001:2::1",
+            "100::1",
+            "64:ff9b:1::1",
+        ] {
+            assert!(!is_allowed_address(address(ip), &[]), "{ip} was allowed");
+        }
+
+        // Test private addresses inside of an allowed network are allowed.
+        let allowed = ["10.0.0.0/8".parse::<IpNet>().unwrap()];
+        assert!(is_allowed_address(address("10.1.2.3"), &allowed));
+        assert!(!is_allowed_address(address("192.168.1.1"), &allowed));
+    }
+}
@@ -0,0 +1,66 @@
+use crate::AppState;
+use axum::{Json, extract::State, http::StatusCode};
+use serde::Serialize;
+use tracing::error;
+
+#[derive(Serialize)]
+pub struct ServerInfoResponse {
+    version: &'static str,
+    /// Seconds since the server started.
+    uptime: u64,
+    storage: StorageInfo,
+    /// Number of configured tokens, the tokens themselves are never included.
+    tokens: usize,
+    /// Optional features that are enabled on this server.
+    features: Vec<&'static str>,
+    uploads: usize,
+}
+
+#[derive(Serialize)]
+pub struct StorageInfo {
+    scheme: &'static str,
+    supports_expiry: bool,
+}
+
+pub async fn server_info_handler(
+    State(state): State<AppState>,
+) -> Result<Json<ServerInfoResponse>, (StatusCode, &'static str)> {
+    let uploads = state.storage.list_upload_ids().await.map_err(|err| {
+        error!("Failed to list uploads: {err:?}");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Server info could not be read due to an internal server error",
+        )
+    })?;
+    Ok(Json(ServerInfoResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime: state.started.elapsed().as_secs(),
+        storage: StorageInfo {
+            scheme: state.storage.scheme().name(),
+            supports_expiry: state.storage.provider_supports_expiry(),
+        },
+        tokens: state.auth_provider.token_count(),
+        features: enabled_features(&state),
+        uploads: uploads.len(),
+    }))
+}
+
+/// Get the names of the optional features enabled by the server's configuration.
+fn enabled_features(state: &AppState) -> Vec<&'static str> {
+    [
+        ("expiry", state.upload_expiry.is_some()),
+        ("signed_urls", state.signed_url_lifetime.is_some()),
+        ("master_key", state.storage.has_master_keys()),
+        ("metadata_headers", state.upload_metadata_headers),
+        ("perceptual_hashes", state.upload_perceptual_hashes),
+        ("keep_originals", state.upload_keep_originals),
+        ("transcode_heic", state.transcode_heic),
+        ("moderation", state.moderation),
+        ("require_filename", state.upload_require_filename),
+        ("cors", !state.cors_allowed_origins.is_empty()),
+        ("maintenance", state.maintenance.is_enabled()),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect()
+}
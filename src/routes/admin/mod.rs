@@ -0,0 +1,14 @@
+mod info;
+mod moderation;
+mod purge;
+mod rekey;
+mod similar;
+mod uploads;
+mod verify;
+pub use info::*;
+pub use moderation::*;
+pub use purge::*;
+pub use rekey::*;
+pub use similar::*;
+pub use uploads::*;
+pub use verify::*;
@@ -0,0 +1,44 @@
+use crate::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+};
+use tracing::{error, info};
+
+/// Approve an upload pending moderation so it can be served.
+pub async fn approve_upload_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> StatusCode {
+    let _lock = state.upload_locks.lock(&id).await;
+    match state.storage.approve_upload(&id).await {
+        Ok(true) => {
+            info!("Upload {id} was approved");
+            StatusCode::OK
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(err) => {
+            error!("Failed to approve upload {id}: {err:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Reject an upload pending moderation, deleting it.
+pub async fn reject_upload_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> StatusCode {
+    let _lock = state.upload_locks.lock(&id).await;
+    match state.storage.reject_upload(&id).await {
+        Ok(true) => {
+            info!("Upload {id} was rejected");
+            StatusCode::OK
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(err) => {
+            error!("Failed to reject upload {id}: {err:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use crate::{AppState, mime};
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::StatusCode,
+};
+use duration_human::DurationHuman;
+use mime_guess::Mime;
+use serde::{Deserialize, Serialize};
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+use tracing::{error, info};
+
+const PURGE_ERROR_RESPONSE: (StatusCode, &str) = (
+    StatusCode::INTERNAL_SERVER_ERROR,
+    "Uploads could not be purged due to an internal server error",
+);
+
+#[derive(Deserialize)]
+pub struct PurgeUploadsQuery {
+    /// Only include uploads with a matching MIME type, supports type wildcards (e.g. 'text/*').
+    mimetype: Option<String>,
+    /// Only include uploads created longer ago than this human-readable duration.
+    older_than: Option<String>,
+    /// Delete the matching uploads instead of only listing them.
+    #[serde(default)]
+    confirm: bool,
+}
+
+#[derive(Serialize)]
+pub struct PurgeUploadsResponse {
+    count: usize,
+    deleted: bool,
+    uploads: Vec<String>,
+}
+
+pub async fn purge_uploads_handler(
+    State(state): State<AppState>,
+    Query(query): Query<PurgeUploadsQuery>,
+) -> Result<Json<PurgeUploadsResponse>, (StatusCode, &'static str)> {
+    let mimetype = match query.mimetype.as_deref().map(Mime::from_str).transpose() {
+        Ok(mimetype) => mimetype,
+        Err(_) => return Err((StatusCode::BAD_REQUEST, "MIME type filter is invalid")),
+    };
+    // Uploads are listed by their stored names, which have no extension to match a type against.
+    if mimetype.is_some() && state.storage.hides_extensions() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Uploads can't be filtered by MIME type while storage hides their extensions",
+        ));
+    }
+    let older_than = match query
+        .older_than
+        .as_deref()
+        .map(DurationHuman::try_from)
+        .transpose()
+    {
+        Ok(older_than) => older_than.as_ref().map(Duration::from),
+        Err(_) => return Err((StatusCode::BAD_REQUEST, "Age filter is invalid")),
+    };
+
+    let storage = &state.storage;
+    let now = SystemTime::now();
+    let mut uploads = Vec::new();
+    for id in storage.list_upload_ids().await.map_err(|err| {
+        error!("Failed to list uploads: {err:?}");
+        PURGE_ERROR_RESPONSE
+    })? {
+        let created = storage.upload_created(&id).await.map_err(|err| {
+            error!("Failed to get creation time of upload {id}: {err:?}");
+            PURGE_ERROR_RESPONSE
+        })?;
+        if matches_filters(&id, created, mimetype.as_ref(), older_than, now) {
+            uploads.push(id);
+        }
+    }
+
+    if query.confirm {
+        for id in &uploads {
+            let _lock = state.upload_locks.lock(id).await;
+            storage.remove_upload(id).await.map_err(|err| {
+                error!("Failed to delete upload {id}: {err:?}");
+                PURGE_ERROR_RESPONSE
+            })?;
+            state.metrics.record_delete();
+        }
+        info!("Purged {} uploads from storage", uploads.len());
+    }
+
+    Ok(Json(PurgeUploadsResponse {
+        count: uploads.len(),
+        deleted: query.confirm,
+        uploads,
+    }))
+}
+
+/// Whether an upload matches all of the given filters.
+///
+/// Uploads without a known creation time never match an age filter.
+fn matches_filters(
+    id: &str,
+    created: Option<SystemTime>,
+    mimetype: Option<&Mime>,
+    older_than: Option<Duration>,
+    now: SystemTime,
+) -> bool {
+    if let Some(mimetype) = mimetype
+        && !mime::is_mime_allowed(
+            &mime_guess::from_path(id).first_or_octet_stream(),
+            &vec![mimetype.clone()],
+        )
+    {
+        return false;
+    }
+    if let Some(older_than) = older_than
+        && created.is_none_or(|created| created + older_than > now)
+    {
+        return false;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use mime_guess::mime;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_matches_filters() {
+        let now = SystemTime::now();
+        let day = Duration::from_secs(60 * 60 * 24);
+
+        // Test filtering by MIME type.
+        assert!(super::matches_filters(
+            "abc.html",
+            Some(now),
+            Some(&mime::TEXT_HTML),
+            None,
+            now
+        ));
+        assert!(super::matches_filters(
+            "abc.html",
+            Some(now),
+            Some(&mime::TEXT_STAR),
+            None,
+            now
+        ));
+        assert!(!super::matches_filters(
+            "abc.png",
+            Some(now),
+            Some(&mime::TEXT_HTML),
+            None,
+            now
+        ));
+
+        // Test filtering by age.
+        assert!(super::matches_filters(
+            "abc.png",
+            Some(now - day * 31),
+            None,
+            Some(day * 30),
+            now
+        ));
+        assert!(!super::matches_filters(
+            "abc.png",
+            Some(now - day),
+            None,
+            Some(day * 30),
+            now
+        ));
+        assert!(!super::matches_filters(
+            "abc.png",
+            None,
+            None,
+            Some(day * 30),
+            now
+        ));
+
+        // Test uploads must match every filter.
+        assert!(!super::matches_filters(
+            "abc.png",
+            Some(now - day * 31),
+            Some(&mime::TEXT_HTML),
+            Some(day * 30),
+            now
+        ));
+    }
+}
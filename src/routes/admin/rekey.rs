@@ -0,0 +1,43 @@
+use crate::AppState;
+use axum::{Json, extract::State, http::StatusCode};
+use serde::Serialize;
+use tracing::{error, warn};
+
+#[derive(Serialize)]
+pub struct RekeyUploadsResponse {
+    /// Number of upload keys re-wrapped with the current master key.
+    rewrapped: usize,
+    /// Number of upload keys that were already wrapped with the current master key.
+    current: usize,
+    /// Uploads whose key could not be unwrapped with any configured master key.
+    failed: Vec<String>,
+}
+
+pub async fn rekey_uploads_handler(
+    State(state): State<AppState>,
+) -> Result<Json<RekeyUploadsResponse>, (StatusCode, &'static str)> {
+    let storage = &state.storage;
+    let mut response = RekeyUploadsResponse {
+        rewrapped: 0,
+        current: 0,
+        failed: vec![],
+    };
+    for id in storage.list_wrapped_key_ids().await.map_err(|err| {
+        error!("Failed to list wrapped upload keys: {err:?}");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Uploads could not be re-keyed due to an internal server error",
+        )
+    })? {
+        let _lock = state.upload_locks.lock(&id).await;
+        match storage.rewrap_upload_key(&id).await {
+            Ok(true) => response.rewrapped += 1,
+            Ok(false) => response.current += 1,
+            Err(err) => {
+                warn!("Failed to re-wrap key of upload {id}: {err:?}");
+                response.failed.push(id);
+            }
+        }
+    }
+    Ok(Json(response))
+}
@@ -0,0 +1,71 @@
+use crate::{AppState, perceptual_hash};
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+/// Maximum number of differing bits for uploads to be considered similar when not specified.
+const DEFAULT_MAX_DISTANCE: u32 = 10;
+
+#[derive(Deserialize)]
+pub struct SimilarUploadsQuery {
+    /// Perceptual hash to compare uploads against, as hex.
+    hash: String,
+    /// Maximum number of differing bits for an upload to be included.
+    #[serde(default = "default_max_distance")]
+    distance: u32,
+}
+
+fn default_max_distance() -> u32 {
+    DEFAULT_MAX_DISTANCE
+}
+
+#[derive(Serialize)]
+pub struct SimilarUpload {
+    id: String,
+    perceptual_hash: String,
+    distance: u32,
+}
+
+#[derive(Serialize)]
+pub struct SimilarUploadsResponse {
+    uploads: Vec<SimilarUpload>,
+}
+
+pub async fn similar_uploads_handler(
+    State(state): State<AppState>,
+    Query(query): Query<SimilarUploadsQuery>,
+) -> Result<Json<SimilarUploadsResponse>, (StatusCode, &'static str)> {
+    let Ok(hash) = u64::from_str_radix(&query.hash, 16) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Perceptual hash must be a 64-bit hexadecimal value",
+        ));
+    };
+
+    let records = state.storage.list_upload_records().await.map_err(|err| {
+        error!("Failed to list upload records: {err:?}");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Similar uploads could not be found due to an internal server error",
+        )
+    })?;
+
+    let mut uploads: Vec<SimilarUpload> = records
+        .into_iter()
+        .filter_map(|(id, record)| {
+            let perceptual_hash = record.perceptual_hash?;
+            let distance = perceptual_hash::distance(hash, perceptual_hash);
+            (distance <= query.distance).then(|| SimilarUpload {
+                id,
+                perceptual_hash: format!("{perceptual_hash:016x}"),
+                distance,
+            })
+        })
+        .collect();
+    uploads.sort_by_key(|upload| upload.distance);
+    Ok(Json(SimilarUploadsResponse { uploads }))
+}
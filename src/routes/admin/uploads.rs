@@ -0,0 +1,100 @@
+use crate::AppState;
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::time::UNIX_EPOCH;
+use tracing::error;
+
+/// Number of uploads listed per page when not specified.
+const DEFAULT_LIMIT: usize = 100;
+
+/// Maximum number of uploads that can be listed per page.
+const MAX_LIMIT: usize = 1000;
+
+const LIST_ERROR_RESPONSE: (StatusCode, &str) = (
+    StatusCode::INTERNAL_SERVER_ERROR,
+    "Uploads could not be listed due to an internal server error",
+);
+
+#[derive(Deserialize)]
+pub struct ListUploadsQuery {
+    /// Maximum number of uploads to list.
+    #[serde(default = "default_limit")]
+    limit: usize,
+    /// Only list uploads after this one, as given by the previous page's `next_cursor`.
+    cursor: Option<String>,
+}
+
+fn default_limit() -> usize {
+    DEFAULT_LIMIT
+}
+
+#[derive(Serialize)]
+pub struct ListedUpload {
+    id: String,
+    /// Size of the upload's encrypted contents in bytes.
+    size: u64,
+    /// Seconds since the Unix epoch that the upload was last accessed, if tracked by storage.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_access: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ListUploadsResponse {
+    uploads: Vec<ListedUpload>,
+    /// Cursor for the next page of uploads, absent on the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+pub async fn list_uploads_handler(
+    State(state): State<AppState>,
+    Query(query): Query<ListUploadsQuery>,
+) -> Result<Json<ListUploadsResponse>, (StatusCode, &'static str)> {
+    if !(1..=MAX_LIMIT).contains(&query.limit) {
+        return Err((StatusCode::BAD_REQUEST, "Limit must be between 1 and 1000"));
+    }
+
+    let storage = &state.storage;
+    let mut ids = storage.list_upload_ids().await.map_err(|err| {
+        error!("Failed to list uploads: {err:?}");
+        LIST_ERROR_RESPONSE
+    })?;
+    ids.sort_unstable();
+    ids.dedup();
+    if let Some(cursor) = &query.cursor {
+        ids.retain(|id| id > cursor);
+    }
+    let next_cursor = (ids.len() > query.limit).then(|| ids[query.limit - 1].clone());
+    ids.truncate(query.limit);
+
+    let mut uploads = Vec::with_capacity(ids.len());
+    for id in ids {
+        let size = storage.upload_size(&id).await.map_err(|err| {
+            error!("Failed to get size of upload {id}: {err:?}");
+            LIST_ERROR_RESPONSE
+        })?;
+        // Skip uploads that were deleted since they were listed.
+        let Some(size) = size else {
+            continue;
+        };
+        let last_access = storage.upload_last_access(&id).await.map_err(|err| {
+            error!("Failed to get last access time of upload {id}: {err:?}");
+            LIST_ERROR_RESPONSE
+        })?;
+        uploads.push(ListedUpload {
+            id,
+            size,
+            last_access: last_access
+                .and_then(|last_access| last_access.duration_since(UNIX_EPOCH).ok())
+                .map(|since_epoch| since_epoch.as_secs()),
+        });
+    }
+    Ok(Json(ListUploadsResponse {
+        uploads,
+        next_cursor,
+    }))
+}
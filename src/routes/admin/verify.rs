@@ -0,0 +1,49 @@
+use crate::AppState;
+use axum::{Json, extract::State, http::StatusCode};
+use serde::Serialize;
+use tracing::{error, warn};
+
+#[derive(Serialize)]
+pub struct VerifyUploadsResponse {
+    /// Number of uploads whose checksum matched.
+    verified: usize,
+    /// Uploads without a stored checksum, such as those saved before checksums were taken.
+    unverifiable: Vec<String>,
+    /// Uploads whose stored ciphertext no longer matches its checksum.
+    corrupt: Vec<String>,
+}
+
+pub async fn verify_uploads_handler(
+    State(state): State<AppState>,
+) -> Result<Json<VerifyUploadsResponse>, (StatusCode, &'static str)> {
+    let internal_error = || {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Uploads could not be verified due to an internal server error",
+        )
+    };
+
+    let storage = &state.storage;
+    let mut response = VerifyUploadsResponse {
+        verified: 0,
+        unverifiable: vec![],
+        corrupt: vec![],
+    };
+    for id in storage.list_upload_ids().await.map_err(|err| {
+        error!("Failed to list uploads: {err:?}");
+        internal_error()
+    })? {
+        match storage.verify_upload(&id).await.map_err(|err| {
+            error!("Failed to verify upload {id}: {err:?}");
+            internal_error()
+        })? {
+            Some(true) => response.verified += 1,
+            Some(false) => {
+                warn!("Upload {id} does not match its checksum and may be corrupt");
+                response.corrupt.push(id);
+            }
+            None => response.unverifiable.push(id),
+        }
+    }
+    Ok(Json(response))
+}
@@ -1,5 +1,29 @@
-use axum::http::StatusCode;
+use crate::AppState;
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use tracing::warn;
 
-pub async fn health_handler() -> StatusCode {
-    StatusCode::OK
+pub async fn health_handler(State(state): State<AppState>) -> Response {
+    match state.health_body {
+        Some(body) => (StatusCode::OK, body).into_response(),
+        None => StatusCode::OK.into_response(),
+    }
+}
+
+/// Respond as healthy only when storage can be reached within the readiness timeout.
+pub async fn readiness_handler(State(state): State<AppState>) -> Response {
+    match tokio::time::timeout(state.readiness_timeout, state.storage.check_ready()).await {
+        Ok(Ok(())) => health_handler(State(state)).await,
+        Ok(Err(err)) => {
+            warn!("Readiness check failed to reach storage: {err:?}");
+            (StatusCode::SERVICE_UNAVAILABLE, "Storage is unavailable").into_response()
+        }
+        Err(_) => {
+            warn!("Readiness check timed out waiting for storage");
+            (StatusCode::SERVICE_UNAVAILABLE, "Storage is unavailable").into_response()
+        }
+    }
 }
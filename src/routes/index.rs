@@ -1,11 +1,59 @@
+use crate::AppState;
 use axum::{
+    extract::State,
     http::header,
     response::{Html, IntoResponse},
 };
 use axum_extra::response::{Css, JavaScript};
+use bytesize::ByteSize;
+use duration_human::DurationHuman;
+use mime_guess::Mime;
+use std::time::Duration;
 
-pub async fn index_handler() -> Html<&'static str> {
-    Html::from(include_str!("../static/index.html"))
+pub async fn index_handler(State(state): State<AppState>) -> Html<String> {
+    Html::from(render_index(
+        &state.ui_title,
+        state.upload_size_limit,
+        &state.upload_allowed_mimetypes,
+        state.upload_expiry,
+    ))
+}
+
+/// Fill the placeholders of the index page with the server's title and upload limits.
+fn render_index(
+    title: &str,
+    upload_size_limit: u64,
+    allowed_mimetypes: &[Mime],
+    expiry: Option<Duration>,
+) -> String {
+    let allowed_mimetypes = allowed_mimetypes
+        .iter()
+        .map(Mime::essence_str)
+        .collect::<Vec<_>>()
+        .join(", ");
+    let expiry = match expiry {
+        Some(expiry) => format!(
+            "after {:#} without being accessed",
+            DurationHuman::from(expiry)
+        ),
+        None => "only when deleted".to_string(),
+    };
+    include_str!("../static/index.html")
+        .replace("{{ui_title}}", &escape_html(title))
+        .replace("{{allowed_mimetypes}}", &escape_html(&allowed_mimetypes))
+        .replace(
+            "{{upload_size_limit}}",
+            &escape_html(&ByteSize(upload_size_limit).display().si().to_string()),
+        )
+        .replace("{{upload_expiry}}", &escape_html(&expiry))
+}
+
+/// Escape text so it can be safely placed in HTML content or a quoted attribute.
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
 
 pub async fn favicon_handler() -> impl IntoResponse {
@@ -19,6 +67,66 @@ pub async fn index_js_handler() -> JavaScript<&'static str> {
     JavaScript::from(include_str!("../static/index.js"))
 }
 
-pub async fn index_css_handler() -> Css<&'static str> {
-    Css::from(include_str!("../static/index.css"))
+pub async fn index_css_handler(State(state): State<AppState>) -> Css<String> {
+    Css::from(render_css(state.ui_accent_color.as_deref()))
+}
+
+/// Get the stylesheet of the index page, overriding its accent color when one is configured.
+fn render_css(accent_color: Option<&str>) -> String {
+    let css = include_str!("../static/index.css");
+    match accent_color {
+        Some(accent_color) => format!("{css}\n:root {{\n  --col-accent: {accent_color};\n}}\n"),
+        None => css.to_string(),
+    }
+}
+
+/// Whether a color can be safely placed in the stylesheet.
+///
+/// Only the characters used by hex, named and functional colors such as `rgb(0 0 0 / 50%)`
+/// are accepted so the value cannot close the declaration it is placed in.
+pub fn is_valid_css_color(color: &str) -> bool {
+    !color.trim().is_empty()
+        && color.chars().all(|char| {
+            char.is_ascii_alphanumeric()
+                || matches!(char, '#' | '(' | ')' | ',' | '.' | '%' | '/' | ' ' | '-')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use mime_guess::mime;
+    use std::time::Duration;
+
+    #[test]
+    fn test_render_index() {
+        let html = super::render_index(
+            "Dollshare",
+            50_000_000,
+            &[mime::IMAGE_STAR, mime::TEXT_PLAIN],
+            Some(Duration::from_secs(60 * 60)),
+        );
+
+        // Test the configured limits are shown.
+        assert!(html.contains("up to 50.0 MB"));
+        assert!(html.contains("accept=\"image/*, text/plain\""));
+        assert!(html.contains("after 1h without being accessed"));
+        assert!(!html.contains("{{"));
+
+        // Test uploads without expiry are described as kept.
+        let html = super::render_index("Dollshare", 50_000_000, &[], None);
+        assert!(html.contains("removed only when deleted"));
+    }
+
+    #[test]
+    fn test_css_color() {
+        // Test the bundled stylesheet is served unchanged without an accent color.
+        assert_eq!(super::render_css(None), include_str!("../static/index.css"));
+        assert!(super::render_css(Some("teal")).ends_with("--col-accent: teal;\n}\n"));
+
+        // Test colors that could escape their declaration are rejected.
+        assert!(super::is_valid_css_color("#ff69b4"));
+        assert!(super::is_valid_css_color("rgb(255 105 180 / 50%)"));
+        assert!(!super::is_valid_css_color("red; } body { display: none"));
+        assert!(!super::is_valid_css_color(""));
+    }
 }
@@ -1,5 +1,10 @@
+pub mod admin;
 mod health;
 mod index;
+mod metrics;
+mod statistics;
 pub mod uploads;
 pub use health::*;
 pub use index::*;
+pub use metrics::*;
+pub use statistics::*;
@@ -0,0 +1,24 @@
+use crate::AppState;
+use axum::{Json, extract::State, http::StatusCode};
+use serde::Serialize;
+use tracing::error;
+
+#[derive(Serialize)]
+pub struct StatisticsResponse {
+    uploads: usize,
+    /// Total size of the encrypted contents of every upload in bytes.
+    bytes: u64,
+}
+
+pub async fn statistics_handler(
+    State(state): State<AppState>,
+) -> Result<Json<StatisticsResponse>, (StatusCode, &'static str)> {
+    let (uploads, bytes) = state.storage.upload_statistics().await.map_err(|err| {
+        error!("Failed to count uploads: {err:?}");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Statistics could not be read due to an internal server error",
+        )
+    })?;
+    Ok(Json(StatisticsResponse { uploads, bytes }))
+}
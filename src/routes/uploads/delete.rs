@@ -1,30 +1,82 @@
-use crate::AppState;
+use crate::{AppState, auth::AuthProvider};
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, Request, State},
     http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
 };
+use serde::Deserialize;
 use tracing::error;
 
+/// The response for if a file does not exist or the token given to delete it is invalid.
+///
+/// The same response must be given for both so the file is not confirmed to exist
+/// unless the requester actually has its delete token.
+const DELETE_NOT_FOUND_RESPONSE: (StatusCode, &str) = (
+    StatusCode::NOT_FOUND,
+    "This file could not be deleted. Either it does not exist, or your delete token is invalid.",
+);
+
+#[derive(Deserialize)]
+pub struct DeleteUploadQuery {
+    /// Delete token given to the uploader when the upload was made, which is not part of its link.
+    delete_token: Option<String>,
+}
+
+/// Middleware that lets deletions made with the upload's delete token through, requiring a
+/// valid bearer token for any other deletion.
+///
+/// The delete token itself is verified by [`delete_upload_handler`].
+pub async fn delete_auth_middleware(
+    state: State<AppState>,
+    Query(query): Query<DeleteUploadQuery>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match query.delete_token {
+        Some(_) => next.run(request).await,
+        None => AuthProvider::valid_auth_middleware(state, request, next).await,
+    }
+}
+
+/// Delete an upload, either with its delete token or as the holder of a valid bearer token.
+///
+/// Delete tokens only delete the upload they were given for, leaving any other uploads sharing
+/// its content in place. Bearer tokens remove the content outright.
 pub async fn delete_upload_handler(
     State(state): State<AppState>,
     Path(id): Path<String>,
-) -> StatusCode {
-    match state.storage.read().await.upload_exists(&id).await {
+    Query(query): Query<DeleteUploadQuery>,
+) -> Response {
+    let _lock = state.upload_locks.lock(&id).await;
+    match state.storage.upload_exists(&id).await {
         Ok(exists) => {
             if !exists {
-                return StatusCode::NOT_FOUND;
+                return match query.delete_token {
+                    Some(_) => DELETE_NOT_FOUND_RESPONSE.into_response(),
+                    None => StatusCode::NOT_FOUND.into_response(),
+                };
             }
         }
         Err(err) => {
             error!("Failed to check if upload exists: {}", err);
-            return StatusCode::INTERNAL_SERVER_ERROR;
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
         }
     }
 
-    if let Err(err) = state.storage.write().await.delete_upload(&id).await {
-        error!("Failed to delete upload {}: {}", id, err);
-        return StatusCode::INTERNAL_SERVER_ERROR;
+    let deleted = match &query.delete_token {
+        Some(delete_token) => state.storage.delete_upload(&id, delete_token).await,
+        None => state.storage.remove_upload(&id).await.map(|_| true),
+    };
+    match deleted {
+        Ok(true) => {}
+        Ok(false) => return DELETE_NOT_FOUND_RESPONSE.into_response(),
+        Err(err) => {
+            error!("Failed to delete upload {}: {}", id, err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
     }
+    state.metrics.record_delete();
 
-    StatusCode::OK
+    StatusCode::OK.into_response()
 }
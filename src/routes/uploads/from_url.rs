@@ -0,0 +1,145 @@
+use super::post::{
+    UPLOAD_TOO_LARGE_RESPONSE, UploadFile, UploadOptions, describe_rejection, store_upload,
+};
+use super::{CreateUploadsResponse, IdStrategy};
+use crate::{
+    AppState,
+    cryptography::StreamingHash,
+    remote_fetch::{self, FetchError},
+};
+use axum::{
+    Json,
+    extract::{State, rejection::JsonRejection},
+    http::{HeaderMap, StatusCode},
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{borrow::Cow, collections::BTreeMap};
+use tracing::{debug, warn};
+use url::Url;
+
+#[derive(Deserialize)]
+pub struct CreateUploadFromUrlRequest {
+    /// HTTP or HTTPS URL of the file to upload.
+    url: Url,
+    /// Upload options, named and validated as the multipart fields of other uploads are.
+    #[serde(flatten)]
+    options: BTreeMap<String, Value>,
+}
+
+pub async fn create_upload_from_url_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    request: Result<Json<CreateUploadFromUrlRequest>, JsonRejection>,
+) -> Result<Json<CreateUploadsResponse>, (StatusCode, Cow<'static, str>)> {
+    let Ok(Json(request)) = request else {
+        debug!("Rejecting upload from URL - request is not a JSON object with a valid URL");
+        return Err(describe_rejection(
+            &state,
+            (
+                StatusCode::BAD_REQUEST,
+                "Uploads from a URL must be sent as a JSON object with a 'url' field",
+            ),
+        ));
+    };
+
+    let mut options = UploadOptions::from_headers(&state, &headers)
+        .map_err(|rejection| describe_rejection(&state, rejection))?;
+    for (name, value) in &request.options {
+        let value = match value {
+            Value::String(value) => value.clone(),
+            Value::Number(_) | Value::Bool(_) => value.to_string(),
+            _ => {
+                debug!("Rejecting upload from URL - option is not a string, number or boolean");
+                return Err(describe_rejection(
+                    &state,
+                    (
+                        StatusCode::BAD_REQUEST,
+                        "Upload options must be strings, numbers or booleans",
+                    ),
+                ));
+            }
+        };
+        options
+            .set(&state, name, &value)
+            .map_err(|rejection| describe_rejection(&state, rejection))?;
+    }
+
+    if state.upload_require_filename && remote_fetch::file_name(&request.url).is_none() {
+        debug!("Rejecting upload from URL - URL does not end with a filename");
+        return Err(describe_rejection(
+            &state,
+            (
+                StatusCode::BAD_REQUEST,
+                "Uploads must include a filename with the file",
+            ),
+        ));
+    }
+
+    let file = match state
+        .remote_fetcher
+        .fetch(&request.url, state.upload_size_limit)
+        .await
+    {
+        Ok(file) => file,
+        Err(FetchError::UnsupportedScheme) => {
+            debug!("Rejecting upload from URL - URL is not HTTP or HTTPS");
+            return Err(describe_rejection(
+                &state,
+                (
+                    StatusCode::BAD_REQUEST,
+                    "Uploads can only be fetched from HTTP or HTTPS URLs",
+                ),
+            ));
+        }
+        Err(FetchError::AddressNotAllowed) => {
+            debug!("Rejecting upload from URL - URL resolves to an address that is not allowed");
+            return Err(describe_rejection(
+                &state,
+                (
+                    StatusCode::BAD_REQUEST,
+                    "Uploads cannot be fetched from this address",
+                ),
+            ));
+        }
+        Err(FetchError::TooLarge) => {
+            debug!(
+                "Rejecting upload from URL - content is larger than the server's maximum allowed size"
+            );
+            return Err(describe_rejection(&state, UPLOAD_TOO_LARGE_RESPONSE));
+        }
+        Err(FetchError::Failed(err)) => {
+            warn!("Failed to fetch upload from URL: {err:?}");
+            return Err(describe_rejection(
+                &state,
+                (
+                    StatusCode::BAD_GATEWAY,
+                    "The file could not be fetched from the given URL",
+                ),
+            ));
+        }
+    };
+
+    options
+        .verify_checksum(&file.bytes)
+        .map_err(|rejection| describe_rejection(&state, rejection))?;
+
+    let content_hash = (state.upload_id_strategy == IdStrategy::ContentHash).then(|| {
+        let mut hash = StreamingHash::default();
+        hash.update(&file.bytes);
+        hash
+    });
+    store_upload(
+        &state,
+        &headers,
+        &options,
+        UploadFile {
+            bytes: file.bytes,
+            declared_type: (file.content_type, file.file_name),
+            content_hash,
+        },
+    )
+    .await
+    .map(|response| Json(CreateUploadsResponse::Single(response)))
+    .map_err(|rejection| describe_rejection(&state, rejection))
+}
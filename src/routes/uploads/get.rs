@@ -1,39 +1,383 @@
-use crate::AppState;
+use crate::{
+    AppState,
+    auth::{self, AuthState},
+    link_signing, mime,
+    storage::UploadMetadata,
+};
 use axum::{
     extract::{Path, Query, State},
-    http::{StatusCode, header},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header},
     response::IntoResponse,
 };
+use image::{DynamicImage, ImageFormat};
+use mime_guess::Mime;
 use serde::Deserialize;
+use std::{
+    io::Cursor,
+    ops::RangeInclusive,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tracing::error;
 
+pub(super) const PENDING_MODERATION_RESPONSE: (StatusCode, &str) = (
+    StatusCode::FORBIDDEN,
+    "This file is pending moderation and can't be viewed until it is approved.",
+);
+
 /// The response for if a file does not exist or for a decryption failure.
 ///
 /// # Notes:
 /// The same response must be given for both scenarios to ensure the file is
 /// not confirmed to exist unless the end user actually has the decryption key.
-const DECRYPT_OR_NOT_FOUND_RESPONSE: (StatusCode, &str) = (
+pub(super) const DECRYPT_OR_NOT_FOUND_RESPONSE: (StatusCode, &str) = (
     StatusCode::NOT_FOUND,
     "This file could not be displayed. Either it does not exist, or your decryption key is invalid.",
 );
 
+pub(super) const INVALID_SIGNATURE_RESPONSE: (StatusCode, &str) = (
+    StatusCode::FORBIDDEN,
+    "This link has expired or its signature is invalid.",
+);
+
+pub(super) const DECRYPT_TIMEOUT_RESPONSE: (StatusCode, &str) = (
+    StatusCode::SERVICE_UNAVAILABLE,
+    "This file took too long to decrypt, please try again later.",
+);
+
+const MISSING_KEY_RESPONSE: (StatusCode, &str) = (
+    StatusCode::BAD_REQUEST,
+    "A decryption key must be given with the key query parameter or X-Upload-Key header.",
+);
+
+const ORIGINAL_UNAUTHORIZED_RESPONSE: (StatusCode, &str) = (
+    StatusCode::UNAUTHORIZED,
+    "Originals can only be downloaded with a valid bearer token.",
+);
+
+const NOT_AN_IMAGE_RESPONSE: (StatusCode, &str) = (
+    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+    "Thumbnails can only be made of image files.",
+);
+
+/// Size of thumbnails along their longest side when not specified, in pixels.
+const DEFAULT_THUMBNAIL_SIZE: u32 = 256;
+
+/// Maximum size of thumbnails along their longest side in pixels, larger sizes are capped to it.
+const MAX_THUMBNAIL_SIZE: u32 = 1024;
+
+const UPLOAD_SIZE_HEADER: HeaderName = HeaderName::from_static("x-upload-size");
+const UPLOAD_CREATED_HEADER: HeaderName = HeaderName::from_static("x-upload-created");
+const UPLOAD_EXPIRES_HEADER: HeaderName = HeaderName::from_static("x-upload-expires");
+/// Header that the decryption key can be given in, for links that don't include it.
+const UPLOAD_KEY_HEADER: HeaderName = HeaderName::from_static("x-upload-key");
+
+#[derive(Deserialize)]
+pub struct GetUploadPath {
+    /// Identifier of the upload in storage.
+    id: String,
+    /// Optional display name, only used as the suggested download filename.
+    filename: Option<String>,
+}
+
 #[derive(Deserialize)]
 pub struct GetUploadQuery {
-    /// Decryption key for the upload.
-    key: String,
+    /// Decryption key for the upload, otherwise read from the `X-Upload-Key` header.
+    key: Option<String>,
+    /// Charset to serve text uploads with instead of the server default.
+    charset: Option<String>,
+    /// When a signed link expires, as seconds since the Unix epoch.
+    exp: Option<u64>,
+    /// Signature of a signed link.
+    sig: Option<String>,
+    /// Serve the original bytes kept for an altered upload (`1` or `true`), requires a valid
+    /// bearer token.
+    original: Option<String>,
+    /// Serve the upload as an attachment to be downloaded (`1` or `true`) instead of displayed.
+    download: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct GetThumbnailQuery {
+    /// Decryption key for the upload, otherwise read from the `X-Upload-Key` header.
+    key: Option<String>,
+    /// Size of the thumbnail along its longest side in pixels.
+    w: Option<u32>,
+    /// When a signed link expires, as seconds since the Unix epoch.
+    exp: Option<u64>,
+    /// Signature of a signed link.
+    sig: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct HeadUploadQuery {
+    /// Decryption key for the upload, otherwise read from the `X-Upload-Key` header.
+    key: Option<String>,
+    /// Charset to serve text uploads with instead of the server default.
+    charset: Option<String>,
+    /// When a signed link expires, as seconds since the Unix epoch.
+    exp: Option<u64>,
+    /// Signature of a signed link.
+    sig: Option<String>,
+    /// Report the upload as an attachment to be downloaded (`1` or `true`).
+    download: Option<String>,
 }
 
 pub async fn get_upload_handler(
     query: Query<GetUploadQuery>,
-    Path(id): Path<String>,
+    Path(GetUploadPath { id, filename }): Path<GetUploadPath>,
     State(state): State<AppState>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    let storage = state.storage.read().await;
+    let Some(key) = upload_key(query.key.as_deref(), &headers) else {
+        return MISSING_KEY_RESPONSE.into_response();
+    };
+
+    // Only serve signed links that are still valid.
+    if !has_valid_signature(&state, &id, key, query.exp, query.sig.as_deref()) {
+        return INVALID_SIGNATURE_RESPONSE.into_response();
+    }
+
+    // Originals are only served to holders of a valid token as well as the link.
+    let original = query
+        .original
+        .as_deref()
+        .is_some_and(|original| original == "1" || original == "true");
+    if original
+        && !auth::bearer_token(&headers)
+            .ok()
+            .flatten()
+            .is_some_and(|token| state.auth_provider.state_for_token(token) == AuthState::Valid)
+    {
+        return ORIGINAL_UNAUTHORIZED_RESPONSE.into_response();
+    }
+
+    let storage = &state.storage;
 
     // Don't bother trying to decrypt if we know the file doesn't exist.
-    match state.storage.read().await.upload_exists(&id).await {
+    match storage.upload_exists(&id).await {
+        Ok(exists) => {
+            if !exists {
+                if matches!(storage.is_upload_pending(&id).await, Ok(true)) {
+                    return PENDING_MODERATION_RESPONSE.into_response();
+                }
+                return StatusCode::NOT_FOUND.into_response();
+            }
+        }
+        Err(err) => {
+            error!("Failed to check if upload exists: {}", err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    if !is_type_servable(&state, &id) {
+        return DECRYPT_OR_NOT_FOUND_RESPONSE.into_response();
+    }
+
+    // The record is read before decrypting so an upload whose last view is taken by a
+    // concurrent request is either refused when its view is counted or fails to decrypt,
+    // rather than being read as an upload without a view limit once its record is gone.
+    let record = match storage.get_upload_record(&id).await {
+        Ok(record) => record.unwrap_or_default(),
+        Err(err) => {
+            error!("Failed to get record of upload {id}: {err:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let bytes = if original {
+        match within_decrypt_timeout(&state, storage.get_upload_original(&id, key)).await {
+            Some(Ok(Some(bytes))) => bytes,
+            Some(Ok(None) | Err(_)) => return DECRYPT_OR_NOT_FOUND_RESPONSE.into_response(),
+            None => return DECRYPT_TIMEOUT_RESPONSE.into_response(),
+        }
+    } else {
+        // The upload may have been deleted since it was checked to exist, which is treated
+        // the same as it never having existed.
+        match within_decrypt_timeout(&state, storage.get_upload(&id, key)).await {
+            Some(Ok(Some(bytes))) => bytes,
+            Some(Ok(None) | Err(_)) => return DECRYPT_OR_NOT_FOUND_RESPONSE.into_response(),
+            None => return DECRYPT_TIMEOUT_RESPONSE.into_response(),
+        }
+    };
+
+    // Ranges are only checked once the upload has been decrypted so they can't reveal whether
+    // an upload exists to anybody without its key. Uploads with a view limit are always served
+    // in full, as every request for part of one would otherwise use up a view.
+    let range = match headers
+        .get(header::RANGE)
+        .filter(|_| record.max_views.is_none())
+        .and_then(|value| value.to_str().ok())
+        .map(|value| byte_range(value, bytes.len()))
+    {
+        Some(Ok(range)) => range,
+        None => None,
+        Some(Err(())) => {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", bytes.len()))],
+            )
+                .into_response();
+        }
+    };
+
+    // Conditional requests are only answered once the upload has been decrypted so they can't
+    // reveal whether an upload exists to anybody without its key. Revalidating a copy the
+    // client already has doesn't count as a view.
+    let charset = query
+        .charset
+        .as_deref()
+        .filter(|charset| is_valid_charset(charset))
+        .unwrap_or(&state.text_charset);
+    let content_type = content_type(&id, &bytes, charset);
+    let etag = match upload_checksum(&state, &id, &bytes, original).await {
+        Ok(checksum) => entity_tag(&checksum, &content_type, original),
+        Err(err) => {
+            error!("Failed to get checksum of upload {id}: {err:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| etag_matches(value, &etag))
+    {
+        let mut response = StatusCode::NOT_MODIFIED.into_response();
+        response.headers_mut().insert(header::ETAG, etag);
+        response.headers_mut().extend(cache_headers(
+            &state.cache_policy,
+            &mime_guess::from_path(&id).first_or_octet_stream(),
+            record.sensitive || original,
+            record.is_transient(),
+        ));
+        return response;
+    }
+
+    // Count views of uploads with a view limit, refusing those that have reached it. Uploads
+    // deleted by a concurrent view are refused the same as any other missing upload.
+    if record.max_views.is_some() {
+        let _lock = state.upload_locks.lock(&id).await;
+        match storage.record_upload_view(&id).await {
+            Ok(true) => {}
+            Ok(false) => return DECRYPT_OR_NOT_FOUND_RESPONSE.into_response(),
+            Err(err) => {
+                error!("Failed to record view of upload {id}: {err:?}");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+    }
+
+    let response_size = bytes.len();
+    let mut response = match range {
+        Some(range) => {
+            let content_range = format!("bytes {}-{}/{response_size}", range.start(), range.end());
+            let bytes = bytes[range].to_vec();
+            state.throughput.record_download(bytes.len() as u64);
+            state.metrics.record_upload_served();
+            (
+                StatusCode::PARTIAL_CONTENT,
+                [
+                    (header::CONTENT_TYPE, content_type),
+                    (
+                        header::CONTENT_RANGE,
+                        HeaderValue::from_str(&content_range).unwrap(),
+                    ),
+                ],
+                bytes,
+            )
+                .into_response()
+        }
+        None => {
+            state.throughput.record_download(response_size as u64);
+            state.metrics.record_upload_served();
+            ([(header::CONTENT_TYPE, content_type)], bytes).into_response()
+        }
+    };
+    if record.max_views.is_none() {
+        response
+            .headers_mut()
+            .insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    }
+    response.headers_mut().insert(header::ETAG, etag);
+    // Originals keep the metadata that was stripped from the upload, so are never cached.
+    response.headers_mut().extend(cache_headers(
+        &state.cache_policy,
+        &mime_guess::from_path(&id).first_or_octet_stream(),
+        record.sensitive || original,
+        record.is_transient(),
+    ));
+    // Metadata is only needed for the uploader's filename when none was given in the link.
+    let metadata = if filename.is_none() || !state.upload_passthrough_headers.is_empty() {
+        match storage.get_upload_metadata(&id, key).await {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                error!("Failed to get metadata of upload {id}: {err:?}");
+                UploadMetadata::default()
+            }
+        }
+    } else {
+        UploadMetadata::default()
+    };
+    let download = query
+        .download
+        .as_deref()
+        .is_some_and(|download| download == "1" || download == "true");
+    if let Some(disposition) = content_disposition(
+        filename.as_deref().or(metadata.filename.as_deref()),
+        record.sensitive || download,
+    ) {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    response.headers_mut().extend(passthrough_headers(
+        &metadata,
+        &state.upload_passthrough_headers,
+    ));
+    if state.upload_metadata_headers {
+        let created = match storage.upload_created(&id).await {
+            Ok(created) => created,
+            Err(err) => {
+                error!("Failed to get creation time of upload {id}: {err:?}");
+                None
+            }
+        };
+        // Reading the upload just refreshed its access time, so expiry counts from now.
+        let expires = state
+            .upload_expiry
+            .map(|expire_after| SystemTime::now() + record.expiry().unwrap_or(expire_after));
+        response
+            .headers_mut()
+            .extend(upload_metadata_headers(response_size, created, expires));
+    }
+    response
+}
+
+/// Respond to a `HEAD` request for an upload without sending its contents.
+///
+/// The upload must still be decrypted, as that is the only way to verify the key, but views
+/// are not counted and metadata is only read for the uploader's filename. Requests without a
+/// valid key get the same response as for a missing upload, so its existence isn't revealed.
+pub async fn head_upload_handler(
+    query: Query<HeadUploadQuery>,
+    Path(GetUploadPath { id, filename }): Path<GetUploadPath>,
+    State(state): State<AppState>,
+    request_headers: HeaderMap,
+) -> impl IntoResponse {
+    let Some(key) = upload_key(query.key.as_deref(), &request_headers) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if !has_valid_signature(&state, &id, key, query.exp, query.sig.as_deref()) {
+        return INVALID_SIGNATURE_RESPONSE.into_response();
+    }
+
+    let storage = &state.storage;
+    match storage.upload_exists(&id).await {
         Ok(exists) => {
             if !exists {
+                if matches!(storage.is_upload_pending(&id).await, Ok(true)) {
+                    return PENDING_MODERATION_RESPONSE.into_response();
+                }
                 return StatusCode::NOT_FOUND.into_response();
             }
         }
@@ -43,20 +387,798 @@ pub async fn get_upload_handler(
         }
     };
 
-    match storage.get_upload(&id, &query.key).await {
-        Ok(bytes) => (
-            [
-                (
-                    header::CONTENT_TYPE,
-                    mime_guess::from_path(&id)
-                        .first_or_octet_stream()
-                        .essence_str(),
-                ),
-                (header::CACHE_CONTROL, "private, max-age=1800, immutable"),
-            ],
-            (bytes),
+    if !is_type_servable(&state, &id) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let record = match storage.get_upload_record(&id).await {
+        Ok(record) => record.unwrap_or_default(),
+        Err(err) => {
+            error!("Failed to get record of upload {id}: {err:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let mut headers = cache_headers(
+        &state.cache_policy,
+        &mime_guess::from_path(&id).first_or_octet_stream(),
+        record.sensitive,
+        record.is_transient(),
+    );
+    let mut filename = filename;
+    let bytes = match within_decrypt_timeout(&state, storage.get_upload(&id, key)).await {
+        Some(Ok(Some(bytes))) => bytes,
+        Some(Ok(None) | Err(_)) => return StatusCode::NOT_FOUND.into_response(),
+        None => return StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    };
+    let charset = query
+        .charset
+        .as_deref()
+        .filter(|charset| is_valid_charset(charset))
+        .unwrap_or(&state.text_charset);
+    let content_type = content_type(&id, &bytes, charset);
+    match upload_checksum(&state, &id, &bytes, false).await {
+        Ok(checksum) => {
+            headers.insert(header::ETAG, entity_tag(&checksum, &content_type, false));
+        }
+        Err(err) => {
+            error!("Failed to get checksum of upload {id}: {err:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+    headers.insert(header::CONTENT_TYPE, content_type);
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+    if record.max_views.is_none() {
+        headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    }
+    if filename.is_none() {
+        match storage.get_upload_metadata(&id, key).await {
+            Ok(metadata) => filename = metadata.filename,
+            Err(err) => error!("Failed to get metadata of upload {id}: {err:?}"),
+        }
+    }
+    let download = query
+        .download
+        .as_deref()
+        .is_some_and(|download| download == "1" || download == "true");
+    if let Some(disposition) =
+        content_disposition(filename.as_deref(), record.sensitive || download)
+    {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    headers.into_response()
+}
+
+/// Serve a downscaled copy of an image upload.
+///
+/// The upload is decrypted and checked the same as when it is downloaded, so a thumbnail
+/// reveals nothing more about an upload than its link does. Views of uploads with a view
+/// limit are counted.
+pub async fn get_thumbnail_handler(
+    query: Query<GetThumbnailQuery>,
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let Some(key) = upload_key(query.key.as_deref(), &headers) else {
+        return MISSING_KEY_RESPONSE.into_response();
+    };
+    if !has_valid_signature(&state, &id, key, query.exp, query.sig.as_deref()) {
+        return INVALID_SIGNATURE_RESPONSE.into_response();
+    }
+
+    let storage = &state.storage;
+    match storage.upload_exists(&id).await {
+        Ok(exists) => {
+            if !exists {
+                if matches!(storage.is_upload_pending(&id).await, Ok(true)) {
+                    return PENDING_MODERATION_RESPONSE.into_response();
+                }
+                return StatusCode::NOT_FOUND.into_response();
+            }
+        }
+        Err(err) => {
+            error!("Failed to check if upload exists: {}", err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    if !is_type_servable(&state, &id) {
+        return DECRYPT_OR_NOT_FOUND_RESPONSE.into_response();
+    }
+
+    let bytes = match within_decrypt_timeout(&state, storage.get_upload(&id, key)).await {
+        Some(Ok(Some(bytes))) => bytes,
+        Some(Ok(None) | Err(_)) => return DECRYPT_OR_NOT_FOUND_RESPONSE.into_response(),
+        None => return DECRYPT_TIMEOUT_RESPONSE.into_response(),
+    };
+
+    let record = match storage.get_upload_record(&id).await {
+        Ok(record) => record.unwrap_or_default(),
+        Err(err) => {
+            error!("Failed to get record of upload {id}: {err:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    if record.max_views.is_some() {
+        let _lock = state.upload_locks.lock(&id).await;
+        match storage.record_upload_view(&id).await {
+            Ok(true) => {}
+            Ok(false) => return DECRYPT_OR_NOT_FOUND_RESPONSE.into_response(),
+            Err(err) => {
+                error!("Failed to record view of upload {id}: {err:?}");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+    }
+
+    let size = query
+        .w
+        .unwrap_or(DEFAULT_THUMBNAIL_SIZE)
+        .clamp(1, MAX_THUMBNAIL_SIZE);
+    let (bytes, format) = match tokio::task::spawn_blocking(move || thumbnail(&bytes, size)).await {
+        Ok(Ok(Some(thumbnail))) => thumbnail,
+        Ok(Ok(None)) => return NOT_AN_IMAGE_RESPONSE.into_response(),
+        Ok(Err(err)) => {
+            error!("Failed to make thumbnail of upload {id}: {err:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        Err(err) => {
+            error!("Thumbnail task for upload {id} failed: {err:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    state.throughput.record_download(bytes.len() as u64);
+    state.metrics.record_upload_served();
+    let mut response = (
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(format.to_mime_type()),
+        )],
+        bytes,
+    )
+        .into_response();
+    response.headers_mut().extend(cache_headers(
+        &state.cache_policy,
+        &Mime::from_str(format.to_mime_type()).unwrap(),
+        record.sensitive,
+        record.is_transient(),
+    ));
+    response
+}
+
+/// Make a thumbnail of an image that fits within the given size without enlarging it.
+///
+/// Images with transparency are encoded as WebP so it is kept, and all others as JPEG.
+/// Returns `None` if the bytes are not an image that can be decoded.
+fn thumbnail(bytes: &[u8], size: u32) -> anyhow::Result<Option<(Vec<u8>, ImageFormat)>> {
+    let Ok(image) = image::load_from_memory(bytes) else {
+        return Ok(None);
+    };
+    let image = if image.width().max(image.height()) > size {
+        image.thumbnail(size, size)
+    } else {
+        image
+    };
+    let (image, format) = if image.color().has_alpha() {
+        (
+            DynamicImage::ImageRgba8(image.to_rgba8()),
+            ImageFormat::WebP,
+        )
+    } else {
+        (DynamicImage::ImageRgb8(image.to_rgb8()), ImageFormat::Jpeg)
+    };
+    let mut thumbnail = Vec::new();
+    image.write_to(Cursor::new(&mut thumbnail), format)?;
+    Ok(Some((thumbnail, format)))
+}
+
+/// Wait for an upload to be decrypted, returning `None` once the decrypt timeout has elapsed.
+///
+/// Decryption that has already started on a blocking thread still runs to completion, the
+/// timeout only stops requests waiting on it.
+pub(super) async fn within_decrypt_timeout<T>(
+    state: &AppState,
+    decrypt: impl Future<Output = T>,
+) -> Option<T> {
+    match state.decrypt_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, decrypt).await.ok(),
+        None => Some(decrypt.await),
+    }
+}
+
+/// Get the checksum an upload's entity tag is taken from, which is the one stored with it or,
+/// for originals and uploads saved without one, a hash of the bytes being served.
+async fn upload_checksum(
+    state: &AppState,
+    id: &str,
+    bytes: &[u8],
+    original: bool,
+) -> anyhow::Result<String> {
+    let checksum = match original {
+        true => None,
+        false => state.storage.get_upload_checksum(id).await?,
+    };
+    Ok(checksum.unwrap_or_else(|| blake3::hash(bytes).to_hex().to_string()))
+}
+
+/// Build the strong `ETag` of an upload as it is served, or of its original when it was kept.
+///
+/// The tag is taken from the checksum of the stored content along with the content type it is
+/// served as, which changes with the charset, so each representation is tagged differently.
+fn entity_tag(checksum: &str, content_type: &HeaderValue, original: bool) -> HeaderValue {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(checksum.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(content_type.as_bytes());
+    let suffix = if original { "-original" } else { "" };
+    HeaderValue::from_str(&format!(
+        "\"{}{suffix}\"",
+        &hasher.finalize().to_hex()[..32]
+    ))
+    .unwrap()
+}
+
+/// Whether an `If-None-Match` header matches an entity tag, using the weak comparison
+/// the header calls for.
+fn etag_matches(if_none_match: &str, etag: &HeaderValue) -> bool {
+    let Ok(etag) = etag.to_str() else {
+        return false;
+    };
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .any(|tag| tag.trim().trim_start_matches("W/") == etag)
+}
+
+/// Parse a single range of a `bytes=` `Range` header for content of the given length.
+///
+/// Returns `Ok(None)` for headers that can't be parsed or request multiple ranges, which are
+/// ignored so the whole content is served, and `Err` for ranges that can't be satisfied.
+fn byte_range(value: &str, len: usize) -> Result<Option<RangeInclusive<usize>>, ()> {
+    let Some((start, end)) = value
+        .trim()
+        .strip_prefix("bytes=")
+        .filter(|ranges| !ranges.contains(','))
+        .and_then(|range| range.split_once('-'))
+    else {
+        return Ok(None);
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    // Suffix ranges request the last bytes of the content.
+    if start.is_empty() {
+        let Ok(suffix) = end.parse::<usize>() else {
+            return Ok(None);
+        };
+        if suffix == 0 || len == 0 {
+            return Err(());
+        }
+        return Ok(Some(len.saturating_sub(suffix)..=len - 1));
+    }
+
+    let Ok(start) = start.parse::<usize>() else {
+        return Ok(None);
+    };
+    let end = if end.is_empty() {
+        usize::MAX
+    } else {
+        match end.parse::<usize>() {
+            Ok(end) if end >= start => end,
+            _ => return Ok(None),
+        }
+    };
+    if start >= len {
+        return Err(());
+    }
+    Ok(Some(start..=end.min(len - 1)))
+}
+
+/// Whether an upload's type may still be served, which is only checked when the upload
+/// MIME types are enforced on download.
+pub(super) fn is_type_servable(state: &AppState, id: &str) -> bool {
+    !state.enforce_mimetypes_on_download
+        || mime::is_mime_allowed(
+            &mime_guess::from_path(id).first_or_octet_stream(),
+            &state.upload_allowed_mimetypes,
+        )
+}
+
+/// Get the decryption key given with a request, preferring the `key` query parameter.
+fn upload_key<'a>(query_key: Option<&'a str>, headers: &'a HeaderMap) -> Option<&'a str> {
+    query_key.or_else(|| headers.get(UPLOAD_KEY_HEADER)?.to_str().ok())
+}
+
+/// Whether a request carries a valid, unexpired link signature when signed links are enabled.
+pub(super) fn has_valid_signature(
+    state: &AppState,
+    id: &str,
+    key: &str,
+    expires: Option<u64>,
+    signature: Option<&str>,
+) -> bool {
+    if state.signed_url_lifetime.is_none() {
+        return true;
+    }
+    match (expires, signature) {
+        (Some(expires), Some(signature)) => link_signing::verify_link(
+            id,
+            key,
+            expires,
+            signature,
+            &state.persisted_salt,
+            SystemTime::now(),
+        ),
+        _ => false,
+    }
+}
+
+/// Get the `Content-Type` to serve an upload with.
+///
+/// Text uploads are given the charset parameter unless it is empty, or it is UTF-8 and
+/// the upload is not valid UTF-8.
+fn content_type(id: &str, bytes: &[u8], charset: &str) -> HeaderValue {
+    let mime = mime_guess::from_path(id).first_or_octet_stream();
+    if mime.type_() != mime_guess::mime::TEXT
+        || charset.is_empty()
+        || (charset.eq_ignore_ascii_case("utf-8") && str::from_utf8(bytes).is_err())
+    {
+        return HeaderValue::from_str(mime.essence_str()).unwrap();
+    }
+    HeaderValue::from_str(&format!("{}; charset={charset}", mime.essence_str())).unwrap()
+}
+
+/// Whether a charset name only contains characters that are valid in a `Content-Type` parameter.
+pub fn is_valid_charset(charset: &str) -> bool {
+    charset
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+/// `Cache-Control` policies to serve uploads with, which can differ by the type of upload.
+#[derive(Debug, Clone)]
+pub struct CachePolicy {
+    pub default: HeaderValue,
+    pub images: Option<HeaderValue>,
+    pub videos: Option<HeaderValue>,
+}
+
+impl CachePolicy {
+    /// Get the `Cache-Control` value for an upload of the given type.
+    fn for_type(&self, mimetype: &Mime) -> &HeaderValue {
+        let policy = match mimetype.type_() {
+            mime_guess::mime::IMAGE => self.images.as_ref(),
+            mime_guess::mime::VIDEO => self.videos.as_ref(),
+            _ => None,
+        };
+        policy.unwrap_or(&self.default)
+    }
+}
+
+/// Build the caching headers for an upload of the given type.
+///
+/// Sensitive uploads are never stored by caches, regardless of any other cache policy.
+/// Transient uploads, which stop being served after being viewed or their own expiry,
+/// are not stored either so that caches don't keep serving them.
+pub(super) fn cache_headers(
+    policy: &CachePolicy,
+    mimetype: &Mime,
+    sensitive: bool,
+    transient: bool,
+) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if sensitive {
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("no-store, private"),
+        );
+        headers.insert(header::PRAGMA, HeaderValue::from_static("no-cache"));
+    } else if transient {
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    } else {
+        headers.insert(header::CACHE_CONTROL, policy.for_type(mimetype).clone());
+    }
+    headers
+}
+
+/// Maximum length of a filename suggested for an upload.
+const MAX_FILENAME_LENGTH: usize = 255;
+
+/// Make a filename safe to be suggested in a `Content-Disposition` header.
+///
+/// Only printable ASCII is kept and characters that could break out of the quoted
+/// filename or name a directory are removed. Returns `None` if nothing usable remains.
+pub fn sanitize_filename(filename: &str) -> Option<String> {
+    let filename: String = filename
+        .chars()
+        .filter(|c| (c.is_ascii_graphic() || *c == ' ') && !matches!(c, '"' | '\\' | '/'))
+        .take(MAX_FILENAME_LENGTH)
+        .collect();
+    let filename = filename.trim();
+    (!filename.is_empty()).then(|| filename.to_string())
+}
+
+/// Build the `Content-Disposition` header for an upload, suggesting the given filename.
+///
+/// Inline dispositions are only given when a usable filename remains once it is sanitized.
+fn content_disposition(filename: Option<&str>, attachment: bool) -> Option<HeaderValue> {
+    let disposition = if attachment { "attachment" } else { "inline" };
+    let Some(filename) = filename.and_then(sanitize_filename) else {
+        return attachment.then(|| HeaderValue::from_static("attachment"));
+    };
+    HeaderValue::from_str(&format!("{disposition}; filename=\"{filename}\"")).ok()
+}
+
+/// Build the `X-Upload-*` metadata headers for an upload.
+///
+/// Times are given as seconds since the Unix epoch and are omitted when unknown.
+fn upload_metadata_headers(
+    size: usize,
+    created: Option<SystemTime>,
+    expires: Option<SystemTime>,
+) -> HeaderMap {
+    let unix_seconds = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|duration| HeaderValue::from(duration.as_secs()))
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(UPLOAD_SIZE_HEADER, HeaderValue::from(size));
+    if let Some(created) = created.and_then(unix_seconds) {
+        headers.insert(UPLOAD_CREATED_HEADER, created);
+    }
+    if let Some(expires) = expires.and_then(unix_seconds) {
+        headers.insert(UPLOAD_EXPIRES_HEADER, expires);
+    }
+    headers
+}
+
+/// Build the stored uploader headers that are still permitted to be passed through.
+fn passthrough_headers(metadata: &UploadMetadata, permitted: &[HeaderName]) -> HeaderMap {
+    metadata
+        .headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let name = HeaderName::from_str(name).ok()?;
+            if !permitted.contains(&name) {
+                return None;
+            }
+            Some((name, HeaderValue::from_str(value).ok()?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_etag_matches() {
+        use super::{entity_tag, etag_matches};
+        use axum::http::HeaderValue;
+
+        let text = HeaderValue::from_static("text/plain; charset=utf-8");
+        let etag = entity_tag("checksum", &text, false);
+        let tag = etag.to_str().unwrap();
+        assert!(tag.starts_with('"') && tag.ends_with('"'));
+
+        // Test tags differ by checksum, content type and charset, and for originals.
+        assert_eq!(entity_tag("checksum", &text, false), etag);
+        assert_ne!(entity_tag("other", &text, false), etag);
+        assert_ne!(
+            entity_tag("checksum", &HeaderValue::from_static("text/plain"), false),
+            etag
+        );
+        assert_ne!(
+            entity_tag(
+                "checksum",
+                &HeaderValue::from_static("text/plain; charset=iso-8859-1"),
+                false
+            ),
+            etag
+        );
+        let original = entity_tag("checksum", &text, true);
+        assert!(original.to_str().unwrap().ends_with("-original\""));
+
+        // Test tags match exactly or weakly, alone or in a list.
+        assert!(etag_matches(tag, &etag));
+        assert!(etag_matches(&format!("W/{tag}"), &etag));
+        assert!(etag_matches(&format!("\"other\", {tag}"), &etag));
+        assert!(etag_matches("*", &etag));
+
+        // Test other tags and the original's tag don't match.
+        assert!(!etag_matches("\"other\"", &etag));
+        assert!(!etag_matches(tag.trim_matches('"'), &etag));
+        assert!(!etag_matches(original.to_str().unwrap(), &etag));
+    }
+
+    #[test]
+    fn test_thumbnail() {
+        use image::{DynamicImage, ImageFormat};
+        use std::io::Cursor;
+
+        let encode = |image: DynamicImage| {
+            let mut bytes = Vec::new();
+            image
+                .write_to(Cursor::new(&mut bytes), ImageFormat::Png)
+                .unwrap();
+            bytes
+        };
+
+        // Test thumbnails keep the image's aspect ratio and are encoded as JPEG.
+        let (thumbnail, format) = super::thumbnail(&encode(DynamicImage::new_rgb8(400, 200)), 100)
+            .unwrap()
+            .unwrap();
+        assert_eq!(format, ImageFormat::Jpeg);
+        let thumbnail = image::load_from_memory(&thumbnail).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (100, 50));
+
+        // Test images with transparency are encoded as WebP and small images aren't enlarged.
+        let (thumbnail, format) = super::thumbnail(&encode(DynamicImage::new_rgba8(20, 40)), 100)
+            .unwrap()
+            .unwrap();
+        assert_eq!(format, ImageFormat::WebP);
+        let thumbnail = image::load_from_memory(&thumbnail).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (20, 40));
+
+        // Test bytes that aren't an image have no thumbnail.
+        assert!(super::thumbnail(b"not an image", 100).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_byte_range() {
+        // Test bounded, open-ended and suffix ranges.
+        assert_eq!(super::byte_range("bytes=0-99", 1000), Ok(Some(0..=99)));
+        assert_eq!(super::byte_range("bytes=500-", 1000), Ok(Some(500..=999)));
+        assert_eq!(super::byte_range("bytes=-500", 1000), Ok(Some(500..=999)));
+
+        // Test ranges past the end of the content are clamped to it.
+        assert_eq!(
+            super::byte_range("bytes=900-2000", 1000),
+            Ok(Some(900..=999))
+        );
+        assert_eq!(super::byte_range("bytes=-2000", 1000), Ok(Some(0..=999)));
+
+        // Test ranges that start past the end of the content can't be satisfied.
+        assert_eq!(super::byte_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(super::byte_range("bytes=-0", 1000), Err(()));
+
+        // Test unparseable and multiple ranges are ignored.
+        assert_eq!(super::byte_range("bytes=abc", 1000), Ok(None));
+        assert_eq!(super::byte_range("bytes=10-5", 1000), Ok(None));
+        assert_eq!(super::byte_range("items=0-1", 1000), Ok(None));
+        assert_eq!(super::byte_range("bytes=0-1,5-6", 1000), Ok(None));
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        // Test a plain display name is used as the filename.
+        assert_eq!(
+            super::content_disposition(Some("my-photo.png"), false).unwrap(),
+            "inline; filename=\"my-photo.png\""
+        );
+
+        // Test quotes and separators cannot escape the filename.
+        assert_eq!(
+            super::content_disposition(Some("a\"b\\c/d.png"), false).unwrap(),
+            "inline; filename=\"abcd.png\""
+        );
+
+        // Test names with nothing usable are ignored.
+        assert!(super::content_disposition(Some(" \"/ "), false).is_none());
+        assert!(super::content_disposition(None, false).is_none());
+
+        // Test attachments are always given a disposition.
+        assert_eq!(
+            super::content_disposition(Some("my-photo.png"), true).unwrap(),
+            "attachment; filename=\"my-photo.png\""
+        );
+        assert_eq!(
+            super::content_disposition(None, true).unwrap(),
+            "attachment"
+        );
+    }
+
+    #[test]
+    fn test_content_type() {
+        // Test text uploads include the charset.
+        assert_eq!(
+            super::content_type("abc.txt", b"hello", "utf-8"),
+            "text/plain; charset=utf-8"
+        );
+
+        // Test text uploads that are not valid UTF-8 are not labelled as UTF-8.
+        assert_eq!(
+            super::content_type("abc.txt", &[0xff, 0xfe], "utf-8"),
+            "text/plain"
+        );
+
+        // Test other charsets are used as given.
+        assert_eq!(
+            super::content_type("abc.txt", &[0xff, 0xfe], "iso-8859-1"),
+            "text/plain; charset=iso-8859-1"
+        );
+
+        // Test an empty charset is not added.
+        assert_eq!(super::content_type("abc.txt", b"hello", ""), "text/plain");
+
+        // Test non-text uploads are unaffected.
+        assert_eq!(
+            super::content_type("abc.png", b"hello", "utf-8"),
+            "image/png"
+        );
+    }
+
+    #[test]
+    fn test_upload_metadata_headers() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        // Test all headers are present when all metadata is known.
+        let headers = super::upload_metadata_headers(
+            1024,
+            Some(UNIX_EPOCH + Duration::from_secs(1000)),
+            Some(UNIX_EPOCH + Duration::from_secs(2000)),
+        );
+        assert_eq!(headers.get("x-upload-size").unwrap(), "1024");
+        assert_eq!(headers.get("x-upload-created").unwrap(), "1000");
+        assert_eq!(headers.get("x-upload-expires").unwrap(), "2000");
+
+        // Test unknown times are omitted.
+        let headers = super::upload_metadata_headers(1024, None, None);
+        assert_eq!(headers.get("x-upload-size").unwrap(), "1024");
+        assert!(headers.get("x-upload-created").is_none());
+        assert!(headers.get("x-upload-expires").is_none());
+    }
+
+    #[cfg(feature = "storage-memory")]
+    #[tokio::test]
+    async fn test_thumbnail_handler() {
+        use crate::test_support::{
+            download, png_fixture, send, serve, status_line, test_state, upload, upload_json,
+        };
+
+        let address = serve(test_state()).await;
+        let image = png_fixture(8, 4);
+        let url = |response: &str| {
+            let json = upload_json(response);
+            json["url"].as_str().unwrap().to_string()
+        };
+        let thumbnail = |url: &str| {
+            let path = url[url.find("/upload/").unwrap()..]
+                .replacen("/upload/", "/thumbnail/", 1)
+                .replacen("?key=", "?w=2&key=", 1);
+            async move {
+                send(
+                    address,
+                    format!("GET {path} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n")
+                        .as_bytes(),
+                )
+                .await
+            }
+        };
+
+        // Test thumbnails of images are served as JPEG.
+        let response = upload(address, "", &image).await;
+        let image_url = url(&response);
+        let response = thumbnail(&image_url).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(response.contains("content-type: image/jpeg\r\n"));
+
+        // Test an upload can still be given "thumbnail" as its display filename.
+        let response = download(address, &image_url.replacen("?key=", "/thumbnail?key=", 1)).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(response.contains("content-type: image/png\r\n"));
+
+        // Test uploads that aren't images have no thumbnail.
+        let response = upload(address, "", b"not an image").await;
+        let response = thumbnail(&url(&response)).await;
+        assert_eq!(
+            status_line(&response),
+            "HTTP/1.1 415 Unsupported Media Type"
+        );
+
+        // Test an invalid key is masked the same as a missing upload.
+        let response = upload(address, "", b"masked").await;
+        let response = thumbnail(&url(&response).replace("key=", "key=invalid")).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 404 Not Found");
+    }
+
+    #[cfg(feature = "storage-memory")]
+    #[tokio::test]
+    async fn test_burn_after_read() {
+        use crate::test_support::{
+            download, response_body, serve, status_line, test_state, upload_json,
+            upload_with_fields,
+        };
+        use std::sync::Arc;
+
+        let state = test_state();
+        let storage = Arc::clone(&state.storage);
+        let address = serve(state).await;
+
+        // Test burn uploads are served once and then removed.
+        let response = upload_with_fields(address, "", &[("burn", "true")], b"secret").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let json = upload_json(&response);
+        let url = json["url"].as_str().unwrap();
+        let response = download(address, url).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert_eq!(response_body(&response), "secret");
+        assert!(
+            !storage
+                .upload_exists(json["id"].as_str().unwrap())
+                .await
+                .unwrap()
+        );
+
+        // Test burnt uploads can't be told apart from uploads that never existed.
+        let burnt = download(address, url).await;
+        let missing = download(
+            address,
+            &url.replace(json["id"].as_str().unwrap(), "missing"),
         )
-            .into_response(),
-        Err(_) => DECRYPT_OR_NOT_FOUND_RESPONSE.into_response(),
+        .await;
+        assert_eq!(status_line(&burnt), "HTTP/1.1 404 Not Found");
+        assert_eq!(response_body(&burnt), response_body(&missing));
+
+        // Test only one of many concurrent downloads is served.
+        let response = upload_with_fields(address, "", &[("burn", "true")], b"raced").await;
+        let json = upload_json(&response);
+        let url = json["url"].as_str().unwrap().to_string();
+        let downloads: Vec<_> = (0..8)
+            .map(|_| {
+                let url = url.clone();
+                tokio::spawn(async move { download(address, &url).await })
+            })
+            .collect();
+        let mut served = 0;
+        for download in downloads {
+            if status_line(&download.await.unwrap()) == "HTTP/1.1 200 OK" {
+                served += 1;
+            }
+        }
+        assert_eq!(served, 1);
+    }
+
+    #[cfg(feature = "storage-memory")]
+    #[tokio::test]
+    async fn test_cache_control() {
+        use super::CachePolicy;
+        use crate::test_support::{
+            download, png_fixture, serve, test_state, upload, upload_json, upload_with_fields,
+        };
+        use axum::http::HeaderValue;
+        use std::time::Duration;
+
+        let mut state = test_state();
+        state.upload_size_limit = 64 * 1024;
+        state.upload_expiry = Some(Duration::from_secs(24 * 60 * 60));
+        state.cache_policy = CachePolicy {
+            default: HeaderValue::from_static("private, max-age=60"),
+            images: Some(HeaderValue::from_static("public, max-age=86400, immutable")),
+            videos: None,
+        };
+        let address = serve(state).await;
+        let image = png_fixture(4, 4);
+
+        // Test images are served with the image policy.
+        let response = upload(address, "", &image).await;
+        let json = upload_json(&response);
+        let response = download(address, json["url"].as_str().unwrap()).await;
+        assert!(response.contains("cache-control: public, max-age=86400, immutable\r\n"));
+
+        // Test other types are served with the default policy.
+        let response = upload(address, "", b"cached briefly").await;
+        let json = upload_json(&response);
+        let response = download(address, json["url"].as_str().unwrap()).await;
+        assert!(response.contains("cache-control: private, max-age=60\r\n"));
+
+        // Test uploads with a view limit or their own expiry are never stored.
+        let response = upload_with_fields(address, "", &[("max_views", "2")], &image).await;
+        let json = upload_json(&response);
+        let response = download(address, json["url"].as_str().unwrap()).await;
+        assert!(response.contains("cache-control: no-store\r\n"));
+        let response = upload(address, "X-Upload-Expiry: 1h\r\n", b"expires soon").await;
+        let json = upload_json(&response);
+        let response = download(address, json["url"].as_str().unwrap()).await;
+        assert!(response.contains("cache-control: no-store\r\n"));
     }
 }
@@ -1,6 +1,12 @@
 mod delete;
+#[cfg(feature = "upload-from-url")]
+mod from_url;
 mod get;
 mod post;
+mod view;
 pub use delete::*;
+#[cfg(feature = "upload-from-url")]
+pub use from_url::*;
 pub use get::*;
 pub use post::*;
+pub use view::*;
@@ -1,44 +1,582 @@
-use crate::{AppState, cryptography::Cryptography, mime};
+use crate::{
+    AppState, archive, auth,
+    cryptography::{Cryptography, StreamingHash},
+    idempotency::MAX_IDEMPOTENCY_KEY_LENGTH,
+    link_signing, mime, perceptual_hash,
+    storage::{InsufficientStorageError, UploadMetadata, UploadRecord, UploadReference},
+    transcode,
+};
 use axum::{
     Json,
-    extract::{Multipart, State},
-    http::StatusCode,
+    body::Bytes,
+    extract::{
+        Multipart, Request, State,
+        multipart::{Field, MultipartRejection},
+    },
+    http::{HeaderMap, StatusCode, header},
+    middleware::Next,
+    response::Response,
+};
+use clap::ValueEnum;
+use duration_human::DurationHuman;
+use image::{
+    DynamicImage, ImageDecoder, ImageEncoder, ImageFormat, ImageReader, ImageResult,
+    codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    metadata::Orientation,
 };
-use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, metadata::Orientation};
 use infer::MatcherType;
 use mime_guess::{
     Mime,
-    mime::{APPLICATION_OCTET_STREAM, STAR_STAR},
+    mime::{APPLICATION_OCTET_STREAM, IMAGE_JPEG, STAR_STAR},
 };
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::{
-    io::{BufReader, BufWriter, Cursor, Write},
+    borrow::Cow,
+    io::{BufReader, BufWriter, Cursor, Seek, Write},
     str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
 };
 use tracing::{debug, error, warn};
+use url::Url;
 
 const FALLBACK_ENABLED_MIME: Mime = STAR_STAR;
 
-#[derive(Serialize)]
+/// MIME type inferred for both HEIC and HEIF images.
+const HEIF_MIME: &str = "image/heif";
+
+/// Allowance on top of the upload size limit for the multipart boundaries and field headers
+/// surrounding the uploaded file in the request body.
+const MULTIPART_OVERHEAD_ALLOWANCE: u64 = 16 * 1024;
+
+pub(super) const UPLOAD_TOO_LARGE_RESPONSE: (StatusCode, &str) = (
+    StatusCode::PAYLOAD_TOO_LARGE,
+    "Upload is too big to be processed by this server",
+);
+
+const EMPTY_UPLOAD_RESPONSE: (StatusCode, &str) = (
+    StatusCode::BAD_REQUEST,
+    "Your upload was rejected because the file is empty",
+);
+
+const UPLOAD_TOO_SMALL_RESPONSE: (StatusCode, &str) = (
+    StatusCode::BAD_REQUEST,
+    "Upload is too small to be accepted by this server",
+);
+
+const INVALID_CONTENT_TYPE_RESPONSE: (StatusCode, &str) = (
+    StatusCode::BAD_REQUEST,
+    "Uploads must be sent with a 'Content-Type: multipart/form-data; boundary=<boundary>' header",
+);
+
+const INVALID_IDEMPOTENCY_KEY_RESPONSE: (StatusCode, &str) = (
+    StatusCode::BAD_REQUEST,
+    "Idempotency key must be visible ASCII of at most 255 characters",
+);
+
+const ARCHIVE_TOO_LARGE_RESPONSE: (StatusCode, &str) = (
+    StatusCode::PAYLOAD_TOO_LARGE,
+    "Archive expands to more than this server permits",
+);
+
+const POST_PROCESSING_ERROR: (StatusCode, &str) = (
+    StatusCode::INTERNAL_SERVER_ERROR,
+    "Your upload could not be completed due to a post-processing error",
+);
+
+const FIELD_TOO_LARGE_RESPONSE: (StatusCode, &str) = (
+    StatusCode::PAYLOAD_TOO_LARGE,
+    "A multipart field is too big to be processed by this server",
+);
+
+/// Count a rejection in the metrics and add the applicable size limit to rejections for being
+/// too large so clients can adapt.
+pub(super) fn describe_rejection(
+    state: &AppState,
+    (status, message): (StatusCode, &'static str),
+) -> (StatusCode, Cow<'static, str>) {
+    match status {
+        StatusCode::PAYLOAD_TOO_LARGE => state.metrics.record_rejected_size(),
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => state.metrics.record_rejected_mimetype(),
+        _ => {}
+    }
+    let limit = match (status, message) {
+        UPLOAD_TOO_LARGE_RESPONSE => Some(state.upload_size_limit),
+        UPLOAD_TOO_SMALL_RESPONSE => Some(state.upload_min_size),
+        FIELD_TOO_LARGE_RESPONSE => state.upload_field_size_limit,
+        _ => None,
+    };
+    match limit {
+        Some(limit) => (
+            status,
+            format!("{message}, the limit is {limit} bytes").into(),
+        ),
+        None => (status, message.into()),
+    }
+}
+
+/// Header uploaders can set so retried requests return the original response.
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Name of the multipart field for the number of views after which an upload is removed.
+const MAX_VIEWS_FIELD: &str = "max_views";
+
+/// Name of the multipart field for whether an upload is removed after it is first viewed.
+const BURN_FIELD: &str = "burn";
+
+/// Name of the multipart field for whether an upload must never be cached.
+const SENSITIVE_FIELD: &str = "sensitive";
+
+/// Name of the multipart field for whether the original bytes of an altered upload are kept.
+const KEEP_ORIGINAL_FIELD: &str = "keep_original";
+
+/// Name of the multipart field for the name browsers are told to give the upload.
+const FILENAME_FIELD: &str = "filename";
+
+/// Name of the multipart field for a checksum the upload must match, as `<algorithm>:<hex digest>`.
+const CHECKSUM_FIELD: &str = "checksum";
+
+/// Name of the multipart field for how long after last access an upload expires.
+const EXPIRY_FIELD: &str = "expiry";
+
+/// Header uploaders can set instead of the expiry field, the field takes precedence.
+const EXPIRY_HEADER: &str = "x-upload-expiry";
+
+/// Header uploaders can set to store images with their metadata instead of stripping it.
+const KEEP_METADATA_HEADER: &str = "x-keep-metadata";
+
+/// Shortest expiry an uploader can give an upload, matching the minimum server expiry.
+const MIN_UPLOAD_EXPIRY: Duration = Duration::from_secs(30 * 60);
+
+/// How the id of a new upload is chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum IdStrategy {
+    /// Derive the id from a salted hash of the content, deduplicating identical uploads.
+    #[value(name = "content-hash")]
+    ContentHash,
+    /// Generate a random id for every upload, without deduplication.
+    #[value(name = "random")]
+    Random,
+}
+
+impl IdStrategy {
+    /// Get the id of an upload with the given content, excluding its extension.
+    ///
+    /// Content hashes are given in full and are shortened to the configured id length when stored.
+    /// A hash streamed while the content was received is used instead of hashing it again.
+    fn upload_id(
+        self,
+        bytes: &[u8],
+        streamed_hash: Option<StreamingHash>,
+        salt: &str,
+    ) -> anyhow::Result<String> {
+        match self {
+            Self::ContentHash => streamed_hash.map_or_else(
+                || Cryptography::hash_bytes(bytes, salt),
+                |hash| Ok(hash.finalize(salt)),
+            ),
+            Self::Random => Ok(Cryptography::random_id()),
+        }
+    }
+}
+
+/// How the public url used for the primary link to a new upload is chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PublicUrlStrategy {
+    /// Always use the first public url.
+    #[value(name = "first")]
+    First,
+    /// Cycle through the public urls with every upload.
+    #[value(name = "round-robin")]
+    RoundRobin,
+    /// Choose a public url from a hash of the upload id, so an upload always gets the same one.
+    #[value(name = "hash")]
+    Hash,
+}
+
+impl PublicUrlStrategy {
+    /// Get the index of the public url to use for an upload out of `count` public urls.
+    fn select(self, count: usize, id: &str, counter: &AtomicUsize) -> usize {
+        if count == 0 {
+            return 0;
+        }
+        match self {
+            Self::First => 0,
+            Self::RoundRobin => counter.fetch_add(1, Ordering::Relaxed) % count,
+            Self::Hash => {
+                let hash = blake3::hash(id.as_bytes());
+                let hash = u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap());
+                (hash % count as u64) as usize
+            }
+        }
+    }
+}
+
+/// Options that can be given as named multipart fields alongside the upload.
+#[derive(Default)]
+pub(super) struct UploadOptions {
+    max_views: Option<u64>,
+    burn: bool,
+    sensitive: bool,
+    keep_original: bool,
+    keep_metadata: bool,
+    expiry: Option<Duration>,
+    /// Whether the expiry given was longer than the server's, which it was shortened to.
+    expiry_clamped: bool,
+    filename: Option<String>,
+    checksum: Option<String>,
+}
+
+impl UploadOptions {
+    /// Parse the options uploaders can give as headers, which named options take precedence over.
+    pub(super) fn from_headers(
+        state: &AppState,
+        headers: &HeaderMap,
+    ) -> Result<Self, (StatusCode, &'static str)> {
+        let mut options = Self {
+            keep_metadata: parse_keep_metadata(headers)?,
+            ..Default::default()
+        };
+        if let Some(expiry) = headers.get(EXPIRY_HEADER) {
+            options.set(state, EXPIRY_FIELD, expiry.to_str().unwrap_or_default())?;
+        }
+        Ok(options)
+    }
+
+    /// Whether a name is that of an option rather than a file.
+    pub(super) fn is_option(name: &str) -> bool {
+        matches!(
+            name,
+            MAX_VIEWS_FIELD
+                | BURN_FIELD
+                | SENSITIVE_FIELD
+                | KEEP_ORIGINAL_FIELD
+                | FILENAME_FIELD
+                | CHECKSUM_FIELD
+                | EXPIRY_FIELD
+        )
+    }
+
+    /// Validate and set the named option, rejecting names that aren't options.
+    pub(super) fn set(
+        &mut self,
+        state: &AppState,
+        name: &str,
+        value: &str,
+    ) -> Result<(), (StatusCode, &'static str)> {
+        match name {
+            MAX_VIEWS_FIELD => match value.trim().parse() {
+                Ok(max_views) if max_views > 0 => self.max_views = Some(max_views),
+                _ => {
+                    debug!("Rejecting upload - invalid maximum views option");
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        "Maximum views must be a whole number greater than zero",
+                    ));
+                }
+            },
+            BURN_FIELD => match value.trim().parse() {
+                Ok(burn) => self.burn = burn,
+                Err(_) => {
+                    debug!("Rejecting upload - invalid burn option");
+                    return Err((StatusCode::BAD_REQUEST, "Burn must be either true or false"));
+                }
+            },
+            SENSITIVE_FIELD => match value.trim().parse() {
+                Ok(sensitive) => self.sensitive = sensitive,
+                Err(_) => {
+                    debug!("Rejecting upload - invalid sensitive option");
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        "Sensitive must be either true or false",
+                    ));
+                }
+            },
+            KEEP_ORIGINAL_FIELD => match value.trim().parse::<bool>() {
+                Ok(keep_original) if !keep_original || state.upload_keep_originals => {
+                    self.keep_original = keep_original
+                }
+                Ok(_) => {
+                    debug!("Rejecting upload - keeping originals is not enabled");
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        "Keeping originals is not enabled on this server",
+                    ));
+                }
+                Err(_) => {
+                    debug!("Rejecting upload - invalid keep original option");
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        "Keep original must be either true or false",
+                    ));
+                }
+            },
+            FILENAME_FIELD => self.filename = super::sanitize_filename(value),
+            CHECKSUM_FIELD => {
+                if verify_checksum(value, &[]).is_none() {
+                    debug!("Rejecting upload - invalid checksum option");
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        "Checksum must be in the format 'sha256:<hex digest>' or 'blake3:<hex digest>'",
+                    ));
+                }
+                self.checksum = Some(value.to_string());
+            }
+            EXPIRY_FIELD => {
+                let (expiry, clamped) = parse_upload_expiry(value, state.upload_expiry)?;
+                self.expiry = Some(expiry);
+                self.expiry_clamped = clamped;
+            }
+            _ => {
+                debug!("Rejecting upload - unknown option");
+                return Err((StatusCode::BAD_REQUEST, "Unknown upload option"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the bytes of an upload as they were received against the checksum it was given,
+    /// if any, as processing may alter them.
+    pub(super) fn verify_checksum(&self, bytes: &[u8]) -> Result<(), (StatusCode, &'static str)> {
+        match &self.checksum {
+            Some(checksum) if verify_checksum(checksum, bytes) != Some(true) => {
+                debug!("Rejecting upload - content does not match the given checksum");
+                Err((
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "Your upload was rejected because it does not match the given checksum",
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct CreateUploadResponse {
     url: String,
+    urls: Vec<String>,
     id: String,
     key: String,
-    mimetype: &'static str,
+    /// Token that deletes this upload without a bearer token, which is not part of its link.
+    delete_token: String,
+    mimetype: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    perceptual_hash: Option<String>,
+    /// Whether the upload is held for moderation and won't be served until it is approved.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pending: bool,
+    /// Whether the upload matched content that was already stored under the same id, which was
+    /// kept as it was instead of being written again.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    deduplicated: bool,
+    /// Whether the expiry the upload was given was longer than the server's and was shortened
+    /// to it.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    expiry_clamped: bool,
+}
+
+/// Response to an upload request, which is a single object unless several files were uploaded.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum CreateUploadsResponse {
+    Single(CreateUploadResponse),
+    Batch(Vec<BatchUploadResult>),
+}
+
+/// Result of one file in a request that uploaded several files.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum BatchUploadResult {
+    Uploaded(CreateUploadResponse),
+    Failed {
+        status: u16,
+        error: Cow<'static, str>,
+    },
+}
+
+/// A file read from an upload request that is yet to be processed and stored.
+pub(super) struct UploadFile {
+    pub(super) bytes: Bytes,
+    /// Content type and filename the file was declared with.
+    pub(super) declared_type: (Option<String>, Option<String>),
+    pub(super) content_hash: Option<StreamingHash>,
+}
+
+/// Get the MIME type and extension of an upload from the content type and filename it was
+/// declared with, preferring the content type unless it is missing or only an octet stream.
+///
+/// Extensions are only taken from the filename when they are plain alphanumerics that
+/// match the type, otherwise a known extension for the type is used.
+fn declared_type_of(content_type: Option<&str>, file_name: Option<&str>) -> (Mime, String) {
+    let file_ext = file_name
+        .and_then(|name| name.rsplit_once('.'))
+        .map(|(_, ext)| ext.to_ascii_lowercase())
+        .filter(|ext| {
+            !ext.is_empty() && ext.len() <= 16 && ext.chars().all(|c| c.is_ascii_alphanumeric())
+        });
+    let mimetype = content_type
+        .and_then(|content_type| Mime::from_str(content_type).ok())
+        .filter(|mimetype| mimetype.essence_str() != APPLICATION_OCTET_STREAM.essence_str())
+        .or_else(|| mime_guess::from_ext(file_ext.as_deref()?).first())
+        .unwrap_or(APPLICATION_OCTET_STREAM);
+    let ext = match file_ext {
+        Some(ext)
+            if mime_guess::from_ext(&ext)
+                .iter()
+                .any(|guess| guess.essence_str() == mimetype.essence_str()) =>
+        {
+            ext
+        }
+        // Octet streams are stored without an extension, as they are when their type is inferred.
+        _ if mimetype.essence_str() == APPLICATION_OCTET_STREAM.essence_str() => String::new(),
+        _ => mime_guess::get_mime_extensions(&mimetype)
+            .and_then(|exts| exts.first())
+            .map_or_else(String::new, |ext| ext.to_string()),
+    };
+    (mimetype, ext)
+}
+
+/// Parse whether an uploader asked for the metadata of their images to be kept.
+fn parse_keep_metadata(headers: &HeaderMap) -> Result<bool, (StatusCode, &'static str)> {
+    let Some(keep_metadata) = headers.get(KEEP_METADATA_HEADER) else {
+        return Ok(false);
+    };
+    keep_metadata
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .ok_or_else(|| {
+            debug!("Rejecting upload - invalid keep metadata header");
+            (
+                StatusCode::BAD_REQUEST,
+                "Keep metadata must be either true or false",
+            )
+        })
+}
+
+/// Parse an expiry given by an uploader, which may only shorten the server's expiry.
+///
+/// Expiries longer than the server's are shortened to it, returned along with whether it was.
+fn parse_upload_expiry(
+    value: &str,
+    server_expiry: Option<Duration>,
+) -> Result<(Duration, bool), (StatusCode, &'static str)> {
+    let Some(server_expiry) = server_expiry else {
+        debug!("Rejecting upload - expiry given but the server does not expire uploads");
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "This server does not expire uploads",
+        ));
+    };
+    let Ok(expiry) = DurationHuman::parse(value.trim()) else {
+        debug!("Rejecting upload - invalid expiry option");
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Expiry must be a duration such as 1h or 7days",
+        ));
+    };
+    let expiry = Duration::from(&expiry);
+    if expiry < MIN_UPLOAD_EXPIRY {
+        debug!("Rejecting upload - expiry is shorter than the minimum");
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Expiry must be at least 30 minutes",
+        ));
+    }
+    if expiry > server_expiry {
+        debug!("Shortening upload expiry to the server's expiry");
+        return Ok((server_expiry, true));
+    }
+    Ok((expiry, false))
+}
+
+/// Get the maximum request body size for uploads with the given upload size limit.
+///
+/// Uploads exactly at the upload size limit are accepted, so the body limit allows for the
+/// multipart encoding around the file as well.
+pub fn upload_body_limit(upload_size_limit: u64) -> u64 {
+    upload_size_limit.saturating_add(MULTIPART_OVERHEAD_ALLOWANCE)
+}
+
+/// Middleware that rejects uploads with a declared `Content-Length` over the upload body limit
+/// before any of the body is read.
+///
+/// As the body is never polled, clients sending `Expect: 100-continue` will not be told to continue.
+pub async fn declared_size_middleware(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, (StatusCode, Cow<'static, str>)> {
+    let declared_size = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if declared_size.is_some_and(|size| size > upload_body_limit(state.upload_size_limit)) {
+        debug!("Rejecting upload - declared size is larger than the server's maximum allowed size");
+        return Err(describe_rejection(&state, UPLOAD_TOO_LARGE_RESPONSE));
+    }
+    Ok(next.run(request).await)
 }
 
 pub async fn create_upload_handler(
     State(state): State<AppState>,
-    mut multipart: Multipart,
-) -> Result<Json<CreateUploadResponse>, (StatusCode, &'static str)> {
-    // Extract upload data from multipart field
-    let upload_bytes = {
-        let upload_field = match multipart.next_field().await {
+    headers: HeaderMap,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Json<CreateUploadsResponse>, (StatusCode, Cow<'static, str>)> {
+    let Some(idempotency_key) = headers.get(IDEMPOTENCY_KEY_HEADER) else {
+        return create_upload(&state, &headers, multipart)
+            .await
+            .map(Json)
+            .map_err(|rejection| describe_rejection(&state, rejection));
+    };
+    let idempotency_key = match idempotency_key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LENGTH => key,
+        _ => {
+            debug!("Rejecting upload - invalid idempotency key");
+            return Err(describe_rejection(&state, INVALID_IDEMPOTENCY_KEY_RESPONSE));
+        }
+    };
+
+    // Keys are scoped to the token they were used with so uploaders cannot see each other's responses.
+    let token = auth::bearer_token(&headers)
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    let cache_key = format!("{token}\0{idempotency_key}");
+    let _lock = state.idempotency_cache.lock(&cache_key).await;
+    if let Some(response) = state.idempotency_cache.get(&cache_key) {
+        debug!("Replaying upload response for a repeated idempotency key");
+        return Ok(Json(response));
+    }
+    let response = create_upload(&state, &headers, multipart)
+        .await
+        .map_err(|rejection| describe_rejection(&state, rejection))?;
+    state.idempotency_cache.insert(&cache_key, response.clone());
+    Ok(Json(response))
+}
+
+async fn create_upload(
+    state: &AppState,
+    headers: &HeaderMap,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<CreateUploadsResponse, (StatusCode, &'static str)> {
+    let Ok(mut multipart) = multipart else {
+        debug!("Rejecting upload - request is not multipart form data with a boundary");
+        return Err(INVALID_CONTENT_TYPE_RESPONSE);
+    };
+
+    // Extract upload data and options from multipart fields.
+    // The first field that is not a recognised option is used as the upload, along with any
+    // later fields that have a filename.
+    let mut files = Vec::new();
+    let mut options = UploadOptions::from_headers(state, headers)?;
+    let mut leading_fields = 0;
+    loop {
+        let field = match multipart.next_field().await {
             Ok(Some(field)) => field,
-            Ok(None) => {
-                debug!("Rejecting upload - does not contain a valid multipart field");
-                return Err((StatusCode::BAD_REQUEST, "Multipart field not found"));
-            }
+            Ok(None) => break,
             Err(_) => {
                 debug!("Rejecting upload - contains one or more unparseable multipart fields");
                 return Err((
@@ -47,117 +585,287 @@ pub async fn create_upload_handler(
                 ));
             }
         };
-        match upload_field.bytes().await {
-            Ok(bytes) => bytes,
-            Err(_) => {
-                debug!(
-                    "Rejecting upload - content is larger than the server's maximum allowed size"
+        match field.name().map(str::to_string) {
+            Some(name) if UploadOptions::is_option(&name) => {
+                let value = field.text().await.unwrap_or_default();
+                options.set(state, &name, &value)?;
+            }
+            _ if files.is_empty() || field.file_name().is_some_and(|name| !name.is_empty()) => {
+                if state.upload_require_filename && field.file_name().is_none_or(str::is_empty) {
+                    debug!("Rejecting upload - multipart field does not have a filename");
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        "Uploads must include a filename with the file",
+                    ));
+                }
+                let declared_type = (
+                    field.content_type().map(str::to_string),
+                    field.file_name().map(str::to_string),
                 );
+                let mut content_hash = (state.upload_id_strategy == IdStrategy::ContentHash)
+                    .then(StreamingHash::default);
+                let bytes =
+                    read_field(field, state.upload_field_size_limit, content_hash.as_mut()).await?;
+                files.push(UploadFile {
+                    bytes,
+                    declared_type,
+                    content_hash,
+                });
+            }
+            // Other fields are still checked against the field size limit when one is set.
+            _ if state.upload_field_size_limit.is_some() => {
+                read_field(field, state.upload_field_size_limit, None).await?;
+            }
+            _ => {}
+        }
+        // Bound the fields parsed while searching for the file.
+        if files.is_empty() {
+            leading_fields += 1;
+            if state
+                .upload_max_leading_fields
+                .is_some_and(|limit| leading_fields > limit)
+            {
+                debug!("Rejecting upload - too many multipart fields before the file");
                 return Err((
-                    StatusCode::PAYLOAD_TOO_LARGE,
-                    "Upload is too big to be processed by this server",
+                    StatusCode::BAD_REQUEST,
+                    "Too many multipart fields before the file",
                 ));
             }
         }
-    };
+    }
+    if files.is_empty() {
+        debug!("Rejecting upload - does not contain a valid multipart field");
+        return Err((StatusCode::BAD_REQUEST, "Multipart field not found"));
+    }
+    if files
+        .iter()
+        .map(|file| file.bytes.len() as u64)
+        .sum::<u64>()
+        > state.upload_size_limit
+    {
+        debug!("Rejecting upload - content is larger than the server's maximum allowed size");
+        return Err(UPLOAD_TOO_LARGE_RESPONSE);
+    }
+
+    // Checksums are verified against the bytes as they were received, as processing may alter them.
+    if options.checksum.is_some() {
+        let [file] = files.as_slice() else {
+            debug!("Rejecting upload - checksum given for more than one file");
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "A checksum can only be given when uploading a single file",
+            ));
+        };
+        options.verify_checksum(&file.bytes)?;
+    }
+
+    // Batches report the result of each file so one rejected file doesn't fail the others.
+    if files.len() == 1 {
+        let file = files.pop().unwrap();
+        return store_upload(state, headers, &options, file)
+            .await
+            .map(CreateUploadsResponse::Single);
+    }
+    let mut results = Vec::with_capacity(files.len());
+    for file in files {
+        results.push(match store_upload(state, headers, &options, file).await {
+            Ok(response) => BatchUploadResult::Uploaded(response),
+            Err(rejection) => {
+                let (status, error) = describe_rejection(state, rejection);
+                BatchUploadResult::Failed {
+                    status: status.as_u16(),
+                    error,
+                }
+            }
+        });
+    }
+    Ok(CreateUploadsResponse::Batch(results))
+}
+
+/// Process a single uploaded file and store it.
+pub(super) async fn store_upload(
+    state: &AppState,
+    headers: &HeaderMap,
+    options: &UploadOptions,
+    UploadFile {
+        bytes: upload_bytes,
+        declared_type,
+        mut content_hash,
+    }: UploadFile,
+) -> Result<CreateUploadResponse, (StatusCode, &'static str)> {
+    // A file field without any content is told apart from a request without a file field.
+    if (upload_bytes.len() as u64) < state.upload_min_size {
+        if upload_bytes.is_empty() {
+            debug!("Rejecting upload - file is empty");
+            return Err(EMPTY_UPLOAD_RESPONSE);
+        }
+        debug!("Rejecting upload - content is smaller than the server's minimum allowed size");
+        return Err(UPLOAD_TOO_SMALL_RESPONSE);
+    }
+
+    // Uploads made with a restricted token may only use a subset of the allowed types.
+    let token = auth::bearer_token(headers).ok().flatten();
+    let token_mimetypes = token.and_then(|token| state.auth_provider.mimetypes_for_token(token));
+    let trusted = token.is_some_and(|token| state.auth_provider.is_trusted(token));
 
     // Infer mimetype by magic numbers and check if it is allowed.
     // (Octet stream is used as fallback when */* is allowed, otherwise unknown types are rejected.)
-    let (infer_str, infer_ext, matcher_type) = match infer::get(&upload_bytes) {
-        Some(infer_result) => {
-            // Check if the inferred MIME type is allowed
-            if !mime::is_mime_allowed(
-                &Mime::from_str(infer_result.mime_type()).unwrap(),
-                &state.upload_allowed_mimetypes,
-            ) {
-                // Reject as unsupported type.
-                debug!(
-                    "Rejecting upload - server unsupported MIME type: {}",
-                    infer_result.mime_type()
-                );
-                return Err((
-                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
-                    "Your upload was rejected because uploading files of this type is not permitted",
-                ));
+    // Trusted uploads are never inspected and use the type they were declared with instead.
+    let (infer_str, infer_ext, matcher_type): (Cow<str>, Cow<str>, _) = if trusted {
+        let (declared_mime, declared_ext) =
+            declared_type_of(declared_type.0.as_deref(), declared_type.1.as_deref());
+        if !mime::is_mime_allowed(&declared_mime, &state.upload_allowed_mimetypes)
+            || token_mimetypes
+                .is_some_and(|allowed| !mime::is_mime_allowed(&declared_mime, allowed))
+        {
+            debug!("Rejecting upload - server unsupported declared MIME type: {declared_mime}");
+            return Err((
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Your upload was rejected because uploading files of this type is not permitted",
+            ));
+        }
+        // No type that is post-processed is matched so trusted uploads are stored as-is.
+        (
+            declared_mime.essence_str().to_string().into(),
+            declared_ext.into(),
+            MatcherType::Custom,
+        )
+    } else {
+        match infer::get(&upload_bytes) {
+            Some(infer_result) => {
+                // Check if the inferred MIME type is allowed
+                let infer_mime = Mime::from_str(infer_result.mime_type()).unwrap();
+                if !mime::is_mime_allowed(&infer_mime, &state.upload_allowed_mimetypes)
+                    || token_mimetypes
+                        .is_some_and(|allowed| !mime::is_mime_allowed(&infer_mime, allowed))
+                {
+                    // Reject as unsupported type.
+                    debug!(
+                        "Rejecting upload - server unsupported MIME type: {}",
+                        infer_result.mime_type()
+                    );
+                    return Err((
+                        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                        "Your upload was rejected because uploading files of this type is not permitted",
+                    ));
+                }
+                (
+                    infer_result.mime_type().into(),
+                    infer_result.extension().into(),
+                    infer_result.matcher_type(),
+                )
+            }
+            None => {
+                // If no MIME type could be inferred, check if fallback is allowed.
+                if state
+                    .upload_allowed_mimetypes
+                    .contains(&FALLBACK_ENABLED_MIME)
+                    && token_mimetypes.is_none_or(|allowed| {
+                        mime::is_mime_allowed(&APPLICATION_OCTET_STREAM, allowed)
+                    })
+                {
+                    // Fallback to octet stream
+                    debug!(
+                        "Could not infer upload MIME type - falling back to application/octet-stream"
+                    );
+                    (
+                        APPLICATION_OCTET_STREAM.essence_str().into(),
+                        "".into(),
+                        MatcherType::Archive,
+                    )
+                } else {
+                    // Reject as unsupported type.
+                    debug!("Rejecting upload - No MIME type could be inferred from content");
+                    return Err((
+                        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                        "Your upload was rejected because the MIME type could not be determined",
+                    ));
+                }
             }
-            (
-                infer_result.mime_type(),
-                infer_result.extension(),
-                infer_result.matcher_type(),
-            )
         }
-        None => {
-            // If no MIME type could be inferred, check if fallback is allowed.
-            if state
-                .upload_allowed_mimetypes
-                .contains(&FALLBACK_ENABLED_MIME)
-            {
-                // Fallback to octet stream
-                debug!(
-                    "Could not infer upload MIME type - falling back to application/octet-stream"
-                );
+    };
+
+    // Reject archives that declare they would expand past the configured limit.
+    if let Some(limit) = state.upload_archive_expansion_limit
+        && matches!(matcher_type, MatcherType::Archive | MatcherType::Doc)
+        && archive::zip_uncompressed_size(&upload_bytes).is_some_and(|size| size > limit)
+    {
+        debug!("Rejecting upload - archive declares an uncompressed size over the limit");
+        return Err(ARCHIVE_TOO_LARGE_RESPONSE);
+    }
+
+    // Transcode HEIF images that most browsers can't display to JPEG, keeping the original
+    // image if transcoding is unavailable or fails.
+    let mut original_bytes = None;
+    let (upload_bytes, infer_str, infer_ext) = if state.transcode_heic
+        && !trusted
+        && infer_str == HEIF_MIME
+        && mime::is_mime_allowed(&IMAGE_JPEG, &state.upload_allowed_mimetypes)
+        && token_mimetypes.is_none_or(|allowed| mime::is_mime_allowed(&IMAGE_JPEG, allowed))
+    {
+        match transcode::heif_to_jpeg(transcode::FFMPEG_PROGRAM, &upload_bytes).await {
+            Some(jpeg) => {
+                debug!("Transcoded HEIF upload to JPEG");
+                content_hash = None;
+                if options.keep_original {
+                    original_bytes = Some(upload_bytes);
+                }
                 (
-                    APPLICATION_OCTET_STREAM.essence_str(),
-                    "",
-                    MatcherType::Archive,
+                    Bytes::from(jpeg),
+                    IMAGE_JPEG.essence_str().into(),
+                    "jpg".into(),
                 )
-            } else {
-                // Reject as unsupported type.
-                debug!("Rejecting upload - No MIME type could be inferred from content");
-                return Err((
-                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
-                    "Your upload was rejected because the MIME type could not be determined",
-                ));
             }
+            None => (upload_bytes, infer_str, infer_ext),
         }
+    } else {
+        (upload_bytes, infer_str, infer_ext)
     };
 
     // Additional post-processing.
     let upload_bytes = match matcher_type {
-        // Strip most EXIF data from images.
-        MatcherType::Image => {
+        // Strip most EXIF data from images, unless they are to be stored as uploaded.
+        MatcherType::Image if state.upload_strip_exif && !options.keep_metadata => {
             match image::guess_format(&upload_bytes) {
-                Ok(ImageFormat::Gif) => upload_bytes, // GIFs cannot be processed as animation data is not preserved.
+                // GIFs are copied frame by frame so animations are kept exactly as they were.
+                Ok(ImageFormat::Gif) => {
+                    let gif = upload_bytes.clone();
+                    match tokio::task::spawn_blocking(move || strip_gif_metadata(&gif))
+                        .await
+                        .unwrap_or_else(|err| Err(err.into()))
+                    {
+                        Ok(stripped) => {
+                            content_hash = None;
+                            if options.keep_original && original_bytes.is_none() {
+                                original_bytes = Some(upload_bytes);
+                            }
+                            Bytes::from(stripped)
+                        }
+                        Err(err) => {
+                            warn!(
+                                "Failed to strip metadata from GIF - storing as uploaded: {err:?}"
+                            );
+                            upload_bytes
+                        }
+                    }
+                }
                 Ok(image_format) => {
-                    const POST_PROCESSING_ERROR: (StatusCode, &str) = (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        "Your upload could not be completed due to a post-processing error",
-                    );
-
+                    if options.keep_original && original_bytes.is_none() {
+                        original_bytes = Some(upload_bytes.clone());
+                    }
                     let image_size = upload_bytes.len();
-                    let reader = BufReader::new(Cursor::new(upload_bytes));
-                    let mut decoder = ImageReader::new(reader)
-                        .with_guessed_format()
-                        .map_err(|err| {
-                            error!("Failed to guess image format from upload bytes: {err:?}");
-                            POST_PROCESSING_ERROR
-                        })?
-                        .into_decoder()
-                        .map_err(|err| {
-                            error!("Failed to create image decoder from upload bytes: {err:?}");
-                            POST_PROCESSING_ERROR
-                        })?;
-                    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
-                    let mut image = DynamicImage::from_decoder(decoder).map_err(|err| {
-                        error!("Failed to decode image from upload bytes: {err:?}");
+                    let jpeg_quality = state.upload_jpeg_quality;
+                    let image_bytes = tokio::task::spawn_blocking(move || {
+                        strip_image_metadata(upload_bytes, image_format, jpeg_quality)
+                    })
+                    .await
+                    .map_err(|err| {
+                        error!("Image post-processing task failed: {err:?}");
                         POST_PROCESSING_ERROR
-                    })?;
-                    image.apply_orientation(orientation);
-
-                    // Re-encode the image without EXIF data
-                    let mut image_bytes = Vec::with_capacity(image_size);
-                    {
-                        let mut writer = BufWriter::new(Cursor::new(&mut image_bytes));
-                        image.write_to(&mut writer, image_format).map_err(|err| {
-                            error!("Failed to write image to bytes: {err:?}");
-                            POST_PROCESSING_ERROR
-                        })?;
-                        writer.flush().map_err(|err| {
-                            error!("Failed to flush image writer: {err:?}");
-                            POST_PROCESSING_ERROR
-                        })?;
-                    }
+                    })??;
 
+                    // The id must be derived from the processed bytes rather than those received.
+                    content_hash = None;
                     debug!(
                         "Stripped EXIF data from image upload (original: {} bytes, processed: {} bytes)",
                         image_size,
@@ -174,44 +882,269 @@ pub async fn create_upload_handler(
         _ => upload_bytes,
     };
 
-    // Store file by hash to prevent duplicating uploads.
-    let filename = format!(
-        "{}{}{}",
-        Cryptography::hash_bytes(&upload_bytes, &state.persisted_salt)
-            .unwrap()
-            .get(..10)
-            .unwrap(),
-        if !infer_ext.is_empty() { "." } else { "" },
-        infer_ext
-    );
-
-    match state
-        .storage
-        .write()
-        .await
-        .save_upload(&filename, &upload_bytes)
-        .await
+    // Keep any uploader headers that should be replayed when the upload is served.
+    let metadata = UploadMetadata {
+        headers: state
+            .upload_passthrough_headers
+            .iter()
+            .filter_map(|name| {
+                let value = headers.get(name)?.to_str().ok()?;
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect(),
+        filename: options.filename.clone(),
+        ..Default::default()
+    };
+
+    // Store file by hash to prevent duplicating uploads, unless configured otherwise.
+    // Identical uploads share their record, metadata and original, so uploads with any of their
+    // own are given a random id where they can't be changed by or apply to other uploads.
+    let id_strategy = if options.burn
+        || options.max_views.is_some()
+        || options.sensitive
+        || options.expiry.is_some()
+        || original_bytes.is_some()
+        || metadata != UploadMetadata::default()
+    {
+        IdStrategy::Random
+    } else {
+        state.upload_id_strategy
+    };
+    let upload_id = match id_strategy.upload_id(&upload_bytes, content_hash, &state.persisted_salt)
     {
-        Ok(decryption_key) => {
+        Ok(upload_id) => upload_id,
+        Err(err) => {
+            error!("Failed to hash upload for its id: {err:?}");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Your upload could not be completed successfully due to an internal server error",
+            ));
+        }
+    };
+    let content_hash = (id_strategy == IdStrategy::ContentHash).then(|| upload_id.clone());
+    let mut id_length = match content_hash {
+        Some(_) => state.upload_id_length.min(upload_id.len()),
+        None => upload_id.len(),
+    };
+    let storage = &state.storage;
+    let (filename, _lock) = loop {
+        let filename = format!(
+            "{}{}{}",
+            &upload_id[..id_length],
+            if !infer_ext.is_empty() { "." } else { "" },
+            infer_ext
+        );
+        let lock = state.upload_locks.lock(&filename).await;
+        // Different content that shares the start of its hash must never replace another upload,
+        // so the id is lengthened until it is free or belongs to an identical upload.
+        let Some(content_hash) = &content_hash else {
+            break (filename, lock);
+        };
+        match storage.is_upload_id_taken(&filename, content_hash).await {
+            Ok(true) if id_length < upload_id.len() => {
+                warn!("Upload id {filename} is taken by different content - lengthening id");
+                id_length += 1;
+            }
+            Ok(_) => break (filename, lock),
+            Err(err) => {
+                error!("Failed to check if upload id {filename} is taken: {err:?}");
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Your upload could not be completed successfully due to an internal server error",
+                ));
+            }
+        }
+    };
+
+    // Uploads count against the quota of the token they were made with, including uploads of
+    // content that is already stored, one at a time so concurrent uploads can't each fit into
+    // what is left.
+    let owner = match token
+        .map(|token| Cryptography::hash_bytes(token.as_bytes(), &state.persisted_salt))
+        .transpose()
+    {
+        Ok(owner) => owner,
+        Err(err) => {
+            error!("Failed to hash token of upload owner: {err:?}");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Your upload could not be completed successfully due to an internal server error",
+            ));
+        }
+    };
+    let _quota_lock = match (
+        owner.as_deref(),
+        token.and_then(|token| state.auth_provider.quota_for_token(token)),
+    ) {
+        (Some(owner), Some(quota)) => {
+            let lock = state.upload_locks.lock(&format!("quota/{owner}")).await;
+            match storage.owner_usage(owner).await {
+                Ok(usage) if quota.is_exceeded_by(usage, upload_bytes.len() as u64) => {
+                    return Err((
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "Your upload would exceed the storage quota of your token",
+                    ));
+                }
+                Ok(_) => Some(lock),
+                Err(err) => {
+                    error!("Failed to count usage of upload owner: {err:?}");
+                    return Err((
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Your upload could not be completed successfully due to an internal server error",
+                    ));
+                }
+            }
+        }
+        _ => None,
+    };
+
+    // Hash images so near-duplicates can be found later.
+    let record = UploadRecord {
+        perceptual_hash: match matcher_type {
+            MatcherType::Image if state.upload_perceptual_hashes => {
+                let image = upload_bytes.clone();
+                match tokio::task::spawn_blocking(move || {
+                    image::load_from_memory(&image).map(|image| perceptual_hash::hash_image(&image))
+                })
+                .await
+                {
+                    Ok(Ok(hash)) => Some(hash),
+                    Ok(Err(err)) => {
+                        warn!("Failed to decode image for perceptual hashing: {err:?}");
+                        None
+                    }
+                    Err(err) => {
+                        error!("Perceptual hashing task failed: {err:?}");
+                        None
+                    }
+                }
+            }
+            _ => None,
+        },
+        max_views: if options.burn {
+            Some(1)
+        } else {
+            options.max_views
+        },
+        views: 0,
+        sensitive: options.sensitive,
+        expiry_secs: options.expiry.map(|expiry| expiry.as_secs()),
+        content_hash,
+    };
+
+    // Uploads identical to one that was already approved don't need approving again.
+    let exists = match storage.upload_exists(&filename).await {
+        Ok(exists) => exists,
+        Err(err) => {
+            error!("Failed to check if upload {filename} exists: {err:?}");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Your upload could not be completed successfully due to an internal server error",
+            ));
+        }
+    };
+    let pending = state.moderation && !exists;
+    // Content addressed uploads are encrypted with a key derived from their content, so identical
+    // uploads share a single encrypted copy that every one of their keys can decrypt.
+    let content_key = record
+        .content_hash
+        .is_some()
+        .then(|| Cryptography::derive_content_key(&upload_bytes, &state.persisted_salt));
+    // Each upload is given its own delete token, so deleting it leaves identical uploads alone.
+    let delete_token = Cryptography::random_id();
+    let reference = UploadReference::new(&delete_token, owner, upload_bytes.len() as u64);
+    let saved = match &content_key {
+        _ if pending => storage
+            .save_pending_upload(
+                &filename,
+                &upload_bytes,
+                &metadata,
+                content_key.as_deref(),
+                reference,
+            )
+            .await
+            .map(|key| (key, false)),
+        Some(content_key) => {
+            storage
+                .save_shared_upload(&filename, &upload_bytes, &metadata, content_key, reference)
+                .await
+        }
+        None => storage
+            .save_upload(&filename, &upload_bytes, &metadata, reference)
+            .await
+            .map(|key| (key, false)),
+    };
+    match saved {
+        Ok((decryption_key, deduplicated)) => {
             debug!("Successfully saved upload {filename} to storage.");
-            Ok(Json(CreateUploadResponse {
-                mimetype: infer_str,
-                url: format!(
-                    "{}://{}/upload/{}?key={}",
-                    state.public_base_url.scheme(),
-                    state.public_base_url.port().map_or(
-                        state.public_base_url.host_str().unwrap().to_string(),
-                        |f| format!("{}:{}", state.public_base_url.host_str().unwrap(), f,)
-                    ),
-                    filename,
-                    decryption_key
-                ),
+            state.throughput.record_upload(upload_bytes.len() as u64);
+            state.metrics.record_upload_created();
+            // The record of shared content belongs to the upload that first stored it.
+            if !deduplicated && let Err(err) = storage.save_upload_record(&filename, &record).await
+            {
+                error!("Failed to save record for upload {filename}: {err:?}");
+            }
+            if let Some(original_bytes) = original_bytes
+                && let Err(err) = storage
+                    .save_upload_original(&filename, &decryption_key, &original_bytes)
+                    .await
+            {
+                error!("Failed to save original of upload {filename}: {err:?}");
+            }
+            let query = match state.signed_url_lifetime {
+                Some(lifetime) => {
+                    let (expires, signature) = link_signing::sign_link(
+                        &filename,
+                        &decryption_key,
+                        lifetime,
+                        &state.persisted_salt,
+                    );
+                    if state.signed_url_omit_key {
+                        format!("exp={expires}&sig={signature}")
+                    } else {
+                        format!("key={decryption_key}&exp={expires}&sig={signature}")
+                    }
+                }
+                None => format!("key={decryption_key}"),
+            };
+            let urls: Vec<String> = state
+                .public_base_urls
+                .iter()
+                .filter_map(|base_url| upload_url(base_url, &filename, &query))
+                .collect();
+            let primary =
+                state
+                    .public_url_strategy
+                    .select(urls.len(), &filename, &state.public_url_counter);
+            let Some(url) = urls.get(primary).cloned() else {
+                error!("No public url with a host to build a link to upload {filename} with");
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Your upload could not be completed successfully due to an internal server error",
+                ));
+            };
+            Ok(CreateUploadResponse {
+                mimetype: infer_str.into_owned(),
+                url,
+                urls,
+                perceptual_hash: record.perceptual_hash.map(|hash| format!("{hash:016x}")),
                 id: filename,
                 key: decryption_key,
-            }))
+                delete_token,
+                pending,
+                deduplicated,
+                expiry_clamped: options.expiry_clamped,
+            })
+        }
+        Err(err) if err.is::<InsufficientStorageError>() => {
+            warn!("Rejecting upload {filename} - insufficient storage space");
+            Err((
+                StatusCode::INSUFFICIENT_STORAGE,
+                "Your upload could not be completed because the server is out of storage space",
+            ))
         }
         Err(err) => {
-            error!("Failed to encrypting/writing file {filename}: {err:?}");
+            error!("Failed to encrypt/write file {filename}: {err:?}");
             Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Your upload could not be completed successfully due to an internal server error",
@@ -219,3 +1152,668 @@ pub async fn create_upload_handler(
         }
     }
 }
+
+/// Read the contents of a multipart field, rejecting it once it is larger than `limit`.
+///
+/// Each chunk is fed to `hash` as it is read when one is given.
+async fn read_field(
+    mut field: Field<'_>,
+    limit: Option<u64>,
+    mut hash: Option<&mut StreamingHash>,
+) -> Result<Bytes, (StatusCode, &'static str)> {
+    let mut bytes = Vec::new();
+    loop {
+        let chunk = match field.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(_) => {
+                debug!(
+                    "Rejecting upload - content is larger than the server's maximum allowed size"
+                );
+                return Err(UPLOAD_TOO_LARGE_RESPONSE);
+            }
+        };
+        if limit.is_some_and(|limit| (bytes.len() + chunk.len()) as u64 > limit) {
+            debug!("Rejecting upload - a field is larger than the server's maximum field size");
+            return Err(FIELD_TOO_LARGE_RESPONSE);
+        }
+        if let Some(hash) = hash.as_deref_mut() {
+            hash.update(&chunk);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(Bytes::from(bytes))
+}
+
+/// Decode an image and re-encode it in the same format without its metadata, applying its
+/// orientation so it is still displayed the right way up.
+fn strip_image_metadata(
+    bytes: Bytes,
+    image_format: ImageFormat,
+    jpeg_quality: u8,
+) -> Result<Vec<u8>, (StatusCode, &'static str)> {
+    let image_size = bytes.len();
+    let reader = BufReader::new(Cursor::new(bytes));
+    let mut decoder = ImageReader::new(reader)
+        .with_guessed_format()
+        .map_err(|err| {
+            error!("Failed to guess image format from upload bytes: {err:?}");
+            POST_PROCESSING_ERROR
+        })?
+        .into_decoder()
+        .map_err(|err| {
+            error!("Failed to create image decoder from upload bytes: {err:?}");
+            POST_PROCESSING_ERROR
+        })?;
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let icc_profile = decoder.icc_profile().unwrap_or_else(|err| {
+        warn!("Failed to read color profile from image upload: {err:?}");
+        None
+    });
+    let mut image = DynamicImage::from_decoder(decoder).map_err(|err| {
+        error!("Failed to decode image from upload bytes: {err:?}");
+        POST_PROCESSING_ERROR
+    })?;
+    image.apply_orientation(orientation);
+
+    // Re-encode the image without EXIF data
+    let mut image_bytes = Vec::with_capacity(image_size);
+    {
+        let mut writer = BufWriter::new(Cursor::new(&mut image_bytes));
+        write_image(&image, &mut writer, image_format, icc_profile, jpeg_quality).map_err(
+            |err| {
+                error!("Failed to write image to bytes: {err:?}");
+                POST_PROCESSING_ERROR
+            },
+        )?;
+        writer.flush().map_err(|err| {
+            error!("Failed to flush image writer: {err:?}");
+            POST_PROCESSING_ERROR
+        })?;
+    }
+    Ok(image_bytes)
+}
+
+/// Encode an image, embedding its ICC color profile where the format can carry one so
+/// re-encoding it doesn't shift its colors.
+///
+/// JPEGs are encoded at the given quality, while PNG and WebP images are encoded losslessly.
+fn write_image(
+    image: &DynamicImage,
+    writer: impl Write + Seek,
+    format: ImageFormat,
+    icc_profile: Option<Vec<u8>>,
+    jpeg_quality: u8,
+) -> ImageResult<()> {
+    fn write_with_profile(
+        image: &DynamicImage,
+        mut encoder: impl ImageEncoder,
+        icc_profile: Option<Vec<u8>>,
+    ) -> ImageResult<()> {
+        if let Some(icc_profile) = icc_profile
+            && let Err(err) = encoder.set_icc_profile(icc_profile)
+        {
+            warn!("Failed to embed color profile in image - it will be discarded: {err:?}");
+        }
+        image.write_with_encoder(encoder)
+    }
+
+    match format {
+        ImageFormat::Png => write_with_profile(image, PngEncoder::new(writer), icc_profile),
+        ImageFormat::Jpeg => write_with_profile(
+            image,
+            JpegEncoder::new_with_quality(writer, jpeg_quality),
+            icc_profile,
+        ),
+        ImageFormat::WebP => {
+            write_with_profile(image, WebPEncoder::new_lossless(writer), icc_profile)
+        }
+        _ => {
+            if icc_profile.is_some() {
+                warn!(
+                    "Color profiles cannot be embedded in {format:?} images - it will be discarded"
+                );
+            }
+            image.write_to(writer, format)
+        }
+    }
+}
+
+/// Re-encode a GIF without any of its comment or application extensions.
+///
+/// Frames are copied with their palettes, delays and disposal, and the loop count is kept,
+/// so the image is otherwise unchanged.
+fn strip_gif_metadata(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(bytes)?;
+    let mut stripped = Vec::new();
+    let mut encoder = gif::Encoder::new(
+        &mut stripped,
+        decoder.width(),
+        decoder.height(),
+        decoder.global_palette().unwrap_or_default(),
+    )?;
+    // The loop count is read along with the header, before the first frame.
+    if decoder.repeat() != gif::Repeat::Finite(0) {
+        encoder.set_repeat(decoder.repeat())?;
+    }
+    while let Some(frame) = decoder.read_next_frame()? {
+        // Frames are decoded in display order, so they are no longer interlaced.
+        let mut frame = frame.clone();
+        frame.interlaced = false;
+        encoder.write_frame(&frame)?;
+    }
+    drop(encoder);
+    Ok(stripped)
+}
+
+/// Check bytes against a checksum in the format `<algorithm>:<hex digest>`, where the
+/// algorithm is either `sha256` or `blake3`.
+///
+/// Returns `None` if the checksum is not in that format.
+fn verify_checksum(checksum: &str, bytes: &[u8]) -> Option<bool> {
+    let (algorithm, digest) = checksum.trim().split_once(':')?;
+    let actual = match algorithm.to_ascii_lowercase().as_str() {
+        "sha256" => format!("{:x}", Sha256::digest(bytes)),
+        "blake3" => blake3::hash(bytes).to_hex().to_string(),
+        _ => return None,
+    };
+    if digest.len() != actual.len() || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(digest.eq_ignore_ascii_case(&actual))
+}
+
+/// Build the link to an upload for the given public base url.
+///
+/// Returns `None` if the public url does not have a host.
+fn upload_url(base_url: &Url, id: &str, query: &str) -> Option<String> {
+    let host = base_url.host_str()?;
+    Some(format!(
+        "{}://{}/upload/{}?{}",
+        base_url.scheme(),
+        base_url
+            .port()
+            .map_or(host.to_string(), |port| format!("{host}:{port}")),
+        id,
+        query
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PublicUrlStrategy;
+    use std::{collections::HashSet, sync::atomic::AtomicUsize};
+    use url::Url;
+
+    #[test]
+    fn test_upload_url() {
+        let base_urls = [
+            Url::parse("https://example.com").unwrap(),
+            Url::parse("https://cdn.example.com").unwrap(),
+            Url::parse("http://127.0.0.1:8731").unwrap(),
+        ];
+        let urls: Vec<String> = base_urls
+            .iter()
+            .filter_map(|base_url| super::upload_url(base_url, "abc.png", "key=key"))
+            .collect();
+
+        // Test a fully-formed link is built for every public url.
+        assert_eq!(
+            urls,
+            [
+                "https://example.com/upload/abc.png?key=key",
+                "https://cdn.example.com/upload/abc.png?key=key",
+                "http://127.0.0.1:8731/upload/abc.png?key=key",
+            ]
+        );
+
+        // Test no link is built for a public url without a host.
+        let base_url = Url::parse("file:///srv/uploads").unwrap();
+        assert!(super::upload_url(&base_url, "abc.png", "key=key").is_none());
+    }
+
+    #[test]
+    fn test_public_url_strategy() {
+        let counter = AtomicUsize::new(0);
+
+        // Test the first strategy always picks the first url.
+        assert_eq!(PublicUrlStrategy::First.select(3, "abc.png", &counter), 0);
+
+        // Test round robin cycles through every url.
+        let picked: Vec<usize> = (0..4)
+            .map(|_| PublicUrlStrategy::RoundRobin.select(3, "abc.png", &counter))
+            .collect();
+        assert_eq!(picked, [0, 1, 2, 0]);
+
+        // Test hashing picks the same url for an id every time, and spreads ids across urls.
+        let hash = |id: &str| PublicUrlStrategy::Hash.select(3, id, &counter);
+        assert_eq!(hash("abc.png"), hash("abc.png"));
+        let picked: HashSet<usize> = (0..32).map(|i| hash(&format!("upload-{i}"))).collect();
+        assert_eq!(picked.len(), 3);
+    }
+
+    #[test]
+    fn test_strip_gif_metadata() {
+        // An animated GIF with a comment, looping three times.
+        let mut animation = Vec::new();
+        {
+            let mut encoder =
+                gif::Encoder::new(&mut animation, 2, 2, &[0, 0, 0, 255, 255, 255]).unwrap();
+            encoder.set_repeat(gif::Repeat::Finite(3)).unwrap();
+            encoder
+                .write_raw_extension(gif::Extension::Comment.into(), &[b"camera-serial"])
+                .unwrap();
+            for (index, delay) in [10, 20, 30].into_iter().enumerate() {
+                let mut frame =
+                    gif::Frame::from_indexed_pixels(2, 2, vec![index as u8 % 2; 4], None);
+                frame.delay = delay;
+                encoder.write_frame(&frame).unwrap();
+            }
+        }
+
+        // Test comments are removed while every frame and the loop count are kept.
+        let stripped = super::strip_gif_metadata(&animation).unwrap();
+        assert!(
+            !stripped
+                .windows(13)
+                .any(|window| window == b"camera-serial")
+        );
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(stripped.as_slice()).unwrap();
+        assert_eq!(decoder.repeat(), gif::Repeat::Finite(3));
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames.push((frame.delay, frame.buffer.to_vec()));
+        }
+        assert_eq!(
+            frames,
+            vec![(10, vec![0; 4]), (20, vec![1; 4]), (30, vec![0; 4])]
+        );
+
+        // Test bytes that aren't a GIF are an error rather than being passed through.
+        assert!(super::strip_gif_metadata(b"not a gif").is_err());
+    }
+
+    #[test]
+    fn test_write_image_color_profile() {
+        use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+        use std::io::Cursor;
+
+        let image = DynamicImage::new_rgb8(2, 2);
+        let icc_profile = b"color profile".to_vec();
+        for format in [ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::WebP] {
+            // Test the color profile is embedded in formats that can carry one.
+            let mut bytes = Vec::new();
+            super::write_image(
+                &image,
+                Cursor::new(&mut bytes),
+                format,
+                Some(icc_profile.clone()),
+                90,
+            )
+            .unwrap();
+            let mut decoder = ImageReader::new(Cursor::new(&bytes))
+                .with_guessed_format()
+                .unwrap()
+                .into_decoder()
+                .unwrap();
+            assert_eq!(decoder.icc_profile().unwrap(), Some(icc_profile.clone()));
+
+            // Test images without a color profile are encoded without one.
+            let mut bytes = Vec::new();
+            super::write_image(&image, Cursor::new(&mut bytes), format, None, 90).unwrap();
+            let mut decoder = ImageReader::new(Cursor::new(&bytes))
+                .with_guessed_format()
+                .unwrap()
+                .into_decoder()
+                .unwrap();
+            assert_eq!(decoder.icc_profile().unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_write_image_jpeg_quality() {
+        use image::{DynamicImage, ImageFormat, RgbImage};
+        use std::io::Cursor;
+
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(32, 32, |x, y| {
+            image::Rgb([(x * 8) as u8, (y * 8) as u8, ((x * y) % 256) as u8])
+        }));
+        let encode = |quality| {
+            let mut bytes = Vec::new();
+            super::write_image(
+                &image,
+                Cursor::new(&mut bytes),
+                ImageFormat::Jpeg,
+                None,
+                quality,
+            )
+            .unwrap();
+            bytes
+        };
+
+        // Test JPEGs are encoded at the given quality.
+        assert!(encode(10).len() < encode(100).len());
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let sha256 = "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let blake3 = format!("blake3:{}", blake3::hash(b"hello").to_hex());
+
+        // Test checksums of either algorithm are checked against the bytes.
+        assert_eq!(super::verify_checksum(sha256, b"hello"), Some(true));
+        assert_eq!(super::verify_checksum(&blake3, b"hello"), Some(true));
+        assert_eq!(super::verify_checksum(sha256, b"hello!"), Some(false));
+        assert_eq!(
+            super::verify_checksum(&sha256.to_uppercase(), b"hello"),
+            Some(true)
+        );
+
+        // Test malformed checksums and unsupported algorithms are rejected.
+        assert_eq!(super::verify_checksum("sha256:abc", b"hello"), None);
+        assert_eq!(
+            super::verify_checksum("md5:5d41402abc4b2a76b9719d911017c592", b"hello"),
+            None
+        );
+        assert_eq!(super::verify_checksum("hello", b"hello"), None);
+    }
+
+    #[test]
+    fn test_declared_type_of() {
+        // Test the declared content type is preferred, with an extension for it.
+        let (mimetype, ext) = super::declared_type_of(Some("image/png"), Some("photo.bin"));
+        assert_eq!((mimetype.essence_str(), ext.as_str()), ("image/png", "png"));
+
+        // Test the filename is used when the content type is missing or only an octet stream.
+        let (mimetype, ext) =
+            super::declared_type_of(Some("application/octet-stream"), Some("photo.JPG"));
+        assert_eq!(
+            (mimetype.essence_str(), ext.as_str()),
+            ("image/jpeg", "jpg")
+        );
+
+        // Test unusable extensions are never used and unknown types fall back to an octet stream.
+        let (mimetype, ext) = super::declared_type_of(None, Some("file.p/n g"));
+        assert_eq!(
+            (mimetype.essence_str(), ext.as_str()),
+            ("application/octet-stream", "")
+        );
+    }
+
+    #[cfg(feature = "storage-memory")]
+    #[tokio::test]
+    async fn test_upload_passthrough_headers() {
+        use crate::test_support::{download, serve, status_line, test_state, upload, upload_json};
+        use axum::http::HeaderName;
+
+        let mut state = test_state();
+        state.upload_passthrough_headers = vec![HeaderName::from_static("x-cache-tag")];
+        let address = serve(state).await;
+
+        let response = upload(
+            address,
+            "X-Cache-Tag: holiday-photos\r\nX-Not-Permitted: secret\r\n",
+            b"passthrough",
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let json = upload_json(&response);
+
+        // Test permitted headers are replayed on download while others are not.
+        let response = download(address, json["url"].as_str().unwrap()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(response.contains("x-cache-tag: holiday-photos\r\n"));
+        assert!(!response.contains("x-not-permitted"));
+        assert!(response.ends_with("passthrough"));
+    }
+
+    #[cfg(feature = "storage-memory")]
+    #[tokio::test]
+    async fn test_sensitive_upload() {
+        use crate::test_support::{
+            download, serve, status_line, test_state, upload, upload_json, upload_with_fields,
+        };
+
+        let address = serve(test_state()).await;
+
+        // Test sensitive uploads are never cached and are served as attachments.
+        let response = upload_with_fields(address, "", &[("sensitive", "true")], b"secret").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let json = upload_json(&response);
+        let response = download(address, json["url"].as_str().unwrap()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(response.contains("cache-control: no-store, private\r\n"));
+        assert!(response.contains("pragma: no-cache\r\n"));
+        assert!(response.contains("content-disposition: attachment\r\n"));
+
+        // Test other uploads keep the default cache policy.
+        let response = upload(address, "", b"not secret").await;
+        let json = upload_json(&response);
+        let response = download(address, json["url"].as_str().unwrap()).await;
+        assert!(response.contains("cache-control: private, max-age=1800\r\n"));
+        assert!(!response.contains("pragma"));
+        assert!(!response.contains("content-disposition"));
+    }
+
+    #[cfg(feature = "storage-memory")]
+    #[tokio::test]
+    async fn test_batch_upload() {
+        use crate::test_support::{
+            download, png_fixture, serve, status_line, test_state, upload_files, upload_json,
+        };
+
+        let mut state = test_state();
+        state.upload_allowed_mimetypes = vec![mime_guess::mime::IMAGE_STAR];
+        state.upload_min_size = 8;
+        let address = serve(state.clone()).await;
+        let images = [png_fixture(4, 4), png_fixture(5, 5)];
+
+        // Test each file is stored and reported in order, with rejected files not failing the rest.
+        let response = upload_files(address, &[&images[0], b"not an image", &images[1]]).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let json = upload_json(&response);
+        let results = json.as_array().unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0]["mimetype"], "image/png");
+        assert_eq!(results[1]["status"], 415);
+        assert!(
+            results[1]["error"]
+                .as_str()
+                .unwrap()
+                .contains("could not be determined")
+        );
+        assert_eq!(results[2]["mimetype"], "image/png");
+        for result in [&results[0], &results[2]] {
+            let response = download(address, result["url"].as_str().unwrap()).await;
+            assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        }
+
+        // Test rejected files are described and counted the same as a rejected single upload.
+        assert!(
+            state
+                .metrics
+                .render()
+                .contains("dollshare_uploads_rejected_mimetype_total 1\n")
+        );
+        let response = upload_files(address, &[&images[0], b"tiny"]).await;
+        let json = upload_json(&response);
+        assert_eq!(json[1]["status"], 400);
+        assert!(
+            json[1]["error"]
+                .as_str()
+                .unwrap()
+                .ends_with("the limit is 8 bytes")
+        );
+
+        // Test a single file is still given a single response.
+        let response = upload_files(address, &[&images[0]]).await;
+        let json = upload_json(&response);
+        assert_eq!(json["mimetype"], "image/png");
+
+        // Test the combined size of every file is held to the upload size limit.
+        let response = upload_files(address, &[&[0; 600], &[0; 600]]).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 413 Payload Too Large");
+    }
+
+    #[cfg(feature = "storage-memory")]
+    #[tokio::test]
+    async fn test_upload_checksum() {
+        use crate::test_support::{
+            png_fixture, serve, status_line, test_state, upload_json, upload_with_fields,
+        };
+        use std::sync::Arc;
+
+        let state = test_state();
+        let storage = Arc::clone(&state.storage);
+        let address = serve(state).await;
+        let mut image = png_fixture(4, 4);
+        image.extend_from_slice(b"Exif-camera-serial");
+        let checksum = format!("blake3:{}", blake3::hash(&image).to_hex());
+
+        // Test a matching checksum is checked against the upload as it was sent, before it is processed.
+        let response = upload_with_fields(address, "", &[("checksum", &checksum)], &image).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let json = upload_json(&response);
+        let stored = storage
+            .get_upload(json["id"].as_str().unwrap(), json["key"].as_str().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(stored, image);
+
+        // Test a mismatching checksum rejects the upload.
+        let response =
+            upload_with_fields(address, "", &[("checksum", &checksum)], b"corrupted").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 422 Unprocessable Entity");
+
+        // Test a malformed checksum is a bad request.
+        let response =
+            upload_with_fields(address, "", &[("checksum", "md5:abc")], b"corrupted").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+    }
+
+    #[cfg(feature = "storage-memory")]
+    #[tokio::test]
+    async fn test_keep_metadata() {
+        use crate::test_support::{
+            download, png_fixture, serve, status_line, test_state, upload, upload_json,
+        };
+
+        let mut state = test_state();
+        state.upload_size_limit = 64 * 1024;
+        let address = serve(state.clone()).await;
+
+        // An image with metadata after its image data that is lost when it is re-encoded.
+        let mut image = png_fixture(4, 4);
+        image.extend_from_slice(b"Exif-camera-serial");
+        let stored = |address, headers: &'static str| {
+            let image = image.clone();
+            async move {
+                let response = upload(address, headers, &image).await;
+                assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+                let json = upload_json(&response);
+                download(address, json["url"].as_str().unwrap()).await
+            }
+        };
+
+        // Test metadata is stripped by default.
+        assert!(!stored(address, "").await.contains("Exif-camera-serial"));
+
+        // Test uploaders can keep the metadata of their images.
+        assert!(
+            stored(address, "X-Keep-Metadata: true\r\n")
+                .await
+                .contains("Exif-camera-serial")
+        );
+        let response = upload(address, "X-Keep-Metadata: maybe\r\n", &image).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+
+        // Test images are stored as uploaded when stripping is disabled.
+        state.upload_strip_exif = false;
+        let address = serve(state).await;
+        assert!(stored(address, "").await.contains("Exif-camera-serial"));
+    }
+
+    #[cfg(feature = "storage-memory")]
+    #[tokio::test]
+    async fn test_trusted_token() {
+        use crate::auth::AuthProvider;
+        use crate::test_support::{
+            TEST_TOKEN, download, png_fixture, serve, status_line, test_state, upload, upload_json,
+        };
+        use std::sync::Arc;
+
+        let mut state = test_state();
+        state.auth_provider = Arc::new(
+            AuthProvider::new(vec![TEST_TOKEN.to_string()], vec![])
+                .with_trusted_tokens(vec![TEST_TOKEN.to_string()]),
+        );
+        let storage = Arc::clone(&state.storage);
+        let address = serve(state).await;
+
+        // An image with metadata after its image data that would be lost if it were re-encoded.
+        let mut image = png_fixture(4, 4);
+        image.extend_from_slice(b"Exif-camera-serial");
+
+        // Test uploads made with a trusted token are stored byte-identically.
+        let response = upload(address, "", &image).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let json = upload_json(&response);
+        let id = json["id"].as_str().unwrap();
+        let key = json["key"].as_str().unwrap();
+        assert_eq!(storage.get_upload(id, key).await.unwrap().unwrap(), image);
+        let response = download(address, json["url"].as_str().unwrap()).await;
+        assert!(response.contains("Exif-camera-serial"));
+    }
+
+    #[cfg(feature = "storage-memory")]
+    #[tokio::test]
+    async fn test_keep_original() {
+        use crate::test_support::{
+            TEST_TOKEN, download, png_fixture, send, serve, status_line, test_state, upload_json,
+            upload_with_fields,
+        };
+
+        let mut state = test_state();
+        state.upload_size_limit = 64 * 1024;
+        state.upload_keep_originals = true;
+        let address = serve(state).await;
+
+        // An image with metadata after its image data that is lost when it is re-encoded.
+        let mut image = png_fixture(4, 4);
+        image.extend_from_slice(b"Exif-camera-serial");
+        let response = upload_with_fields(address, "", &[("keep_original", "true")], &image).await;
+        let json = upload_json(&response);
+        let url = json["url"].as_str().unwrap();
+        let path = &url[url.find("/upload/").unwrap()..];
+
+        // Test the public download is stripped.
+        let response = download(address, url).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(!response.contains("Exif-camera-serial"));
+
+        // Test the original can only be downloaded with a valid token.
+        let original = |authorization: &str| {
+            format!(
+                "GET {path}&original=1 HTTP/1.1\r\n\
+                 Host: {address}\r\n\
+                 {authorization}\
+                 Connection: close\r\n\r\n"
+            )
+        };
+        let response = send(address, original("").as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 401 Unauthorized");
+        let response = send(
+            address,
+            original(&format!("Authorization: Bearer {TEST_TOKEN}\r\n")).as_bytes(),
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(response.contains("Exif-camera-serial"));
+        assert!(response.to_lowercase().contains("cache-control: no-store"));
+    }
+}
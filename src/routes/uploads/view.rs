@@ -0,0 +1,232 @@
+use super::get::{
+    DECRYPT_OR_NOT_FOUND_RESPONSE, DECRYPT_TIMEOUT_RESPONSE, INVALID_SIGNATURE_RESPONSE,
+    PENDING_MODERATION_RESPONSE, cache_headers, has_valid_signature, is_type_servable,
+    within_decrypt_timeout,
+};
+use crate::{AppState, routes::escape_html};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{Html, IntoResponse},
+};
+use bytesize::ByteSize;
+use duration_human::DurationHuman;
+use mime_guess::{Mime, mime};
+use serde::Deserialize;
+use std::time::Duration;
+use tracing::error;
+use url::form_urlencoded;
+
+#[derive(Deserialize)]
+pub struct ViewUploadQuery {
+    /// Decryption key for the upload.
+    key: String,
+    /// When a signed link expires, as seconds since the Unix epoch.
+    exp: Option<u64>,
+    /// Signature of a signed link.
+    sig: Option<String>,
+}
+
+/// Serve a page for viewing an upload in a browser.
+///
+/// Text uploads are shown on the page and images are embedded from their download link,
+/// other uploads are only linked to. The upload is decrypted and checked the same as when
+/// it is downloaded, so the page reveals nothing more about an upload than its link does.
+pub async fn view_upload_handler(
+    query: Query<ViewUploadQuery>,
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    if !has_valid_signature(&state, &id, &query.key, query.exp, query.sig.as_deref()) {
+        return INVALID_SIGNATURE_RESPONSE.into_response();
+    }
+
+    let storage = &state.storage;
+    match storage.upload_exists(&id).await {
+        Ok(exists) => {
+            if !exists {
+                if matches!(storage.is_upload_pending(&id).await, Ok(true)) {
+                    return PENDING_MODERATION_RESPONSE.into_response();
+                }
+                return StatusCode::NOT_FOUND.into_response();
+            }
+        }
+        Err(err) => {
+            error!("Failed to check if upload exists: {}", err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    if !is_type_servable(&state, &id) {
+        return DECRYPT_OR_NOT_FOUND_RESPONSE.into_response();
+    }
+
+    let bytes = match within_decrypt_timeout(&state, storage.get_upload(&id, &query.key)).await {
+        Some(Ok(Some(bytes))) => bytes,
+        Some(Ok(None) | Err(_)) => return DECRYPT_OR_NOT_FOUND_RESPONSE.into_response(),
+        None => return DECRYPT_TIMEOUT_RESPONSE.into_response(),
+    };
+
+    let record = match storage.get_upload_record(&id).await {
+        Ok(record) => record.unwrap_or_default(),
+        Err(err) => {
+            error!("Failed to get record of upload {id}: {err:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    // Text is shown on the page itself so counts as a view, whereas other uploads are
+    // counted when the page fetches or links to them.
+    let mimetype = mime_guess::from_path(&id).first_or_octet_stream();
+    let is_text = is_text(&mimetype, &bytes);
+    if is_text && record.max_views.is_some() {
+        let _lock = state.upload_locks.lock(&id).await;
+        match storage.record_upload_view(&id).await {
+            Ok(true) => {}
+            Ok(false) => return DECRYPT_OR_NOT_FOUND_RESPONSE.into_response(),
+            Err(err) => {
+                error!("Failed to record view of upload {id}: {err:?}");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+    }
+
+    let filename = match storage.get_upload_metadata(&id, &query.key).await {
+        Ok(metadata) => metadata.filename,
+        Err(err) => {
+            error!("Failed to get metadata of upload {id}: {err:?}");
+            None
+        }
+    };
+    let mut download_query = form_urlencoded::Serializer::new(String::new());
+    download_query.append_pair("key", &query.key);
+    if let (Some(exp), Some(sig)) = (query.exp, &query.sig) {
+        download_query
+            .append_pair("exp", &exp.to_string())
+            .append_pair("sig", sig);
+    }
+    let download_url = format!("../upload/{id}?{}", download_query.finish());
+    let content = if is_text {
+        format!(
+            "<pre>{}</pre>",
+            escape_html(&String::from_utf8_lossy(&bytes))
+        )
+    } else if mimetype.type_() == mime::IMAGE {
+        format!("<img src=\"{}\" alt=\"\">", escape_html(&download_url))
+    } else {
+        String::new()
+    };
+
+    let mut response = Html::from(render_view(
+        &state.ui_title,
+        filename.as_deref().unwrap_or(&id),
+        &content,
+        bytes.len(),
+        state
+            .upload_expiry
+            .map(|expire_after| record.expiry().unwrap_or(expire_after)),
+        &download_url,
+    ))
+    .into_response();
+    response.headers_mut().extend(cache_headers(
+        &state.cache_policy,
+        &mime::TEXT_HTML,
+        record.sensitive,
+        record.is_transient(),
+    ));
+    response
+}
+
+/// Whether an upload should be shown as text.
+///
+/// Plain text can't be identified by its content so is stored without a type, such uploads
+/// are shown as text when they are valid UTF-8 without any null bytes.
+fn is_text(mimetype: &Mime, bytes: &[u8]) -> bool {
+    mimetype.type_() == mime::TEXT
+        || (*mimetype == mime::APPLICATION_OCTET_STREAM
+            && !bytes.contains(&0)
+            && str::from_utf8(bytes).is_ok())
+}
+
+/// Fill the placeholders of the view page, `content` is placed on the page as HTML.
+fn render_view(
+    title: &str,
+    filename: &str,
+    content: &str,
+    size: usize,
+    expiry: Option<Duration>,
+    download_url: &str,
+) -> String {
+    let expiry = match expiry {
+        Some(expiry) => format!(
+            "removed after {:#} without being accessed",
+            DurationHuman::from(expiry)
+        ),
+        None => "removed only when deleted".to_string(),
+    };
+    include_str!("../../static/view.html")
+        .replace("{{ui_title}}", &escape_html(title))
+        .replace("{{filename}}", &escape_html(filename))
+        .replace(
+            "{{upload_size}}",
+            &escape_html(&ByteSize(size as u64).display().si().to_string()),
+        )
+        .replace("{{upload_expiry}}", &escape_html(&expiry))
+        .replace("{{download_url}}", &escape_html(download_url))
+        .replace("{{content}}", content)
+}
+
+#[cfg(test)]
+mod tests {
+    use mime_guess::mime;
+
+    #[test]
+    fn test_is_text() {
+        // Test text types and untyped UTF-8 are shown as text.
+        assert!(super::is_text(&mime::TEXT_PLAIN, b"hello"));
+        assert!(super::is_text(&mime::APPLICATION_OCTET_STREAM, b"hello"));
+
+        // Test untyped binary data and other types are not.
+        assert!(!super::is_text(&mime::APPLICATION_OCTET_STREAM, b"a\0b"));
+        assert!(!super::is_text(
+            &mime::APPLICATION_OCTET_STREAM,
+            &[0xff, 0xfe]
+        ));
+        assert!(!super::is_text(&mime::IMAGE_PNG, b"hello"));
+    }
+
+    #[cfg(feature = "storage-memory")]
+    #[tokio::test]
+    async fn test_view_upload() {
+        use crate::test_support::{
+            response_body, send, serve, status_line, test_state, upload, upload_json,
+        };
+
+        let address = serve(test_state()).await;
+        let view = |url: &str| {
+            let path = url[url.find("/upload/").unwrap()..].replacen("/upload/", "/view/", 1);
+            async move {
+                send(
+                    address,
+                    format!("GET {path} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n")
+                        .as_bytes(),
+                )
+                .await
+            }
+        };
+
+        // Test the view page of a text upload shows its decoded text, escaped.
+        let response = upload(address, "", b"hello <world>").await;
+        let json = upload_json(&response);
+        let url = json["url"].as_str().unwrap();
+        let response = view(url).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(response.contains("content-type: text/html"));
+        assert!(response_body(&response).contains("<pre>hello &lt;world&gt;</pre>"));
+        assert!(!response_body(&response).contains("<world>"));
+
+        // Test an invalid key is masked the same as a missing upload.
+        let response = view(&url.replace("key=", "key=invalid")).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 404 Not Found");
+    }
+}
@@ -1,84 +1,2054 @@
 use std::{
-    path::Path,
+    collections::HashSet,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
     time::{Duration, SystemTime},
 };
 
-use super::{StorageCapabilities, StorageOperations, StorageProvider};
-use crate::cryptography::Cryptography;
+use super::{
+    StorageCapabilities, StorageOperations, StorageProvider, StorageScheme, UploadMetadata,
+    UploadRecord, UploadReference, backends::TimedStorage,
+};
+use crate::{
+    compression, cryptography::Cryptography, metrics::Metrics, mime, upload_locks::UploadLocks,
+};
 use anyhow::{Context, Result, bail};
-use tracing::{debug, info};
+use mime_guess::Mime;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+/// Top-level storage directories that a [`StoragePrefix`] cannot use.
+const RESERVED_PREFIXES: [&str; 9] = [
+    "uploads",
+    "quarantine",
+    "metadata",
+    "records",
+    "checksums",
+    "keys",
+    "originals",
+    "references",
+    "usage",
+];
 
 pub struct AppStorage {
-    provider: StorageProvider,
+    provider: TimedStorage,
+    master_keys: Option<MasterKeys>,
+    prefixes: Vec<StoragePrefix>,
+    hide_extensions: bool,
+    compression_level: Option<u32>,
+    /// Most bytes a compressed upload may decompress to.
+    decompressed_size_limit: u64,
+    /// Locks held while an owner's usage is updated so concurrent updates aren't lost.
+    usage_locks: UploadLocks,
+}
+
+/// Total size in bytes and number of the uploads made by an owner.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct OwnerUsage {
+    bytes: u64,
+    files: u64,
+}
+
+/// A storage prefix that uploads of matching MIME types are stored under.
+#[derive(Debug, Clone)]
+pub struct StoragePrefix {
+    pub mimetypes: Vec<Mime>,
+    pub prefix: String,
+}
+
+impl FromStr for StoragePrefix {
+    type Err = String;
+
+    /// Parse from the format `<mimetype>;<mimetype>=<prefix>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mimetypes, prefix) = s
+            .trim()
+            .split_once('=')
+            .ok_or("storage prefixes must be in the format '<mimetype>;<mimetype>=<prefix>'")?;
+        let prefix = prefix.trim().trim_matches('/');
+        if prefix.is_empty()
+            || !prefix
+                .split('/')
+                .all(|part| !part.is_empty() && part != "." && part != "..")
+            || !prefix
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'))
+        {
+            return Err(format!("'{prefix}' is not a valid storage prefix"));
+        }
+        if RESERVED_PREFIXES.contains(&prefix.split('/').next().unwrap()) {
+            return Err(format!(
+                "'{prefix}' is reserved and cannot be used as a storage prefix"
+            ));
+        }
+        let mimetypes = mimetypes
+            .split(';')
+            .map(|mimetype| Mime::from_str(mimetype.trim()).map_err(|err| err.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            mimetypes,
+            prefix: prefix.to_string(),
+        })
+    }
+}
+
+/// Server-held keys used to wrap the keys of uploads.
+#[derive(Debug, Clone)]
+pub struct MasterKeys {
+    /// Key that new and re-wrapped upload keys are wrapped with.
+    pub current: String,
+    /// Keys that upload keys may still be wrapped with until they are re-wrapped.
+    pub previous: Vec<String>,
+}
+
+impl MasterKeys {
+    fn all(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.current).chain(&self.previous)
+    }
 }
 
 impl AppStorage {
     pub fn new(provider: StorageProvider) -> Self {
-        Self { provider }
+        Self {
+            provider: TimedStorage::new(provider, Arc::default()),
+            master_keys: None,
+            prefixes: Vec::new(),
+            hide_extensions: false,
+            compression_level: None,
+            decompressed_size_limit: u64::MAX,
+            usage_locks: UploadLocks::default(),
+        }
+    }
+
+    /// Store uploads of matching MIME types under the first matching prefix.
+    ///
+    /// The prefix is chosen from the MIME type of the upload's id, so changing the prefixes
+    /// leaves existing uploads in place where they can no longer be found.
+    pub fn with_prefixes(mut self, prefixes: Vec<StoragePrefix>) -> Self {
+        self.prefixes = prefixes;
+        self
+    }
+
+    /// Store uploads under their id without its extension so storage does not reveal their types.
+    ///
+    /// Uploads are still encrypted against their full id, so they can't be served under another
+    /// extension. Changing this leaves existing uploads in place where they can no longer be found.
+    ///
+    /// Storage prefixes are not used while extensions are hidden, as they are chosen by type and
+    /// the ids listed from storage have no extension to choose them from.
+    pub fn with_hidden_extensions(mut self, hide_extensions: bool) -> Self {
+        self.hide_extensions = hide_extensions;
+        self
+    }
+
+    /// Whether uploads are stored without their extension, so ids listed from storage don't
+    /// have one.
+    pub fn hides_extensions(&self) -> bool {
+        self.hide_extensions
+    }
+
+    /// Compress new uploads with gzip at the given level before they are encrypted.
+    ///
+    /// Uploads of types that are already compressed, or that compression doesn't make smaller,
+    /// are stored uncompressed.
+    pub fn with_compression(mut self, compression_level: Option<u32>) -> Self {
+        self.compression_level = compression_level;
+        self
+    }
+
+    /// Refuse to serve compressed uploads that decompress to more than the given number of bytes.
+    ///
+    /// Uploads are never stored larger than the upload size limit, so this is normally set to it.
+    /// Lowering it leaves compressed uploads larger than the new limit unreadable.
+    pub fn with_decompressed_size_limit(mut self, limit: u64) -> Self {
+        self.decompressed_size_limit = limit;
+        self
+    }
+
+    /// Record the durations of storage operations and decrypt failures in the given metrics.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.provider.set_metrics(metrics);
+        self
+    }
+
+    /// Wrap the keys of new uploads with a master key, handing out a reference in place of the key.
+    pub fn with_master_keys(mut self, master_keys: Option<MasterKeys>) -> Self {
+        self.master_keys = master_keys;
+        self
+    }
+
+    /// Get the name an upload is stored under from its id.
+    fn storage_id<'a>(&self, id: &'a str) -> &'a str {
+        match id.split_once('.') {
+            Some((name, _)) if self.hide_extensions => name,
+            _ => id,
+        }
     }
 
     fn upload_path() -> &'static Path {
         Path::new("uploads/")
     }
 
+    /// Get the path to an upload's encrypted contents, under its storage prefix if it has one.
+    fn upload_file(&self, id: &str) -> PathBuf {
+        if self.hide_extensions {
+            return Self::upload_path().join(self.storage_id(id));
+        }
+        let mimetype = mime_guess::from_path(id).first_or_octet_stream();
+        match self
+            .prefixes
+            .iter()
+            .find(|prefix| mime::is_mime_allowed(&mimetype, &prefix.mimetypes))
+        {
+            Some(prefix) => Path::new(&prefix.prefix)
+                .join(Self::upload_path())
+                .join(self.storage_id(id)),
+            None => Self::upload_path().join(self.storage_id(id)),
+        }
+    }
+
+    fn quarantine_path() -> &'static Path {
+        Path::new("quarantine")
+    }
+
+    /// Get the path to the encrypted contents of an upload that is pending moderation.
+    fn pending_file(&self, id: &str) -> PathBuf {
+        Self::quarantine_path().join(self.storage_id(id))
+    }
+
+    fn metadata_path() -> &'static Path {
+        Path::new("metadata/")
+    }
+
+    fn record_path() -> &'static Path {
+        Path::new("records/")
+    }
+
+    fn checksum_path() -> &'static Path {
+        Path::new("checksums/")
+    }
+
+    fn key_path() -> &'static Path {
+        Path::new("keys/")
+    }
+
+    fn original_path() -> &'static Path {
+        Path::new("originals/")
+    }
+
+    fn reference_path() -> &'static Path {
+        Path::new("references/")
+    }
+
+    fn usage_path() -> &'static Path {
+        Path::new("usage/")
+    }
+
+    /// Additional authenticated data for an upload's metadata, distinct from the upload's own.
+    fn metadata_aad(id: &str) -> Vec<u8> {
+        format!("metadata/{id}").into_bytes()
+    }
+
+    /// Additional authenticated data for an upload's original bytes, distinct from the upload's own.
+    fn original_aad(id: &str) -> Vec<u8> {
+        format!("originals/{id}").into_bytes()
+    }
+
+    /// Additional authenticated data for an upload's wrapped key.
+    fn key_aad(id: &str) -> Vec<u8> {
+        format!("keys/{id}").into_bytes()
+    }
+
+    /// Get the key that an upload was encrypted with from the key given for it.
+    ///
+    /// Uploads with a wrapped key are given a reference that is unwrapped with the master keys,
+    /// other uploads are given their key directly.
+    async fn upload_key(&self, id: &str, key: &str) -> Result<String> {
+        let Some(wrapped) = self
+            .provider
+            .read(&Self::key_path().join(self.storage_id(id)))
+            .await?
+        else {
+            return Ok(key.to_string());
+        };
+        let Some(master_keys) = &self.master_keys else {
+            bail!("upload key is wrapped but no master key is configured");
+        };
+        master_keys
+            .all()
+            .find_map(|master_key| {
+                Cryptography::unwrap_key(
+                    &wrapped,
+                    key,
+                    master_key,
+                    &Self::key_aad(self.storage_id(id)),
+                )
+                .ok()
+            })
+            .context("failed to unwrap upload key")
+    }
+
+    /// Persist anything the storage provider needs to keep before the server exits.
+    pub fn shutdown(&self) -> Result<()> {
+        self.provider.provider().shutdown()
+    }
+
     pub fn provider_supports_expiry(&self) -> bool {
         self.provider.supports_expiry()
     }
 
-    pub async fn remove_all_expired_uploads(&mut self, expire_after: Duration) -> Result<()> {
-        if !self.provider.supports_expiry() {
-            return Ok(());
+    pub fn scheme(&self) -> StorageScheme {
+        self.provider.provider().scheme()
+    }
+
+    /// Check that storage can be reached by looking for a file that is never written.
+    pub async fn check_ready(&self) -> Result<()> {
+        self.provider.exists(Path::new("readiness")).await?;
+        Ok(())
+    }
+
+    /// Whether the keys of new uploads are wrapped with a master key.
+    pub fn has_master_keys(&self) -> bool {
+        self.master_keys.is_some()
+    }
+
+    /// Remove all uploads that have reached their view limit, as well as uploads that have
+    /// not been accessed within `expire_after` when the provider supports expiry.
+    ///
+    /// Uploads with their own expiry in their record use it instead of `expire_after`.
+    ///
+    /// Uploads created within `grace_period` are never removed for not being accessed.
+    /// Each upload is locked while it is checked so it can't be replaced mid-removal.
+    ///
+    /// Uploads that fail to be checked or removed are skipped until the next sweep, only a
+    /// failure to list uploads stops the sweep. Every upload sharing stored content expires on
+    /// its own, and the content is removed in the same sweep as the last of them.
+    ///
+    /// Returns the number of uploads that were scanned and removed.
+    pub async fn remove_all_expired_uploads(
+        &self,
+        expire_after: Option<Duration>,
+        grace_period: Option<Duration>,
+        locks: &UploadLocks,
+    ) -> Result<(usize, usize)> {
+        let mut scanned = HashSet::new();
+        let mut removed = 0;
+        if let Some(expire_after) = expire_after
+            && self.provider.supports_expiry()
+        {
+            for id in self.list_upload_ids().await? {
+                let _lock = locks.lock(&id).await;
+                match self
+                    .remove_upload_if_expired(&id, expire_after, grace_period)
+                    .await
+                {
+                    Ok(expired) => removed += expired,
+                    Err(err) => {
+                        warn!("Failed to check expiry of upload {id} - skipping: {err:?}")
+                    }
+                }
+                scanned.insert(id);
+            }
         }
 
-        let paths = self.provider.list(Self::upload_path()).await?;
-        for path in paths.iter() {
-            if self.is_upload_expired(path, expire_after).await? {
-                info!("file '{}' expired - deleting from storage.", path.display());
-                self.provider.delete(path).await?;
+        for (id, _) in self.list_upload_records().await? {
+            let _lock = locks.lock(&id).await;
+            match self.remove_upload_if_view_limit_reached(&id).await {
+                Ok(true) => removed += 1,
+                Ok(false) => {}
+                Err(err) => {
+                    warn!("Failed to check view limit of upload {id} - skipping: {err:?}")
+                }
             }
+            scanned.insert(id);
         }
-        Ok(())
+        Ok((scanned.len(), removed))
     }
 
-    async fn is_upload_expired(&self, file: &Path, expire_after: Duration) -> Result<bool> {
-        if !self.provider.supports_expiry() {
-            return Ok(false);
-        }
-        let Some(last_access) = self.provider.last_access(file).await? else {
+    /// Remove the uploads of stored content that have expired, removing the content once none
+    /// are left.
+    ///
+    /// Returns the number of uploads that were removed.
+    async fn remove_upload_if_expired(
+        &self,
+        id: &str,
+        expire_after: Duration,
+        grace_period: Option<Duration>,
+    ) -> Result<usize> {
+        let expire_after = self
+            .get_upload_record(id)
+            .await?
+            .and_then(|record| record.expiry())
+            .unwrap_or(expire_after);
+        let Some(last_access) = self.provider.last_access(&self.upload_file(id)).await? else {
             bail!("File does not have a last access time");
         };
-        Ok(last_access + expire_after <= SystemTime::now())
+        let now = SystemTime::now();
+        let (expired, kept): (Vec<_>, Vec<_>) = self
+            .upload_references(id)
+            .await?
+            .into_iter()
+            .partition(|reference| {
+                reference.is_expired(last_access, expire_after, grace_period, now)
+            });
+        if expired.is_empty() {
+            return Ok(0);
+        }
+        if kept.is_empty() {
+            info!("file '{id}' expired - deleting from storage.");
+            self.remove_upload(id).await?;
+        } else {
+            debug!(
+                "{} uploads of {id} expired, {} remain",
+                expired.len(),
+                kept.len()
+            );
+            self.save_upload_references(id, &kept).await?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Returns whether the upload was removed.
+    async fn remove_upload_if_view_limit_reached(&self, id: &str) -> Result<bool> {
+        if self
+            .get_upload_record(id)
+            .await?
+            .is_some_and(|record| record.is_view_limit_reached())
+        {
+            info!("file '{id}' reached its view limit - deleting from storage.");
+            self.remove_upload(id).await?;
+            return Ok(true);
+        }
+        Ok(false)
     }
 
-    pub async fn get_upload(&self, id: &str, key: &str) -> Result<Vec<u8>> {
+    /// Decrypt and get the contents of an upload.
+    ///
+    /// Uploads are encrypted as a single AEAD message that can only be authenticated once all
+    /// of it has been read, so the whole upload is held in memory. Serving uploads without
+    /// buffering them would need the ciphertext to be split into separately authenticated chunks.
+    ///
+    /// Decryption and decompression run on a blocking thread so large uploads don't hold up
+    /// other requests.
+    ///
+    /// Returns `None` if the upload does not exist, including when it was deleted after its
+    /// existence was last checked.
+    pub async fn get_upload(&self, id: &str, key: &str) -> Result<Option<Vec<u8>>> {
         debug!("Decrypting and fetching {id} from storage");
-        let file = self
-            .provider
-            .read(&Self::upload_path().join(Path::new(id)))
+        let Some(file) = self.provider.read(&self.upload_file(id)).await? else {
+            return Ok(None);
+        };
+        let key = self.upload_key(id, key).await?;
+        let aad = id.as_bytes().to_vec();
+        let decrypt_key = key.clone();
+        let bytes =
+            tokio::task::spawn_blocking(move || Cryptography::decrypt(&file, &decrypt_key, &aad))
+                .await?
+                .inspect_err(|_| self.provider.metrics().record_decrypt_failure())?;
+        if !self.read_upload_metadata(id, &key).await?.compressed {
+            return Ok(Some(bytes));
+        }
+        let limit = self.decompressed_size_limit;
+        tokio::task::spawn_blocking(move || compression::decompress(&bytes, limit))
             .await?
-            .context("file does not exist")?;
-        Cryptography::decrypt(&file, key, id.as_bytes())
+            .map(Some)
+    }
+
+    /// Whether an upload other than one with the given content hash is stored under the id.
+    ///
+    /// Uploads stored without a content hash are assumed to be different.
+    pub async fn is_upload_id_taken(&self, id: &str, content_hash: &str) -> Result<bool> {
+        if !self.upload_exists(id).await? && !self.is_upload_pending(id).await? {
+            return Ok(false);
+        }
+        Ok(self
+            .get_upload_record(id)
+            .await?
+            .and_then(|record| record.content_hash)
+            .is_none_or(|stored| stored != content_hash))
     }
 
     pub async fn upload_exists(&self, id: &str) -> Result<bool> {
         debug!("Checking if {id} exists in storage");
-        self.provider
-            .exists(&Self::upload_path().join(Path::new(id)))
-            .await
+        self.provider.exists(&self.upload_file(id)).await
+    }
+
+    /// Whether an upload is being held in quarantine until it is approved.
+    pub async fn is_upload_pending(&self, id: &str) -> Result<bool> {
+        debug!("Checking if {id} is pending moderation in storage");
+        self.provider.exists(&self.pending_file(id)).await
+    }
+
+    /// Get the ids of all uploads pending moderation.
+    pub async fn list_pending_upload_ids(&self) -> Result<Vec<String>> {
+        debug!("Listing all uploads pending moderation in storage");
+        Ok(self
+            .provider
+            .list(Self::quarantine_path())
+            .await?
+            .iter()
+            .filter_map(|path| path.file_name()?.to_str().map(str::to_string))
+            .collect())
+    }
+
+    /// Move an upload pending moderation out of quarantine so it can be served.
+    ///
+    /// The uploads of it made while it was pending were counted when they were saved.
+    /// Returns `false` if the upload is not pending moderation.
+    pub async fn approve_upload(&self, id: &str) -> Result<bool> {
+        debug!("Approving {id} in storage");
+        let Some(bytes) = self.provider.read(&self.pending_file(id)).await? else {
+            return Ok(false);
+        };
+        self.provider.write(&self.upload_file(id), &bytes).await?;
+        self.provider.delete(&self.pending_file(id)).await?;
+        Ok(true)
+    }
+
+    /// Delete an upload pending moderation along with everything stored alongside it.
+    ///
+    /// Returns `false` if the upload is not pending moderation.
+    pub async fn reject_upload(&self, id: &str) -> Result<bool> {
+        debug!("Rejecting {id} in storage");
+        if !self.is_upload_pending(id).await? {
+            return Ok(false);
+        }
+        self.provider.delete(&self.pending_file(id)).await?;
+        self.delete_upload_extras(id).await?;
+        Ok(true)
+    }
+
+    /// Get the ids of all uploads in storage.
+    pub async fn list_upload_ids(&self) -> Result<Vec<String>> {
+        debug!("Listing all uploads in storage");
+        let mut upload_paths = vec![Self::upload_path().to_path_buf()];
+        for prefix in self.prefixes.iter().filter(|_| !self.hide_extensions) {
+            let path = Path::new(&prefix.prefix).join(Self::upload_path());
+            if !upload_paths.contains(&path) {
+                upload_paths.push(path);
+            }
+        }
+        let mut ids = Vec::new();
+        for path in upload_paths {
+            ids.extend(
+                self.provider
+                    .list(&path)
+                    .await?
+                    .iter()
+                    .filter_map(|path| path.file_name()?.to_str().map(str::to_string)),
+            );
+        }
+        Ok(ids)
+    }
+
+    /// Get the number of uploads in storage and the total size of their encrypted contents in bytes.
+    pub async fn upload_statistics(&self) -> Result<(usize, u64)> {
+        let ids = self.list_upload_ids().await?;
+        let mut bytes = 0;
+        for id in &ids {
+            bytes += self.upload_size(id).await?.unwrap_or_default();
+        }
+        Ok((ids.len(), bytes))
+    }
+
+    pub async fn upload_created(&self, id: &str) -> Result<Option<SystemTime>> {
+        debug!("Obtaining creation time of {id} from storage");
+        self.provider.created(&self.upload_file(id)).await
+    }
+
+    /// Get the size of an upload's encrypted contents in bytes.
+    pub async fn upload_size(&self, id: &str) -> Result<Option<u64>> {
+        debug!("Obtaining size of {id} from storage");
+        self.provider.size(&self.upload_file(id)).await
+    }
+
+    /// Get when an upload was last accessed, `None` if the storage provider doesn't track it.
+    pub async fn upload_last_access(&self, id: &str) -> Result<Option<SystemTime>> {
+        debug!("Obtaining last access time of {id} from storage");
+        if !self.provider.supports_expiry() {
+            return Ok(None);
+        }
+        self.provider.last_access(&self.upload_file(id)).await
     }
 
-    pub async fn save_upload(&mut self, id: &str, bytes: &[u8]) -> Result<String> {
+    pub async fn get_upload_metadata(&self, id: &str, key: &str) -> Result<UploadMetadata> {
+        debug!("Decrypting and fetching metadata for {id} from storage");
+        let Some(file) = self
+            .provider
+            .read(&Self::metadata_path().join(self.storage_id(id)))
+            .await?
+        else {
+            return Ok(UploadMetadata::default());
+        };
+        let key = self.upload_key(id, key).await?;
+        Self::decrypt_metadata(id, &file, &key)
+    }
+
+    /// Read an upload's metadata with the key the upload was encrypted with.
+    async fn read_upload_metadata(&self, id: &str, key: &str) -> Result<UploadMetadata> {
+        let Some(file) = self
+            .provider
+            .read(&Self::metadata_path().join(self.storage_id(id)))
+            .await?
+        else {
+            return Ok(UploadMetadata::default());
+        };
+        Self::decrypt_metadata(id, &file, key)
+    }
+
+    fn decrypt_metadata(id: &str, file: &[u8], key: &str) -> Result<UploadMetadata> {
+        let bytes = Cryptography::decrypt(file, key, &Self::metadata_aad(id))?;
+        serde_json::from_slice(&bytes).context("failed to parse upload metadata")
+    }
+
+    /// Save an upload encrypted with a new random key, replacing anything stored under its id.
+    pub async fn save_upload(
+        &self,
+        id: &str,
+        bytes: &[u8],
+        metadata: &UploadMetadata,
+        reference: UploadReference,
+    ) -> Result<String> {
         debug!("Encrypting and saving {id} to storage");
-        let (key, bytes) = Cryptography::encrypt(bytes, id.as_bytes())?;
+        // Remove a copy pending moderation that would otherwise replace this upload if approved.
+        self.provider.delete(&self.pending_file(id)).await?;
+        self.save_upload_references(id, &[reference]).await?;
+        self.save_upload_to(
+            &self.upload_file(id),
+            id,
+            bytes,
+            metadata,
+            &Cryptography::generate_key(),
+            None,
+        )
+        .await
+    }
+
+    /// Save an upload of content that identical uploads share, encrypted with the key derived
+    /// from the content by [`Cryptography::derive_content_key`].
+    ///
+    /// Content that is already stored is kept as it is and another upload of it is counted
+    /// instead, so the keys given for earlier uploads of it keep working.
+    ///
+    /// Returns the key for the upload and whether its content was already stored.
+    pub async fn save_shared_upload(
+        &self,
+        id: &str,
+        bytes: &[u8],
+        metadata: &UploadMetadata,
+        content_key: &str,
+        reference: UploadReference,
+    ) -> Result<(String, bool)> {
+        // Remove a copy pending moderation that would otherwise replace this upload if approved.
+        self.provider.delete(&self.pending_file(id)).await?;
+        if self.is_upload_encrypted_with(id, content_key).await? {
+            debug!("Counting another upload of {id} in storage");
+            self.add_upload_reference(id, reference).await?;
+            return Ok((self.shared_upload_key(id, content_key).await?, true));
+        }
+        debug!("Encrypting and saving {id} to storage");
+        self.save_upload_references(id, &[reference]).await?;
+        let key = self
+            .save_upload_to(
+                &self.upload_file(id),
+                id,
+                bytes,
+                metadata,
+                content_key,
+                Some(Self::shared_reference(content_key)),
+            )
+            .await?;
+        Ok((key, false))
+    }
+
+    /// Save an upload into quarantine, where it is not served until it is approved.
+    ///
+    /// Uploads of shared content are encrypted with their content key so they can still be
+    /// shared once approved, and identical uploads made while it is pending are counted
+    /// alongside it. Other uploads are encrypted with a new random key.
+    pub async fn save_pending_upload(
+        &self,
+        id: &str,
+        bytes: &[u8],
+        metadata: &UploadMetadata,
+        content_key: Option<&str>,
+        reference: UploadReference,
+    ) -> Result<String> {
+        debug!("Encrypting and saving {id} to storage pending moderation");
+        let path = self.pending_file(id);
+        match content_key {
+            Some(_) if self.is_upload_pending(id).await? => {
+                self.add_upload_reference(id, reference).await?;
+            }
+            _ => self.save_upload_references(id, &[reference]).await?,
+        }
+        match content_key {
+            Some(content_key) => {
+                self.save_upload_to(
+                    &path,
+                    id,
+                    bytes,
+                    metadata,
+                    content_key,
+                    Some(Self::shared_reference(content_key)),
+                )
+                .await
+            }
+            None => {
+                self.save_upload_to(
+                    &path,
+                    id,
+                    bytes,
+                    metadata,
+                    &Cryptography::generate_key(),
+                    None,
+                )
+                .await
+            }
+        }
+    }
+
+    /// Whether the content stored for an upload can be decrypted with the given key.
+    async fn is_upload_encrypted_with(&self, id: &str, key: &str) -> Result<bool> {
+        let Some(file) = self.provider.read(&self.upload_file(id)).await? else {
+            return Ok(false);
+        };
+        let key = key.to_string();
+        let aad = id.as_bytes().to_vec();
+        Ok(
+            tokio::task::spawn_blocking(move || Cryptography::decrypt(&file, &key, &aad).is_ok())
+                .await?,
+        )
+    }
+
+    /// Get the reference that the key of shared content is wrapped with, which every upload of
+    /// the content must be given so it can unwrap the same key.
+    fn shared_reference(content_key: &str) -> String {
+        Cryptography::derive_subkey(content_key, "dollshare 2025 shared key references")
+    }
+
+    /// Get the key to give an upload of shared content that is already stored.
+    async fn shared_upload_key(&self, id: &str, content_key: &str) -> Result<String> {
+        match self
+            .provider
+            .exists(&Self::key_path().join(self.storage_id(id)))
+            .await?
+        {
+            true => Ok(Self::shared_reference(content_key)),
+            false => Ok(content_key.to_string()),
+        }
+    }
+
+    /// Encrypt and write an upload with the given key, along with everything stored alongside it.
+    ///
+    /// When master keys are configured the key is wrapped with the given reference, or a new
+    /// random one, and the reference is returned in place of the key.
+    async fn save_upload_to(
+        &self,
+        path: &Path,
+        id: &str,
+        bytes: &[u8],
+        metadata: &UploadMetadata,
+        key: &str,
+        reference: Option<String>,
+    ) -> Result<String> {
+        // Compression happens before encryption, so the upload is still bound to its id.
+        let compressed = match self.compression_level {
+            Some(level) if compression::is_compressible(bytes) => {
+                Some(compression::compress(bytes, level)?)
+                    .filter(|compressed| compressed.len() < bytes.len())
+            }
+            _ => None,
+        };
+        let metadata = &UploadMetadata {
+            compressed: compressed.is_some(),
+            ..metadata.clone()
+        };
+        let bytes = Cryptography::encrypt_with_key(
+            compressed.as_deref().unwrap_or(bytes),
+            key,
+            id.as_bytes(),
+        )?;
+        self.provider.write(path, &bytes).await?;
         self.provider
-            .write(&Self::upload_path().join(id), &bytes)
+            .write(
+                &Self::checksum_path().join(self.storage_id(id)),
+                blake3::hash(&bytes).to_hex().as_bytes(),
+            )
             .await?;
-        Ok(key)
+        // Remove an original kept by a previous upload of the same content.
+        self.provider
+            .delete(&Self::original_path().join(self.storage_id(id)))
+            .await?;
+        if *metadata == UploadMetadata::default() {
+            // Remove metadata left behind by a previous upload of the same content.
+            self.provider
+                .delete(&Self::metadata_path().join(self.storage_id(id)))
+                .await?;
+        } else {
+            let metadata = Cryptography::encrypt_with_key(
+                &serde_json::to_vec(metadata)?,
+                key,
+                &Self::metadata_aad(id),
+            )?;
+            self.provider
+                .write(&Self::metadata_path().join(self.storage_id(id)), &metadata)
+                .await?;
+        }
+        match &self.master_keys {
+            Some(master_keys) => {
+                let reference = reference.unwrap_or_else(Cryptography::generate_key);
+                let wrapped = Cryptography::wrap_key(
+                    key,
+                    &reference,
+                    &master_keys.current,
+                    &Self::key_aad(self.storage_id(id)),
+                )?;
+                self.provider
+                    .write(&Self::key_path().join(self.storage_id(id)), &wrapped)
+                    .await?;
+                Ok(reference)
+            }
+            None => {
+                // Remove a wrapped key left behind by a previous upload of the same content.
+                self.provider
+                    .delete(&Self::key_path().join(self.storage_id(id)))
+                    .await?;
+                Ok(key.to_string())
+            }
+        }
     }
 
-    pub async fn delete_upload(&mut self, id: &str) -> Result<()> {
-        debug!("Deleting {id} from storage");
-        self.provider.delete(&Self::upload_path().join(id)).await?;
+    /// Save the original bytes of an upload that was altered before it was saved.
+    ///
+    /// The original is encrypted with the upload's key, so it must be saved after the upload.
+    pub async fn save_upload_original(&self, id: &str, key: &str, bytes: &[u8]) -> Result<()> {
+        debug!("Encrypting and saving original of {id} to storage");
+        let key = self.upload_key(id, key).await?;
+        let bytes = Cryptography::encrypt_with_key(bytes, &key, &Self::original_aad(id))?;
+        self.provider
+            .write(&Self::original_path().join(self.storage_id(id)), &bytes)
+            .await
+    }
+
+    /// Get the original bytes of an upload, if they were kept when it was saved.
+    pub async fn get_upload_original(&self, id: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        debug!("Decrypting and fetching original of {id} from storage");
+        let Some(file) = self
+            .provider
+            .read(&Self::original_path().join(self.storage_id(id)))
+            .await?
+        else {
+            return Ok(None);
+        };
+        let key = self.upload_key(id, key).await?;
+        let aad = Self::original_aad(id);
+        tokio::task::spawn_blocking(move || Cryptography::decrypt(&file, &key, &aad))
+            .await?
+            .inspect_err(|_| self.provider.metrics().record_decrypt_failure())
+            .map(Some)
+    }
+
+    /// Get the ids of all uploads with a wrapped key.
+    pub async fn list_wrapped_key_ids(&self) -> Result<Vec<String>> {
+        debug!("Listing all wrapped upload keys in storage");
+        Ok(self
+            .provider
+            .list(Self::key_path())
+            .await?
+            .iter()
+            .filter_map(|path| path.file_name()?.to_str().map(str::to_string))
+            .collect())
+    }
+
+    /// Re-wrap the key of an upload with the current master key.
+    ///
+    /// Returns `false` if the key is already wrapped with the current master key.
+    pub async fn rewrap_upload_key(&self, id: &str) -> Result<bool> {
+        debug!("Re-wrapping key of {id} in storage");
+        let Some(master_keys) = &self.master_keys else {
+            bail!("no master key is configured");
+        };
+        let path = Self::key_path().join(self.storage_id(id));
+        let Some(wrapped) = self.provider.read(&path).await? else {
+            bail!("upload key is not wrapped");
+        };
+        for (index, master_key) in master_keys.all().enumerate() {
+            let Ok(rewrapped) = Cryptography::rewrap_key(
+                &wrapped,
+                master_key,
+                &master_keys.current,
+                &Self::key_aad(self.storage_id(id)),
+            ) else {
+                continue;
+            };
+            if index == 0 {
+                return Ok(false);
+            }
+            self.provider.write(&path, &rewrapped).await?;
+            return Ok(true);
+        }
+        bail!("upload key is not wrapped with any configured master key")
+    }
+
+    /// Delete every upload in storage along with everything stored alongside them.
+    ///
+    /// Returns the number of uploads that were deleted.
+    pub async fn purge_all_uploads(&self) -> Result<usize> {
+        let ids = self.list_upload_ids().await?;
+        for id in &ids {
+            self.remove_upload(id).await?;
+        }
+        let pending_ids = self.list_pending_upload_ids().await?;
+        for id in &pending_ids {
+            self.reject_upload(id).await?;
+        }
+        Ok(ids.len() + pending_ids.len())
+    }
+
+    /// Get the checksum of an upload's stored ciphertext taken when it was saved.
+    ///
+    /// Returns `None` if the upload was saved without one.
+    pub async fn get_upload_checksum(&self, id: &str) -> Result<Option<String>> {
+        Ok(self
+            .provider
+            .read(&Self::checksum_path().join(self.storage_id(id)))
+            .await?
+            .and_then(|checksum| String::from_utf8(checksum).ok()))
+    }
+
+    /// Check the stored ciphertext of an upload against the checksum taken when it was saved.
+    ///
+    /// Returns `None` if the upload or its checksum does not exist.
+    pub async fn verify_upload(&self, id: &str) -> Result<Option<bool>> {
+        debug!("Verifying integrity of {id} in storage");
+        let Some(checksum) = self
+            .provider
+            .read(&Self::checksum_path().join(self.storage_id(id)))
+            .await?
+        else {
+            return Ok(None);
+        };
+        // The ciphertext is hashed as it is streamed so it is never held in memory all at once.
+        let Some(mut stream) = self.provider.read_stream(&self.upload_file(id)).await? else {
+            return Ok(None);
+        };
+        let mut hasher = blake3::Hasher::new();
+        while let Some(chunk) = std::future::poll_fn(|cx| stream.as_mut().poll_next(cx)).await {
+            hasher.update(&chunk?);
+        }
+        Ok(Some(
+            hasher.finalize().to_hex().as_bytes() == checksum.as_slice(),
+        ))
+    }
+
+    pub async fn get_upload_record(&self, id: &str) -> Result<Option<UploadRecord>> {
+        debug!("Fetching record for {id} from storage");
+        let Some(file) = self
+            .provider
+            .read(&Self::record_path().join(self.storage_id(id)))
+            .await?
+        else {
+            return Ok(None);
+        };
+        Ok(Some(
+            serde_json::from_slice(&file).context("failed to parse upload record")?,
+        ))
+    }
+
+    /// Get the records of all uploads that have one.
+    pub async fn list_upload_records(&self) -> Result<Vec<(String, UploadRecord)>> {
+        debug!("Listing all upload records in storage");
+        let mut records = Vec::new();
+        for path in self.provider.list(Self::record_path()).await? {
+            let Some(id) = path.file_name().and_then(|id| id.to_str()) else {
+                continue;
+            };
+            if let Some(record) = self.get_upload_record(id).await? {
+                records.push((id.to_string(), record));
+            }
+        }
+        Ok(records)
+    }
+
+    /// Get the total size in bytes and number of the uploads made by an owner.
+    ///
+    /// Usage is kept up to date as uploads are saved and removed, so it is read without
+    /// looking at any of the owner's uploads.
+    pub async fn owner_usage(&self, owner: &str) -> Result<(u64, u64)> {
+        debug!("Fetching usage of uploads by owner");
+        let usage = self.read_owner_usage(owner).await?;
+        Ok((usage.bytes, usage.files))
+    }
+
+    async fn read_owner_usage(&self, owner: &str) -> Result<OwnerUsage> {
+        match self.provider.read(&Self::usage_path().join(owner)).await? {
+            Some(usage) => serde_json::from_slice(&usage).context("failed to parse owner usage"),
+            None => Ok(OwnerUsage::default()),
+        }
+    }
+
+    /// Charge the owner of an upload for it in their usage, or refund them once it is removed.
+    async fn update_owner_usage(&self, reference: &UploadReference, charge: bool) -> Result<()> {
+        let Some(owner) = &reference.owner else {
+            return Ok(());
+        };
+        let _lock = self.usage_locks.lock(owner).await;
+        let mut usage = self.read_owner_usage(owner).await?;
+        if charge {
+            usage.bytes += reference.size;
+            usage.files += 1;
+        } else {
+            usage.bytes = usage.bytes.saturating_sub(reference.size);
+            usage.files = usage.files.saturating_sub(1);
+        }
+        let path = Self::usage_path().join(owner);
+        if usage.files == 0 {
+            self.provider.delete(&path).await?;
+        } else {
+            self.provider
+                .write(&path, &serde_json::to_vec(&usage)?)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Count a view of an upload that has a view limit, deleting the upload once its last
+    /// permitted view is counted.
+    ///
+    /// Must be called while holding the upload's lock so concurrent views are counted one at a
+    /// time. Returns `false` without counting the view if the upload has already reached its
+    /// limit or was deleted by an earlier view.
+    pub async fn record_upload_view(&self, id: &str) -> Result<bool> {
+        let Some(mut record) = self.get_upload_record(id).await? else {
+            return Ok(false);
+        };
+        if record.max_views.is_none() {
+            return Ok(true);
+        }
+        if record.is_view_limit_reached() {
+            return Ok(false);
+        }
+        record.views += 1;
+        if record.is_view_limit_reached() {
+            info!("file '{id}' reached its view limit - deleting from storage.");
+            self.remove_upload(id).await?;
+        } else {
+            self.save_upload_record(id, &record).await?;
+        }
+        Ok(true)
+    }
+
+    pub async fn save_upload_record(&self, id: &str, record: &UploadRecord) -> Result<()> {
+        debug!("Saving record for {id} to storage");
+        if *record == UploadRecord::default() {
+            self.provider
+                .delete(&Self::record_path().join(self.storage_id(id)))
+                .await?;
+        } else {
+            self.provider
+                .write(
+                    &Self::record_path().join(self.storage_id(id)),
+                    &serde_json::to_vec(record)?,
+                )
+                .await?;
+        }
         Ok(())
     }
+
+    /// Get the uploads of identical content sharing an upload's stored content.
+    ///
+    /// Uploads saved before they were recorded are a single upload made when the content was.
+    async fn upload_references(&self, id: &str) -> Result<Vec<UploadReference>> {
+        match self.read_upload_references(id).await? {
+            Some(references) => Ok(references),
+            None => Ok(vec![UploadReference::created_at(
+                self.upload_created(id)
+                    .await?
+                    .unwrap_or(SystemTime::UNIX_EPOCH),
+            )]),
+        }
+    }
+
+    async fn read_upload_references(&self, id: &str) -> Result<Option<Vec<UploadReference>>> {
+        match self
+            .provider
+            .read(&Self::reference_path().join(self.storage_id(id)))
+            .await?
+        {
+            Some(references) => Ok(Some(
+                serde_json::from_slice(&references).context("failed to parse upload references")?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Record the uploads sharing an upload's stored content, charging the owners of uploads
+    /// that were added and refunding the owners of those that were removed.
+    async fn save_upload_references(&self, id: &str, references: &[UploadReference]) -> Result<()> {
+        let path = Self::reference_path().join(self.storage_id(id));
+        let stored = self.read_upload_references(id).await?.unwrap_or_default();
+        if references.is_empty() {
+            self.provider.delete(&path).await?;
+        } else {
+            self.provider
+                .write(&path, &serde_json::to_vec(references)?)
+                .await?;
+        }
+        for reference in stored.iter().filter(|stored| !references.contains(stored)) {
+            self.update_owner_usage(reference, false).await?;
+        }
+        for reference in references.iter().filter(|added| !stored.contains(added)) {
+            self.update_owner_usage(reference, true).await?;
+        }
+        Ok(())
+    }
+
+    /// Count another upload of an upload's content, which must be called before it is saved.
+    async fn add_upload_reference(&self, id: &str, reference: UploadReference) -> Result<()> {
+        let mut references =
+            match self.upload_exists(id).await? || self.is_upload_pending(id).await? {
+                true => self.upload_references(id).await?,
+                false => Vec::new(),
+            };
+        references.push(reference);
+        self.save_upload_references(id, &references).await
+    }
+
+    /// Delete the upload of an upload's content that was made with the given delete token,
+    /// only removing the content from storage once every upload of it has been deleted.
+    ///
+    /// Returns `false` if no upload of the content was made with the token.
+    pub async fn delete_upload(&self, id: &str, delete_token: &str) -> Result<bool> {
+        let mut references = self.upload_references(id).await?;
+        let Some(index) = references
+            .iter()
+            .position(|reference| reference.is_deleted_by(delete_token))
+        else {
+            return Ok(false);
+        };
+        references.remove(index);
+        if references.is_empty() {
+            self.remove_upload(id).await?;
+        } else {
+            debug!("Deleting an upload of {id}, {} remain", references.len());
+            self.save_upload_references(id, &references).await?;
+        }
+        Ok(true)
+    }
+
+    /// Remove an upload from storage along with every other upload sharing its content.
+    pub async fn remove_upload(&self, id: &str) -> Result<()> {
+        debug!("Deleting {id} from storage");
+        self.provider.delete(&self.upload_file(id)).await?;
+        self.delete_upload_extras(id).await
+    }
+
+    /// Delete everything stored alongside an upload.
+    async fn delete_upload_extras(&self, id: &str) -> Result<()> {
+        self.provider
+            .delete(&Self::metadata_path().join(self.storage_id(id)))
+            .await?;
+        self.provider
+            .delete(&Self::record_path().join(self.storage_id(id)))
+            .await?;
+        self.provider
+            .delete(&Self::checksum_path().join(self.storage_id(id)))
+            .await?;
+        self.provider
+            .delete(&Self::key_path().join(self.storage_id(id)))
+            .await?;
+        self.provider
+            .delete(&Self::original_path().join(self.storage_id(id)))
+            .await?;
+        self.save_upload_references(id, &[]).await
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "storage-memory")]
+mod tests {
+    use super::{AppStorage, MasterKeys, StoragePrefix};
+    use crate::cryptography::Cryptography;
+    use crate::storage::{
+        StorageOperations, StorageProvider, UploadMetadata, UploadRecord, UploadReference,
+    };
+    use crate::upload_locks::UploadLocks;
+    use std::{
+        path::Path,
+        str::FromStr,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    fn reference() -> UploadReference {
+        UploadReference::new("delete-token", None, 0)
+    }
+
+    #[tokio::test]
+    async fn test_view_limit_sweep() {
+        let storage = AppStorage::new(StorageProvider::from_str("memory://").unwrap());
+        storage
+            .save_upload("abc", b"content", &UploadMetadata::default(), reference())
+            .await
+            .unwrap();
+        storage
+            .save_upload_record(
+                "abc",
+                &UploadRecord {
+                    max_views: Some(2),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        // Test the upload is kept while it has views remaining.
+        assert!(storage.record_upload_view("abc").await.unwrap());
+        storage
+            .remove_all_expired_uploads(None, None, &UploadLocks::default())
+            .await
+            .unwrap();
+        assert!(storage.upload_exists("abc").await.unwrap());
+
+        // Test the upload is deleted once its last view is counted and later views are refused.
+        assert!(storage.record_upload_view("abc").await.unwrap());
+        assert!(!storage.upload_exists("abc").await.unwrap());
+        assert!(storage.get_upload_record("abc").await.unwrap().is_none());
+        assert!(!storage.record_upload_view("abc").await.unwrap());
+
+        // Test uploads already at their limit are swept.
+        storage
+            .save_upload("def", b"content", &UploadMetadata::default(), reference())
+            .await
+            .unwrap();
+        storage
+            .save_upload_record(
+                "def",
+                &UploadRecord {
+                    max_views: Some(1),
+                    views: 1,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        storage
+            .remove_all_expired_uploads(None, None, &UploadLocks::default())
+            .await
+            .unwrap();
+        assert!(!storage.upload_exists("def").await.unwrap());
+        assert!(storage.get_upload_record("def").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_verify_upload() {
+        let storage = AppStorage::new(StorageProvider::from_str("memory://").unwrap());
+        storage
+            .save_upload("abc", b"content", &UploadMetadata::default(), reference())
+            .await
+            .unwrap();
+
+        // Test an untouched upload passes verification.
+        assert_eq!(storage.verify_upload("abc").await.unwrap(), Some(true));
+
+        // Test a corrupted upload fails verification.
+        let path = storage.upload_file("abc");
+        let mut bytes = storage.provider.read(&path).await.unwrap().unwrap();
+        bytes[0] ^= 1;
+        storage.provider.write(&path, &bytes).await.unwrap();
+        assert_eq!(storage.verify_upload("abc").await.unwrap(), Some(false));
+
+        // Test uploads without a checksum can't be verified.
+        storage
+            .provider
+            .delete(&AppStorage::checksum_path().join(Path::new("abc")))
+            .await
+            .unwrap();
+        assert_eq!(storage.verify_upload("abc").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_saves() {
+        let storage = Arc::new(AppStorage::new(
+            StorageProvider::from_str("memory://").unwrap(),
+        ));
+
+        // Test saves of different uploads can run concurrently through a shared reference.
+        let saves: Vec<_> = (0..16)
+            .map(|i| {
+                let storage = Arc::clone(&storage);
+                tokio::spawn(async move {
+                    storage
+                        .save_upload(
+                            &format!("upload-{i}"),
+                            b"content",
+                            &UploadMetadata::default(),
+                            reference(),
+                        )
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect();
+        for (i, save) in saves.into_iter().enumerate() {
+            let key = save.await.unwrap();
+            assert_eq!(
+                storage
+                    .get_upload(&format!("upload-{i}"), &key)
+                    .await
+                    .unwrap()
+                    .unwrap(),
+                b"content"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expiry_grace_period() {
+        let storage = AppStorage::new(StorageProvider::from_str("memory://").unwrap());
+        let locks = UploadLocks::default();
+        storage
+            .save_upload("abc", b"content", &UploadMetadata::default(), reference())
+            .await
+            .unwrap();
+
+        // Test a fresh upload is kept within the grace period, even once its access has expired.
+        storage
+            .remove_all_expired_uploads(
+                Some(Duration::ZERO),
+                Some(Duration::from_secs(60 * 60)),
+                &locks,
+            )
+            .await
+            .unwrap();
+        assert!(storage.upload_exists("abc").await.unwrap());
+
+        // Test the same upload is removed without a grace period.
+        storage
+            .remove_all_expired_uploads(Some(Duration::ZERO), None, &locks)
+            .await
+            .unwrap();
+        assert!(!storage.upload_exists("abc").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_upload_expiry_override() {
+        let storage = AppStorage::new(StorageProvider::from_str("memory://").unwrap());
+        let locks = UploadLocks::default();
+        for id in ["abc", "def"] {
+            storage
+                .save_upload(id, b"content", &UploadMetadata::default(), reference())
+                .await
+                .unwrap();
+        }
+        storage
+            .save_upload_record(
+                "abc",
+                &UploadRecord {
+                    expiry_secs: Some(0),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        // Test only the upload with its own shorter expiry is removed.
+        assert_eq!(
+            storage
+                .remove_all_expired_uploads(Some(Duration::from_secs(60 * 60)), None, &locks)
+                .await
+                .unwrap(),
+            (2, 1)
+        );
+        assert!(!storage.upload_exists("abc").await.unwrap());
+        assert!(storage.upload_exists("def").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_is_upload_id_taken() {
+        let storage = AppStorage::new(StorageProvider::from_str("memory://").unwrap());
+        storage
+            .save_upload(
+                "abcd1234",
+                b"content",
+                &UploadMetadata::default(),
+                reference(),
+            )
+            .await
+            .unwrap();
+        storage
+            .save_upload_record(
+                "abcd1234",
+                &UploadRecord {
+                    content_hash: Some("abcd1234aaaa".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        // Test an id is only taken by uploads with different content.
+        assert!(
+            storage
+                .is_upload_id_taken("abcd1234", "abcd1234bbbb")
+                .await
+                .unwrap()
+        );
+        assert!(
+            !storage
+                .is_upload_id_taken("abcd1234", "abcd1234aaaa")
+                .await
+                .unwrap()
+        );
+        assert!(
+            !storage
+                .is_upload_id_taken("abcd5678", "abcd5678aaaa")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_deleted_upload() {
+        let storage = AppStorage::new(StorageProvider::from_str("memory://").unwrap());
+        let key = storage
+            .save_upload("abc", b"content", &UploadMetadata::default(), reference())
+            .await
+            .unwrap();
+
+        // Test an upload deleted after its existence was checked is reported as missing.
+        assert!(storage.upload_exists("abc").await.unwrap());
+        storage.remove_upload("abc").await.unwrap();
+        assert!(storage.get_upload("abc", &key).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_upload_references() {
+        let storage = AppStorage::new(StorageProvider::from_str("memory://").unwrap());
+        let content_key = Cryptography::derive_content_key(b"content", "salt");
+        let (first_key, deduplicated) = storage
+            .save_shared_upload(
+                "abc",
+                b"content",
+                &UploadMetadata::default(),
+                &content_key,
+                UploadReference::new("first", None, 0),
+            )
+            .await
+            .unwrap();
+        assert!(!deduplicated);
+        let stored = storage
+            .provider
+            .read(&storage.upload_file("abc"))
+            .await
+            .unwrap();
+        let (_, deduplicated) = storage
+            .save_shared_upload(
+                "abc",
+                b"content",
+                &UploadMetadata::default(),
+                &content_key,
+                UploadReference::new("second", None, 0),
+            )
+            .await
+            .unwrap();
+
+        // Test identical content is kept as it was stored and the first key still decrypts it.
+        assert!(deduplicated);
+        assert_eq!(
+            storage
+                .provider
+                .read(&storage.upload_file("abc"))
+                .await
+                .unwrap(),
+            stored
+        );
+        assert_eq!(
+            storage
+                .get_upload("abc", &first_key)
+                .await
+                .unwrap()
+                .unwrap(),
+            b"content"
+        );
+
+        // Test only the tokens given to the uploads of the content delete them.
+        assert!(!storage.delete_upload("abc", "other").await.unwrap());
+        assert!(!storage.delete_upload("abc", &first_key).await.unwrap());
+
+        // Test deleting one of two identical uploads leaves the other available, and its token
+        // can't be used to delete the other.
+        assert!(storage.delete_upload("abc", "second").await.unwrap());
+        assert!(!storage.delete_upload("abc", "second").await.unwrap());
+        assert_eq!(
+            storage
+                .get_upload("abc", &first_key)
+                .await
+                .unwrap()
+                .unwrap(),
+            b"content"
+        );
+
+        // Test the content is removed once the last upload of it is deleted.
+        assert!(storage.delete_upload("abc", "first").await.unwrap());
+        assert!(!storage.upload_exists("abc").await.unwrap());
+
+        // Test removing shared content removes every upload of it at once.
+        for _ in 0..2 {
+            storage
+                .save_shared_upload(
+                    "abc",
+                    b"content",
+                    &UploadMetadata::default(),
+                    &content_key,
+                    reference(),
+                )
+                .await
+                .unwrap();
+        }
+        storage.remove_upload("abc").await.unwrap();
+        assert!(!storage.upload_exists("abc").await.unwrap());
+        assert!(
+            !storage
+                .provider
+                .exists(&AppStorage::reference_path().join("abc"))
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shared_upload_expiry() {
+        let storage = AppStorage::new(StorageProvider::from_str("memory://").unwrap());
+        let locks = UploadLocks::default();
+        let content_key = Cryptography::derive_content_key(b"content", "salt");
+        for _ in 0..3 {
+            storage
+                .save_shared_upload(
+                    "abc",
+                    b"content",
+                    &UploadMetadata::default(),
+                    &content_key,
+                    reference(),
+                )
+                .await
+                .unwrap();
+        }
+
+        // Test each upload of shared content expires on its own schedule, so only the upload
+        // made before the grace period is removed.
+        let mut references = storage.upload_references("abc").await.unwrap();
+        references[0] =
+            UploadReference::created_at(SystemTime::now() - Duration::from_secs(2 * 60 * 60));
+        storage
+            .save_upload_references("abc", &references)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage
+                .remove_all_expired_uploads(
+                    Some(Duration::ZERO),
+                    Some(Duration::from_secs(60 * 60)),
+                    &locks,
+                )
+                .await
+                .unwrap(),
+            (1, 1)
+        );
+        assert_eq!(storage.upload_references("abc").await.unwrap().len(), 2);
+        assert!(storage.upload_exists("abc").await.unwrap());
+
+        // Test every expired upload is removed in a single sweep, along with their content.
+        assert_eq!(
+            storage
+                .remove_all_expired_uploads(Some(Duration::ZERO), None, &locks)
+                .await
+                .unwrap(),
+            (1, 2)
+        );
+        assert!(!storage.upload_exists("abc").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_owner_usage() {
+        let storage = AppStorage::new(StorageProvider::from_str("memory://").unwrap());
+        let locks = UploadLocks::default();
+        let content_key = Cryptography::derive_content_key(b"content", "salt");
+        for (owner, token) in [("alice", "alice-token"), ("bob", "bob-token")] {
+            storage
+                .save_shared_upload(
+                    "abc",
+                    b"content",
+                    &UploadMetadata::default(),
+                    &content_key,
+                    UploadReference::new(token, Some(owner.to_string()), 7),
+                )
+                .await
+                .unwrap();
+        }
+        storage
+            .save_upload(
+                "def",
+                b"other",
+                &UploadMetadata::default(),
+                UploadReference::new("other-token", Some("alice".to_string()), 5),
+            )
+            .await
+            .unwrap();
+
+        // Test every upload is charged to the owner that made it, including duplicates.
+        assert_eq!(storage.owner_usage("alice").await.unwrap(), (12, 2));
+        assert_eq!(storage.owner_usage("bob").await.unwrap(), (7, 1));
+        assert_eq!(storage.owner_usage("carol").await.unwrap(), (0, 0));
+
+        // Test deleting an upload only refunds the owner that made it.
+        assert!(storage.delete_upload("abc", "bob-token").await.unwrap());
+        assert_eq!(storage.owner_usage("alice").await.unwrap(), (12, 2));
+        assert_eq!(storage.owner_usage("bob").await.unwrap(), (0, 0));
+
+        // Test expired and removed uploads are refunded.
+        storage
+            .remove_all_expired_uploads(Some(Duration::ZERO), None, &locks)
+            .await
+            .unwrap();
+        assert_eq!(storage.owner_usage("alice").await.unwrap(), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_shared_upload_master_keys() {
+        let storage = AppStorage::new(StorageProvider::from_str("memory://").unwrap())
+            .with_master_keys(Some(MasterKeys {
+                current: Cryptography::generate_key(),
+                previous: vec![],
+            }));
+        let content_key = Cryptography::derive_content_key(b"content", "salt");
+        let (first, _) = storage
+            .save_shared_upload(
+                "abc",
+                b"content",
+                &UploadMetadata::default(),
+                &content_key,
+                reference(),
+            )
+            .await
+            .unwrap();
+        let (second, _) = storage
+            .save_shared_upload(
+                "abc",
+                b"content",
+                &UploadMetadata::default(),
+                &content_key,
+                reference(),
+            )
+            .await
+            .unwrap();
+
+        // Test every upload of shared content is given a reference that unwraps its key.
+        assert_ne!(first, content_key);
+        assert_eq!(first, second);
+        assert_eq!(
+            storage.get_upload("abc", &first).await.unwrap().unwrap(),
+            b"content"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_purge_all_uploads() {
+        let storage = AppStorage::new(StorageProvider::from_str("memory://").unwrap());
+        for id in ["abc", "def", "ghi"] {
+            storage
+                .save_upload(id, b"content", &UploadMetadata::default(), reference())
+                .await
+                .unwrap();
+            storage
+                .save_upload_record(
+                    id,
+                    &UploadRecord {
+                        max_views: Some(1),
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+        }
+
+        // Test every upload and its record is deleted from a populated store.
+        assert_eq!(storage.purge_all_uploads().await.unwrap(), 3);
+        assert!(storage.list_upload_ids().await.unwrap().is_empty());
+        assert!(storage.list_upload_records().await.unwrap().is_empty());
+
+        // Test purging an empty store deletes nothing.
+        assert_eq!(storage.purge_all_uploads().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_moderation() {
+        let storage = AppStorage::new(StorageProvider::from_str("memory://").unwrap());
+        let key = storage
+            .save_pending_upload(
+                "abc",
+                b"content",
+                &UploadMetadata::default(),
+                None,
+                reference(),
+            )
+            .await
+            .unwrap();
+        storage
+            .save_pending_upload(
+                "def",
+                b"content",
+                &UploadMetadata::default(),
+                None,
+                reference(),
+            )
+            .await
+            .unwrap();
+
+        // Test pending uploads are held apart from served uploads.
+        assert!(storage.is_upload_pending("abc").await.unwrap());
+        assert!(!storage.upload_exists("abc").await.unwrap());
+        assert!(storage.list_upload_ids().await.unwrap().is_empty());
+        let mut pending = storage.list_pending_upload_ids().await.unwrap();
+        pending.sort();
+        assert_eq!(pending, vec!["abc", "def"]);
+
+        // Test approved uploads are served with the key given when they were saved.
+        assert!(storage.approve_upload("abc").await.unwrap());
+        assert!(!storage.is_upload_pending("abc").await.unwrap());
+        assert_eq!(
+            storage.get_upload("abc", &key).await.unwrap().unwrap(),
+            b"content"
+        );
+
+        // Test rejected uploads are deleted.
+        assert!(storage.reject_upload("def").await.unwrap());
+        assert!(!storage.is_upload_pending("def").await.unwrap());
+        assert!(!storage.upload_exists("def").await.unwrap());
+
+        // Test only pending uploads can be approved or rejected.
+        assert!(!storage.approve_upload("abc").await.unwrap());
+        assert!(!storage.reject_upload("abc").await.unwrap());
+        assert!(storage.upload_exists("abc").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_hidden_extensions() {
+        let storage = AppStorage::new(StorageProvider::from_str("memory://").unwrap())
+            .with_hidden_extensions(true);
+        let key = storage
+            .save_upload(
+                "abc.png",
+                b"content",
+                &UploadMetadata::default(),
+                reference(),
+            )
+            .await
+            .unwrap();
+
+        // Test uploads are stored without their extension.
+        assert_eq!(storage.list_upload_ids().await.unwrap(), vec!["abc"]);
+        assert!(storage.upload_exists("abc.png").await.unwrap());
+
+        // Test uploads can only be decrypted under the id they were saved with.
+        assert_eq!(
+            storage.get_upload("abc.png", &key).await.unwrap().unwrap(),
+            b"content"
+        );
+        assert!(storage.get_upload("abc.html", &key).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hidden_extensions_with_prefixes() {
+        let storage = AppStorage::new(StorageProvider::from_str("memory://").unwrap())
+            .with_prefixes(vec![StoragePrefix::from_str("image/*=images").unwrap()])
+            .with_hidden_extensions(true);
+        storage
+            .save_upload(
+                "abc.png",
+                b"content",
+                &UploadMetadata::default(),
+                reference(),
+            )
+            .await
+            .unwrap();
+
+        // Test prefixes aren't used, so the ids listed from storage resolve to their uploads.
+        assert!(
+            storage
+                .provider
+                .exists(Path::new("uploads/abc"))
+                .await
+                .unwrap()
+        );
+        let ids = storage.list_upload_ids().await.unwrap();
+        assert_eq!(ids, vec!["abc"]);
+        assert!(storage.upload_size("abc").await.unwrap().is_some());
+        assert_eq!(storage.verify_upload("abc").await.unwrap(), Some(true));
+
+        // Test listed uploads can be expired.
+        storage
+            .remove_all_expired_uploads(Some(Duration::ZERO), None, &UploadLocks::default())
+            .await
+            .unwrap();
+        assert!(!storage.upload_exists("abc.png").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let storage = AppStorage::new(StorageProvider::from_str("memory://").unwrap())
+            .with_compression(Some(6))
+            .with_decompressed_size_limit(1024 * 1024);
+        let text = "compressible text ".repeat(100);
+        let key = storage
+            .save_upload(
+                "abc.txt",
+                text.as_bytes(),
+                &UploadMetadata::default(),
+                reference(),
+            )
+            .await
+            .unwrap();
+
+        // Test compressible uploads are compressed before they are encrypted.
+        let metadata = storage.get_upload_metadata("abc.txt", &key).await.unwrap();
+        assert!(metadata.compressed);
+        let stored = storage
+            .provider
+            .read(&storage.upload_file("abc.txt"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(stored.len() < text.len());
+
+        // Test compressed uploads decrypt and decompress to the original.
+        assert_eq!(
+            storage.get_upload("abc.txt", &key).await.unwrap().unwrap(),
+            text.as_bytes()
+        );
+
+        // Test compressed uploads are still bound to their id.
+        storage
+            .provider
+            .write(&storage.upload_file("xyz.txt"), &stored)
+            .await
+            .unwrap();
+        assert!(storage.get_upload("xyz.txt", &key).await.is_err());
+
+        // Test uploads that compression doesn't make smaller are stored as they are.
+        let key = storage
+            .save_upload("def", b"tiny", &UploadMetadata::default(), reference())
+            .await
+            .unwrap();
+        assert!(
+            !storage
+                .get_upload_metadata("def", &key)
+                .await
+                .unwrap()
+                .compressed
+        );
+        assert_eq!(
+            storage.get_upload("def", &key).await.unwrap().unwrap(),
+            b"tiny"
+        );
+
+        // Test uploads that decompress to more than the limit are refused.
+        let key = storage
+            .save_upload(
+                "bomb.txt",
+                &vec![0; 16 * 1024 * 1024],
+                &UploadMetadata::default(),
+                reference(),
+            )
+            .await
+            .unwrap();
+        assert!(
+            storage
+                .get_upload_metadata("bomb.txt", &key)
+                .await
+                .unwrap()
+                .compressed
+        );
+        assert!(storage.get_upload("bomb.txt", &key).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_storage_prefixes() {
+        let storage = AppStorage::new(StorageProvider::from_str("memory://").unwrap())
+            .with_prefixes(vec![
+                StoragePrefix::from_str("image/*=images").unwrap(),
+                StoragePrefix::from_str("video/mp4;video/webm=media/videos").unwrap(),
+            ]);
+        let mut keys = Vec::new();
+        for id in ["a.png", "b.mp4", "c.txt"] {
+            keys.push(
+                storage
+                    .save_upload(id, id.as_bytes(), &UploadMetadata::default(), reference())
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        // Test uploads are stored under the prefix matching their type.
+        for path in [
+            "images/uploads/a.png",
+            "media/videos/uploads/b.mp4",
+            "uploads/c.txt",
+        ] {
+            assert!(storage.provider.exists(Path::new(path)).await.unwrap());
+        }
+
+        // Test uploads under every prefix are found and resolve.
+        let mut ids = storage.list_upload_ids().await.unwrap();
+        ids.sort();
+        assert_eq!(ids, ["a.png", "b.mp4", "c.txt"]);
+        for (id, key) in ["a.png", "b.mp4", "c.txt"].iter().zip(keys) {
+            assert_eq!(
+                storage.get_upload(id, &key).await.unwrap().unwrap(),
+                id.as_bytes()
+            );
+        }
+
+        // Test invalid and reserved prefixes are rejected.
+        assert!(StoragePrefix::from_str("image/*=../images").is_err());
+        assert!(StoragePrefix::from_str("image/*=records").is_err());
+        assert!(StoragePrefix::from_str("image/*").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_master_key_rotation() {
+        let old_master_key = Cryptography::generate_key();
+        let new_master_key = Cryptography::generate_key();
+        let storage = AppStorage::new(StorageProvider::from_str("memory://").unwrap())
+            .with_master_keys(Some(MasterKeys {
+                current: old_master_key.clone(),
+                previous: vec![],
+            }));
+        let metadata = UploadMetadata {
+            headers: [("x-test".to_string(), "value".to_string())].into(),
+            ..Default::default()
+        };
+        let reference = storage
+            .save_upload("abc", b"content", &metadata, reference())
+            .await
+            .unwrap();
+
+        // Test uploads are accessed with the reference handed out in place of their key.
+        assert_eq!(
+            storage
+                .get_upload("abc", &reference)
+                .await
+                .unwrap()
+                .unwrap(),
+            b"content"
+        );
+        assert_eq!(
+            storage
+                .get_upload_metadata("abc", &reference)
+                .await
+                .unwrap(),
+            metadata
+        );
+
+        // Test the reference keeps working after rotating and re-wrapping the master key.
+        let storage = storage.with_master_keys(Some(MasterKeys {
+            current: new_master_key.clone(),
+            previous: vec![old_master_key],
+        }));
+        assert_eq!(
+            storage
+                .get_upload("abc", &reference)
+                .await
+                .unwrap()
+                .unwrap(),
+            b"content"
+        );
+        assert!(storage.rewrap_upload_key("abc").await.unwrap());
+        assert!(!storage.rewrap_upload_key("abc").await.unwrap());
+        let storage = storage.with_master_keys(Some(MasterKeys {
+            current: new_master_key,
+            previous: vec![],
+        }));
+        assert_eq!(
+            storage
+                .get_upload("abc", &reference)
+                .await
+                .unwrap()
+                .unwrap(),
+            b"content"
+        );
+        assert_eq!(
+            storage
+                .get_upload_metadata("abc", &reference)
+                .await
+                .unwrap(),
+            metadata
+        );
+
+        // Test the reference can't be used without a master key.
+        let storage = storage.with_master_keys(None);
+        assert!(storage.get_upload("abc", &reference).await.is_err());
+    }
+
+    #[cfg(feature = "storage-filesystem")]
+    #[tokio::test]
+    async fn test_expiry_sweep_skips_failures() {
+        let base_path = std::env::temp_dir().join("dollshare-test-expiry-sweep-failures");
+        let _ = std::fs::remove_dir_all(&base_path);
+        let storage = AppStorage::new(
+            StorageProvider::from_str(&format!("fs://{}", base_path.display())).unwrap(),
+        );
+        storage
+            .save_upload("abc", b"content", &UploadMetadata::default(), reference())
+            .await
+            .unwrap();
+        // A directory can't be deleted as an upload so removing it always fails.
+        std::fs::create_dir_all(base_path.join("uploads/broken")).unwrap();
+
+        // Test an upload that fails to be removed doesn't stop the rest of the sweep.
+        storage
+            .remove_all_expired_uploads(Some(Duration::ZERO), None, &UploadLocks::default())
+            .await
+            .unwrap();
+        assert!(!storage.upload_exists("abc").await.unwrap());
+        assert!(base_path.join("uploads/broken").exists());
+
+        let _ = std::fs::remove_dir_all(base_path);
+    }
+
+    #[tokio::test]
+    async fn test_storage_hide_extensions() {
+        use crate::test_support::{
+            download, png_fixture, serve, status_line, test_state, upload, upload_json,
+        };
+
+        let mut state = test_state();
+        state.storage = Arc::new(
+            AppStorage::new(StorageProvider::from_str("memory://").unwrap())
+                .with_hidden_extensions(true),
+        );
+        let storage = Arc::clone(&state.storage);
+        let address = serve(state).await;
+        let image = png_fixture(4, 4);
+
+        // Test uploads are stored without an extension while links keep it.
+        let response = upload(address, "", &image).await;
+        let json = upload_json(&response);
+        let id = json["id"].as_str().unwrap();
+        assert!(id.ends_with(".png"));
+        let stored = storage.list_upload_ids().await.unwrap();
+        assert_eq!(stored, vec![id.trim_end_matches(".png")]);
+
+        // Test downloads are still served with the upload's type.
+        let url = json["url"].as_str().unwrap();
+        let response = download(address, url).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(response.contains("content-type: image/png\r\n"));
+
+        // Test uploads can't be served under another extension.
+        let response = download(address, &url.replace(".png", ".html")).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 404 Not Found");
+    }
 }
@@ -0,0 +1,560 @@
+use crate::{
+    http_client::{self, StorageClient},
+    storage::{StorageCapabilities, StorageOperations},
+};
+use anyhow::{Context, Result, anyhow, bail};
+use axum::body::Bytes;
+use base64ct::{Base64, Encoding};
+use hmac::{Hmac, Mac};
+use hyper::{Method, StatusCode, header::HeaderMap};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+use tracing::{debug, warn};
+use url::Url;
+
+/// Version of the Blob service REST API that requests are made against.
+const API_VERSION: &str = "2021-08-06";
+/// Number of blobs requested per page when listing, the most Azure returns at once.
+const LIST_PAGE_SIZE: &str = "5000";
+
+/// How requests to an Azure storage account are authorized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Credential {
+    /// Requests are signed with the name and key of the account.
+    SharedKey { name: String, key: Vec<u8> },
+    /// Requests carry a shared access signature token in their query string.
+    SharedAccessSignature(String),
+}
+
+/// Credentials and endpoint of an Azure storage account.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Account {
+    credential: Credential,
+    blob_endpoint: Url,
+}
+
+impl Account {
+    /// Load the account from the standard Azure environment variables.
+    ///
+    /// `AZURE_STORAGE_CONNECTION_STRING` is used when set, otherwise the account is read from
+    /// `AZURE_STORAGE_ACCOUNT` and either `AZURE_STORAGE_KEY` or `AZURE_STORAGE_SAS_TOKEN`.
+    fn from_env() -> Result<Self> {
+        if let Ok(connection_string) = std::env::var("AZURE_STORAGE_CONNECTION_STRING") {
+            return Self::from_connection_string(&connection_string);
+        }
+        let name = std::env::var("AZURE_STORAGE_ACCOUNT")
+            .context("AZURE_STORAGE_ACCOUNT or AZURE_STORAGE_CONNECTION_STRING must be set")?;
+        let credential = match (
+            std::env::var("AZURE_STORAGE_KEY"),
+            std::env::var("AZURE_STORAGE_SAS_TOKEN"),
+        ) {
+            (Ok(key), _) => Credential::SharedKey {
+                key: Base64::decode_vec(key.trim()).map_err(|_| anyhow!("invalid account key"))?,
+                name: name.clone(),
+            },
+            (_, Ok(token)) => Credential::SharedAccessSignature(token.trim().to_string()),
+            _ => bail!("AZURE_STORAGE_KEY or AZURE_STORAGE_SAS_TOKEN must be set"),
+        };
+        Ok(Self {
+            blob_endpoint: Url::parse(&format!("https://{name}.blob.core.windows.net"))?,
+            credential,
+        })
+    }
+
+    /// Parse an account from a connection string such as
+    /// `AccountName=<name>;AccountKey=<key>;BlobEndpoint=<url>` or
+    /// `BlobEndpoint=<url>;SharedAccessSignature=<token>`.
+    fn from_connection_string(connection_string: &str) -> Result<Self> {
+        let mut name = None;
+        let mut key = None;
+        let mut token = None;
+        let mut blob_endpoint = None;
+        let mut protocol = "https";
+        let mut suffix = "core.windows.net";
+        for (setting, value) in connection_string
+            .split(';')
+            .filter_map(|part| part.trim().split_once('='))
+        {
+            match setting {
+                "AccountName" => name = Some(value),
+                "AccountKey" => key = Some(value),
+                "SharedAccessSignature" => token = Some(value),
+                "BlobEndpoint" => blob_endpoint = Some(value),
+                "DefaultEndpointsProtocol" => protocol = value,
+                "EndpointSuffix" => suffix = value,
+                _ => {}
+            }
+        }
+        let credential = match (key, token) {
+            (Some(key), _) => Credential::SharedKey {
+                name: name
+                    .context("connection string does not include an AccountName")?
+                    .to_string(),
+                key: Base64::decode_vec(key).map_err(|_| anyhow!("invalid account key"))?,
+            },
+            (None, Some(token)) => Credential::SharedAccessSignature(token.to_string()),
+            (None, None) => {
+                bail!("connection string does not include an AccountKey or SharedAccessSignature")
+            }
+        };
+        Ok(Self {
+            blob_endpoint: match (blob_endpoint, name) {
+                (Some(endpoint), _) => Url::parse(endpoint)?,
+                (None, Some(name)) => Url::parse(&format!("{protocol}://{name}.blob.{suffix}"))?,
+                (None, None) => {
+                    bail!("connection string does not include an AccountName or BlobEndpoint")
+                }
+            },
+            credential,
+        })
+    }
+
+    /// Sign a request with the account's shared key, returning the `Authorization` header value.
+    ///
+    /// See <https://learn.microsoft.com/en-us/rest/api/storageservices/authorize-with-shared-key>.
+    fn sign(
+        name: &str,
+        key: &[u8],
+        method: &Method,
+        url: &Url,
+        headers: &HeaderMap,
+    ) -> Result<String> {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+        };
+        // Content-Length is left empty when zero since API version 2015-02-21.
+        let content_length = match header("content-length") {
+            "0" => "",
+            length => length,
+        };
+        let mut string_to_sign = format!(
+            "{method}\n\n\n{content_length}\n\n{}\n\n\n\n\n\n\n",
+            header("content-type")
+        );
+
+        let mut ms_headers = headers
+            .iter()
+            .filter(|(name, _)| name.as_str().starts_with("x-ms-"))
+            .map(|(name, value)| Ok((name.as_str(), value.to_str()?.trim())))
+            .collect::<Result<Vec<_>>>()?;
+        ms_headers.sort_unstable();
+        for (name, value) in ms_headers {
+            string_to_sign.push_str(&format!("{name}:{value}\n"));
+        }
+
+        string_to_sign.push_str(&format!("/{name}{}", url.path()));
+        let mut query = url.query_pairs().collect::<Vec<_>>();
+        query.sort_unstable();
+        for (name, value) in query {
+            string_to_sign.push_str(&format!("\n{}:{value}", name.to_lowercase()));
+        }
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(key)?;
+        mac.update(string_to_sign.as_bytes());
+        Ok(format!(
+            "SharedKey {name}:{}",
+            Base64::encode_string(&mac.finalize().into_bytes())
+        ))
+    }
+}
+
+/// A page of blobs listed from a container.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct EnumerationResults {
+    #[serde(default)]
+    blobs: Blobs,
+    next_marker: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Blobs {
+    #[serde(default, rename = "Blob")]
+    blobs: Vec<Blob>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Blob {
+    name: String,
+}
+
+/// Storage kept in an Azure Blob Storage container.
+///
+/// Requests are made against the Blob service REST API directly rather than through the Azure
+/// SDK, which has yet to reach a stable release and brings a second HTTP client along with it,
+/// while only a handful of blob operations are needed here.
+#[derive(Debug, Clone)]
+pub struct AzureBlobStorage {
+    client: StorageClient,
+    account: Arc<Account>,
+    container: String,
+}
+
+impl AzureBlobStorage {
+    pub fn new(container: String) -> Result<Self> {
+        Self::with_account(Account::from_env()?, container)
+    }
+
+    fn with_account(account: Account, container: String) -> Result<Self> {
+        let storage = Self {
+            client: http_client::storage_client()?,
+            account: Arc::new(account),
+            container,
+        };
+
+        // Connections are tied to the runtime that opened them, so the container is created
+        // with its own client rather than leaving dead connections in the shared one's pool.
+        let init_storage = Self {
+            client: http_client::storage_client()?,
+            ..storage.clone()
+        };
+        match std::thread::spawn(move || {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(init_storage.create_container_if_missing())
+        })
+        .join()
+        {
+            Ok(result) => result?,
+            Err(panic_err) => {
+                return Err(anyhow!(
+                    "Azure client creation thread error: {:?}",
+                    panic_err
+                ));
+            }
+        };
+        debug!(
+            "Initialised Azure Blob Storage client with endpoint {}",
+            storage.account.blob_endpoint
+        );
+        Ok(storage)
+    }
+
+    async fn create_container_if_missing(&self) -> Result<()> {
+        let url = self.url(None, &[("restype", "container")])?;
+        let (status, headers, _) = self.send(Method::HEAD, url.clone(), &[], None).await?;
+        match status {
+            StatusCode::OK => Ok(()),
+            StatusCode::NOT_FOUND => {
+                let (status, headers, _) = self.send(Method::PUT, url, &[], None).await?;
+                // Another instance may have created the container in the meantime.
+                if status.is_success() || status == StatusCode::CONFLICT {
+                    Ok(())
+                } else {
+                    bail!(
+                        "Error while creating Azure container for storage: {}",
+                        error_message(status, &headers)
+                    );
+                }
+            }
+            status => bail!(
+                "Error while initialising Azure container for storage: {}",
+                error_message(status, &headers)
+            ),
+        }
+    }
+
+    /// Build the URL of the container, or a blob inside of it.
+    fn url(&self, path: Option<&Path>, query: &[(&str, &str)]) -> Result<Url> {
+        let mut url = self.account.blob_endpoint.clone();
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|_| anyhow!("blob endpoint cannot be used as a base URL"))?;
+            segments.pop_if_empty().push(&self.container);
+            if let Some(path) = path {
+                let blob = path.to_str().context("failed to convert path to str")?;
+                segments.extend(blob.split('/'));
+            }
+        }
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+        if let Credential::SharedAccessSignature(token) = &self.account.credential {
+            let token = token.trim_start_matches('?');
+            url.set_query(Some(&match url.query() {
+                Some(query) => format!("{query}&{token}"),
+                None => token.to_string(),
+            }));
+        }
+        Ok(url)
+    }
+
+    /// Send a signed request, returning the status, headers and body of the response.
+    async fn send(
+        &self,
+        method: Method,
+        url: Url,
+        headers: &[(&'static str, &str)],
+        body: Option<&[u8]>,
+    ) -> Result<(StatusCode, HeaderMap, Bytes)> {
+        let date = httpdate::fmt_http_date(SystemTime::now());
+        let mut all_headers = vec![("x-ms-date", date.as_str()), ("x-ms-version", API_VERSION)];
+        all_headers.extend_from_slice(headers);
+        let mut request = http_client::request(method.clone(), &url, &all_headers, body)?;
+        if let Credential::SharedKey { name, key } = &self.account.credential {
+            let authorization = Account::sign(name, key, &method, &url, request.headers())?;
+            request
+                .headers_mut()
+                .insert("authorization", authorization.parse()?);
+        }
+        http_client::send(&self.client, request).await
+    }
+
+    /// Get the headers of a blob, or `None` if it does not exist.
+    async fn properties(&self, path: &Path) -> Result<Option<HeaderMap>> {
+        let (status, headers, _) = self
+            .send(Method::HEAD, self.url(Some(path), &[])?, &[], None)
+            .await?;
+        match status {
+            StatusCode::OK => Ok(Some(headers)),
+            StatusCode::NOT_FOUND => Ok(None),
+            status => Err(anyhow!(error_message(status, &headers))),
+        }
+    }
+}
+
+/// Describe a failed response using the error code Azure includes with it.
+fn error_message(status: StatusCode, headers: &HeaderMap) -> String {
+    match headers
+        .get("x-ms-error-code")
+        .and_then(|code| code.to_str().ok())
+    {
+        Some(code) => format!("Azure request failed with {status} ({code})"),
+        None => format!("Azure request failed with {status}"),
+    }
+}
+
+impl StorageCapabilities for AzureBlobStorage {
+    fn supports_expiry(&self) -> bool {
+        false
+    }
+}
+
+impl StorageOperations for AzureBlobStorage {
+    async fn read(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        debug!("Reading {path:?} from container {}", self.container);
+        let (status, headers, body) = self
+            .send(Method::GET, self.url(Some(path), &[])?, &[], None)
+            .await?;
+        match status {
+            StatusCode::OK => Ok(Some(body.to_vec())),
+            StatusCode::NOT_FOUND => Ok(None),
+            status => Err(anyhow!(error_message(status, &headers))),
+        }
+    }
+
+    async fn write(&self, path: &Path, data: &[u8]) -> Result<()> {
+        debug!("Writing {path:?} to container {}", self.container);
+        let (status, headers, _) = self
+            .send(
+                Method::PUT,
+                self.url(Some(path), &[])?,
+                &[("x-ms-blob-type", "BlockBlob")],
+                Some(data),
+            )
+            .await?;
+        if !status.is_success() {
+            bail!(error_message(status, &headers));
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, path: &Path) -> Result<bool> {
+        debug!("Deleting {path:?} from container {}", self.container);
+        let (status, headers, _) = self
+            .send(Method::DELETE, self.url(Some(path), &[])?, &[], None)
+            .await?;
+        match status {
+            StatusCode::ACCEPTED => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            status => Err(anyhow!(error_message(status, &headers))),
+        }
+    }
+
+    async fn exists(&self, path: &Path) -> Result<bool> {
+        debug!(
+            "Checking if {path:?} exists in container {}",
+            self.container
+        );
+        Ok(self.properties(path).await?.is_some())
+    }
+
+    async fn list(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        debug!(
+            "Listing files inside of {path:?} in container {}",
+            self.container
+        );
+        let prefix = path.to_str().context("failed to convert path to str")?;
+        let mut paths = Vec::new();
+        let mut marker = String::new();
+        loop {
+            let mut query = vec![
+                ("restype", "container"),
+                ("comp", "list"),
+                ("prefix", prefix),
+                ("maxresults", LIST_PAGE_SIZE),
+            ];
+            if !marker.is_empty() {
+                query.push(("marker", &marker));
+            }
+            let (status, headers, body) = self
+                .send(Method::GET, self.url(None, &query)?, &[], None)
+                .await?;
+            if status != StatusCode::OK {
+                bail!(error_message(status, &headers));
+            }
+            let page: EnumerationResults = http_client::parse_xml(&body)?;
+            paths.extend(
+                page.blobs
+                    .blobs
+                    .into_iter()
+                    .map(|blob| PathBuf::from(blob.name)),
+            );
+            match page.next_marker {
+                Some(next_marker) if !next_marker.is_empty() => marker = next_marker,
+                _ => break,
+            }
+        }
+        Ok(paths)
+    }
+
+    async fn last_access(&self, _path: &Path) -> Result<Option<SystemTime>> {
+        // Use lifecycle management policies instead.
+        warn!("last_access is an unsupported operation that will always return Err");
+        bail!("Unsupported operation");
+    }
+
+    async fn created(&self, path: &Path) -> Result<Option<SystemTime>> {
+        debug!(
+            "Obtaining creation time for {path:?} in container {}",
+            self.container
+        );
+        Ok(self.properties(path).await?.and_then(|headers| {
+            httpdate::parse_http_date(headers.get("x-ms-creation-time")?.to_str().ok()?).ok()
+        }))
+    }
+
+    async fn size(&self, path: &Path) -> Result<Option<u64>> {
+        debug!("Obtaining size of {path:?} in container {}", self.container);
+        Ok(self
+            .properties(path)
+            .await?
+            .and_then(|headers| headers.get("content-length")?.to_str().ok()?.parse().ok()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Account, AzureBlobStorage, Credential, EnumerationResults};
+    use crate::{http_client, storage::StorageOperations};
+    use std::path::{Path, PathBuf};
+    use url::Url;
+
+    /// Connection string of the development account built into Azurite.
+    const AZURITE_CONNECTION_STRING: &str = "DefaultEndpointsProtocol=http;AccountName=devstoreaccount1;AccountKey=Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==;BlobEndpoint=http://127.0.0.1:10000/devstoreaccount1;";
+
+    #[test]
+    fn test_from_connection_string() {
+        // Test a connection string with an explicit blob endpoint, as used by Azurite.
+        let account = Account::from_connection_string(
+            "DefaultEndpointsProtocol=http;AccountName=devstoreaccount1;AccountKey=a2V5;BlobEndpoint=http://127.0.0.1:10000/devstoreaccount1;",
+        )
+        .unwrap();
+        assert_eq!(
+            account.credential,
+            Credential::SharedKey {
+                name: "devstoreaccount1".to_string(),
+                key: b"key".to_vec()
+            }
+        );
+        assert_eq!(
+            account.blob_endpoint,
+            Url::parse("http://127.0.0.1:10000/devstoreaccount1").unwrap()
+        );
+
+        // Test the blob endpoint is derived from the account name when not given.
+        let account = Account::from_connection_string(
+            "DefaultEndpointsProtocol=https;AccountName=dollshare;AccountKey=a2V5;EndpointSuffix=core.windows.net",
+        )
+        .unwrap();
+        assert_eq!(
+            account.blob_endpoint,
+            Url::parse("https://dollshare.blob.core.windows.net").unwrap()
+        );
+
+        // Test a shared access signature can be used in place of the account key.
+        let account = Account::from_connection_string(
+            "BlobEndpoint=https://dollshare.blob.core.windows.net;SharedAccessSignature=sv=2021-08-06&sig=c2ln",
+        )
+        .unwrap();
+        assert_eq!(
+            account.credential,
+            Credential::SharedAccessSignature("sv=2021-08-06&sig=c2ln".to_string())
+        );
+
+        // Test connection strings without credentials or an endpoint are rejected.
+        assert!(Account::from_connection_string("AccountName=dollshare").is_err());
+        assert!(Account::from_connection_string("AccountKey=a2V5").is_err());
+        assert!(Account::from_connection_string("SharedAccessSignature=sig=c2ln").is_err());
+    }
+
+    #[test]
+    fn test_enumeration_results() {
+        let xml = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+            <EnumerationResults ServiceEndpoint=\"http://127.0.0.1\" ContainerName=\"dollshare\">\
+            <Prefix>uploads/</Prefix><Blobs>\
+            <Blob><Name>uploads/a&amp;b</Name><Properties><Content-Length>1</Content-Length></Properties></Blob>\
+            <Blob><Name><![CDATA[uploads/<c>]]></Name></Blob>\
+            </Blobs><NextMarker /></EnumerationResults>";
+
+        // Test every blob is found and unescaped, and an empty marker ends the listing.
+        let page: EnumerationResults = http_client::parse_xml(xml.as_bytes()).unwrap();
+        let names: Vec<_> = page.blobs.blobs.iter().map(|blob| &blob.name).collect();
+        assert_eq!(names, ["uploads/a&b", "uploads/<c>"]);
+        assert!(page.next_marker.is_none_or(|marker| marker.is_empty()));
+
+        // Test an empty container has no blobs.
+        let xml = "<EnumerationResults><Blobs /><NextMarker>next</NextMarker></EnumerationResults>";
+        let page: EnumerationResults = http_client::parse_xml(xml.as_bytes()).unwrap();
+        assert!(page.blobs.blobs.is_empty());
+        assert_eq!(page.next_marker.as_deref(), Some("next"));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires Azurite listening on localhost:10000"]
+    async fn test_operations() {
+        let account = Account::from_connection_string(AZURITE_CONNECTION_STRING).unwrap();
+        let storage =
+            AzureBlobStorage::with_account(account, "dollshare-test".to_string()).unwrap();
+        let path = Path::new("uploads/azure test&1");
+
+        // Test binary data is written and read back intact.
+        let data: Vec<u8> = (0..=255).collect();
+        storage.write(path, &data).await.unwrap();
+        assert_eq!(storage.read(path).await.unwrap().unwrap(), data);
+        assert_eq!(storage.size(path).await.unwrap(), Some(256));
+        assert!(storage.created(path).await.unwrap().is_some());
+        assert!(
+            storage
+                .list(Path::new("uploads/"))
+                .await
+                .unwrap()
+                .contains(&PathBuf::from("uploads/azure test&1"))
+        );
+
+        // Test deleted files no longer exist.
+        assert!(storage.delete(path).await.unwrap());
+        assert!(!storage.delete(path).await.unwrap());
+        assert!(!storage.exists(path).await.unwrap());
+        assert!(storage.read(path).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,181 @@
+use crate::storage::{ReadStream, StorageCapabilities, StorageOperations, StorageProvider};
+use anyhow::Result;
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, warn};
+
+/// Storage that reads from a fallback provider when data is missing from its primary one.
+///
+/// Data is only ever written to the primary provider, so a backend can be migrated to
+/// without downtime by reading anything not yet moved from the old backend. Deletes are
+/// applied to both providers so deleted data is never served from the fallback.
+#[derive(Debug, Clone)]
+pub struct FallbackStorage {
+    primary: StorageProvider,
+    fallback: StorageProvider,
+    copy_forward: bool,
+}
+
+impl FallbackStorage {
+    pub fn new(primary: StorageProvider, fallback: StorageProvider) -> Self {
+        Self {
+            primary,
+            fallback,
+            copy_forward: false,
+        }
+    }
+
+    /// Copy data read from the fallback provider to the primary one so it is found there next time.
+    pub fn with_copy_forward(mut self, copy_forward: bool) -> Self {
+        self.copy_forward = copy_forward;
+        self
+    }
+
+    pub fn primary(&self) -> &StorageProvider {
+        &self.primary
+    }
+
+    pub fn fallback(&self) -> &StorageProvider {
+        &self.fallback
+    }
+
+    /// Get the provider that holds the data at the given path, preferring the primary one.
+    async fn provider_for(&self, path: &Path) -> Result<&StorageProvider> {
+        if self.primary.exists(path).await? || !self.fallback.exists(path).await? {
+            Ok(&self.primary)
+        } else {
+            Ok(&self.fallback)
+        }
+    }
+}
+
+impl StorageCapabilities for FallbackStorage {
+    fn supports_expiry(&self) -> bool {
+        self.primary.supports_expiry() && self.fallback.supports_expiry()
+    }
+}
+
+impl StorageOperations for FallbackStorage {
+    async fn read(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        if let Some(data) = self.primary.read(path).await? {
+            return Ok(Some(data));
+        }
+        let Some(data) = self.fallback.read(path).await? else {
+            return Ok(None);
+        };
+        debug!("Read {path:?} from fallback storage");
+        if self.copy_forward
+            && let Err(err) = self.primary.write(path, &data).await
+        {
+            warn!("Failed to copy {path:?} from fallback storage to primary storage: {err:?}");
+        }
+        Ok(Some(data))
+    }
+
+    async fn read_stream(&self, path: &Path) -> Result<Option<ReadStream>> {
+        if let Some(stream) = self.primary.read_stream(path).await? {
+            return Ok(Some(stream));
+        }
+        // Data that is copied forward has to be read in full before it can be written.
+        if self.copy_forward {
+            return Ok(self.read(path).await?.map(|data| {
+                Box::pin(ReaderStream::new(std::io::Cursor::new(data))) as ReadStream
+            }));
+        }
+        self.fallback.read_stream(path).await
+    }
+
+    async fn write(&self, path: &Path, data: &[u8]) -> Result<()> {
+        self.primary.write(path, data).await
+    }
+
+    async fn delete(&self, path: &Path) -> Result<bool> {
+        let deleted_primary = self.primary.delete(path).await?;
+        let deleted_fallback = self.fallback.delete(path).await?;
+        Ok(deleted_primary || deleted_fallback)
+    }
+
+    async fn exists(&self, path: &Path) -> Result<bool> {
+        Ok(self.primary.exists(path).await? || self.fallback.exists(path).await?)
+    }
+
+    async fn list(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let mut paths = self.primary.list(path).await?;
+        for path in self.fallback.list(path).await? {
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+
+    async fn last_access(&self, path: &Path) -> Result<Option<SystemTime>> {
+        self.provider_for(path).await?.last_access(path).await
+    }
+
+    async fn created(&self, path: &Path) -> Result<Option<SystemTime>> {
+        self.provider_for(path).await?.created(path).await
+    }
+
+    async fn size(&self, path: &Path) -> Result<Option<u64>> {
+        self.provider_for(path).await?.size(path).await
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "storage-memory")]
+mod tests {
+    use super::FallbackStorage;
+    use crate::storage::{StorageOperations, StorageProvider};
+    use std::{path::Path, str::FromStr};
+
+    #[tokio::test]
+    async fn test_fallback_reads() {
+        let path = Path::new("uploads/abc");
+        let storage = |copy_forward| async move {
+            let fallback = StorageProvider::from_str("memory://").unwrap();
+            fallback.write(path, b"old").await.unwrap();
+            FallbackStorage::new(StorageProvider::from_str("memory://").unwrap(), fallback)
+                .with_copy_forward(copy_forward)
+        };
+
+        // Test data missing from the primary is read from the fallback without copying it.
+        let storage_without_copy = storage(false).await;
+        assert!(storage_without_copy.exists(path).await.unwrap());
+        assert_eq!(
+            storage_without_copy.read(path).await.unwrap().as_deref(),
+            Some(b"old".as_slice())
+        );
+        assert!(!storage_without_copy.primary().exists(path).await.unwrap());
+
+        // Test reads from the fallback populate the primary when copying forward.
+        let storage = storage(true).await;
+        assert_eq!(
+            storage.read(path).await.unwrap().as_deref(),
+            Some(b"old".as_slice())
+        );
+        assert_eq!(
+            storage.primary().read(path).await.unwrap().as_deref(),
+            Some(b"old".as_slice())
+        );
+
+        // Test writes only go to the primary and are preferred over the fallback.
+        storage.write(path, b"new").await.unwrap();
+        assert_eq!(
+            storage.read(path).await.unwrap().as_deref(),
+            Some(b"new".as_slice())
+        );
+        assert_eq!(
+            storage.fallback().read(path).await.unwrap().as_deref(),
+            Some(b"old".as_slice())
+        );
+
+        // Test deleted data is no longer served from either provider.
+        assert!(storage.delete(path).await.unwrap());
+        assert!(storage.read(path).await.unwrap().is_none());
+        assert!(!storage.exists(path).await.unwrap());
+    }
+}
@@ -1,15 +1,88 @@
-use crate::storage::{StorageCapabilities, StorageOperations};
-use anyhow::{Context, Result};
+use crate::storage::{
+    InsufficientStorageError, ReadStream, StorageCapabilities, StorageOperations,
+};
+use anyhow::{Context, Result, bail};
 use std::{
+    fmt::Debug,
     fs::{self, File, FileTimes},
     io::{self, Read},
-    time::SystemTime,
+    path::Path,
+    process::Command,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
 };
+use tokio_util::io::ReaderStream;
 use tracing::{debug, trace};
 
+/// How long a reading of the available disk space is reused for.
+const FREE_SPACE_CACHE_DURATION: Duration = Duration::from_secs(5);
+
+/// A source of the amount of disk space available at a path.
+pub trait FreeSpaceSource: Debug + Send + Sync {
+    fn available_space(&self, path: &Path) -> Result<u64>;
+}
+
+/// Obtains the available disk space from the POSIX `df` utility.
+#[derive(Debug)]
+pub struct DfFreeSpaceSource;
+
+impl FreeSpaceSource for DfFreeSpaceSource {
+    fn available_space(&self, path: &Path) -> Result<u64> {
+        let output = Command::new("df")
+            .arg("-Pk")
+            .arg(path)
+            .output()
+            .context("failed to run df")?;
+        if !output.status.success() {
+            bail!("df exited with {}", output.status);
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let available_kib: u64 = stdout
+            .lines()
+            .nth(1)
+            .and_then(|line| line.split_whitespace().nth(3))
+            .context("df output did not contain available space")?
+            .parse()
+            .context("df output contained an invalid available space")?;
+        Ok(available_kib.saturating_mul(1024))
+    }
+}
+
+/// Rejects writes that would leave less than a minimum amount of free disk space.
+#[derive(Debug)]
+struct MinFreeSpace {
+    min_free_bytes: u64,
+    source: Box<dyn FreeSpaceSource>,
+    last_reading: Mutex<Option<(Instant, u64)>>,
+}
+
+impl MinFreeSpace {
+    /// Get the available space at a path, reusing recent readings.
+    fn available_space(&self, path: &Path) -> Result<u64> {
+        let mut last_reading = self.last_reading.lock().unwrap();
+        if let Some((read_at, available)) = *last_reading
+            && read_at.elapsed() < FREE_SPACE_CACHE_DURATION
+        {
+            return Ok(available);
+        }
+        let available = self.source.available_space(path)?;
+        *last_reading = Some((Instant::now(), available));
+        Ok(available)
+    }
+
+    fn check_write(&self, path: &Path, size: u64) -> Result<()> {
+        let available = self.available_space(path)?;
+        if available.saturating_sub(size) < self.min_free_bytes {
+            return Err(InsufficientStorageError.into());
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct FilesystemStorage {
     base_path: std::path::PathBuf,
+    min_free_space: Option<Arc<MinFreeSpace>>,
 }
 
 impl FilesystemStorage {
@@ -17,8 +90,24 @@ impl FilesystemStorage {
         let _ = fs::create_dir_all(&base_path);
         Ok(Self {
             base_path: fs::canonicalize(base_path)?,
+            min_free_space: None,
         })
     }
+
+    /// Reject writes that would leave less than the given amount of free disk space,
+    /// as reported by the given source.
+    pub fn with_min_free_space(
+        mut self,
+        min_free_bytes: u64,
+        source: impl FreeSpaceSource + 'static,
+    ) -> Self {
+        self.min_free_space = Some(Arc::new(MinFreeSpace {
+            min_free_bytes,
+            source: Box::new(source),
+            last_reading: Mutex::new(None),
+        }));
+        self
+    }
 }
 
 impl FilesystemStorage {
@@ -46,14 +135,9 @@ impl FilesystemStorage {
     }
 }
 
-impl StorageCapabilities for FilesystemStorage {
-    fn supports_expiry(&self) -> bool {
-        true
-    }
-}
-
-impl StorageOperations for FilesystemStorage {
-    async fn read(&self, path: &std::path::Path) -> Result<Option<Vec<u8>>> {
+impl FilesystemStorage {
+    /// Open a file for reading, updating its access time.
+    fn open_for_read(&self, path: &std::path::Path) -> Result<Option<File>> {
         let path = self.base_path.join(path);
 
         let metadata = match fs::metadata(&path) {
@@ -62,7 +146,7 @@ impl StorageOperations for FilesystemStorage {
             Err(err) => return Err(err.into()),
         };
         debug!("Updating access time for file {path:?}");
-        let mut file = match File::options().read(true).write(true).open(&path) {
+        let file = match File::options().read(true).write(true).open(&path) {
             Ok(file) => file,
             Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
             Err(err) => return Err(err.into()),
@@ -72,6 +156,21 @@ impl StorageOperations for FilesystemStorage {
                 .set_accessed(SystemTime::now())
                 .set_modified(metadata.modified()?),
         );
+        Ok(Some(file))
+    }
+}
+
+impl StorageCapabilities for FilesystemStorage {
+    fn supports_expiry(&self) -> bool {
+        true
+    }
+}
+
+impl StorageOperations for FilesystemStorage {
+    async fn read(&self, path: &std::path::Path) -> Result<Option<Vec<u8>>> {
+        let Some(mut file) = self.open_for_read(path)? else {
+            return Ok(None);
+        };
         debug!("Reading file at {path:?}");
         let mut buf = Vec::new();
         match file.read_to_end(&mut buf) {
@@ -81,8 +180,21 @@ impl StorageOperations for FilesystemStorage {
         }
     }
 
-    async fn write(&mut self, path: &std::path::Path, data: &[u8]) -> Result<()> {
+    async fn read_stream(&self, path: &std::path::Path) -> Result<Option<ReadStream>> {
+        let Some(file) = self.open_for_read(path)? else {
+            return Ok(None);
+        };
+        debug!("Streaming file at {path:?}");
+        Ok(Some(Box::pin(ReaderStream::new(
+            tokio::fs::File::from_std(file),
+        ))))
+    }
+
+    async fn write(&self, path: &std::path::Path, data: &[u8]) -> Result<()> {
         let path = &self.join_to_base(path)?;
+        if let Some(min_free_space) = &self.min_free_space {
+            min_free_space.check_write(&self.base_path, data.len() as u64)?;
+        }
         debug!("Reading file at {path:?}");
         fs::create_dir_all(
             path.parent()
@@ -92,7 +204,7 @@ impl StorageOperations for FilesystemStorage {
         Ok(fs::write(path, data)?)
     }
 
-    async fn delete(&mut self, path: &std::path::Path) -> Result<bool> {
+    async fn delete(&self, path: &std::path::Path) -> Result<bool> {
         let path = self.join_to_base(path)?;
         debug!("Deleting file at {path:?}");
         match fs::remove_file(path) {
@@ -144,4 +256,95 @@ impl StorageOperations for FilesystemStorage {
             }
         }))
     }
+
+    async fn created(&self, path: &std::path::Path) -> Result<Option<std::time::SystemTime>> {
+        let path = self.join_to_base(path)?;
+        debug!("Obtaining creation time for {path:?}");
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Some(match metadata.created() {
+            Ok(btime) => btime,
+            Err(err) => {
+                trace!("Unable to get btime for {path:?} - using mtime: {err:?}");
+                metadata
+                    .modified()
+                    .context("unable to get mtime or btime for file")?
+            }
+        }))
+    }
+
+    async fn size(&self, path: &std::path::Path) -> Result<Option<u64>> {
+        let path = self.join_to_base(path)?;
+        debug!("Obtaining size of {path:?}");
+        match fs::metadata(&path) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FilesystemStorage, FreeSpaceSource};
+    use crate::storage::{InsufficientStorageError, StorageOperations};
+    use anyhow::Result;
+    use std::path::Path;
+
+    #[derive(Debug)]
+    struct FixedFreeSpaceSource(u64);
+
+    impl FreeSpaceSource for FixedFreeSpaceSource {
+        fn available_space(&self, _path: &Path) -> Result<u64> {
+            Ok(self.0)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_stream() {
+        let base_path = std::env::temp_dir().join("dollshare-test-read-stream");
+        let storage = FilesystemStorage::new(base_path.clone()).unwrap();
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        storage.write(Path::new("a"), &data).await.unwrap();
+
+        // Test a file is streamed in multiple chunks that match a buffered read.
+        let mut stream = storage.read_stream(Path::new("a")).await.unwrap().unwrap();
+        let mut chunks = 0;
+        let mut streamed = Vec::new();
+        while let Some(chunk) = std::future::poll_fn(|cx| stream.as_mut().poll_next(cx)).await {
+            streamed.extend_from_slice(&chunk.unwrap());
+            chunks += 1;
+        }
+        assert!(chunks > 1);
+        assert_eq!(
+            streamed,
+            storage.read(Path::new("a")).await.unwrap().unwrap()
+        );
+
+        // Test missing files are not streamed.
+        assert!(storage.read_stream(Path::new("b")).await.unwrap().is_none());
+
+        let _ = std::fs::remove_dir_all(base_path);
+    }
+
+    #[tokio::test]
+    async fn test_min_free_space() {
+        let base_path = std::env::temp_dir().join("dollshare-test-min-free-space");
+        let storage = FilesystemStorage::new(base_path.clone())
+            .unwrap()
+            .with_min_free_space(1000, FixedFreeSpaceSource(1500));
+
+        // Test writes that leave enough free space are accepted.
+        assert!(storage.write(Path::new("a"), &[0; 500]).await.is_ok());
+
+        // Test writes that would fall below the threshold are rejected.
+        let err = storage.write(Path::new("b"), &[0; 501]).await.unwrap_err();
+        assert!(err.is::<InsufficientStorageError>());
+        assert!(!storage.exists(Path::new("b")).await.unwrap());
+
+        let _ = std::fs::remove_dir_all(base_path);
+    }
 }
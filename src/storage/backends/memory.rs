@@ -1,18 +1,101 @@
 use crate::storage::{StorageCapabilities, StorageOperations};
-use anyhow::Result;
+use anyhow::{Context, Result};
+use base64ct::Encoding;
 use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
 use std::{path::PathBuf, time::SystemTime};
+use tracing::info;
+
+#[derive(Debug, Clone)]
+struct MemoryEntry {
+    data: Vec<u8>,
+    created: SystemTime,
+    last_access: SystemTime,
+}
+
+/// A [`MemoryEntry`] as it is written to a snapshot file.
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    path: PathBuf,
+    /// Stored data as unpadded URL-safe base64.
+    data: String,
+    created: SystemTime,
+    last_access: SystemTime,
+}
 
 #[derive(Debug, Clone)]
 pub struct MemoryStorage {
-    memory: DashMap<PathBuf, (Vec<u8>, SystemTime)>,
+    memory: DashMap<PathBuf, MemoryEntry>,
+    snapshot_path: Option<PathBuf>,
 }
 
 impl MemoryStorage {
     pub fn new() -> Self {
         MemoryStorage {
             memory: DashMap::new(),
+            snapshot_path: None,
+        }
+    }
+
+    /// Create storage that is preserved in a snapshot file between runs.
+    ///
+    /// Existing contents are loaded from the snapshot file if it exists, and are only
+    /// written back to it by [`MemoryStorage::save_snapshot`].
+    pub fn with_snapshot(path: PathBuf) -> Result<Self> {
+        let memory = DashMap::new();
+        if path.exists() {
+            let entries: Vec<SnapshotEntry> = serde_json::from_slice(
+                &std::fs::read(&path).context("failed to read memory snapshot")?,
+            )
+            .context("failed to parse memory snapshot")?;
+            for entry in entries {
+                memory.insert(
+                    entry.path,
+                    MemoryEntry {
+                        data: base64ct::Base64UrlUnpadded::decode_vec(&entry.data)
+                            .context("failed to decode memory snapshot entry")?,
+                        created: entry.created,
+                        last_access: entry.last_access,
+                    },
+                );
+            }
+            info!(
+                "Loaded {} entries from memory snapshot {path:?}",
+                memory.len()
+            );
         }
+        Ok(Self {
+            memory,
+            snapshot_path: Some(path),
+        })
+    }
+
+    /// Write the current contents to the snapshot file, if one is configured.
+    ///
+    /// The snapshot is replaced atomically so an interrupted save keeps the previous one.
+    pub fn save_snapshot(&self) -> Result<()> {
+        let Some(path) = &self.snapshot_path else {
+            return Ok(());
+        };
+        let entries: Vec<SnapshotEntry> = self
+            .memory
+            .iter()
+            .map(|entry| SnapshotEntry {
+                path: entry.key().clone(),
+                data: base64ct::Base64UrlUnpadded::encode_string(&entry.value().data),
+                created: entry.value().created,
+                last_access: entry.value().last_access,
+            })
+            .collect();
+        let temp_path = path.with_extension("tmp");
+        std::fs::write(&temp_path, serde_json::to_vec(&entries)?)
+            .context("failed to write memory snapshot")?;
+        std::fs::rename(&temp_path, path).context("failed to replace memory snapshot")?;
+        info!(
+            "Saved {} entries to memory snapshot {path:?}",
+            entries.len()
+        );
+        Ok(())
     }
 }
 
@@ -25,22 +108,28 @@ impl StorageCapabilities for MemoryStorage {
 impl StorageOperations for MemoryStorage {
     async fn read(&self, path: &std::path::Path) -> Result<Option<Vec<u8>>> {
         if let Some(mut entry) = self.memory.get_mut(path) {
-            let (data, access_time) = entry.value_mut();
-            let data = data.clone();
-            *access_time = SystemTime::now();
-            Ok(Some(data))
+            let entry = entry.value_mut();
+            entry.last_access = SystemTime::now();
+            Ok(Some(entry.data.clone()))
         } else {
             Ok(None)
         }
     }
 
-    async fn write(&mut self, path: &std::path::Path, data: &[u8]) -> Result<()> {
-        self.memory
-            .insert(path.to_path_buf(), (data.to_vec(), SystemTime::now()));
+    async fn write(&self, path: &std::path::Path, data: &[u8]) -> Result<()> {
+        let now = SystemTime::now();
+        self.memory.insert(
+            path.to_path_buf(),
+            MemoryEntry {
+                data: data.to_vec(),
+                created: now,
+                last_access: now,
+            },
+        );
         Ok(())
     }
 
-    async fn delete(&mut self, path: &std::path::Path) -> Result<bool> {
+    async fn delete(&self, path: &std::path::Path) -> Result<bool> {
         Ok(self.memory.remove(path).is_some())
     }
 
@@ -58,6 +147,60 @@ impl StorageOperations for MemoryStorage {
     }
 
     async fn last_access(&self, path: &std::path::Path) -> Result<Option<SystemTime>> {
-        Ok(self.memory.get(path).map(|entry| entry.value().1))
+        Ok(self.memory.get(path).map(|entry| entry.value().last_access))
+    }
+
+    async fn created(&self, path: &std::path::Path) -> Result<Option<SystemTime>> {
+        Ok(self.memory.get(path).map(|entry| entry.value().created))
+    }
+
+    async fn size(&self, path: &std::path::Path) -> Result<Option<u64>> {
+        Ok(self
+            .memory
+            .get(path)
+            .map(|entry| entry.value().data.len() as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryStorage;
+    use crate::storage::StorageOperations;
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let snapshot_path = std::env::temp_dir().join("dollshare-test-memory-snapshot.json");
+        let _ = std::fs::remove_file(&snapshot_path);
+
+        // Test written data is preserved across a shutdown and restart.
+        let storage = MemoryStorage::with_snapshot(snapshot_path.clone()).unwrap();
+        storage
+            .write(Path::new("uploads/abc"), b"content")
+            .await
+            .unwrap();
+        let created = storage.created(Path::new("uploads/abc")).await.unwrap();
+        storage.save_snapshot().unwrap();
+        drop(storage);
+
+        let storage = MemoryStorage::with_snapshot(snapshot_path.clone()).unwrap();
+        assert_eq!(
+            storage
+                .read(Path::new("uploads/abc"))
+                .await
+                .unwrap()
+                .unwrap(),
+            b"content"
+        );
+        assert_eq!(
+            storage.created(Path::new("uploads/abc")).await.unwrap(),
+            created
+        );
+
+        // Test storage without a snapshot starts empty.
+        let storage = MemoryStorage::new();
+        assert!(!storage.exists(Path::new("uploads/abc")).await.unwrap());
+
+        let _ = std::fs::remove_file(snapshot_path);
     }
 }
@@ -1,3 +1,7 @@
+mod fallback;
+pub use fallback::*;
+mod timed;
+pub use timed::*;
 #[cfg(feature = "storage-memory")]
 mod memory;
 #[cfg(feature = "storage-memory")]
@@ -10,3 +14,15 @@ pub use filesystem::*;
 mod s3;
 #[cfg(feature = "storage-s3")]
 pub use s3::*;
+#[cfg(feature = "storage-redis")]
+mod redis;
+#[cfg(feature = "storage-redis")]
+pub use redis::*;
+#[cfg(feature = "storage-azure")]
+mod azure;
+#[cfg(feature = "storage-azure")]
+pub use azure::*;
+#[cfg(feature = "storage-webdav")]
+mod webdav;
+#[cfg(feature = "storage-webdav")]
+pub use webdav::*;
@@ -1,17 +1,84 @@
-use crate::storage::{StorageCapabilities, StorageOperations};
+use crate::storage::{ReadStream, StorageCapabilities, StorageOperations};
 use anyhow::{Context, Result, anyhow, bail};
-use aws_sdk_s3::{Client, primitives::ByteStream};
-use std::path::PathBuf;
+use aws_sdk_s3::{
+    Client,
+    config::http::HttpResponse,
+    error::{ProvideErrorMetadata, SdkError},
+    primitives::ByteStream,
+    types::{CompletedMultipartUpload, CompletedPart},
+};
+use std::{path::PathBuf, time::Duration};
+use tokio_util::io::ReaderStream;
 use tracing::{debug, warn};
 
+/// Delay before the first retry of a failed operation, doubled for every attempt after.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+/// Upper bound for the delay between retries of a failed operation.
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(5);
+/// Size of each part of a multipart upload, objects larger than this are written in parts.
+///
+/// S3 requires every part except the last to be at least 5MiB.
+const MULTIPART_PART_SIZE: usize = 5 * 1024 * 1024;
+/// Number of keys requested per page when listing objects, the most S3 returns at once.
+const LIST_PAGE_SIZE: i32 = 1000;
+
 #[derive(Debug, Clone)]
 pub struct S3Storage {
     client: Client,
     bucket: String,
+    max_attempts: u32,
+}
+
+/// Run an operation until it succeeds, returns an error that is not retryable, or has
+/// been attempted `max_attempts` times, sleeping with exponential backoff between attempts.
+async fn retry<T, E, F, Fut>(
+    max_attempts: u32,
+    is_retryable: impl Fn(&E) -> bool,
+    mut operation: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Err(err) if attempt < max_attempts && is_retryable(&err) => {
+                let delay = RETRY_BASE_DELAY
+                    .saturating_mul(2u32.saturating_pow(attempt - 1))
+                    .min(RETRY_MAX_DELAY);
+                warn!(
+                    "S3 operation failed on attempt {attempt}/{max_attempts} - retrying in {delay:?}"
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether an S3 request failed for a transient reason and may succeed if retried.
+fn is_retryable<E: ProvideErrorMetadata>(err: &SdkError<E, HttpResponse>) -> bool {
+    match err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            true
+        }
+        SdkError::ServiceError(service_err) => {
+            let status = service_err.raw().status();
+            status.is_server_error()
+                || status.as_u16() == 429
+                || matches!(
+                    service_err.err().code(),
+                    Some("SlowDown" | "Throttling" | "RequestTimeout")
+                )
+        }
+        _ => false,
+    }
 }
 
 impl S3Storage {
-    pub fn new(bucket: String) -> Result<Self> {
+    pub fn new(bucket: String, max_attempts: u32) -> Result<Self> {
         let bucket_clone = bucket.clone();
         let client = match std::thread::spawn(move || {
             let rt = tokio::runtime::Runtime::new().unwrap();
@@ -45,7 +112,87 @@ impl S3Storage {
             }
         }?;
 
-        Ok(Self { client, bucket })
+        Ok(Self {
+            client,
+            bucket,
+            max_attempts,
+        })
+    }
+
+    /// Write an object using a multipart upload, aborting it on failure so no parts are orphaned.
+    async fn write_multipart(&self, key: &str, data: &[u8]) -> Result<()> {
+        let output = retry(self.max_attempts, is_retryable, || {
+            self.client
+                .create_multipart_upload()
+                .bucket(&self.bucket)
+                .key(key)
+                .send()
+        })
+        .await?;
+        let upload_id = output
+            .upload_id()
+            .context("multipart upload was created without an upload id")?;
+
+        let result = self.upload_parts(key, upload_id, data).await;
+        if let Err(err) = &result {
+            warn!("Aborting multipart upload of {key} after failure: {err:?}");
+            if let Err(err) = self
+                .client
+                .abort_multipart_upload()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .send()
+                .await
+            {
+                warn!("Failed to abort multipart upload of {key}: {err:?}");
+            }
+        }
+        result
+    }
+
+    /// Upload every part of an object to an existing multipart upload and complete it.
+    async fn upload_parts(&self, key: &str, upload_id: &str, data: &[u8]) -> Result<()> {
+        let mut parts = Vec::new();
+        for (index, chunk) in data.chunks(MULTIPART_PART_SIZE).enumerate() {
+            let part_number = i32::try_from(index + 1).context("too many multipart parts")?;
+            debug!(
+                "Uploading part {part_number} of {key} to bucket {}",
+                self.bucket
+            );
+            let output = retry(self.max_attempts, is_retryable, || {
+                self.client
+                    .upload_part()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .part_number(part_number)
+                    .body(ByteStream::from(chunk.to_vec()))
+                    .send()
+            })
+            .await?;
+            parts.push(
+                CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(output.e_tag().map(str::to_string))
+                    .build(),
+            );
+        }
+
+        let upload = CompletedMultipartUpload::builder()
+            .set_parts(Some(parts))
+            .build();
+        retry(self.max_attempts, is_retryable, || {
+            self.client
+                .complete_multipart_upload()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .multipart_upload(upload.clone())
+                .send()
+        })
+        .await?;
+        Ok(())
     }
 }
 
@@ -58,13 +205,15 @@ impl StorageCapabilities for S3Storage {
 impl StorageOperations for S3Storage {
     async fn read(&self, path: &std::path::Path) -> Result<Option<Vec<u8>>> {
         debug!("Reading {path:?} from bucket {}", self.bucket);
-        match self
-            .client
-            .get_object()
-            .bucket(&self.bucket)
-            .key(path.to_str().context("failed to convert path to str")?)
-            .send()
-            .await
+        let key = path.to_str().context("failed to convert path to str")?;
+        match retry(self.max_attempts, is_retryable, || {
+            self.client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .send()
+        })
+        .await
         {
             Ok(output) => {
                 let data = output.body.collect().await?.into_bytes().to_vec();
@@ -80,29 +229,63 @@ impl StorageOperations for S3Storage {
         }
     }
 
-    async fn write(&mut self, path: &std::path::Path, data: &[u8]) -> Result<()> {
+    async fn read_stream(&self, path: &std::path::Path) -> Result<Option<ReadStream>> {
+        debug!("Streaming {path:?} from bucket {}", self.bucket);
+        let key = path.to_str().context("failed to convert path to str")?;
+        match retry(self.max_attempts, is_retryable, || {
+            self.client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .send()
+        })
+        .await
+        {
+            Ok(output) => Ok(Some(Box::pin(ReaderStream::new(
+                output.body.into_async_read(),
+            )))),
+            Err(err) => {
+                if err.as_service_error().map(|e| e.is_no_such_key()) == Some(true) {
+                    Ok(None)
+                } else {
+                    Err(err.into())
+                }
+            }
+        }
+    }
+
+    async fn write(&self, path: &std::path::Path, data: &[u8]) -> Result<()> {
         debug!("Writing {path:?} to bucket {}", self.bucket);
-        self.client
-            .put_object()
-            .bucket(&self.bucket)
-            .key(path.to_str().context("failed to convert path to str")?)
-            .body(ByteStream::from(data.to_vec()))
-            .send()
-            .await?;
+        let key = path.to_str().context("failed to convert path to str")?;
+        if data.len() > MULTIPART_PART_SIZE {
+            return self.write_multipart(key, data).await;
+        }
+        retry(self.max_attempts, is_retryable, || {
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .body(ByteStream::from(data.to_vec()))
+                .send()
+        })
+        .await?;
         Ok(())
     }
 
-    async fn delete(&mut self, path: &std::path::Path) -> Result<bool> {
+    async fn delete(&self, path: &std::path::Path) -> Result<bool> {
         debug!("Deleting {path:?} from bucket {}", self.bucket);
         if !self.exists(path).await? {
             return Ok(false);
         }
-        self.client
-            .delete_object()
-            .bucket(&self.bucket)
-            .key(path.to_str().context("failed to convert path to str")?)
-            .send()
-            .await?;
+        let key = path.to_str().context("failed to convert path to str")?;
+        retry(self.max_attempts, is_retryable, || {
+            self.client
+                .delete_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .send()
+        })
+        .await?;
         Ok(true)
     }
 
@@ -128,22 +311,33 @@ impl StorageOperations for S3Storage {
     }
 
     async fn list(&self, path: &std::path::Path) -> Result<Vec<std::path::PathBuf>> {
-        // FIXME: This needs work as it's highly unoptimal if storing a large amount of files
-        // as it will only return up to 1000.
         debug!("Listing files inside of {path:?} in bucket {}", self.bucket);
-        let output = self
-            .client
-            .list_objects_v2()
-            .bucket(&self.bucket)
-            .prefix(path.to_str().context("failed to convert path to str")?)
-            .send()
-            .await?;
+        let prefix = path.to_str().context("failed to convert path to str")?;
         let mut paths = Vec::new();
-        if let Some(objects) = output.contents {
-            for object in objects {
-                if let Some(key) = object.key {
-                    paths.push(PathBuf::from(key));
+        let mut continuation_token = None;
+        loop {
+            let output = retry(self.max_attempts, is_retryable, || {
+                self.client
+                    .list_objects_v2()
+                    .bucket(&self.bucket)
+                    .prefix(prefix)
+                    .max_keys(LIST_PAGE_SIZE)
+                    .set_continuation_token(continuation_token.clone())
+                    .send()
+            })
+            .await?;
+            paths.extend(
+                output
+                    .contents
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|object| object.key.map(PathBuf::from)),
+            );
+            match output.next_continuation_token {
+                Some(token) if output.is_truncated.unwrap_or_default() => {
+                    continuation_token = Some(token)
                 }
+                _ => break,
             }
         }
         Ok(paths)
@@ -181,4 +375,156 @@ impl StorageOperations for S3Storage {
         //     }
         // }
     }
+
+    async fn created(&self, path: &std::path::Path) -> Result<Option<std::time::SystemTime>> {
+        debug!(
+            "Obtaining creation time for {path:?} in bucket {}",
+            self.bucket
+        );
+        // S3 objects are immutable, so the last modified time is when the object was written.
+        match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(path.to_str().context("failed to convert path to str")?)
+            .send()
+            .await
+        {
+            Ok(output) => Ok(output
+                .last_modified
+                .and_then(|last_modified| std::time::SystemTime::try_from(last_modified).ok())),
+            Err(err) => {
+                if err.as_service_error().map(|e| e.is_not_found()) == Some(true) {
+                    Ok(None)
+                } else {
+                    Err(err.into())
+                }
+            }
+        }
+    }
+
+    async fn size(&self, path: &std::path::Path) -> Result<Option<u64>> {
+        debug!("Obtaining size of {path:?} in bucket {}", self.bucket);
+        match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(path.to_str().context("failed to convert path to str")?)
+            .send()
+            .await
+        {
+            Ok(output) => Ok(output
+                .content_length
+                .and_then(|length| u64::try_from(length).ok())),
+            Err(err) => {
+                if err.as_service_error().map(|e| e.is_not_found()) == Some(true) {
+                    Ok(None)
+                } else {
+                    Err(err.into())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LIST_PAGE_SIZE, MULTIPART_PART_SIZE, S3Storage};
+    use crate::storage::StorageOperations;
+    use std::{cell::Cell, path::Path};
+
+    #[tokio::test]
+    async fn test_retry() {
+        // Test an operation that fails twice is retried until it succeeds.
+        let attempts = Cell::new(0);
+        let result = super::retry(
+            3,
+            |_: &&str| true,
+            || async {
+                attempts.set(attempts.get() + 1);
+                if attempts.get() < 3 {
+                    Err("transient")
+                } else {
+                    Ok(())
+                }
+            },
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts.get(), 3);
+
+        // Test an operation is not attempted more than the maximum number of times.
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = super::retry(
+            2,
+            |_: &&str| true,
+            || async {
+                attempts.set(attempts.get() + 1);
+                Err("transient")
+            },
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 2);
+
+        // Test an operation that fails with a non-retryable error fails immediately.
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = super::retry(
+            3,
+            |_: &&str| false,
+            || async {
+                attempts.set(attempts.get() + 1);
+                Err("permanent")
+            },
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires an S3-compatible server such as localstack, configured through AWS_* environment variables"]
+    async fn test_multipart_write() {
+        let storage = tokio::task::spawn_blocking(|| {
+            S3Storage::new("dollshare-multipart-test".to_string(), 3).unwrap()
+        })
+        .await
+        .unwrap();
+        let path = Path::new("uploads/multipart");
+
+        // Test objects spanning several parts are written and read back intact.
+        let data: Vec<u8> = (0..MULTIPART_PART_SIZE * 2 + 1024)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        storage.write(path, &data).await.unwrap();
+        assert_eq!(storage.read(path).await.unwrap().unwrap(), data);
+        assert!(storage.delete(path).await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires an S3-compatible server such as localstack, configured through AWS_* environment variables"]
+    async fn test_list_pagination() {
+        let storage = tokio::task::spawn_blocking(|| {
+            S3Storage::new("dollshare-list-test".to_string(), 3).unwrap()
+        })
+        .await
+        .unwrap();
+
+        // Test objects past the first page of results are listed.
+        let count = LIST_PAGE_SIZE as usize * 2 + 1;
+        for i in 0..count {
+            storage
+                .write(&Path::new("uploads").join(i.to_string()), b"")
+                .await
+                .unwrap();
+        }
+        let mut paths = storage.list(Path::new("uploads/")).await.unwrap();
+        paths.sort();
+        paths.dedup();
+        assert_eq!(paths.len(), count);
+
+        for path in paths {
+            storage.delete(&path).await.unwrap();
+        }
+    }
 }
@@ -0,0 +1,90 @@
+use crate::{
+    metrics::Metrics,
+    storage::{ReadStream, StorageCapabilities, StorageOperations, StorageProvider},
+};
+use anyhow::Result;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
+
+/// Storage that records how long each operation on its provider takes.
+pub struct TimedStorage {
+    provider: StorageProvider,
+    metrics: Arc<Metrics>,
+}
+
+impl TimedStorage {
+    pub fn new(provider: StorageProvider, metrics: Arc<Metrics>) -> Self {
+        Self { provider, metrics }
+    }
+
+    pub fn provider(&self) -> &StorageProvider {
+        &self.provider
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    /// Run an operation, recording its duration under the given name.
+    async fn timed<T>(&self, operation: &'static str, future: impl Future<Output = T>) -> T {
+        let start = Instant::now();
+        let output = future.await;
+        self.metrics
+            .record_storage_duration(operation, start.elapsed());
+        output
+    }
+}
+
+impl StorageCapabilities for TimedStorage {
+    fn supports_expiry(&self) -> bool {
+        self.provider.supports_expiry()
+    }
+}
+
+impl StorageOperations for TimedStorage {
+    async fn read(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        self.timed("read", self.provider.read(path)).await
+    }
+
+    /// Only the time taken to open the stream is recorded, not the time taken to consume it.
+    async fn read_stream(&self, path: &Path) -> Result<Option<ReadStream>> {
+        self.timed("read_stream", self.provider.read_stream(path))
+            .await
+    }
+
+    async fn write(&self, path: &Path, data: &[u8]) -> Result<()> {
+        self.timed("write", self.provider.write(path, data)).await
+    }
+
+    async fn delete(&self, path: &Path) -> Result<bool> {
+        self.timed("delete", self.provider.delete(path)).await
+    }
+
+    async fn exists(&self, path: &Path) -> Result<bool> {
+        self.timed("exists", self.provider.exists(path)).await
+    }
+
+    async fn list(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        self.timed("list", self.provider.list(path)).await
+    }
+
+    async fn last_access(&self, path: &Path) -> Result<Option<SystemTime>> {
+        self.timed("last_access", self.provider.last_access(path))
+            .await
+    }
+
+    async fn created(&self, path: &Path) -> Result<Option<SystemTime>> {
+        self.timed("created", self.provider.created(path)).await
+    }
+
+    async fn size(&self, path: &Path) -> Result<Option<u64>> {
+        self.timed("size", self.provider.size(path)).await
+    }
+}
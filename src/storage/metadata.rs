@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Additional information stored alongside an upload.
+///
+/// Metadata is encrypted with the same key as the upload it belongs to.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadMetadata {
+    /// Uploader-supplied headers that are replayed when the upload is served.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// Name the upload was given by the uploader, suggested to browsers when it is served.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    /// Whether the upload was compressed before it was encrypted.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compressed: bool,
+}
+
+/// Information about an upload that the server can read without its decryption key.
+///
+/// Nothing that reveals the content of an upload should be stored here.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadRecord {
+    /// Perceptual hash of image uploads for finding near-duplicates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub perceptual_hash: Option<u64>,
+    /// Number of views after which the upload is removed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_views: Option<u64>,
+    /// Number of times the upload has been viewed, only counted when it has a view limit.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub views: u64,
+    /// Whether the upload must never be cached and is always served as an attachment.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sensitive: bool,
+    /// Seconds since last access after which the upload expires, instead of the server's expiry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiry_secs: Option<u64>,
+    /// Full salted hash of the content the id was taken from, so uploads whose content merely
+    /// shares the start of its hash can be told apart from duplicates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+}
+
+impl UploadRecord {
+    /// Whether the upload has been viewed as many times as it is permitted to be.
+    pub fn is_view_limit_reached(&self) -> bool {
+        self.max_views
+            .is_some_and(|max_views| self.views >= max_views)
+    }
+
+    /// Time since last access after which the upload expires, if it overrides the server's expiry.
+    pub fn expiry(&self) -> Option<Duration> {
+        self.expiry_secs.map(Duration::from_secs)
+    }
+
+    /// Whether the upload stops being served once it has been viewed or after its own expiry.
+    pub fn is_transient(&self) -> bool {
+        self.max_views.is_some() || self.expiry_secs.is_some()
+    }
+}
+
+/// One of the uploads sharing an upload's stored content, which expires and is deleted on
+/// its own without affecting the others.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadReference {
+    /// When the upload was made, as seconds since the unix epoch.
+    pub created: u64,
+    /// Hash of the token that deletes the upload, which is only given to its uploader.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delete_token: Option<String>,
+    /// Salted hash of the token the upload was made with, whose usage the upload counts towards.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// Size of the upload in bytes as it was stored, before encryption.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub size: u64,
+}
+
+impl UploadReference {
+    /// A reference for an upload of the given size made now by the given owner, which is
+    /// deleted with the given token.
+    pub fn new(delete_token: &str, owner: Option<String>, size: u64) -> Self {
+        Self {
+            delete_token: Some(blake3::hash(delete_token.as_bytes()).to_hex().to_string()),
+            owner,
+            size,
+            ..Self::created_at(SystemTime::now())
+        }
+    }
+
+    /// A reference for an upload made at the given time that can't be deleted with a token.
+    pub fn created_at(created: SystemTime) -> Self {
+        Self {
+            created: created
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            delete_token: None,
+            owner: None,
+            size: 0,
+        }
+    }
+
+    /// Whether the given token deletes the upload, compared in constant time.
+    pub fn is_deleted_by(&self, delete_token: &str) -> bool {
+        self.delete_token
+            .as_deref()
+            .and_then(|hash| blake3::Hash::from_hex(hash).ok())
+            .is_some_and(|hash| hash == blake3::hash(delete_token.as_bytes()))
+    }
+
+    /// Whether the upload has expired, given when its content was last accessed.
+    ///
+    /// Uploads made after their content was last accessed count from when they were made, and
+    /// are never expired within the grace period after they were made.
+    pub fn is_expired(
+        &self,
+        last_access: SystemTime,
+        expire_after: Duration,
+        grace_period: Option<Duration>,
+        now: SystemTime,
+    ) -> bool {
+        let created = UNIX_EPOCH + Duration::from_secs(self.created);
+        if grace_period.is_some_and(|grace_period| created + grace_period > now) {
+            return false;
+        }
+        last_access.max(created) + expire_after <= now
+    }
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
@@ -1,11 +1,33 @@
 mod app_storage;
-pub use app_storage::AppStorage;
+pub use app_storage::{AppStorage, MasterKeys, StoragePrefix};
 mod backends;
+mod metadata;
+pub use metadata::{UploadMetadata, UploadRecord, UploadReference};
 
 use anyhow::Result;
+use axum::body::Bytes;
+use clap::ValueEnum;
 use core::str::FromStr;
+use futures_core::Stream;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::time::SystemTime;
+use tokio_util::io::ReaderStream;
+
+/// A stream of the chunks of a stored file, read as they are consumed.
+pub type ReadStream = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send>>;
+
+/// Error returned when a write is rejected because storage is running out of space.
+#[derive(Debug)]
+pub struct InsufficientStorageError;
+
+impl std::fmt::Display for InsufficientStorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("not enough free storage space")
+    }
+}
+
+impl std::error::Error for InsufficientStorageError {}
 
 pub trait StorageCapabilities {
     fn supports_expiry(&self) -> bool;
@@ -13,11 +35,120 @@ pub trait StorageCapabilities {
 
 pub trait StorageOperations: StorageCapabilities {
     async fn read(&self, path: &Path) -> Result<Option<Vec<u8>>>;
-    async fn write(&mut self, path: &Path, data: &[u8]) -> Result<()>;
-    async fn delete(&mut self, path: &Path) -> Result<bool>;
+    /// Read a file as a stream of chunks so it doesn't need to be held in memory all at once.
+    ///
+    /// Backends that can't stream fall back to reading the whole file before streaming it.
+    async fn read_stream(&self, path: &Path) -> Result<Option<ReadStream>> {
+        Ok(self
+            .read(path)
+            .await?
+            .map(|data| Box::pin(ReaderStream::new(std::io::Cursor::new(data))) as ReadStream))
+    }
+    async fn write(&self, path: &Path, data: &[u8]) -> Result<()>;
+    async fn delete(&self, path: &Path) -> Result<bool>;
     async fn exists(&self, path: &Path) -> Result<bool>;
     async fn list(&self, path: &Path) -> Result<Vec<PathBuf>>;
     async fn last_access(&self, path: &Path) -> Result<Option<SystemTime>>;
+    async fn created(&self, path: &Path) -> Result<Option<SystemTime>>;
+    async fn size(&self, path: &Path) -> Result<Option<u64>>;
+}
+
+/// The URL schemes used to select a [`StorageProvider`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StorageScheme {
+    #[value(name = "memory")]
+    Memory,
+    #[value(name = "fs")]
+    Filesystem,
+    #[value(name = "s3")]
+    S3,
+    #[value(name = "redis")]
+    Redis,
+    #[value(name = "azblob")]
+    Azure,
+    #[value(name = "webdav")]
+    Webdav,
+}
+
+impl StorageScheme {
+    /// Every scheme, regardless of which storage backends were enabled at compile time.
+    pub const ALL: [StorageScheme; 6] = [
+        Self::Memory,
+        Self::Filesystem,
+        Self::S3,
+        Self::Redis,
+        Self::Azure,
+        Self::Webdav,
+    ];
+
+    /// Get the name of the scheme as it is used in storage provider strings.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Memory => "memory",
+            Self::Filesystem => "fs",
+            Self::S3 => "s3",
+            Self::Redis => "redis",
+            Self::Azure => "azblob",
+            Self::Webdav => "webdav",
+        }
+    }
+
+    /// Get the scheme used by a storage provider string, if it uses a known one.
+    fn of(s: &str) -> Option<Self> {
+        if s == "memory://" {
+            Some(Self::Memory)
+        } else if s.starts_with("fs://") {
+            Some(Self::Filesystem)
+        } else if s.starts_with("s3://") {
+            Some(Self::S3)
+        } else if s.starts_with("redis://") || s.starts_with("rediss://") {
+            Some(Self::Redis)
+        } else if s.starts_with("azblob://") {
+            Some(Self::Azure)
+        } else if s.starts_with("webdav://") || s.starts_with("webdavs://") {
+            Some(Self::Webdav)
+        } else {
+            None
+        }
+    }
+}
+
+/// Options that control how a [`StorageProvider`] is created.
+#[derive(Debug, Clone)]
+pub struct StorageOptions {
+    /// Schemes that the provider is permitted to use.
+    pub allowed_schemes: Vec<StorageScheme>,
+    /// File that memory storage is loaded from on startup and saved to on shutdown.
+    #[cfg(feature = "storage-memory")]
+    pub memory_snapshot: Option<PathBuf>,
+    /// Minimum free disk space to leave when writing to the filesystem.
+    #[cfg(feature = "storage-filesystem")]
+    pub fs_min_free_disk: Option<u64>,
+    /// Maximum number of attempts for S3 operations that fail with a transient error.
+    #[cfg(feature = "storage-s3")]
+    pub s3_max_attempts: u32,
+    /// Time since last read after which Redis expires upload contents, never when `None`.
+    #[cfg(feature = "storage-redis")]
+    pub redis_ttl: Option<std::time::Duration>,
+    /// Copy data read from a fallback provider to the primary provider.
+    pub fallback_copy_forward: bool,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        Self {
+            allowed_schemes: StorageScheme::ALL.to_vec(),
+            #[cfg(feature = "storage-memory")]
+            memory_snapshot: None,
+            #[cfg(feature = "storage-filesystem")]
+            fs_min_free_disk: None,
+            #[cfg(feature = "storage-s3")]
+            s3_max_attempts: 3,
+            #[cfg(feature = "storage-redis")]
+            redis_ttl: None,
+            fallback_copy_forward: false,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -28,6 +159,14 @@ pub enum StorageProvider {
     Filesystem(backends::FilesystemStorage),
     #[cfg(feature = "storage-s3")]
     S3(backends::S3Storage),
+    #[cfg(feature = "storage-redis")]
+    Redis(backends::RedisStorage),
+    #[cfg(feature = "storage-azure")]
+    Azure(Box<backends::AzureBlobStorage>),
+    #[cfg(feature = "storage-webdav")]
+    Webdav(Box<backends::WebdavStorage>),
+    /// Holds other providers, so its operations are boxed to break the recursion.
+    Fallback(Box<backends::FallbackStorage>),
 }
 
 impl StorageCapabilities for StorageProvider {
@@ -39,6 +178,13 @@ impl StorageCapabilities for StorageProvider {
             StorageProvider::Filesystem(storage) => storage.supports_expiry(),
             #[cfg(feature = "storage-s3")]
             StorageProvider::S3(storage) => storage.supports_expiry(),
+            #[cfg(feature = "storage-redis")]
+            StorageProvider::Redis(storage) => storage.supports_expiry(),
+            #[cfg(feature = "storage-azure")]
+            StorageProvider::Azure(storage) => storage.supports_expiry(),
+            #[cfg(feature = "storage-webdav")]
+            StorageProvider::Webdav(storage) => storage.supports_expiry(),
+            StorageProvider::Fallback(storage) => storage.supports_expiry(),
         }
     }
 }
@@ -52,10 +198,35 @@ impl StorageOperations for StorageProvider {
             StorageProvider::Filesystem(storage) => storage.read(path).await,
             #[cfg(feature = "storage-s3")]
             StorageProvider::S3(storage) => storage.read(path).await,
+            #[cfg(feature = "storage-redis")]
+            StorageProvider::Redis(storage) => storage.read(path).await,
+            #[cfg(feature = "storage-azure")]
+            StorageProvider::Azure(storage) => storage.read(path).await,
+            #[cfg(feature = "storage-webdav")]
+            StorageProvider::Webdav(storage) => storage.read(path).await,
+            StorageProvider::Fallback(storage) => Box::pin(storage.read(path)).await,
+        }
+    }
+
+    async fn read_stream(&self, path: &Path) -> Result<Option<ReadStream>> {
+        match self {
+            #[cfg(feature = "storage-memory")]
+            StorageProvider::Memory(storage) => storage.read_stream(path).await,
+            #[cfg(feature = "storage-filesystem")]
+            StorageProvider::Filesystem(storage) => storage.read_stream(path).await,
+            #[cfg(feature = "storage-s3")]
+            StorageProvider::S3(storage) => storage.read_stream(path).await,
+            #[cfg(feature = "storage-redis")]
+            StorageProvider::Redis(storage) => storage.read_stream(path).await,
+            #[cfg(feature = "storage-azure")]
+            StorageProvider::Azure(storage) => storage.read_stream(path).await,
+            #[cfg(feature = "storage-webdav")]
+            StorageProvider::Webdav(storage) => storage.read_stream(path).await,
+            StorageProvider::Fallback(storage) => Box::pin(storage.read_stream(path)).await,
         }
     }
 
-    async fn write(&mut self, path: &Path, data: &[u8]) -> Result<()> {
+    async fn write(&self, path: &Path, data: &[u8]) -> Result<()> {
         match self {
             #[cfg(feature = "storage-memory")]
             StorageProvider::Memory(storage) => storage.write(path, data).await,
@@ -63,10 +234,17 @@ impl StorageOperations for StorageProvider {
             StorageProvider::Filesystem(storage) => storage.write(path, data).await,
             #[cfg(feature = "storage-s3")]
             StorageProvider::S3(storage) => storage.write(path, data).await,
+            #[cfg(feature = "storage-redis")]
+            StorageProvider::Redis(storage) => storage.write(path, data).await,
+            #[cfg(feature = "storage-azure")]
+            StorageProvider::Azure(storage) => storage.write(path, data).await,
+            #[cfg(feature = "storage-webdav")]
+            StorageProvider::Webdav(storage) => storage.write(path, data).await,
+            StorageProvider::Fallback(storage) => Box::pin(storage.write(path, data)).await,
         }
     }
 
-    async fn delete(&mut self, path: &Path) -> Result<bool> {
+    async fn delete(&self, path: &Path) -> Result<bool> {
         match self {
             #[cfg(feature = "storage-memory")]
             StorageProvider::Memory(storage) => storage.delete(path).await,
@@ -74,6 +252,13 @@ impl StorageOperations for StorageProvider {
             StorageProvider::Filesystem(storage) => storage.delete(path).await,
             #[cfg(feature = "storage-s3")]
             StorageProvider::S3(storage) => storage.delete(path).await,
+            #[cfg(feature = "storage-redis")]
+            StorageProvider::Redis(storage) => storage.delete(path).await,
+            #[cfg(feature = "storage-azure")]
+            StorageProvider::Azure(storage) => storage.delete(path).await,
+            #[cfg(feature = "storage-webdav")]
+            StorageProvider::Webdav(storage) => storage.delete(path).await,
+            StorageProvider::Fallback(storage) => Box::pin(storage.delete(path)).await,
         }
     }
 
@@ -85,6 +270,13 @@ impl StorageOperations for StorageProvider {
             StorageProvider::Filesystem(storage) => storage.exists(path).await,
             #[cfg(feature = "storage-s3")]
             StorageProvider::S3(storage) => storage.exists(path).await,
+            #[cfg(feature = "storage-redis")]
+            StorageProvider::Redis(storage) => storage.exists(path).await,
+            #[cfg(feature = "storage-azure")]
+            StorageProvider::Azure(storage) => storage.exists(path).await,
+            #[cfg(feature = "storage-webdav")]
+            StorageProvider::Webdav(storage) => storage.exists(path).await,
+            StorageProvider::Fallback(storage) => Box::pin(storage.exists(path)).await,
         }
     }
 
@@ -96,6 +288,13 @@ impl StorageOperations for StorageProvider {
             StorageProvider::Filesystem(storage) => storage.list(path).await,
             #[cfg(feature = "storage-s3")]
             StorageProvider::S3(storage) => storage.list(path).await,
+            #[cfg(feature = "storage-redis")]
+            StorageProvider::Redis(storage) => storage.list(path).await,
+            #[cfg(feature = "storage-azure")]
+            StorageProvider::Azure(storage) => storage.list(path).await,
+            #[cfg(feature = "storage-webdav")]
+            StorageProvider::Webdav(storage) => storage.list(path).await,
+            StorageProvider::Fallback(storage) => Box::pin(storage.list(path)).await,
         }
     }
 
@@ -107,16 +306,121 @@ impl StorageOperations for StorageProvider {
             StorageProvider::Filesystem(storage) => storage.last_access(path).await,
             #[cfg(feature = "storage-s3")]
             StorageProvider::S3(storage) => storage.last_access(path).await,
+            #[cfg(feature = "storage-redis")]
+            StorageProvider::Redis(storage) => storage.last_access(path).await,
+            #[cfg(feature = "storage-azure")]
+            StorageProvider::Azure(storage) => storage.last_access(path).await,
+            #[cfg(feature = "storage-webdav")]
+            StorageProvider::Webdav(storage) => storage.last_access(path).await,
+            StorageProvider::Fallback(storage) => Box::pin(storage.last_access(path)).await,
+        }
+    }
+
+    async fn created(&self, path: &Path) -> Result<Option<SystemTime>> {
+        match self {
+            #[cfg(feature = "storage-memory")]
+            StorageProvider::Memory(storage) => storage.created(path).await,
+            #[cfg(feature = "storage-filesystem")]
+            StorageProvider::Filesystem(storage) => storage.created(path).await,
+            #[cfg(feature = "storage-s3")]
+            StorageProvider::S3(storage) => storage.created(path).await,
+            #[cfg(feature = "storage-redis")]
+            StorageProvider::Redis(storage) => storage.created(path).await,
+            #[cfg(feature = "storage-azure")]
+            StorageProvider::Azure(storage) => storage.created(path).await,
+            #[cfg(feature = "storage-webdav")]
+            StorageProvider::Webdav(storage) => storage.created(path).await,
+            StorageProvider::Fallback(storage) => Box::pin(storage.created(path)).await,
+        }
+    }
+
+    async fn size(&self, path: &Path) -> Result<Option<u64>> {
+        match self {
+            #[cfg(feature = "storage-memory")]
+            StorageProvider::Memory(storage) => storage.size(path).await,
+            #[cfg(feature = "storage-filesystem")]
+            StorageProvider::Filesystem(storage) => storage.size(path).await,
+            #[cfg(feature = "storage-s3")]
+            StorageProvider::S3(storage) => storage.size(path).await,
+            #[cfg(feature = "storage-redis")]
+            StorageProvider::Redis(storage) => storage.size(path).await,
+            #[cfg(feature = "storage-azure")]
+            StorageProvider::Azure(storage) => storage.size(path).await,
+            #[cfg(feature = "storage-webdav")]
+            StorageProvider::Webdav(storage) => storage.size(path).await,
+            StorageProvider::Fallback(storage) => Box::pin(storage.size(path)).await,
         }
     }
 }
 
-impl FromStr for StorageProvider {
-    type Err = String;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
+impl StorageProvider {
+    /// Get the scheme of the storage backend in use.
+    pub fn scheme(&self) -> StorageScheme {
+        match self {
+            #[cfg(feature = "storage-memory")]
+            StorageProvider::Memory(_) => StorageScheme::Memory,
+            #[cfg(feature = "storage-filesystem")]
+            StorageProvider::Filesystem(_) => StorageScheme::Filesystem,
+            #[cfg(feature = "storage-s3")]
+            StorageProvider::S3(_) => StorageScheme::S3,
+            #[cfg(feature = "storage-redis")]
+            StorageProvider::Redis(_) => StorageScheme::Redis,
+            #[cfg(feature = "storage-azure")]
+            StorageProvider::Azure(_) => StorageScheme::Azure,
+            #[cfg(feature = "storage-webdav")]
+            StorageProvider::Webdav(_) => StorageScheme::Webdav,
+            StorageProvider::Fallback(storage) => storage.primary().scheme(),
+        }
+    }
+
+    /// Persist anything the provider needs to keep before the server exits.
+    pub fn shutdown(&self) -> Result<()> {
+        match self {
+            #[cfg(feature = "storage-memory")]
+            StorageProvider::Memory(storage) => storage.save_snapshot(),
+            StorageProvider::Fallback(storage) => {
+                storage.primary().shutdown()?;
+                storage.fallback().shutdown()
+            }
+            #[allow(unreachable_patterns)]
+            _ => Ok(()),
+        }
+    }
+
+    /// Create a storage provider from its string representation using the given options.
+    ///
+    /// The scheme is checked against the allowed schemes before the provider is created
+    /// so that disallowed providers never touch their underlying storage.
+    ///
+    /// A fallback provider to read from when data is missing can be given after the primary
+    /// provider as `<primary>?fallback=<fallback>`.
+    pub fn from_str_with_options(s: &str, options: &StorageOptions) -> Result<Self, String> {
+        if let Some((primary, fallback)) = s.split_once("?fallback=") {
+            return Ok(Self::Fallback(Box::new(
+                backends::FallbackStorage::new(
+                    Self::from_str_with_options(primary, options)?,
+                    Self::from_str_with_options(fallback, options)?,
+                )
+                .with_copy_forward(options.fallback_copy_forward),
+            )));
+        }
+
+        if let Some(scheme) = StorageScheme::of(s)
+            && !options.allowed_schemes.contains(&scheme)
+        {
+            return Err(format!(
+                "The '{}' storage scheme is not permitted by this server",
+                scheme.to_possible_value().unwrap().get_name()
+            ));
+        }
+
         match s {
             #[cfg(feature = "storage-memory")]
-            "memory://" => Ok(Self::Memory(backends::MemoryStorage::new())),
+            "memory://" => Ok(Self::Memory(match &options.memory_snapshot {
+                Some(path) => backends::MemoryStorage::with_snapshot(path.clone())
+                    .map_err(|err| format!("Failed to load memory snapshot: {err:?}"))?,
+                None => backends::MemoryStorage::new(),
+            })),
 
             #[cfg(feature = "storage-filesystem")]
             _ if s.starts_with("fs://") => {
@@ -129,10 +433,14 @@ impl FromStr for StorageProvider {
                         "Path specified cannot be read from or written to by the current user\n\nError: {err}"
                     ));
                 }
-                Ok(Self::Filesystem(
-                    backends::FilesystemStorage::new(s)
-                        .map_err(|err| format!("Failed to create filesystem storage: {err:?}"))?,
-                ))
+                let storage = backends::FilesystemStorage::new(s)
+                    .map_err(|err| format!("Failed to create filesystem storage: {err:?}"))?;
+                Ok(Self::Filesystem(match options.fs_min_free_disk {
+                    Some(min_free_disk) => {
+                        storage.with_min_free_space(min_free_disk, backends::DfFreeSpaceSource)
+                    }
+                    None => storage,
+                }))
             }
 
             #[cfg(feature = "storage-s3")]
@@ -148,11 +456,43 @@ impl FromStr for StorageProvider {
                 }
 
                 Ok(Self::S3(
-                    backends::S3Storage::new(bucket.to_string())
+                    backends::S3Storage::new(bucket.to_string(), options.s3_max_attempts)
                         .map_err(|err| format!("failed to create S3 client: {err:?}"))?,
                 ))
             }
 
+            #[cfg(feature = "storage-redis")]
+            _ if s.starts_with("redis://") || s.starts_with("rediss://") => Ok(Self::Redis(
+                backends::RedisStorage::new(s, options.redis_ttl)
+                    .map_err(|err| format!("failed to create redis storage: {err:?}"))?,
+            )),
+
+            #[cfg(feature = "storage-azure")]
+            _ if s.starts_with("azblob://") => {
+                let container = s
+                    .trim_start_matches("azblob://")
+                    .split('/')
+                    .next()
+                    .ok_or("Azure URL must include container: azblob://container")?;
+
+                if container.is_empty() {
+                    return Err("Azure container name cannot be empty".to_string());
+                }
+
+                Ok(Self::Azure(Box::new(
+                    backends::AzureBlobStorage::new(container.to_string())
+                        .map_err(|err| format!("failed to create Azure client: {err:?}"))?,
+                )))
+            }
+
+            #[cfg(feature = "storage-webdav")]
+            _ if s.starts_with("webdav://") || s.starts_with("webdavs://") => {
+                Ok(Self::Webdav(Box::new(
+                    backends::WebdavStorage::new(s)
+                        .map_err(|err| format!("failed to create WebDAV storage: {err:?}"))?,
+                )))
+            }
+
             _ => {
                 let mut valid_sources = Vec::new();
                 #[cfg(feature = "storage-memory")]
@@ -161,6 +501,12 @@ impl FromStr for StorageProvider {
                 valid_sources.push("'fs://path'");
                 #[cfg(feature = "storage-s3")]
                 valid_sources.push("'s3://bucket'");
+                #[cfg(feature = "storage-redis")]
+                valid_sources.push("'redis[s]://host:port/db'");
+                #[cfg(feature = "storage-azure")]
+                valid_sources.push("'azblob://container'");
+                #[cfg(feature = "storage-webdav")]
+                valid_sources.push("'webdav://[user:password@]host[:port]/path'");
 
                 if valid_sources.is_empty() {
                     Err("No storage backends are enabled".to_string())
@@ -171,3 +517,88 @@ impl FromStr for StorageProvider {
         }
     }
 }
+
+impl FromStr for StorageProvider {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_str_with_options(s, &StorageOptions::default())
+    }
+}
+
+#[cfg(test)]
+#[cfg(any(feature = "storage-memory", feature = "storage-filesystem"))]
+mod tests {
+    use super::{StorageOptions, StorageProvider, StorageScheme};
+
+    // Other options only exist when their storage backends are enabled.
+    #[allow(clippy::needless_update)]
+    fn allowing(allowed_schemes: &[StorageScheme]) -> StorageOptions {
+        StorageOptions {
+            allowed_schemes: allowed_schemes.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "storage-filesystem")]
+    fn test_from_str_with_options_rejects_filesystem() {
+        let path = std::env::temp_dir().join("dollshare-test-from-str-with-options");
+        let provider = format!("fs://{}", path.display());
+
+        // Test the filesystem is rejected when it is not in the allow-list.
+        assert!(
+            StorageProvider::from_str_with_options(&provider, &allowing(&[StorageScheme::S3]))
+                .is_err()
+        );
+        assert!(!path.exists());
+
+        // Test the filesystem is accepted when it is in the allow-list.
+        assert!(
+            StorageProvider::from_str_with_options(
+                &provider,
+                &allowing(&[StorageScheme::Filesystem])
+            )
+            .is_ok()
+        );
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    #[cfg(feature = "storage-memory")]
+    fn test_from_str_with_options_rejects_memory() {
+        // Test memory is rejected when it is not in the allow-list.
+        assert!(StorageProvider::from_str_with_options("memory://", &allowing(&[])).is_err());
+
+        // Test memory is accepted when it is in the allow-list.
+        assert!(
+            StorageProvider::from_str_with_options(
+                "memory://",
+                &allowing(&[StorageScheme::Memory])
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "storage-memory", feature = "storage-filesystem"))]
+    fn test_from_str_with_options_fallback() {
+        let path = std::env::temp_dir().join("dollshare-test-from-str-with-options-fallback");
+        let provider = format!("memory://?fallback=fs://{}", path.display());
+
+        // Test the fallback provider is checked against the allow-list too.
+        assert!(
+            StorageProvider::from_str_with_options(&provider, &allowing(&[StorageScheme::Memory]))
+                .is_err()
+        );
+        assert!(!path.exists());
+
+        // Test the primary provider's scheme is reported for the fallback chain.
+        let storage = StorageProvider::from_str_with_options(
+            &provider,
+            &allowing(&[StorageScheme::Memory, StorageScheme::Filesystem]),
+        )
+        .unwrap();
+        assert_eq!(storage.scheme(), StorageScheme::Memory);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
@@ -0,0 +1,260 @@
+use crate::{
+    AppState, AuthProvider, CachePolicy, IdStrategy, IdempotencyCache, MaintenanceMode, Metrics,
+    PublicUrlStrategy, Throughput, UploadLocks, build_router,
+    storage::{AppStorage, StorageProvider},
+};
+use axum::http::HeaderValue;
+use std::{
+    str::FromStr,
+    sync::{Arc, atomic::AtomicUsize},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use url::Url;
+
+pub(crate) const TEST_TOKEN: &str = "test-token";
+
+pub(crate) fn test_state() -> AppState {
+    AppState {
+        storage: Arc::new(AppStorage::new(
+            StorageProvider::from_str("memory://").unwrap(),
+        )),
+        upload_locks: Arc::new(UploadLocks::default()),
+        auth_provider: Arc::new(AuthProvider::new(vec![TEST_TOKEN.to_string()], vec![])),
+        public_base_urls: vec![Url::parse("http://127.0.0.1:8731").unwrap()],
+        public_url_strategy: PublicUrlStrategy::First,
+        public_url_counter: Arc::new(AtomicUsize::new(0)),
+        upload_allowed_mimetypes: vec![mime_guess::mime::STAR_STAR],
+        enforce_mimetypes_on_download: false,
+        upload_archive_expansion_limit: None,
+        upload_size_limit: 1024,
+        upload_min_size: 1,
+        upload_field_size_limit: None,
+        upload_max_leading_fields: None,
+        upload_require_filename: false,
+        upload_budget: None,
+        memory_pressure: None,
+        upload_expiry: None,
+        upload_metadata_headers: false,
+        upload_passthrough_headers: vec![],
+        upload_keep_originals: false,
+        upload_strip_exif: true,
+        upload_jpeg_quality: 90,
+        transcode_heic: false,
+        moderation: false,
+        upload_perceptual_hashes: false,
+        upload_id_strategy: IdStrategy::ContentHash,
+        upload_id_length: 10,
+        idempotency_cache: Arc::new(IdempotencyCache::new(Duration::from_secs(60))),
+        #[cfg(feature = "upload-from-url")]
+        remote_fetcher: Arc::new(crate::remote_fetch::RemoteFetcher::new(vec![]).unwrap()),
+        cors_allowed_origins: vec![],
+        cors_expose_headers: vec![],
+        text_charset: "utf-8".to_string(),
+        cache_policy: CachePolicy {
+            default: HeaderValue::from_static("private, max-age=1800"),
+            images: None,
+            videos: None,
+        },
+        signed_url_lifetime: None,
+        signed_url_omit_key: false,
+        decrypt_timeout: None,
+        maintenance: Arc::new(MaintenanceMode::new(false, Duration::from_secs(60))),
+        throughput: Arc::new(Throughput::default()),
+        metrics: Arc::new(Metrics::default()),
+        metrics_endpoint: false,
+        ui_title: "Dollshare".to_string(),
+        ui_accent_color: None,
+        health_path: "/health".to_string(),
+        health_body: None,
+        readiness_path: "/ready".to_string(),
+        readiness_timeout: Duration::from_secs(2),
+        started: Instant::now(),
+        persisted_salt: "test-secret".to_string(),
+    }
+}
+
+/// Serve the router for the given state, returning the address it is listening on.
+pub(crate) async fn serve(state: AppState) -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let router = build_router(state).unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    address
+}
+
+/// Send only the headers of an upload that expects `100 Continue` and read the first response line.
+pub(crate) async fn expect_continue_status(
+    address: std::net::SocketAddr,
+    token: &str,
+    size: u64,
+) -> String {
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream
+        .write_all(
+            format!(
+                "POST /upload HTTP/1.1\r\n\
+                 Host: {address}\r\n\
+                 Authorization: Bearer {token}\r\n\
+                 Content-Type: multipart/form-data; boundary=boundary\r\n\
+                 Content-Length: {size}\r\n\
+                 Expect: 100-continue\r\n\r\n"
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let mut buf = [0; 64];
+    let read = stream.read(&mut buf).await.unwrap();
+    let response = String::from_utf8_lossy(&buf[..read]);
+    response.lines().next().unwrap().to_string()
+}
+
+/// Send a raw request that closes the connection and read the full response.
+pub(crate) async fn send(address: std::net::SocketAddr, request: &[u8]) -> String {
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream.write_all(request).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    String::from_utf8_lossy(&response).to_string()
+}
+
+pub(crate) fn status_line(response: &str) -> &str {
+    response.lines().next().unwrap()
+}
+
+pub(crate) fn response_body(response: &str) -> &str {
+    response.split_once("\r\n\r\n").unwrap().1
+}
+
+/// Upload a file as multipart form data with additional raw header lines.
+pub(crate) async fn upload(
+    address: std::net::SocketAddr,
+    extra_headers: &str,
+    file: &[u8],
+) -> String {
+    upload_with_fields(address, extra_headers, &[], file).await
+}
+
+/// Upload a file as multipart form data preceded by the given option fields.
+pub(crate) async fn upload_with_fields(
+    address: std::net::SocketAddr,
+    extra_headers: &str,
+    fields: &[(&str, &str)],
+    file: &[u8],
+) -> String {
+    let mut body = Vec::new();
+    for (name, value) in fields {
+        body.extend_from_slice(
+            format!(
+                "--boundary\r\n\
+                 Content-Disposition: form-data; name=\"{name}\"\r\n\r\n\
+                 {value}\r\n"
+            )
+            .as_bytes(),
+        );
+    }
+    body.extend_from_slice(
+        b"--boundary\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"file\"\r\n\
+        Content-Type: application/octet-stream\r\n\r\n",
+    );
+    body.extend_from_slice(file);
+    body.extend_from_slice(b"\r\n--boundary--\r\n");
+
+    let mut request = format!(
+        "POST /upload HTTP/1.1\r\n\
+         Host: {address}\r\n\
+         Authorization: Bearer {TEST_TOKEN}\r\n\
+         Content-Type: multipart/form-data; boundary=boundary\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         {extra_headers}\r\n",
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(&body);
+    send(address, &request).await
+}
+
+/// Upload several files in a single multipart request.
+pub(crate) async fn upload_files(address: std::net::SocketAddr, files: &[&[u8]]) -> String {
+    let mut body = Vec::new();
+    for (index, file) in files.iter().enumerate() {
+        body.extend_from_slice(
+            format!(
+                "--boundary\r\n\
+                 Content-Disposition: form-data; name=\"file{index}\"; filename=\"file{index}\"\r\n\
+                 Content-Type: application/octet-stream\r\n\r\n"
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(file);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(b"--boundary--\r\n");
+
+    let mut request = format!(
+        "POST /upload HTTP/1.1\r\n\
+         Host: {address}\r\n\
+         Authorization: Bearer {TEST_TOKEN}\r\n\
+         Content-Type: multipart/form-data; boundary=boundary\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(&body);
+    send(address, &request).await
+}
+
+/// Download an upload from the path and query of its url.
+pub(crate) async fn download(address: std::net::SocketAddr, url: &str) -> String {
+    let path = &url[url.find("/upload/").unwrap()..];
+    send(
+        address,
+        format!("GET {path} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n").as_bytes(),
+    )
+    .await
+}
+
+/// Download an upload and read the raw bytes of its body.
+pub(crate) async fn download_bytes(address: std::net::SocketAddr, url: &str) -> Vec<u8> {
+    let path = &url[url.find("/upload/").unwrap()..];
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream
+        .write_all(
+            format!("GET {path} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n")
+                .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    let body = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .unwrap()
+        + 4;
+    response.split_off(body)
+}
+
+/// Encode a blank PNG of the given size for tests that upload an image.
+pub(crate) fn png_fixture(width: u32, height: u32) -> Vec<u8> {
+    let mut image = Vec::new();
+    image::RgbImage::new(width, height)
+        .write_to(
+            &mut std::io::Cursor::new(&mut image),
+            image::ImageFormat::Png,
+        )
+        .unwrap();
+    image
+}
+
+/// Parse the JSON body of a response, such as the response to an upload.
+pub(crate) fn upload_json(response: &str) -> serde_json::Value {
+    serde_json::from_str(response_body(response)).unwrap()
+}
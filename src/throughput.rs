@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::info;
+
+/// Counts the bytes and requests of uploads and downloads between periodic summaries.
+#[derive(Debug, Default)]
+pub struct Throughput {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    uploads: AtomicU64,
+    downloads: AtomicU64,
+}
+
+/// Totals counted by a [`Throughput`] since its last summary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThroughputSummary {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub uploads: u64,
+    pub downloads: u64,
+}
+
+impl Throughput {
+    /// Count a completed upload of the given number of bytes.
+    pub fn record_upload(&self, bytes: u64) {
+        self.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+        self.uploads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a served download of the given number of bytes.
+    pub fn record_download(&self, bytes: u64) {
+        self.bytes_out.fetch_add(bytes, Ordering::Relaxed);
+        self.downloads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get the totals counted since the last summary and start counting again.
+    pub fn take_summary(&self) -> ThroughputSummary {
+        ThroughputSummary {
+            bytes_in: self.bytes_in.swap(0, Ordering::Relaxed),
+            bytes_out: self.bytes_out.swap(0, Ordering::Relaxed),
+            uploads: self.uploads.swap(0, Ordering::Relaxed),
+            downloads: self.downloads.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    /// Log the totals counted since the last summary and start counting again.
+    pub fn log_summary(&self) {
+        let summary = self.take_summary();
+        info!(
+            "Throughput since last summary: {} in over {} uploads, {} out over {} downloads",
+            bytesize::ByteSize(summary.bytes_in).display().si(),
+            summary.uploads,
+            bytesize::ByteSize(summary.bytes_out).display().si(),
+            summary.downloads,
+        );
+    }
+}
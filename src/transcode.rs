@@ -0,0 +1,100 @@
+use std::process::Stdio;
+use tokio::{io::AsyncWriteExt, process::Command};
+use tracing::warn;
+
+/// Program used to transcode images, looked up on the `PATH`.
+pub const FFMPEG_PROGRAM: &str = "ffmpeg";
+
+/// Transcode a HEIC/HEIF image to JPEG using ffmpeg.
+///
+/// Returns `None` with a warning if ffmpeg is unavailable, fails, or does not produce a JPEG,
+/// in which case the original image should be kept.
+pub async fn heif_to_jpeg(program: &str, bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut child = match Command::new(program)
+        .args([
+            "-hide_banner",
+            "-loglevel",
+            "error",
+            "-i",
+            "pipe:0",
+            "-frames:v",
+            "1",
+            "-c:v",
+            "mjpeg",
+            "-q:v",
+            "2",
+            "-f",
+            "image2pipe",
+            "pipe:1",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) => {
+            warn!("Failed to start {program} to transcode HEIF image: {err:?}");
+            return None;
+        }
+    };
+
+    // Write the input while the output is read so neither side can block on a full pipe.
+    let mut stdin = child.stdin.take()?;
+    let input = bytes.to_vec();
+    let writer = tokio::spawn(async move {
+        // A failed write surfaces as ffmpeg failing to decode the input.
+        let _ = stdin.write_all(&input).await;
+    });
+    let output = match child.wait_with_output().await {
+        Ok(output) => output,
+        Err(err) => {
+            warn!("Failed to transcode HEIF image with {program}: {err:?}");
+            return None;
+        }
+    };
+    let _ = writer.await;
+
+    if !output.status.success() {
+        warn!(
+            "Failed to transcode HEIF image with {program}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return None;
+    }
+    if infer::get(&output.stdout).is_none_or(|kind| kind.mime_type() != "image/jpeg") {
+        warn!("Transcoding HEIF image with {program} did not produce a JPEG");
+        return None;
+    }
+    Some(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FFMPEG_PROGRAM;
+
+    #[tokio::test]
+    async fn test_heif_to_jpeg_unavailable() {
+        // Test transcoding falls back when the program is unavailable.
+        assert!(
+            super::heif_to_jpeg("dollshare-test-missing-program", b"image")
+                .await
+                .is_none()
+        );
+
+        // Test transcoding falls back when the program does not produce a JPEG.
+        assert!(super::heif_to_jpeg("true", b"image").await.is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires ffmpeg with HEIF support and a sample image at DOLLSHARE_TEST_HEIF"]
+    async fn test_heif_to_jpeg() {
+        let bytes = std::fs::read(std::env::var("DOLLSHARE_TEST_HEIF").unwrap()).unwrap();
+        assert_eq!(infer::get(&bytes).unwrap().mime_type(), "image/heif");
+
+        // Test a HEIF image is transcoded to a JPEG.
+        let jpeg = super::heif_to_jpeg(FFMPEG_PROGRAM, &bytes).await.unwrap();
+        assert_eq!(infer::get(&jpeg).unwrap().mime_type(), "image/jpeg");
+    }
+}
@@ -0,0 +1,126 @@
+use crate::AppState;
+use axum::{
+    extract::{Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::Response,
+};
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+};
+use tracing::debug;
+
+/// Tracks the total number of bytes of uploads that are currently being processed.
+#[derive(Debug)]
+pub struct UploadBudget {
+    max_bytes: u64,
+    reserved_bytes: AtomicU64,
+}
+
+/// A reservation against an [`UploadBudget`] that is released when dropped.
+#[derive(Debug)]
+pub struct UploadBudgetReservation {
+    budget: Arc<UploadBudget>,
+    bytes: u64,
+}
+
+impl UploadBudget {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            reserved_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Reserve bytes against the budget, returns `None` if the reservation would exceed it.
+    pub fn try_reserve(self: &Arc<Self>, bytes: u64) -> Option<UploadBudgetReservation> {
+        self.reserved_bytes
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |reserved| {
+                reserved
+                    .checked_add(bytes)
+                    .filter(|total| *total <= self.max_bytes)
+            })
+            .ok()?;
+        Some(UploadBudgetReservation {
+            budget: Arc::clone(self),
+            bytes,
+        })
+    }
+
+    /// Middleware that reserves the request's declared `Content-Length` against the
+    /// server's [`UploadBudget`] for the duration of the request.
+    ///
+    /// Requests without a declared length reserve the maximum upload size.
+    pub async fn reserve_middleware(
+        State(state): State<AppState>,
+        request: Request,
+        next: Next,
+    ) -> Result<Response, StatusCode> {
+        let Some(budget) = &state.upload_budget else {
+            return Ok(next.run(request).await);
+        };
+        let bytes = request
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(state.upload_size_limit);
+        let Some(_reservation) = budget.try_reserve(bytes) else {
+            debug!("Rejecting upload - in-flight upload budget exhausted");
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        };
+        Ok(next.run(request).await)
+    }
+
+    #[cfg(feature = "upload-from-url")]
+    /// Middleware that reserves the maximum upload size against the server's [`UploadBudget`]
+    /// for the duration of requests whose upload is fetched rather than sent in their body.
+    pub async fn reserve_limit_middleware(
+        State(state): State<AppState>,
+        request: Request,
+        next: Next,
+    ) -> Result<Response, StatusCode> {
+        let Some(budget) = &state.upload_budget else {
+            return Ok(next.run(request).await);
+        };
+        let Some(_reservation) = budget.try_reserve(state.upload_size_limit) else {
+            debug!("Rejecting upload - in-flight upload budget exhausted");
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        };
+        Ok(next.run(request).await)
+    }
+}
+
+impl Drop for UploadBudgetReservation {
+    fn drop(&mut self) {
+        self.budget
+            .reserved_bytes
+            .fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UploadBudget;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_try_reserve() {
+        let budget = Arc::new(UploadBudget::new(100));
+
+        // Test concurrent reservations within the budget succeed.
+        let first = budget.try_reserve(60).unwrap();
+        let second = budget.try_reserve(40).unwrap();
+
+        // Test reservations past the budget are rejected.
+        assert!(budget.try_reserve(1).is_none());
+
+        // Test dropped reservations are released back to the budget.
+        drop(first);
+        assert!(budget.try_reserve(60).is_some());
+        drop(second);
+        assert!(budget.try_reserve(100).is_some());
+        assert!(budget.try_reserve(101).is_none());
+    }
+}
@@ -0,0 +1,72 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+/// Per-upload locks that serialize operations on the same upload id while allowing
+/// operations on different ids to proceed concurrently.
+#[derive(Debug, Default)]
+pub struct UploadLocks {
+    locks: Mutex<HashMap<String, Weak<AsyncMutex<()>>>>,
+}
+
+/// A held lock on an upload id, released when dropped.
+pub type UploadLock = OwnedMutexGuard<()>;
+
+impl UploadLocks {
+    /// Wait for exclusive access to the given upload id.
+    pub async fn lock(&self, id: &str) -> UploadLock {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap();
+            // Forget the locks of ids that are no longer held or waited on.
+            locks.retain(|_, lock| lock.strong_count() > 0);
+            match locks.get(id).and_then(Weak::upgrade) {
+                Some(lock) => lock,
+                None => {
+                    let lock = Arc::new(AsyncMutex::new(()));
+                    locks.insert(id.to_string(), Arc::downgrade(&lock));
+                    lock
+                }
+            }
+        };
+        lock.lock_owned().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UploadLocks;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_lock() {
+        let locks = UploadLocks::default();
+        let held = locks.lock("abc").await;
+
+        // Test a different id can be locked while another is held.
+        assert!(
+            timeout(Duration::from_millis(50), locks.lock("def"))
+                .await
+                .is_ok()
+        );
+
+        // Test the same id waits until the held lock is released.
+        assert!(
+            timeout(Duration::from_millis(50), locks.lock("abc"))
+                .await
+                .is_err()
+        );
+        drop(held);
+        assert!(
+            timeout(Duration::from_millis(50), locks.lock("abc"))
+                .await
+                .is_ok()
+        );
+
+        // Test released locks are forgotten.
+        drop(locks.lock("ghi").await);
+        assert!(locks.locks.lock().unwrap().len() <= 1);
+    }
+}